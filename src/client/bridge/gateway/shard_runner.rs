@@ -27,6 +27,8 @@ use crate::framework::Framework;
 #[cfg(feature = "voice")]
 use crate::client::bridge::voice::ClientVoiceManager;
 #[cfg(feature = "voice")]
+use crate::model::event::{VoiceServerUpdateEvent, VoiceStateUpdateEvent};
+#[cfg(feature = "voice")]
 use tokio::sync::Mutex;
 #[cfg(feature = "collector")]
 use crate::collector::{MessageFilter, ReactionAction, ReactionFilter};
@@ -439,24 +441,29 @@ impl ShardRunner {
                     self.runner_tx.clone(),
                 );
             },
-            Event::VoiceServerUpdate(ref event) => {
-                if let Some(guild_id) = event.guild_id {
-                    let mut manager = self.voice_manager.lock().await;
-                    let search = manager.get_mut(guild_id);
-
-                    if let Some(handler) = search {
-                        handler.update_server(&event.endpoint, &event.token);
+            Event::VoiceServerUpdate(VoiceServerUpdateEvent { guild_id: Some(guild_id), .. })
+            | Event::VoiceStateUpdate(VoiceStateUpdateEvent { guild_id: Some(guild_id), .. }) => {
+                // Handlers check moves against the guild's AFK channel, so
+                // keep theirs up to date, unless the guild is not cached.
+                #[cfg(feature = "cache")]
+                let afk_channel_id = match event {
+                    Event::VoiceStateUpdate(_) => self.cache_and_http.cache
+                        .guild_field(guild_id, |guild| guild.afk_channel_id)
+                        .await,
+                    _ => None,
+                };
+
+                let mut manager = self.voice_manager.lock().await;
+
+                if let Some(handler) = manager.get_mut(guild_id) {
+                    #[cfg(feature = "cache")]
+                    {
+                        if let Some(afk_channel_id) = afk_channel_id {
+                            handler.set_afk_channel(afk_channel_id);
+                        }
                     }
-                }
-            },
-            Event::VoiceStateUpdate(ref event) => {
-                if let Some(guild_id) = event.guild_id {
-                    let mut manager = self.voice_manager.lock().await;
-                    let search = manager.get_mut(guild_id);
 
-                    if let Some(handler) = search {
-                        handler.update_state(&event.voice_state);
-                    }
+                    handler.process_dispatch(event);
                 }
             },
             _ => {},
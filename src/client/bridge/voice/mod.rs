@@ -2,7 +2,7 @@ use crate::gateway::InterMessage;
 use std::collections::HashMap;
 use futures::channel::mpsc::UnboundedSender as Sender;
 use crate::model::id::{ChannelId, GuildId, UserId};
-use crate::voice::{Handler, Manager};
+use crate::voice::{Handler, HandlerConfig, Manager, VoiceError};
 use crate::utils;
 
 pub struct ClientVoiceManager {
@@ -47,6 +47,20 @@ impl ClientVoiceManager {
         self.managers.get_mut(&sid).map(|manager| manager.join(gid, channel_id))
     }
 
+    /// Refer to [`Manager::join_with`].
+    ///
+    /// This is a shortcut to retrieving the inner [`Manager`] and then calling
+    /// its `join_with` method.
+    ///
+    /// [`Manager`]: ../../../voice/struct.Manager.html
+    /// [`Manager::join_with`]: ../../../voice/struct.Manager.html#method.join_with
+    pub fn join_with<C, G>(&mut self, guild_id: G, channel_id: C, config: HandlerConfig)
+        -> Option<Result<&mut Handler, VoiceError>> where C: Into<ChannelId>, G: Into<GuildId> {
+        let (gid, sid) = self.manager_info(guild_id);
+
+        self.managers.get_mut(&sid).map(|manager| manager.join_with(gid, channel_id, config))
+    }
+
     /// Refer to [`Manager::leave`].
     ///
     /// This is a shortcut to retrieving the inner [`Manager`] and then calling
@@ -245,3 +245,57 @@ pub mod close_codes {
     /// Disallowed gateway intents have been provided.
     pub const DISALLOWED_GATEWAY_INTENTS: u16 = 4014;
 }
+
+/// Special codes sent by the voice gateway when it closes a connection.
+pub mod voice_close_codes {
+    /// An invalid opcode was sent.
+    ///
+    /// Can resume.
+    pub const UNKNOWN_OPCODE: u16 = 4001;
+    /// An invalid payload was sent.
+    ///
+    /// Can resume.
+    pub const DECODE_ERROR: u16 = 4002;
+    /// A payload was sent prior to identifying.
+    ///
+    /// Can reconnect.
+    pub const NOT_AUTHENTICATED: u16 = 4003;
+    /// The token sent with the identify payload was incorrect.
+    ///
+    /// Needs a new session.
+    pub const AUTHENTICATION_FAILED: u16 = 4004;
+    /// More than one identify payload was sent.
+    ///
+    /// Can resume.
+    pub const ALREADY_AUTHENTICATED: u16 = 4005;
+    /// The session is no longer valid.
+    ///
+    /// Needs a new session.
+    pub const SESSION_NO_LONGER_VALID: u16 = 4006;
+    /// A session timed out.
+    ///
+    /// Needs a new session.
+    pub const SESSION_TIMEOUT: u16 = 4009;
+    /// The voice server to connect to could not be found.
+    ///
+    /// Needs a new session.
+    pub const SERVER_NOT_FOUND: u16 = 4011;
+    /// The protocol sent in the select protocol payload was not recognised.
+    ///
+    /// Cannot reconnect.
+    pub const UNKNOWN_PROTOCOL: u16 = 4012;
+    /// The connection was removed from its channel, such as by being kicked,
+    /// the channel being deleted, or the guild moving to another server.
+    ///
+    /// Needs a new session.
+    pub const DISCONNECTED: u16 = 4014;
+    /// The voice server crashed.
+    ///
+    /// Can resume.
+    pub const VOICE_SERVER_CRASHED: u16 = 4015;
+    /// The encryption mode sent in the select protocol payload was not
+    /// recognised.
+    ///
+    /// Cannot reconnect.
+    pub const UNKNOWN_ENCRYPTION_MODE: u16 = 4016;
+}
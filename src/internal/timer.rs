@@ -1,4 +1,7 @@
-use tokio::time::{delay_until, Duration, Instant};
+use tokio::{
+    task::yield_now,
+    time::{delay_until, Duration, Instant},
+};
 
 /// A reusable timer that keeps track of its duration/interval/period.
 #[derive(Debug)]
@@ -25,6 +28,19 @@ impl Timer {
         self.increment();
     }
 
+    /// Blocks until the due time as with `hold`, but more exactly: the
+    /// runtime's timer is only trusted to wake within `margin` of it, after
+    /// which other tasks are yielded to until the due time is reached.
+    pub async fn hold_precise(&mut self, margin: Duration) {
+        delay_until(self.due.checked_sub(margin).unwrap_or(self.due)).await;
+
+        while Instant::now() < self.due {
+            let _ = yield_now().await;
+        }
+
+        self.increment();
+    }
+
     /// Returns true if the timer is expired (current instant past `due` time).
     /// Resets the timer `duration` later if it is.
     pub fn check(&mut self) -> bool {
@@ -36,6 +52,21 @@ impl Timer {
         }
     }
 
+    /// Returns how far past the due time the current instant is.
+    pub fn lateness(&self) -> Duration {
+        Instant::now().saturating_duration_since(self.due)
+    }
+
+    /// Moves the due time forward past every whole `duration` which has
+    /// already been missed, returning how many were skipped. The timer is
+    /// left due within the last `duration`.
+    pub fn skip_missed(&mut self) -> u32 {
+        let missed = (self.lateness().as_nanos() / self.duration.as_nanos().max(1)) as u32;
+        self.due += self.duration * missed;
+
+        missed
+    }
+
     /// Resets the timer to expire 1 `duration` later than it was **previously set to expire**.
     /// Does not depend on the actual current time.
     fn increment(&mut self) {
@@ -48,3 +79,23 @@ impl Timer {
         self.due = Instant::now() + self.duration;
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_skip_missed() {
+        let mut timer = Timer::new(20);
+        assert_eq!(timer.skip_missed(), 0);
+
+        // Three whole frames and part of a fourth have been missed.
+        timer.due = Instant::now() - Duration::from_millis(70);
+        let due = timer.due;
+
+        assert_eq!(timer.skip_missed(), 3);
+        assert_eq!(timer.due, due + Duration::from_millis(60));
+        assert!(timer.lateness() < Duration::from_millis(20));
+        assert_eq!(timer.skip_missed(), 0);
+    }
+}
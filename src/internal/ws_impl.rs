@@ -174,3 +174,42 @@ pub(crate) async fn create_native_tls_client(url: Url) -> Result<WsStream> {
 
     Ok(stream)
 }
+
+/// Connects to a WebSocket at the given address, rather than the one the
+/// URL's host resolves to, while still verifying the host's certificate.
+#[cfg(all(feature = "voice", feature = "rustls_backend", not(feature = "native_tls_backend")))]
+#[instrument]
+pub(crate) async fn create_rustls_client_to(url: Url, address: std::net::SocketAddr) -> Result<WsStream> {
+    let socket = tokio::net::TcpStream::connect(address).await?;
+    let (stream, _) = async_tungstenite::tokio::client_async_tls_with_config::<Url, _>(
+        url,
+        socket,
+        Some(async_tungstenite::tungstenite::protocol::WebSocketConfig {
+            max_message_size: None,
+            max_frame_size: None,
+            max_send_queue: None,
+        }))
+        .await
+        .map_err(|_| RustlsError::HandshakeError)?;
+
+    Ok(stream)
+}
+
+/// Connects to a WebSocket at the given address, rather than the one the
+/// URL's host resolves to, while still verifying the host's certificate.
+#[cfg(all(feature = "voice", feature = "native_tls_backend"))]
+#[instrument]
+pub(crate) async fn create_native_tls_client_to(url: Url, address: std::net::SocketAddr) -> Result<WsStream> {
+    let socket = tokio::net::TcpStream::connect(address).await?;
+    let (stream, _) = async_tungstenite::tokio::client_async_tls_with_config::<Url, _>(
+        url,
+        socket,
+        Some(async_tungstenite::tungstenite::protocol::WebSocketConfig {
+            max_message_size: None,
+            max_frame_size: None,
+            max_send_queue: None,
+        }))
+        .await?;
+
+    Ok(stream)
+}
@@ -35,6 +35,8 @@ pub struct VoiceState {
     pub self_deaf: bool,
     pub self_mute: bool,
     pub self_stream: Option<bool>,
+    #[serde(default)]
+    pub self_video: bool,
     pub session_id: String,
     pub suppress: bool,
     pub token: Option<String>,
@@ -53,6 +55,7 @@ impl fmt::Debug for VoiceState {
             .field("self_deaf", &self.self_deaf)
             .field("self_mute", &self.self_mute)
             .field("self_stream", &self.self_stream)
+            .field("self_video", &self.self_video)
             .field("session_id", &self.session_id)
             .field("suppress", &self.suppress)
             .field("user_id", &self.user_id)
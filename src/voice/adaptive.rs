@@ -0,0 +1,329 @@
+use audiopus::Bitrate;
+use std::time::Duration;
+
+/// How much each frame's encode time moves the running average.
+const AVERAGE_WEIGHT: f64 = 0.1;
+
+/// The average encode time above which complexity is lowered: a quarter of a
+/// frame's 20ms budget, leaving the rest for mixing, encryption and sending.
+const SLOW_ENCODE: Duration = Duration::from_millis(5);
+
+/// The average encode time below which complexity is raised again, kept well
+/// under `SLOW_ENCODE` so that the complexity does not flap between levels.
+const FAST_ENCODE: Duration = Duration::from_millis(2);
+
+/// How many frames must pass after a change before another, so that each
+/// level is given a second to settle.
+const ADJUST_INTERVAL_FRAMES: u32 = 50;
+
+/// Tracks how long frames take to encode, and steps the Opus complexity down
+/// while encoding is slow and back up once it has recovered.
+#[derive(Debug)]
+pub(crate) struct ComplexityScaler {
+    enabled: bool,
+    /// The configured complexity, which is never exceeded.
+    max: u8,
+    current: u8,
+    average: Option<f64>,
+    frames_since_change: u32,
+}
+
+impl ComplexityScaler {
+    pub fn new(max: u8) -> Self {
+        Self {
+            enabled: false,
+            max,
+            current: max,
+            average: None,
+            frames_since_change: 0,
+        }
+    }
+
+    /// The running average of encode times.
+    pub fn average(&self) -> Duration {
+        Duration::from_secs_f64(self.average.unwrap_or_default())
+    }
+
+    /// The complexity which should be in use.
+    pub fn current(&self) -> u8 {
+        self.current
+    }
+
+    /// Enables or disables scaling, returning the complexity to apply if it
+    /// changes as a result.
+    pub fn set_enabled(&mut self, enabled: bool) -> Option<u8> {
+        self.enabled = enabled;
+        self.frames_since_change = 0;
+
+        if !enabled && self.current != self.max {
+            self.current = self.max;
+
+            return Some(self.current);
+        }
+
+        None
+    }
+
+    /// Sets the configured complexity, which the encoder has been reset to.
+    pub fn set_max(&mut self, max: u8) {
+        self.max = max;
+        self.current = max;
+        self.frames_since_change = 0;
+    }
+
+    /// Records how long a frame took to encode, returning a new complexity
+    /// to apply if one is needed.
+    pub fn record(&mut self, took: Duration) -> Option<u8> {
+        let took = took.as_secs_f64();
+
+        self.average = Some(match self.average {
+            Some(average) => average + (took - average) * AVERAGE_WEIGHT,
+            None => took,
+        });
+
+        if !self.enabled {
+            return None;
+        }
+
+        self.frames_since_change = self.frames_since_change.saturating_add(1);
+
+        if self.frames_since_change < ADJUST_INTERVAL_FRAMES {
+            return None;
+        }
+
+        let average = self.average();
+
+        if average > SLOW_ENCODE && self.current > 0 {
+            self.current -= 1;
+        } else if average < FAST_ENCODE && self.current < self.max {
+            self.current += 1;
+        } else {
+            return None;
+        }
+
+        self.frames_since_change = 0;
+
+        Some(self.current)
+    }
+}
+
+/// How many frames of packet loss are judged at once: 5 seconds.
+const LOSS_WINDOW_FRAMES: u32 = 250;
+
+/// The fewest packets a window must hold for its loss to be judged, so that
+/// a few packets from a quiet channel cannot move the bitrate.
+const MIN_WINDOW_PACKETS: u64 = 50;
+
+/// The loss in a window at or above which the bitrate is stepped down.
+const HIGH_LOSS: f64 = 0.05;
+
+/// The loss in a window below which it counts as clean.
+const LOW_LOSS: f64 = 0.01;
+
+/// How many clean windows in a row are needed before the bitrate is stepped
+/// up again, so that it does not flap between steps.
+const CLEAN_WINDOWS: u32 = 3;
+
+/// The largest jump in sequence numbers treated as lost packets, rather than
+/// the sender having restarted.
+const MAX_SEQUENCE_GAP: u16 = 1000;
+
+/// The bitrate below which an encoder with adaptive channels falls back to
+/// mono, where stereo would leave too few bits for each channel.
+const MIN_STEREO_BITRATE: i32 = 48_000;
+
+/// Whether audio at a bitrate would be better sent in mono. Automatic and
+/// maximum bitrates are never low enough.
+pub(crate) fn prefers_mono(bitrate: Bitrate) -> bool {
+    match bitrate {
+        Bitrate::BitsPerSecond(bits) => bits < MIN_STEREO_BITRATE,
+        _ => false,
+    }
+}
+
+/// Counts the packets missing between two RTP sequence numbers from the same
+/// sender, or returns `None` if the later packet is a duplicate or arrived
+/// out of order. A jump too large to be loss counts as none.
+pub(crate) fn lost_between(previous: u16, sequence: u16) -> Option<u16> {
+    match sequence.wrapping_sub(previous) {
+        gap if gap == 0 || gap >= 0x8000 => None,
+        gap if gap > MAX_SEQUENCE_GAP => Some(0),
+        gap => Some(gap - 1),
+    }
+}
+
+/// Steps the bitrate through a ladder of steps, from the first to the last,
+/// while packet loss is high, and back once it has stayed low.
+#[derive(Debug, Default)]
+pub(crate) struct BitrateLadder {
+    steps: Vec<Bitrate>,
+    index: usize,
+    received: u64,
+    lost: u64,
+    frames: u32,
+    clean_windows: u32,
+}
+
+impl BitrateLadder {
+    /// How many steps down the ladder the bitrate is.
+    pub fn step(&self) -> usize {
+        self.index
+    }
+
+    /// The bitrate which should be in use, if the ladder has any steps.
+    pub fn current(&self) -> Option<Bitrate> {
+        self.steps.get(self.index).copied()
+    }
+
+    /// Replaces the steps, starting again from the first.
+    pub fn set_steps(&mut self, steps: Vec<Bitrate>) {
+        *self = Self {
+            steps,
+            ..Self::default()
+        };
+    }
+
+    /// Records packets which arrived, and those found missing before them.
+    pub fn record(&mut self, received: u64, lost: u64) {
+        self.received += received;
+        self.lost += lost;
+    }
+
+    /// Advances by a frame, returning a new bitrate to apply at the end of a
+    /// window which calls for one.
+    pub fn tick(&mut self) -> Option<Bitrate> {
+        if self.steps.is_empty() {
+            return None;
+        }
+
+        self.frames += 1;
+
+        if self.frames < LOSS_WINDOW_FRAMES {
+            return None;
+        }
+
+        let total = self.received + self.lost;
+        let lost = self.lost;
+        self.frames = 0;
+        self.received = 0;
+        self.lost = 0;
+
+        // Too little was heard to judge, which neither raises nor lowers.
+        if total < MIN_WINDOW_PACKETS {
+            return None;
+        }
+
+        let loss = lost as f64 / total as f64;
+
+        if loss >= HIGH_LOSS {
+            self.clean_windows = 0;
+
+            if self.index + 1 < self.steps.len() {
+                self.index += 1;
+
+                return self.current();
+            }
+        } else if loss < LOW_LOSS {
+            self.clean_windows += 1;
+
+            if self.clean_windows >= CLEAN_WINDOWS && self.index > 0 {
+                self.index -= 1;
+                self.clean_windows = 0;
+
+                return self.current();
+            }
+        } else {
+            self.clean_windows = 0;
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn record_for(scaler: &mut ComplexityScaler, frames: u32, took: Duration) -> Option<u8> {
+        let mut last = None;
+
+        for _ in 0..frames {
+            last = scaler.record(took).or(last);
+        }
+
+        last
+    }
+
+    #[test]
+    fn test_complexity_scaling() {
+        let mut scaler = ComplexityScaler::new(10);
+        let slow = Duration::from_millis(15);
+
+        // Nothing changes while disabled.
+        assert_eq!(record_for(&mut scaler, 100, slow), None);
+
+        scaler.set_enabled(true);
+        assert_eq!(record_for(&mut scaler, ADJUST_INTERVAL_FRAMES, slow), Some(9));
+        assert_eq!(record_for(&mut scaler, ADJUST_INTERVAL_FRAMES * 3, slow), Some(6));
+
+        // Recovery raises complexity once the average has fallen.
+        let fast = Duration::from_micros(500);
+        assert_eq!(record_for(&mut scaler, ADJUST_INTERVAL_FRAMES * 2, fast), Some(8));
+
+        assert_eq!(scaler.set_enabled(false), Some(10));
+    }
+
+    #[test]
+    fn test_lost_between() {
+        assert_eq!(lost_between(10, 11), Some(0));
+        assert_eq!(lost_between(10, 14), Some(3));
+        assert_eq!(lost_between(u16::MAX, 1), Some(1));
+        assert_eq!(lost_between(10, 10), None);
+        assert_eq!(lost_between(10, 9), None);
+        assert_eq!(lost_between(10, 5000), Some(0));
+    }
+
+    fn window(ladder: &mut BitrateLadder, received: u64, lost: u64) -> Option<Bitrate> {
+        ladder.record(received, lost);
+
+        (0..LOSS_WINDOW_FRAMES).filter_map(|_| ladder.tick()).last()
+    }
+
+    #[test]
+    fn test_bitrate_ladder() {
+        let (high, mid, low) = (
+            Bitrate::BitsPerSecond(128_000),
+            Bitrate::BitsPerSecond(64_000),
+            Bitrate::BitsPerSecond(32_000),
+        );
+        let mut ladder = BitrateLadder::default();
+        assert_eq!(window(&mut ladder, 0, 250), None);
+
+        ladder.set_steps(vec![high, mid, low]);
+        assert_eq!(ladder.current(), Some(high));
+
+        // A quiet window is not judged, however lossy.
+        assert_eq!(window(&mut ladder, 10, 10), None);
+
+        assert_eq!(window(&mut ladder, 200, 50), Some(mid));
+        assert_eq!(window(&mut ladder, 200, 50), Some(low));
+        assert_eq!(window(&mut ladder, 200, 50), None);
+
+        // Recovery needs several clean windows in a row.
+        assert_eq!(window(&mut ladder, 250, 0), None);
+        assert_eq!(window(&mut ladder, 250, 0), None);
+        assert_eq!(window(&mut ladder, 245, 5), None);
+        assert_eq!(window(&mut ladder, 250, 0), None);
+        assert_eq!(window(&mut ladder, 250, 0), None);
+        assert_eq!(window(&mut ladder, 250, 0), Some(mid));
+    }
+
+    #[test]
+    fn test_prefers_mono() {
+        assert!(prefers_mono(Bitrate::BitsPerSecond(32_000)));
+        assert!(!prefers_mono(Bitrate::BitsPerSecond(MIN_STEREO_BITRATE)));
+        assert!(!prefers_mono(Bitrate::BitsPerSecond(128_000)));
+        assert!(!prefers_mono(Bitrate::Auto));
+        assert!(!prefers_mono(Bitrate::Max));
+    }
+}
@@ -1,14 +1,40 @@
-use tokio::sync::Mutex;
-use audiopus::{Bitrate, SampleRate};
+use tokio::sync::{watch, Mutex};
+use audiopus::{Bitrate, Error as OpusError, SampleRate};
 use std::{
-    sync::Arc,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
     time::Duration,
 };
 use async_trait::async_trait;
+use futures::future::BoxFuture;
+use crate::model::id::UserId;
+use super::{dynamics::Equalizer, DuckConfig, EqBand, RtcpReport, TrackOptions, VoiceError};
 
 pub const HEADER_LEN: usize = 12;
+
 pub const SAMPLE_RATE: SampleRate = SampleRate::Hz48000;
 pub const DEFAULT_BITRATE: Bitrate = Bitrate::BitsPerSecond(128_000);
+pub const MIN_BITRATE: i32 = 500;
+pub const MAX_BITRATE: i32 = 512_000;
+
+/// The id given to the next track created.
+static NEXT_TRACK_ID: AtomicU64 = AtomicU64::new(1);
+
+/// The default time over which the mixer ramps a change in gain.
+pub(crate) const DEFAULT_VOLUME_RAMP: Duration = Duration::from_millis(15);
+
+/// Clamps a bitrate to the range supported by libopus, failing if it is not
+/// positive.
+pub(crate) fn clamp_bitrate(bitrate: Bitrate) -> Result<Bitrate, VoiceError> {
+    match bitrate {
+        Bitrate::BitsPerSecond(bits) if bits <= 0 => Err(VoiceError::InvalidBitrate(bits)),
+        Bitrate::BitsPerSecond(bits) if bits < MIN_BITRATE => Ok(Bitrate::BitsPerSecond(MIN_BITRATE)),
+        Bitrate::BitsPerSecond(bits) if bits > MAX_BITRATE => Ok(Bitrate::BitsPerSecond(MAX_BITRATE)),
+        other => Ok(other),
+    }
+}
 
 /// A readable audio source.
 #[async_trait]
@@ -21,7 +47,59 @@ pub trait AudioSource: Send + Sync {
 
     async fn read_opus_frame(&mut self) -> Option<Vec<u8>>;
 
+    /// Decodes the next Opus frame and adds it to `float_buffer`, returning
+    /// the number of samples per channel decoded, `Some(0)` once the source
+    /// has ended, or `None` if it failed to read or decode.
     async fn decode_and_add_opus_frame(&mut self, float_buffer: &mut [f32; 1920], volume: f32) -> Option<usize>;
+
+    /// Whether [`seek`] is supported by this source.
+    ///
+    /// [`seek`]: #method.seek
+    fn is_seekable(&self) -> bool {
+        false
+    }
+
+    /// Moves the source to the given position, returning whether this
+    /// succeeded.
+    async fn seek(&mut self, _position: Duration) -> bool {
+        false
+    }
+
+    /// Returns a future which finds the total length of the source's audio
+    /// again, and updates that returned by [`total_duration`] to match, if
+    /// the source is able to; `None` by default.
+    ///
+    /// The future must not borrow the source, so that the source need not be
+    /// locked while it runs.
+    ///
+    /// [`total_duration`]: #method.total_duration
+    fn refresh_duration(&self) -> Option<BoxFuture<'static, Option<Duration>>> {
+        None
+    }
+
+    /// The total length of the source's audio, if known, such as for
+    /// progress bars. Sources read from a stream or live broadcast have none.
+    ///
+    /// The [`ffmpeg`] and [`ytdl`] sources find this when they are created.
+    ///
+    /// [`ffmpeg`]: fn.ffmpeg.html
+    /// [`ytdl`]: fn.ytdl.html
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Takes the title of the stream being played if it has changed since
+    /// this was last called, such as from the ICY metadata of an internet
+    /// radio station read by [`http`]; `None` by default.
+    ///
+    /// This is checked after each frame is read, and a
+    /// [`HandlerEvent::StreamMetadataUpdated`] is emitted for each new title.
+    ///
+    /// [`http`]: fn.http.html
+    /// [`HandlerEvent::StreamMetadataUpdated`]: enum.HandlerEvent.html#variant.StreamMetadataUpdated
+    fn take_stream_title(&mut self) -> Option<String> {
+        None
+    }
 }
 
 /// A receiver for incoming audio.
@@ -41,6 +119,42 @@ pub trait AudioReceiver: Send + Sync {
     async fn client_connect(&self, _ssrc: u32, _user_id: u64) { }
 
     async fn client_disconnect(&self, _user_id: u64) { }
+
+    /// Called when a received packet could not be decoded.
+    ///
+    /// If the packet's decoder is known, the lost audio is then concealed, and
+    /// passed to [`voice_packet`] as usual.
+    ///
+    /// [`voice_packet`]: #method.voice_packet
+    async fn decode_error(&self, _ssrc: u32, _error: &OpusError) { }
+
+    /// Called every 20ms with the audio of all current speakers mixed
+    /// together, as 1920 samples of interleaved 48kHz stereo PCM, unless
+    /// another format is set via [`Handler::set_receive_format`].
+    ///
+    /// This is delivered alongside [`voice_packet`], so a receiver may use
+    /// either or both. Each speaker's decoded audio is buffered by two frames
+    /// before it is mixed, to smooth over jitter in packet arrival, and mono
+    /// audio is upmixed to stereo. Samples are summed, and clipped to the
+    /// range of an `i16` rather than scaled, so many loud speakers will
+    /// distort. Silence is delivered while nobody is speaking.
+    ///
+    /// [`Handler::set_receive_format`]: struct.Handler.html#method.set_receive_format
+    /// [`voice_packet`]: #method.voice_packet
+    async fn mixed_audio(&self, _data: &[i16]) { }
+
+    /// Called with each RTCP sender or receiver report received, such as
+    /// for monitoring the quality of each user's connection.
+    ///
+    /// Discord is not documented to send RTCP, and may never do so, in which
+    /// case this is never called. Reports which refer to the handler's own
+    /// audio are also summarised in [`Handler::network_stats`].
+    ///
+    /// As no sender reports are sent by the handler, reports cannot be used
+    /// to find round trip times to it.
+    ///
+    /// [`Handler::network_stats`]: struct.Handler.html#method.network_stats
+    async fn rtcp_report(&self, _report: &RtcpReport) { }
 }
 
 #[derive(Clone, Copy)]
@@ -48,6 +162,12 @@ pub trait AudioReceiver: Send + Sync {
 pub enum AudioType {
     Opus,
     Pcm,
+    /// Ready Opus frames, read via [`AudioSource::read_opus_frame`] and sent
+    /// as they are, without being decoded or mixed, as from [`opus_frames`].
+    ///
+    /// [`AudioSource::read_opus_frame`]: trait.AudioSource.html#tymethod.read_opus_frame
+    /// [`opus_frames`]: fn.opus_frames.html
+    Passthrough,
 }
 
 /// Control object for audio playback.
@@ -110,10 +230,38 @@ pub struct Audio {
     /// Consider the position fields **read-only** for now.
     pub position: Duration,
     pub position_modified: bool,
+
+    /// How the other sources are ducked while this plays, if it is an
+    /// announcement.
+    pub(crate) announcement: Option<DuckConfig>,
+    /// The bitrate to encode at while this plays, if it has asked for one.
+    pub(crate) bitrate: Option<Bitrate>,
+    /// The bus the audio is grouped into, if any.
+    pub(crate) bus: Option<String>,
+    equalizer: Equalizer,
+    error_handler: Option<ErrorHandler>,
+    fade: Option<Fade>,
+    gain_ramp: GainRamp,
+    id: u64,
+    loop_region: Option<(Duration, Duration)>,
+    muted: bool,
+    /// The position at which the track ends, as if its source had.
+    trim_end: Option<Duration>,
+    /// The user who queued the track, from its metadata.
+    pub(crate) requester: Option<UserId>,
+    /// Whether the last frame of PCM read from the source was partial.
+    pub(crate) short_read: bool,
+    /// Why the track failed while playing, if it has, for the queue to
+    /// report.
+    pub(crate) failure: Option<VoiceError>,
+    status: watch::Sender<TrackStatus>,
+    status_rx: watch::Receiver<TrackStatus>,
 }
 
 impl Audio {
     pub fn new(source: Box<dyn AudioSource>) -> Self {
+        let (status, status_rx) = watch::channel(TrackStatus::Pending);
+
         Self {
             playing: true,
             volume: 1.0,
@@ -121,9 +269,31 @@ impl Audio {
             source,
             position: Duration::new(0, 0),
             position_modified: false,
+            announcement: None,
+            bitrate: None,
+            bus: None,
+            equalizer: Equalizer::default(),
+            error_handler: None,
+            fade: None,
+            gain_ramp: GainRamp::default(),
+            id: NEXT_TRACK_ID.fetch_add(1, Ordering::Relaxed),
+            loop_region: None,
+            muted: false,
+            trim_end: None,
+            requester: None,
+            short_read: false,
+            failure: None,
+            status,
+            status_rx,
         }
     }
 
+    /// Returns an id for the track, unique among all tracks created by the
+    /// process, which tells apart the tracks named in events.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
     /// Sets [`playing`] to `true` in a manner that allows method chaining.
     ///
     /// [`playing`]: #structfield.playing
@@ -151,9 +321,130 @@ impl Audio {
         self
     }
 
+    /// Silences or restores the audio in a manner that allows method
+    /// chaining, without changing its [`volume`].
+    ///
+    /// Unlike [`pause`], the source is still read while muted, so its
+    /// position keeps advancing, and unmuting resumes it in time with any
+    /// other sources. Muting and unmuting are ramped as a change of volume
+    /// is, so that they do not click.
+    ///
+    /// [`pause`]: #method.pause
+    /// [`volume`]: #structfield.volume
+    pub fn mute(&mut self, muted: bool) -> &mut Self {
+        self.muted = muted;
+
+        self
+    }
+
+    /// Whether the audio is muted via [`mute`].
+    ///
+    /// [`mute`]: #method.mute
+    pub fn is_muted(&self) -> bool {
+        self.muted
+    }
+
+    /// Groups the audio into a named bus, such as `"music"` or `"sfx"`, or
+    /// removes it from its bus if `None`, in a manner that allows method
+    /// chaining.
+    ///
+    /// Every source on a bus is scaled by the bus's volume, set via
+    /// [`Handler::set_bus_volume`], on top of its own [`volume`], and can be
+    /// stopped together via [`Handler::stop_bus`].
+    ///
+    /// [`Handler::set_bus_volume`]: struct.Handler.html#method.set_bus_volume
+    /// [`Handler::stop_bus`]: struct.Handler.html#method.stop_bus
+    /// [`volume`]: #structfield.volume
+    pub fn bus<S: Into<String>>(&mut self, bus: Option<S>) -> &mut Self {
+        self.bus = bus.map(Into::into);
+
+        self
+    }
+
+    /// The bus the audio is grouped into via [`bus`], if any.
+    ///
+    /// [`bus`]: #method.bus
+    pub fn current_bus(&self) -> Option<&str> {
+        self.bus.as_deref()
+    }
+
+    /// Gradually raises the gain applied on top of [`volume`] from silence to
+    /// full over the given duration, in a manner that allows method chaining.
+    ///
+    /// [`volume`]: #structfield.volume
+    pub fn fade_in(&mut self, duration: Duration) -> &mut Self {
+        self.fade = Some(Fade::new(0.0, 1.0, duration, false));
+
+        self
+    }
+
+    /// Gradually lowers the gain applied on top of [`volume`] to silence over
+    /// the given duration, in a manner that allows method chaining.
+    ///
+    /// Once the fade completes, the audio is removed from its connection as
+    /// though it had finished.
+    ///
+    /// [`volume`]: #structfield.volume
+    pub fn fade_out(&mut self, duration: Duration) -> &mut Self {
+        self.fade = Some(Fade::new(self.fade_gain(), 0.0, duration, true));
+
+        self
+    }
+
+    /// The gain currently applied by a fade, if any.
+    pub(crate) fn fade_gain(&self) -> f32 {
+        self.fade.map_or(1.0, |fade| fade.gain())
+    }
+
+    /// Fills `gains` with the gain of each sample of the next frame, from
+    /// [`volume`], any fade and whether it is muted, ramping from the
+    /// previous frame's gain over `ramp_len` samples rather than jumping.
+    ///
+    /// Unless this is an announcement, it is also ducked to `duck_gain`. The
+    /// volume of its bus, `bus_gain`, is applied last.
+    ///
+    /// [`volume`]: #structfield.volume
+    pub(crate) fn frame_gains(&mut self, ramp_len: usize, duck_gain: f32, bus_gain: f32, gains: &mut [f32]) {
+        let duck_gain = if self.announcement.is_some() { 1.0 } else { duck_gain };
+        let target = if self.muted {
+            0.0
+        } else {
+            self.volume * self.fade_gain() * duck_gain * bus_gain
+        };
+
+        self.gain_ramp.fill(target, ramp_len, gains);
+    }
+
+    /// Steps any fade forward by one frame, returning whether the audio has
+    /// faded out and should be stopped.
+    pub(crate) fn step_fade(&mut self) -> bool {
+        let fade = match self.fade.as_mut() {
+            Some(fade) => fade,
+            None => return false,
+        };
+
+        fade.elapsed += 1;
+
+        if fade.elapsed < fade.frames {
+            return false;
+        }
+
+        let stop = fade.stop;
+
+        // Keep a completed fade-out in place, so that the audio stays silent.
+        if !stop {
+            self.fade = None;
+        }
+
+        stop
+    }
+
     /// Change the position in the stream for subsequent playback.
     ///
-    /// Currently a No-op.
+    /// This takes effect on the next frame, and only if the source is
+    /// seekable, such as those created via [`voice::ffmpeg`].
+    ///
+    /// [`voice::ffmpeg`]: fn.ffmpeg.html
     pub fn position(&mut self, position: Duration) -> &mut Self {
         self.position = position;
         self.position_modified = true;
@@ -161,18 +452,473 @@ impl Audio {
         self
     }
 
-    /// Steps playback location forward by one frame.
+    /// Sets the bands of an equalizer applied to the audio before mixing,
+    /// replacing any set before. Passing no bands removes the equalizer.
+    ///
+    /// Each band is a peaking filter, and the bands are applied one after
+    /// another. Changes take effect on a playing track without a click, by
+    /// crossfading from the previous bands over the same time as a change of
+    /// volume is ramped.
+    ///
+    /// # Errors
     ///
-    /// *Used internally*, although in future this might affect seek position.
+    /// Returns [`VoiceError::InvalidEqBand`] for the first band with a
+    /// frequency which is not below the Nyquist frequency of 24 kHz, or a
+    /// quality factor which is not positive, in which case nothing is
+    /// changed.
+    ///
+    /// [`VoiceError::InvalidEqBand`]: enum.VoiceError.html#variant.InvalidEqBand
+    pub fn set_equalizer(&mut self, bands: &[EqBand]) -> Result<(), VoiceError> {
+        for band in bands {
+            band.validate()?;
+        }
+
+        self.equalizer.set_bands(bands.to_vec());
+
+        Ok(())
+    }
+
+    /// The bands of the equalizer, as set via [`set_equalizer`].
+    ///
+    /// [`set_equalizer`]: #method.set_equalizer
+    pub fn equalizer(&self) -> &[EqBand] {
+        self.equalizer.bands()
+    }
+
+    /// Applies the equalizer to a frame of interleaved stereo samples, with
+    /// changes crossfaded over `ramp_len` samples.
+    pub(crate) fn equalize(&mut self, frame: &mut [f32], ramp_len: usize) {
+        self.equalizer.apply(frame, ramp_len);
+    }
+
+    /// Repeats a region of the audio, given as its start and end positions,
+    /// until this is called again with `None`.
+    ///
+    /// Once playback reaches the end of the region, it returns to the start.
+    /// The source must be seekable.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`VoiceError::NotSeekable`] if the source cannot seek, and
+    /// [`VoiceError::LoopRegion`] if the region does not start before it ends,
+    /// or ends after the source does.
+    ///
+    /// [`VoiceError::LoopRegion`]: enum.VoiceError.html#variant.LoopRegion
+    /// [`VoiceError::NotSeekable`]: enum.VoiceError.html#variant.NotSeekable
+    pub fn set_loop_region(&mut self, region: Option<(Duration, Duration)>) -> Result<(), VoiceError> {
+        if let Some((start, end)) = region {
+            if !self.source.is_seekable() {
+                return Err(VoiceError::NotSeekable);
+            }
+
+            let too_long = matches!(self.source.total_duration(), Some(length) if end > length);
+
+            if start >= end || too_long {
+                return Err(VoiceError::LoopRegion);
+            }
+        }
+
+        self.loop_region = region;
+
+        Ok(())
+    }
+
+    /// The region being looped, as set via [`set_loop_region`].
+    ///
+    /// [`set_loop_region`]: #method.set_loop_region
+    pub fn loop_region(&self) -> Option<(Duration, Duration)> {
+        self.loop_region
+    }
+
+    /// Applies the start and end of a track's options, before it is played.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`VoiceError::NotSeekable`] if the track starts later than the
+    /// beginning of a source which cannot seek, and
+    /// [`VoiceError::InvalidTrim`] if it does not start before it ends.
+    ///
+    /// [`VoiceError::InvalidTrim`]: enum.VoiceError.html#variant.InvalidTrim
+    /// [`VoiceError::NotSeekable`]: enum.VoiceError.html#variant.NotSeekable
+    pub(crate) fn apply_options(&mut self, options: TrackOptions) -> Result<(), VoiceError> {
+        let start = options.start.unwrap_or_default();
+
+        if options.end.map_or(false, |end| end <= start) {
+            return Err(VoiceError::InvalidTrim);
+        }
+
+        if start > Duration::default() {
+            if !self.source.is_seekable() {
+                return Err(VoiceError::NotSeekable);
+            }
+
+            self.position(start);
+        }
+
+        self.trim_end = options.end;
+
+        Ok(())
+    }
+
+    /// Whether the frame just mixed reached the end set by the track's
+    /// options, so that the track should end.
+    pub(crate) fn trim_end_reached(&self) -> bool {
+        self.trim_end.map_or(false, |end| self.position + Duration::from_millis(20) >= end)
+    }
+
+    /// Steps playback location forward by one frame, returning to the start
+    /// of the loop region if its end has been reached.
+    ///
+    /// *Used internally*.
     pub(crate) fn step_frame(&mut self) {
         self.position += Duration::from_millis(20);
         self.position_modified = false;
+
+        if let Some((start, end)) = self.loop_region {
+            if self.position >= end {
+                self.position(start);
+            }
+        }
     }
 
+    /// Sets what happens when the source fails to read, rather than reaching
+    /// its end, replacing any previous handler.
+    ///
+    /// The handler is called with the error on each failed read. Without one,
+    /// a failing source is skipped.
+    pub fn on_error(&mut self, handler: ErrorHandler) -> &mut Self {
+        self.error_handler = Some(handler);
+
+        self
+    }
+
+    /// Decides what to do about a failed read of the source.
+    pub(crate) fn error_action(&self, error: VoiceError) -> ErrorAction {
+        match self.error_handler {
+            Some(ref handler) => handler(error),
+            None => ErrorAction::Skip,
+        }
+    }
+
+    /// Returns a receiver for updates to this audio's [`TrackStatus`].
+    ///
+    /// [`TrackStatus`]: enum.TrackStatus.html
+    pub(crate) fn subscribe(&self) -> watch::Receiver<TrackStatus> {
+        self.status_rx.clone()
+    }
+
+    /// Marks that a frame of this audio has been mixed into a live connection.
+    pub(crate) fn mark_playing(&mut self) {
+        if *self.status_rx.borrow() == TrackStatus::Pending {
+            let _ = self.status.broadcast(TrackStatus::Playing);
+        }
+    }
+
+    /// Rewinds this audio to its start and resumes it, clearing any fade, so
+    /// that it can be played again after having ended.
+    pub(crate) fn restart(&mut self) {
+        self.position = Duration::default();
+        self.position_modified = true;
+        self.playing = true;
+        self.finished = false;
+        self.fade = None;
+        self.gain_ramp = GainRamp::default();
+
+        if matches!(*self.status_rx.borrow(), TrackStatus::Ended(_)) {
+            let _ = self.status.broadcast(TrackStatus::Pending);
+        }
+    }
+
+    /// Marks that this audio has been removed from its connection, whether it
+    /// finished naturally or was stopped, returning `false` if it had already
+    /// ended.
+    pub(crate) fn mark_ended(&mut self, reason: EndReason) -> bool {
+        if matches!(*self.status_rx.borrow(), TrackStatus::Ended(_)) {
+            return false;
+        }
+
+        let _ = self.status.broadcast(TrackStatus::Ended(reason));
+
+        true
+    }
+}
+
+/// A linear gain ramp, applied to an [`Audio`] one 20ms frame at a time.
+///
+/// [`Audio`]: struct.Audio.html
+#[derive(Clone, Copy, Debug)]
+struct Fade {
+    from: f32,
+    to: f32,
+    frames: u32,
+    elapsed: u32,
+    /// Whether the audio should be stopped once the fade completes.
+    stop: bool,
+}
+
+impl Fade {
+    fn new(from: f32, to: f32, duration: Duration, stop: bool) -> Self {
+        Self {
+            from,
+            to,
+            frames: (duration.as_millis() / 20).max(1) as u32,
+            elapsed: 0,
+            stop,
+        }
+    }
+
+    fn gain(&self) -> f32 {
+        let progress = self.elapsed as f32 / self.frames as f32;
+
+        self.from + (self.to - self.from) * progress.min(1.0)
+    }
+}
+
+/// Smooths changes to the gain of an [`Audio`] within the mixer, ramping
+/// linearly to each new target gain rather than jumping to it, which clicks.
+///
+/// [`Audio`]: struct.Audio.html
+#[derive(Clone, Copy, Debug, Default)]
+struct GainRamp {
+    /// The gain of the last sample mixed, if any has been.
+    current: Option<f32>,
+    from: f32,
+    target: f32,
+    elapsed: usize,
+}
+
+impl GainRamp {
+    /// Fills `gains` with the gain of each sample, ramping to `target` over
+    /// `len` samples from wherever the previous ramp had reached.
+    fn fill(&mut self, target: f32, len: usize, gains: &mut [f32]) {
+        let current = match self.current {
+            Some(current) => current,
+            // Nothing has been heard yet, so there is nothing to ramp from.
+            None => target,
+        };
+
+        if self.current.is_none() || (target - self.target).abs() > f32::EPSILON {
+            self.from = current;
+            self.target = target;
+            self.elapsed = 0;
+        }
+
+        for gain in gains.iter_mut() {
+            self.elapsed = self.elapsed.saturating_add(1);
+
+            *gain = if self.elapsed >= len {
+                self.target
+            } else {
+                self.from + (self.target - self.from) * (self.elapsed as f32 / len as f32)
+            };
+        }
+
+        self.current = Some(gains.last().copied().unwrap_or(current));
+    }
 }
 
+/// The playback status of an [`Audio`] source, as seen by the voice task.
+///
+/// [`Audio`]: struct.Audio.html
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum TrackStatus {
+    /// The audio has not yet been played over a live connection.
+    Pending,
+    /// At least one frame of the audio has been sent over a live connection.
+    Playing,
+    /// The audio has finished, or has been removed from its connection.
+    Ended(EndReason),
+}
+
+/// Why an [`Audio`] source stopped being played.
+///
+/// [`Audio`]: struct.Audio.html
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum EndReason {
+    /// The source reached the end of its audio.
+    Finished,
+    /// The source was stopped or replaced before it finished, including by
+    /// fading out.
+    Stopped,
+    /// The source was stopped, or dropped before it started, by
+    /// [`Handler::stop_and_clear`].
+    ///
+    /// [`Handler::stop_and_clear`]: struct.Handler.html#method.stop_and_clear
+    Cleared,
+    /// The source failed to read, and was skipped or stopped by its
+    /// [`ErrorAction`].
+    ///
+    /// [`ErrorAction`]: enum.ErrorAction.html
+    Failed,
+}
+
+/// What to do when an [`Audio`] source fails to read, as decided by its
+/// handler set via [`Audio::on_error`].
+///
+/// [`Audio`]: struct.Audio.html
+/// [`Audio::on_error`]: struct.Audio.html#method.on_error
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum ErrorAction {
+    /// End the track, letting the queue move on to its next track. A queued
+    /// track emits [`HandlerEvent::TrackAutoSkipped`] as it does.
+    ///
+    /// [`HandlerEvent::TrackAutoSkipped`]: enum.HandlerEvent.html#variant.TrackAutoSkipped
+    Skip,
+    /// Keep the track, reading it again on the next frame. Seekable sources
+    /// are first reopened at their current position.
+    ///
+    /// The handler is called again if the retry fails, so it should
+    /// eventually choose another action for a source which keeps failing.
+    Retry,
+    /// End the track, and stop all other playback as with
+    /// [`Handler::stop_and_clear`].
+    ///
+    /// [`Handler::stop_and_clear`]: struct.Handler.html#method.stop_and_clear
+    Stop,
+}
+
+/// A handler for failed reads of an [`Audio`] source, set via
+/// [`Audio::on_error`].
+///
+/// [`Audio`]: struct.Audio.html
+/// [`Audio::on_error`]: struct.Audio.html#method.on_error
+pub type ErrorHandler = Box<dyn Fn(VoiceError) -> ErrorAction + Send>;
+
 /// Threadsafe form of an instance of the [`Audio`] struct, locked behind a
 /// Mutex.
 ///
 /// [`Audio`]: struct.Audio.html
 pub type LockedAudio = Arc<Mutex<Audio>>;
+
+#[cfg(test)]
+mod test {
+    use audiopus::{coder::Encoder, Application, Channels};
+    use super::*;
+
+    #[test]
+    fn test_clamp_bitrate() {
+        assert_eq!(clamp_bitrate(Bitrate::BitsPerSecond(64_000)).unwrap(), Bitrate::BitsPerSecond(64_000));
+        assert_eq!(clamp_bitrate(Bitrate::BitsPerSecond(1)).unwrap(), Bitrate::BitsPerSecond(MIN_BITRATE));
+        assert_eq!(clamp_bitrate(Bitrate::BitsPerSecond(1_000_000)).unwrap(), Bitrate::BitsPerSecond(MAX_BITRATE));
+        assert_eq!(clamp_bitrate(Bitrate::Max).unwrap(), Bitrate::Max);
+        assert!(clamp_bitrate(Bitrate::BitsPerSecond(0)).is_err());
+        assert!(clamp_bitrate(Bitrate::BitsPerSecond(-64_000)).is_err());
+    }
+
+    #[test]
+    fn test_encoder_accepts_clamped_bitrate() {
+        let mut encoder = Encoder::new(SAMPLE_RATE, Channels::Stereo, Application::Audio).unwrap();
+
+        for &bits in &[1, 96_000, 1_000_000] {
+            let bitrate = clamp_bitrate(Bitrate::BitsPerSecond(bits)).unwrap();
+            encoder.set_bitrate(bitrate).unwrap();
+
+            assert_eq!(encoder.bitrate().unwrap(), bitrate);
+        }
+    }
+
+    /// The largest jump between consecutive samples of constant full-scale
+    /// audio, mixed at each of the given gains for a frame apiece.
+    fn largest_step(ramp: &mut GainRamp, len: usize, targets: &[f32]) -> f32 {
+        let mut gains = [0f32; 960];
+        let mut last: Option<f32> = None;
+        let mut largest = 0f32;
+
+        for &target in targets {
+            ramp.fill(target, len, &mut gains);
+
+            for &gain in gains.iter() {
+                if let Some(last) = last {
+                    largest = largest.max((gain - last).abs());
+                }
+
+                last = Some(gain);
+            }
+        }
+
+        largest
+    }
+
+    #[test]
+    fn test_gain_ramp() {
+        let len = 720;
+        let mut ramp = GainRamp::default();
+
+        // Setting the volume from full to silent and back within frames.
+        let step = largest_step(&mut ramp, len, &[1.0, 0.0, 0.0, 1.0, 0.25]);
+        assert!(step <= 1.0 / len as f32 + f32::EPSILON, "step of {}", step);
+
+        // Each step of a short fade is smoothed into the next.
+        let fade = Fade::new(1.0, 0.0, Duration::from_millis(60), true);
+        let targets: Vec<f32> = (0..=3)
+            .map(|elapsed| Fade { elapsed, ..fade }.gain())
+            .collect();
+        assert!(largest_step(&mut ramp, len, &targets) < 0.01);
+
+        // Once a ramp completes, the target gain is held.
+        let mut gains = [0f32; 960];
+        ramp.fill(0.5, len, &mut gains);
+        assert!((gains[len] - 0.5).abs() < f32::EPSILON);
+        assert!((gains[959] - 0.5).abs() < f32::EPSILON);
+
+        // Without any ramp, gain changes apply at once.
+        ramp.fill(1.0, 0, &mut gains);
+        assert!(gains.iter().all(|&gain| (gain - 1.0).abs() < f32::EPSILON));
+    }
+
+    #[test]
+    fn test_mute() {
+        let mut audio = Audio::new(crate::voice::pcm(true, std::io::Cursor::new(Vec::new())));
+        let mut gains = [0f32; 960];
+
+        audio.volume(0.5).mute(true);
+        audio.frame_gains(0, 1.0, 1.0, &mut gains);
+        assert!(gains.iter().all(|&gain| gain == 0.0));
+        assert!(audio.playing);
+
+        audio.mute(false);
+        audio.frame_gains(0, 1.0, 1.0, &mut gains);
+        assert!(gains.iter().all(|&gain| (gain - 0.5).abs() < f32::EPSILON));
+    }
+
+    #[test]
+    fn test_bus_gain() {
+        let mut audio = Audio::new(crate::voice::pcm(true, std::io::Cursor::new(Vec::new())));
+        let mut gains = [0f32; 960];
+
+        audio.volume(0.5).bus(Some("music"));
+        assert_eq!(audio.current_bus(), Some("music"));
+
+        audio.frame_gains(0, 1.0, 0.5, &mut gains);
+        assert!(gains.iter().all(|&gain| (gain - 0.25).abs() < f32::EPSILON));
+
+        audio.bus(None::<String>);
+        assert_eq!(audio.current_bus(), None);
+    }
+
+    #[test]
+    fn test_apply_options() {
+        let mut audio = Audio::new(crate::voice::pcm(true, std::io::Cursor::new(Vec::new())));
+        let mut options = TrackOptions::default();
+
+        options.end(Duration::from_millis(60));
+        audio.apply_options(options).unwrap();
+        assert!(!audio.trim_end_reached());
+
+        // The track ends once the frame from 40 to 60ms has been mixed.
+        audio.step_frame();
+        assert!(!audio.trim_end_reached());
+        audio.step_frame();
+        assert!(audio.trim_end_reached());
+
+        // Only seekable sources can start later.
+        options.start(Duration::from_secs(1));
+        assert!(matches!(audio.apply_options(options), Err(VoiceError::InvalidTrim)));
+
+        options.end(Duration::from_secs(2));
+        assert!(matches!(audio.apply_options(options), Err(VoiceError::NotSeekable)));
+    }
+}
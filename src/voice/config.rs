@@ -0,0 +1,908 @@
+use audiopus::{coder::Encoder as OpusEncoder, ffi, Result as OpusResult, Signal};
+use std::{
+    ffi::{OsStr, OsString},
+    net::IpAddr,
+    path::{Path, PathBuf},
+    process::{Command as StdCommand, Stdio},
+    time::Duration,
+};
+use tokio::process::Command;
+use super::{audio, connection_info::SessionInfo, Bitrate, EncryptionMode, VoiceError};
+
+/// The highest complexity accepted by the Opus encoder.
+pub(crate) const MAX_COMPLEXITY: u8 = 10;
+
+/// The complexity used by default, which is that of libopus.
+const DEFAULT_COMPLEXITY: u8 = 9;
+
+/// Settings applied to a [`Handler`] before it first joins a channel, via
+/// [`Manager::join_with`].
+///
+/// # Examples
+///
+/// Join a channel deafened, at a lower bitrate:
+///
+/// ```rust,ignore
+/// use serenity::voice::{Bitrate, HandlerConfig};
+///
+/// let mut config = HandlerConfig::default();
+/// config.deaf(true).bitrate(Bitrate::BitsPerSecond(64_000));
+///
+/// manager.join_with(guild_id, channel_id, config)?;
+/// ```
+///
+/// [`Handler`]: struct.Handler.html
+/// [`Manager::join_with`]: struct.Manager.html#method.join_with
+#[derive(Clone, Debug)]
+pub struct HandlerConfig {
+    pub(crate) auto_disconnect: Option<Duration>,
+    pub(crate) bind_address: Option<IpAddr>,
+    pub(crate) bitrate: Bitrate,
+    pub(crate) deaf: bool,
+    pub(crate) encryption_mode: EncryptionMode,
+    pub(crate) mute: bool,
+}
+
+impl HandlerConfig {
+    /// Sets how long the handler may send no audio before leaving its
+    /// channel, or `None`, the default, to stay indefinitely.
+    ///
+    /// Refer to [`Handler::set_auto_disconnect`] for details.
+    ///
+    /// [`Handler::set_auto_disconnect`]: struct.Handler.html#method.set_auto_disconnect
+    pub fn auto_disconnect(&mut self, after: Option<Duration>) -> &mut Self {
+        self.auto_disconnect = after;
+
+        self
+    }
+
+    /// Sets the local address to bind the voice UDP socket to.
+    ///
+    /// Refer to [`Handler::set_bind_address`] for details.
+    ///
+    /// [`Handler::set_bind_address`]: struct.Handler.html#method.set_bind_address
+    pub fn bind_address(&mut self, address: Option<IpAddr>) -> &mut Self {
+        self.bind_address = address;
+
+        self
+    }
+
+    /// Sets the bitrate for encoding sent audio.
+    ///
+    /// Refer to [`Handler::set_bitrate`] for the accepted values.
+    ///
+    /// [`Handler::set_bitrate`]: struct.Handler.html#method.set_bitrate
+    pub fn bitrate(&mut self, bitrate: Bitrate) -> &mut Self {
+        self.bitrate = bitrate;
+
+        self
+    }
+
+    /// Sets whether the handler joins deafened.
+    pub fn deaf(&mut self, deaf: bool) -> &mut Self {
+        self.deaf = deaf;
+
+        self
+    }
+
+    /// Sets the encryption mode to negotiate with the voice server.
+    ///
+    /// Refer to [`Handler::set_encryption_mode`] for details.
+    ///
+    /// [`Handler::set_encryption_mode`]: struct.Handler.html#method.set_encryption_mode
+    pub fn encryption_mode(&mut self, mode: EncryptionMode) -> &mut Self {
+        self.encryption_mode = mode;
+
+        self
+    }
+
+    /// Sets whether the handler joins muted.
+    pub fn mute(&mut self, mute: bool) -> &mut Self {
+        self.mute = mute;
+
+        self
+    }
+}
+
+impl Default for HandlerConfig {
+    fn default() -> Self {
+        Self {
+            auto_disconnect: None,
+            bind_address: None,
+            bitrate: audio::DEFAULT_BITRATE,
+            deaf: false,
+            encryption_mode: EncryptionMode::default(),
+            mute: false,
+        }
+    }
+}
+
+/// Settings for the `ffmpeg`, `ffprobe` and `youtube-dl` processes spawned by
+/// source constructors such as [`ffmpeg_with_options`] and
+/// [`ytdl_with_options`].
+///
+/// By default, processes inherit the environment and working directory of the
+/// bot, and their stderr is discarded, as with [`ffmpeg`] and [`ytdl`].
+///
+/// # Examples
+///
+/// Fetch audio through a proxy, with cookies from the bot's data directory:
+///
+/// ```rust,ignore
+/// use serenity::voice::{self, ProcessOptions};
+///
+/// let mut options = ProcessOptions::default();
+/// options
+///     .env("HTTP_PROXY", "http://proxy.local:3128")
+///     .current_dir("./data")
+///     .ytdl_args(vec!["--cookies", "cookies.txt"]);
+///
+/// let source = voice::ytdl_with_options(url, &options).await?;
+/// ```
+///
+/// [`ffmpeg`]: fn.ffmpeg.html
+/// [`ffmpeg_with_options`]: fn.ffmpeg_with_options.html
+/// [`ytdl`]: fn.ytdl.html
+/// [`ytdl_with_options`]: fn.ytdl_with_options.html
+#[derive(Clone, Debug, Default)]
+pub struct ProcessOptions {
+    pub(crate) env: Vec<(OsString, OsString)>,
+    pub(crate) current_dir: Option<PathBuf>,
+    pub(crate) stderr: bool,
+    pub(crate) prebuffer: Option<Duration>,
+    pub(crate) timeout: Option<Duration>,
+    pub(crate) ytdl_args: Vec<String>,
+}
+
+impl ProcessOptions {
+    /// Sets an environment variable for the processes, such as `HTTP_PROXY`
+    /// or `PATH`, in addition to those inherited from the bot.
+    pub fn env<K: AsRef<OsStr>, V: AsRef<OsStr>>(&mut self, key: K, value: V) -> &mut Self {
+        self.env.push((key.as_ref().to_os_string(), value.as_ref().to_os_string()));
+
+        self
+    }
+
+    /// Sets the working directory of the processes, against which relative
+    /// paths, such as those of cookie files, are resolved.
+    pub fn current_dir<P: AsRef<Path>>(&mut self, dir: P) -> &mut Self {
+        self.current_dir = Some(dir.as_ref().to_path_buf());
+
+        self
+    }
+
+    /// Sets whether the processes' stderr is passed through to that of the
+    /// bot, rather than discarded. Off by default.
+    pub fn stderr(&mut self, inherit: bool) -> &mut Self {
+        self.stderr = inherit;
+
+        self
+    }
+
+    /// Sets how much audio is read ahead before the source is returned, so
+    /// that its start does not stutter while decoding catches up. Refer to
+    /// [`voice::prebuffer`] for details.
+    ///
+    /// By default, 200ms is read ahead for URLs and sources from
+    /// `youtube-dl`, and none for local files.
+    ///
+    /// [`voice::prebuffer`]: fn.prebuffer.html
+    pub fn prebuffer(&mut self, prebuffer: Duration) -> &mut Self {
+        self.prebuffer = Some(prebuffer);
+
+        self
+    }
+
+    /// Sets how long the processes may take, both to probe the audio and to
+    /// produce its first output, before they are killed and the constructor
+    /// fails with [`VoiceError::Timeout`]. By default, they are waited on
+    /// for as long as they take.
+    ///
+    /// This keeps a URL which `youtube-dl` or `ffmpeg` hangs while resolving
+    /// from wedging playback.
+    ///
+    /// [`VoiceError::Timeout`]: enum.VoiceError.html#variant.Timeout
+    pub fn timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.timeout = Some(timeout);
+
+        self
+    }
+
+    /// Adds extra arguments for `youtube-dl`, such as `--cookies` or a `-f`
+    /// format selector. They are not passed to `ffmpeg` or `ffprobe`.
+    ///
+    /// The extra arguments are passed after the defaults, so take precedence
+    /// over them, and a `-f` or `--format` given replaces the default format
+    /// selector.
+    pub fn ytdl_args<I, S>(&mut self, args: I) -> &mut Self
+        where I: IntoIterator<Item = S>, S: Into<String> {
+        self.ytdl_args.extend(args.into_iter().map(Into::into));
+
+        self
+    }
+
+    fn stderr_stdio(&self) -> Stdio {
+        if self.stderr {
+            Stdio::inherit()
+        } else {
+            Stdio::null()
+        }
+    }
+
+    pub(crate) fn apply(&self, command: &mut Command) {
+        command.envs(self.env.iter().map(|(key, value)| (key, value)));
+        command.stderr(self.stderr_stdio());
+
+        if let Some(dir) = self.current_dir.as_ref() {
+            command.current_dir(dir);
+        }
+    }
+
+    pub(crate) fn apply_std(&self, command: &mut StdCommand) {
+        command.envs(self.env.iter().map(|(key, value)| (key, value)));
+        command.stderr(self.stderr_stdio());
+
+        if let Some(dir) = self.current_dir.as_ref() {
+            command.current_dir(dir);
+        }
+    }
+}
+
+/// How [`ytdl_with_retry`] retries `youtube-dl` when it fails to resolve a
+/// URL, such as while YouTube is throttling requests.
+///
+/// Each set of arguments, starting with none beyond the defaults and then
+/// each fallback in order, is tried up to [`retries`] more times after its
+/// first failure, waiting for the [`backoff`] before each new attempt. By
+/// default, nothing is retried.
+///
+/// # Examples
+///
+/// Retry twice, then fall back to another player client:
+///
+/// ```rust,ignore
+/// use serenity::voice::{self, YtdlRetry};
+/// use std::time::Duration;
+///
+/// let mut retry = YtdlRetry::default();
+/// retry
+///     .retries(2)
+///     .backoff(Duration::from_secs(1))
+///     .fallback(vec!["--extractor-args", "youtube:player_client=android"]);
+///
+/// let (source, attempt) = voice::ytdl_with_retry(url, &retry).await?;
+/// ```
+///
+/// [`backoff`]: #method.backoff
+/// [`retries`]: #method.retries
+/// [`ytdl_with_retry`]: fn.ytdl_with_retry.html
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct YtdlRetry {
+    pub(crate) retries: usize,
+    pub(crate) backoff: Duration,
+    pub(crate) fallbacks: Vec<Vec<String>>,
+}
+
+impl YtdlRetry {
+    /// Sets how many more times each set of arguments is tried after it
+    /// first fails. 0 by default.
+    pub fn retries(&mut self, retries: usize) -> &mut Self {
+        self.retries = retries;
+
+        self
+    }
+
+    /// Sets how long to wait before each attempt after the first. None by
+    /// default.
+    pub fn backoff(&mut self, backoff: Duration) -> &mut Self {
+        self.backoff = backoff;
+
+        self
+    }
+
+    /// Adds a set of extra arguments for `youtube-dl`, such as a different
+    /// player client or `-f` format selector, to be tried once those before
+    /// it have failed every attempt.
+    ///
+    /// As with [`ProcessOptions::ytdl_args`], these are passed after the
+    /// default arguments, so take precedence over them.
+    ///
+    /// [`ProcessOptions::ytdl_args`]: struct.ProcessOptions.html#method.ytdl_args
+    pub fn fallback<I, S>(&mut self, args: I) -> &mut Self
+        where I: IntoIterator<Item = S>, S: Into<String> {
+        self.fallbacks.push(args.into_iter().map(Into::into).collect());
+
+        self
+    }
+}
+
+/// What a [`Handler`] does when it is disconnected from its channel by someone
+/// else, such as a moderator, set via [`Handler::set_on_force_disconnect`].
+///
+/// A [`HandlerEvent::ForceDisconnected`] is emitted whichever is chosen.
+///
+/// [`Handler`]: struct.Handler.html
+/// [`Handler::set_on_force_disconnect`]: struct.Handler.html#method.set_on_force_disconnect
+/// [`HandlerEvent::ForceDisconnected`]: enum.HandlerEvent.html#variant.ForceDisconnected
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum ForceDisconnectPolicy {
+    /// Drop the voice connection, as if [`Handler::leave`] had been called.
+    /// This is the default.
+    ///
+    /// [`Handler::leave`]: struct.Handler.html#method.leave
+    Leave,
+    /// Join the channel again.
+    ///
+    /// **Note**: A bot which is repeatedly disconnected will repeatedly
+    /// rejoin, so moderators may need another way to remove it.
+    Rejoin,
+    /// Only record that the handler is no longer in a channel, leaving the
+    /// voice connection for the application to handle.
+    Notify,
+}
+
+impl Default for ForceDisconnectPolicy {
+    fn default() -> Self {
+        ForceDisconnectPolicy::Leave
+    }
+}
+
+/// What a [`Handler`] does when it is moved into its guild's AFK channel,
+/// set via [`Handler::set_afk_policy`].
+///
+/// A [`HandlerEvent::MovedToAfk`] is emitted for every policy but
+/// [`Ignore`].
+///
+/// [`Handler`]: struct.Handler.html
+/// [`Handler::set_afk_policy`]: struct.Handler.html#method.set_afk_policy
+/// [`HandlerEvent::MovedToAfk`]: enum.HandlerEvent.html#variant.MovedToAfk
+/// [`Ignore`]: #variant.Ignore
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum AfkPolicy {
+    /// Carry on as in any other channel. This is the default.
+    Ignore,
+    /// Only emit the event, leaving the application to decide.
+    Notify,
+    /// Stop all playing audio and empty the queue, as if
+    /// [`Handler::stop_and_clear`] had been called.
+    ///
+    /// [`Handler::stop_and_clear`]: struct.Handler.html#method.stop_and_clear
+    Stop,
+    /// Move back to the channel the handler was moved from.
+    ///
+    /// **Note**: Discord may move an inactive bot again, so this is best
+    /// paired with playing audio in the channel.
+    Return,
+}
+
+impl Default for AfkPolicy {
+    fn default() -> Self {
+        AfkPolicy::Ignore
+    }
+}
+
+/// How a [`Handler`] paces the frames it sends, set via
+/// [`Handler::set_pacing_mode`].
+///
+/// Either way, each frame is due a fixed time after the one before it was
+/// due, rather than after it was sent, so that a late wakeup is made up on
+/// the next frame instead of building up.
+///
+/// [`Handler`]: struct.Handler.html
+/// [`Handler::set_pacing_mode`]: struct.Handler.html#method.set_pacing_mode
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum PacingMode {
+    /// Sleep until shortly before each frame is due, then yield to other
+    /// tasks until it is, so that frames are sent evenly spaced. This costs
+    /// some CPU time spent spinning before each frame.
+    Precise,
+    /// Sleep until each frame is due on the runtime's timer alone, which
+    /// may wake a millisecond or more late, bunching packets together for
+    /// listeners. This is the default.
+    Timer,
+}
+
+impl Default for PacingMode {
+    fn default() -> Self {
+        PacingMode::Timer
+    }
+}
+
+/// Settings for a limiter applied to the final mix of sent audio, via
+/// [`Handler::set_limiter`], which turns the mix down while its peaks exceed
+/// a threshold.
+///
+/// This runs before the soft clipping which is always applied, so that many
+/// loud sources are compressed smoothly rather than audibly distorted.
+///
+/// The defaults are a threshold of 0.8, an attack of 5ms and a release of
+/// 100ms.
+///
+/// # Examples
+///
+/// Hold a busy soundboard well below full scale, recovering slowly:
+///
+/// ```rust,ignore
+/// use serenity::voice::LimiterConfig;
+/// use std::time::Duration;
+///
+/// let mut config = LimiterConfig::default();
+/// config.threshold(0.5).release(Duration::from_millis(500));
+///
+/// handler.set_limiter(Some(config));
+/// ```
+///
+/// [`Handler::set_limiter`]: struct.Handler.html#method.set_limiter
+#[derive(Clone, Debug)]
+pub struct LimiterConfig {
+    pub(crate) threshold: f32,
+    pub(crate) attack: Duration,
+    pub(crate) release: Duration,
+}
+
+impl LimiterConfig {
+    /// Sets the peak level, as a fraction of full scale, above which the mix
+    /// is turned down. Values are clamped to between 0.01 and 1.
+    pub fn threshold(&mut self, threshold: f32) -> &mut Self {
+        self.threshold = threshold.max(0.01).min(1.0);
+
+        self
+    }
+
+    /// Sets how quickly the mix is turned down once it exceeds the
+    /// threshold. Shorter attacks let fewer peaks through, but distort more.
+    pub fn attack(&mut self, attack: Duration) -> &mut Self {
+        self.attack = attack;
+
+        self
+    }
+
+    /// Sets how quickly the mix is turned back up once it falls below the
+    /// threshold. Shorter releases recover sooner, but can be heard pumping.
+    pub fn release(&mut self, release: Duration) -> &mut Self {
+        self.release = release;
+
+        self
+    }
+}
+
+impl Default for LimiterConfig {
+    fn default() -> Self {
+        Self {
+            threshold: 0.8,
+            attack: Duration::from_millis(5),
+            release: Duration::from_millis(100),
+        }
+    }
+}
+
+/// Settings for automatic gain control of received audio, via
+/// [`Handler::set_receive_agc`], which turns each speaker up or down towards
+/// a common level, such as to even out quiet and loud users before speech
+/// recognition.
+///
+/// The defaults are a target level of 0.1 of full scale, a maximum gain of
+/// 10, an attack of 50ms and a release of 1s.
+///
+/// # Examples
+///
+/// Bring speakers up to a louder level, allowing a larger boost:
+///
+/// ```rust,ignore
+/// use serenity::voice::AgcConfig;
+///
+/// let mut config = AgcConfig::default();
+/// config.target(0.2).max_gain(20.0);
+///
+/// handler.set_receive_agc(Some(config));
+/// ```
+///
+/// [`Handler::set_receive_agc`]: struct.Handler.html#method.set_receive_agc
+#[derive(Clone, Debug)]
+pub struct AgcConfig {
+    pub(crate) target: f32,
+    pub(crate) max_gain: f32,
+    pub(crate) attack: Duration,
+    pub(crate) release: Duration,
+}
+
+impl AgcConfig {
+    /// Sets the RMS level, as a fraction of full scale, which each speaker is
+    /// brought towards. Values are clamped to between 0.01 and 1.
+    pub fn target(&mut self, target: f32) -> &mut Self {
+        self.target = target.max(0.01).min(1.0);
+
+        self
+    }
+
+    /// Sets the most a speaker may be turned up, as a multiple of their
+    /// level. Higher gains reach quieter speakers, but also raise their
+    /// background noise. Values are clamped to at least 1.
+    pub fn max_gain(&mut self, max_gain: f32) -> &mut Self {
+        self.max_gain = max_gain.max(1.0);
+
+        self
+    }
+
+    /// Sets how quickly a speaker is turned down when they get louder.
+    /// Shorter attacks let fewer loud syllables through, but make the level
+    /// audibly jump.
+    pub fn attack(&mut self, attack: Duration) -> &mut Self {
+        self.attack = attack;
+
+        self
+    }
+
+    /// Sets how quickly a speaker is turned up when they get quieter.
+    /// Shorter releases catch quiet speech sooner, but pump up the noise in
+    /// pauses between words.
+    pub fn release(&mut self, release: Duration) -> &mut Self {
+        self.release = release;
+
+        self
+    }
+}
+
+impl Default for AgcConfig {
+    fn default() -> Self {
+        Self {
+            target: 0.1,
+            max_gain: 10.0,
+            attack: Duration::from_millis(50),
+            release: Duration::from_secs(1),
+        }
+    }
+}
+
+/// Settings for ducking the other sources while an announcement plays, via
+/// [`Handler::announce`].
+///
+/// The defaults are a level of 0.25 of each source's volume, and a fade of
+/// 250ms.
+///
+/// # Examples
+///
+/// Duck the music further, and bring it back more slowly:
+///
+/// ```rust,ignore
+/// use serenity::voice::DuckConfig;
+/// use std::time::Duration;
+///
+/// let mut config = DuckConfig::default();
+/// config.level(0.1).fade(Duration::from_secs(1));
+///
+/// handler.announce(announcement, config).await?;
+/// ```
+///
+/// [`Handler::announce`]: struct.Handler.html#method.announce
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DuckConfig {
+    pub(crate) level: f32,
+    pub(crate) fade: Duration,
+}
+
+impl DuckConfig {
+    /// Sets the fraction of their volume which the other sources are played
+    /// at while ducked. Values are clamped to between 0 and 1.
+    pub fn level(&mut self, level: f32) -> &mut Self {
+        self.level = level.max(0.0).min(1.0);
+
+        self
+    }
+
+    /// Sets how long the other sources take to fade down to the ducked level
+    /// when the announcement starts, and back up once it ends.
+    pub fn fade(&mut self, fade: Duration) -> &mut Self {
+        self.fade = fade;
+
+        self
+    }
+}
+
+impl Default for DuckConfig {
+    fn default() -> Self {
+        Self {
+            level: 0.25,
+            fade: Duration::from_millis(250),
+        }
+    }
+}
+
+/// Options for playing a track, such as to play only a slice of a clip, as
+/// passed to [`Handler::play_with_options`] or
+/// [`TrackQueue::add_with_options`].
+///
+/// # Examples
+///
+/// Play only the part of a clip from 5 to 20 seconds in:
+///
+/// ```rust,ignore
+/// use serenity::voice::TrackOptions;
+/// use std::time::Duration;
+///
+/// let mut options = TrackOptions::default();
+/// options.start(Duration::from_secs(5)).end(Duration::from_secs(20));
+///
+/// handler.play_with_options(source, options)?;
+/// ```
+///
+/// [`Handler::play_with_options`]: struct.Handler.html#method.play_with_options
+/// [`TrackQueue::add_with_options`]: struct.TrackQueue.html#method.add_with_options
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct TrackOptions {
+    pub(crate) start: Option<Duration>,
+    pub(crate) end: Option<Duration>,
+}
+
+impl TrackOptions {
+    /// Sets the position in the source to start playing from, which it is
+    /// seeked to before its first frame is mixed. Starting anywhere but the
+    /// beginning requires a seekable source.
+    pub fn start(&mut self, start: Duration) -> &mut Self {
+        self.start = Some(start);
+
+        self
+    }
+
+    /// Sets the position in the source at which the track ends, as if the
+    /// source had finished there, with [`EndReason::Finished`].
+    ///
+    /// [`EndReason::Finished`]: enum.EndReason.html#variant.Finished
+    pub fn end(&mut self, end: Duration) -> &mut Self {
+        self.end = Some(end);
+
+        self
+    }
+}
+
+/// A band of a track's equalizer, boosting or cutting the audio around a
+/// frequency, as set via [`TrackHandle::set_equalizer`].
+///
+/// # Examples
+///
+/// Boost the bass and soften harsh highs:
+///
+/// ```rust,ignore
+/// use serenity::voice::EqBand;
+///
+/// track.set_equalizer(&[
+///     EqBand::new(80.0, 6.0, 0.7),
+///     EqBand::new(6_000.0, -3.0, 1.0),
+/// ]).await?;
+/// ```
+///
+/// [`TrackHandle::set_equalizer`]: struct.TrackHandle.html#method.set_equalizer
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[non_exhaustive]
+pub struct EqBand {
+    /// The centre frequency of the band, in hertz.
+    pub frequency: f32,
+    /// The gain at the centre frequency, in decibels, positive to boost and
+    /// negative to cut.
+    pub gain: f32,
+    /// The quality factor, where higher values affect a narrower range of
+    /// frequencies around the centre.
+    pub q: f32,
+}
+
+impl EqBand {
+    /// Creates a band from its centre frequency in hertz, gain in decibels,
+    /// and quality factor.
+    pub fn new(frequency: f32, gain: f32, q: f32) -> Self {
+        Self {
+            frequency,
+            gain,
+            q,
+        }
+    }
+
+    /// Checks that the band can be played, with a centre frequency below the
+    /// Nyquist frequency of 24 kHz, and a positive quality factor.
+    pub(crate) fn validate(&self) -> Result<(), VoiceError> {
+        let frequency = self.frequency.is_finite() && self.frequency > 0.0 && self.frequency < 24_000.0;
+        let q = self.q.is_finite() && self.q > 0.0;
+
+        if frequency && q && self.gain.is_finite() {
+            Ok(())
+        } else {
+            Err(VoiceError::InvalidEqBand(*self))
+        }
+    }
+}
+
+/// Settings for the Opus encoder of sent audio, applied together via
+/// [`Handler::set_opus_config`].
+///
+/// The defaults match those of libopus, at the default bitrate of 128 kbps.
+///
+/// # Examples
+///
+/// Favour speed and bandwidth over quality for a speech-only bot:
+///
+/// ```rust,ignore
+/// use serenity::voice::{Bitrate, OpusConfig, Signal};
+///
+/// let mut config = OpusConfig::default();
+/// config
+///     .bitrate(Bitrate::BitsPerSecond(32_000))
+///     .complexity(5)
+///     .signal(Signal::Voice)
+///     .dtx(true);
+///
+/// handler.set_opus_config(config)?;
+/// ```
+///
+/// [`Handler::set_opus_config`]: struct.Handler.html#method.set_opus_config
+#[derive(Clone, Debug)]
+pub struct OpusConfig {
+    pub(crate) bitrate: Bitrate,
+    pub(crate) complexity: u8,
+    pub(crate) dtx: bool,
+    pub(crate) inband_fec: bool,
+    pub(crate) signal: Signal,
+    pub(crate) vbr: bool,
+}
+
+impl OpusConfig {
+    /// Sets the bitrate, as with [`Handler::set_bitrate`].
+    ///
+    /// [`Handler::set_bitrate`]: struct.Handler.html#method.set_bitrate
+    pub fn bitrate(&mut self, bitrate: Bitrate) -> &mut Self {
+        self.bitrate = bitrate;
+
+        self
+    }
+
+    /// Sets the computational complexity of encoding, from 0 to 10, where
+    /// higher values give better quality at more CPU cost. Defaults to 9.
+    pub fn complexity(&mut self, complexity: u8) -> &mut Self {
+        self.complexity = complexity;
+
+        self
+    }
+
+    /// Sets whether discontinuous transmission is used, lowering the bitrate
+    /// during silence or background noise. Off by default.
+    pub fn dtx(&mut self, dtx: bool) -> &mut Self {
+        self.dtx = dtx;
+
+        self
+    }
+
+    /// Sets whether in-band forward error correction is used, letting
+    /// listeners recover from lost packets. Off by default.
+    pub fn inband_fec(&mut self, inband_fec: bool) -> &mut Self {
+        self.inband_fec = inband_fec;
+
+        self
+    }
+
+    /// Sets a hint for the type of audio being sent. Defaults to `Auto`.
+    pub fn signal(&mut self, signal: Signal) -> &mut Self {
+        self.signal = signal;
+
+        self
+    }
+
+    /// Sets whether a variable bitrate is used. On by default.
+    pub fn vbr(&mut self, vbr: bool) -> &mut Self {
+        self.vbr = vbr;
+
+        self
+    }
+
+    /// Checks the settings, clamping the bitrate as with
+    /// [`Handler::set_bitrate`].
+    ///
+    /// [`Handler::set_bitrate`]: struct.Handler.html#method.set_bitrate
+    pub(crate) fn validate(mut self) -> Result<Self, VoiceError> {
+        if self.complexity > MAX_COMPLEXITY {
+            return Err(VoiceError::InvalidComplexity(self.complexity));
+        }
+
+        self.bitrate = audio::clamp_bitrate(self.bitrate)?;
+
+        Ok(self)
+    }
+
+    /// Applies every setting to an encoder.
+    pub(crate) fn apply(&self, encoder: &mut OpusEncoder) -> OpusResult<()> {
+        encoder.set_bitrate(self.bitrate)?;
+        encoder.set_complexity(self.complexity)?;
+        encoder.set_encoder_ctl_request(ffi::OPUS_SET_DTX_REQUEST, i32::from(self.dtx))?;
+        encoder.set_inband_fec(self.inband_fec)?;
+        encoder.set_signal(self.signal)?;
+        encoder.set_vbr(self.vbr)
+    }
+}
+
+/// A snapshot of the Opus encoder settings of a [`Handler`], retrieved via
+/// [`Handler::opus_settings`].
+///
+/// [`Handler`]: struct.Handler.html
+/// [`Handler::opus_settings`]: struct.Handler.html#method.opus_settings
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct OpusSettings {
+    /// The bitrate.
+    pub bitrate: Bitrate,
+    /// The computational complexity, from 0 to 10.
+    pub complexity: u8,
+    /// Whether discontinuous transmission is enabled.
+    pub dtx: bool,
+    /// Whether in-band forward error correction is enabled.
+    pub inband_fec: bool,
+    /// The signal hint.
+    pub signal: Signal,
+    /// Whether a variable bitrate is used.
+    pub vbr: bool,
+    /// Whether these were read back from the encoder of a live connection,
+    /// rather than being the settings which the next connection will use.
+    pub live: bool,
+}
+
+impl From<&OpusConfig> for OpusSettings {
+    fn from(config: &OpusConfig) -> Self {
+        Self {
+            bitrate: config.bitrate,
+            complexity: config.complexity,
+            dtx: config.dtx,
+            inband_fec: config.inband_fec,
+            signal: config.signal,
+            vbr: config.vbr,
+            live: false,
+        }
+    }
+}
+
+impl From<&SessionInfo> for OpusSettings {
+    fn from(info: &SessionInfo) -> Self {
+        Self {
+            bitrate: info.bitrate,
+            complexity: info.complexity,
+            dtx: info.dtx,
+            inband_fec: info.inband_fec,
+            signal: info.signal,
+            vbr: info.vbr,
+            live: true,
+        }
+    }
+}
+
+impl Default for OpusConfig {
+    fn default() -> Self {
+        Self {
+            bitrate: audio::DEFAULT_BITRATE,
+            complexity: DEFAULT_COMPLEXITY,
+            dtx: false,
+            inband_fec: false,
+            signal: Signal::Auto,
+            vbr: true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use audiopus::{Application, Channels};
+    use super::*;
+
+    #[test]
+    fn test_opus_config() {
+        let mut encoder = OpusEncoder::new(audio::SAMPLE_RATE, Channels::Stereo, Application::Audio).unwrap();
+        let mut config = OpusConfig::default();
+        config.complexity(5).dtx(true).signal(Signal::Voice);
+
+        let config = config.validate().unwrap();
+        config.apply(&mut encoder).unwrap();
+        assert_eq!(encoder.complexity().unwrap(), 5);
+        assert_eq!(encoder.signal().unwrap(), Signal::Voice);
+
+        let mut config = OpusConfig::default();
+        config.complexity(11);
+        assert!(matches!(config.validate(), Err(VoiceError::InvalidComplexity(11))));
+    }
+}
@@ -4,16 +4,17 @@ use byteorder::{
     ReadBytesExt,
     WriteBytesExt
 };
-use crate::constants::VOICE_GATEWAY_VERSION;
-use crate::gateway::WsStream;
+use crate::constants::{VoiceOpCode, VOICE_GATEWAY_VERSION};
+use crate::gateway::{GatewayError, WsStream};
 use crate::internal::prelude::*;
 use crate::internal::{
     ws_impl::{ReceiverExt, SenderExt},
     Timer
 };
-use crate::model::event::VoiceEvent;
+use crate::model::{event::VoiceEvent, id::UserId};
 
 use audiopus::{
+    ffi,
     packet as opus_packet,
     Application as CodingMode,
     Bitrate,
@@ -22,14 +23,18 @@ use audiopus::{
     coder::Encoder as OpusEncoder,
     softclip::SoftClip,
 };
-use rand::random;
+use rand::{random, rngs::StdRng, Rng, SeedableRng};
 use serde::Deserialize;
 use std::{
-    collections::HashMap,
+    any::Any,
+    collections::{HashMap, HashSet},
+    future::Future,
     io::Write,
+    mem,
     net::{SocketAddr, ToSocketAddrs},
+    panic::AssertUnwindSafe,
     sync::Arc,
-    time::Duration,
+    time::{Duration, Instant},
 };
 use tokio::{
     task::JoinHandle,
@@ -40,6 +45,8 @@ use tokio::{
     },
 };
 use futures::{
+    future::FutureExt,
+    sink::{Sink, SinkExt},
     stream::{
         SplitStream,
         SplitSink,
@@ -51,27 +58,115 @@ use futures::{
         UnboundedSender as Sender,
     },
 };
-use xsalsa20poly1305::{
-    aead::{Aead, NewAead},
-    Nonce, XSalsa20Poly1305,
-};
-use async_tungstenite::tungstenite::protocol::Message;
-
-use super::audio::{AudioReceiver, AudioType, HEADER_LEN, SAMPLE_RATE, DEFAULT_BITRATE, LockedAudio};
-use super::connection_info::ConnectionInfo;
-use super::{payload, VoiceError, CRYPTO_MODE};
+use async_tungstenite::tungstenite::protocol::{frame::coding::CloseCode, CloseFrame, Message};
+
+use super::adaptive::{self, BitrateLadder, ComplexityScaler};
+use super::audio::{AudioType, EndReason, ErrorAction, DEFAULT_VOLUME_RAMP, HEADER_LEN, SAMPLE_RATE, LockedAudio};
+use super::connection_info::{ConnectionInfo, EndpointResolver, SessionInfo};
+use super::crypto::{EncryptionBackend, EncryptionBackendFactory, EncryptionMode};
+use super::dynamics::{Ducker, PeakLimiter};
+use super::events::HandlerEvent;
+use super::receive::{ActiveSsrcs, DecodedPacket, ReceiveState, SpeakingUsers};
+use super::rtcp::{self, RTCP_HEADER_LEN};
+use super::state::SharedState;
+use super::tee::OutputTee;
+use super::{payload, DuckConfig, LimiterConfig, OpusConfig, PacingMode, PacketObserver, RtpPacketView, VoiceError};
 use url::Url;
-use tracing::{debug, info, warn, instrument};
+use tracing::{debug, error, info, warn, instrument};
 
 #[cfg(all(feature = "rustls_backend", not(feature = "native_tls_backend")))]
-use crate::internal::ws_impl::create_rustls_client;
+use crate::internal::ws_impl::{create_rustls_client, create_rustls_client_to};
 
 #[cfg(feature = "native_tls_backend")]
-use crate::internal::ws_impl::create_native_tls_client;
+use crate::internal::ws_impl::{create_native_tls_client, create_native_tls_client_to};
+
+/// An Opus frame of silence.
+const SILENCE_FRAME: [u8; 3] = [0xf8, 0xff, 0xfe];
+
+/// How many silence frames are sent once audio stops, whether because every
+/// source ended or was paused or the handler was muted, before the speaking
+/// flag is cleared. Without these, clients may show the bot as speaking.
+const SILENCE_FRAME_COUNT: u8 = 5;
+
+/// How many frames in a row may fail to encode before the connection is
+/// considered broken.
+const MAX_ENCODER_FAILURES: u8 = 50;
+
+/// The websocket close code sent when leaving, by default: a normal closure,
+/// which Discord takes as the end of the session.
+pub(crate) const DEFAULT_CLOSE_CODE: u16 = 1000;
+
+/// How long sending a close frame may take when leaving, before the socket
+/// is dropped regardless.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The RTP payload type which Discord expects of Opus audio.
+pub(crate) const DEFAULT_RTP_PAYLOAD_TYPE: u8 = 0x78;
+
+/// An empty one-byte RTP header extension, as defined by RFC 5285. As in the
+/// packets Discord sends, it is placed within the encrypted payload.
+const EMPTY_HEADER_EXTENSION: [u8; 4] = [0xBE, 0xDE, 0, 0];
+
+/// The longest encoded frame which carries no audio, and so need not be sent,
+/// when discontinuous transmission is enabled.
+const DTX_FRAME_LEN: usize = 2;
+
+/// How long before each frame is due that precise pacing stops trusting the
+/// runtime's timer, which has a resolution of a millisecond.
+const PACING_MARGIN: Duration = Duration::from_millis(2);
+
+/// The length of audio carried by each packet sent.
+const FRAME_DURATION: Duration = Duration::from_millis(20);
+
+/// How much of each frame's peak is mixed into the reported output level.
+const LEVEL_SMOOTHING: f32 = 0.3;
+
+/// The length of the UDP header on each datagram, counted towards the bytes
+/// transferred.
+const UDP_HEADER_LEN: usize = 8;
+
+/// Tracks how long nothing has been playing, so that the send loop can go
+/// dormant once a timeout passes.
+#[derive(Debug, Default)]
+struct IdleState {
+    timeout: Option<Duration>,
+    since: Option<Instant>,
+    dormant: bool,
+}
+
+impl IdleState {
+    /// Records whether anything is playing, returning whether the send loop
+    /// has just gone dormant.
+    fn update(&mut self, idle: bool, now: Instant) -> bool {
+        if !idle {
+            self.since = None;
+            self.dormant = false;
+
+            return false;
+        }
+
+        let timeout = match self.timeout {
+            Some(timeout) => timeout,
+            None => return false,
+        };
+
+        let since = *self.since.get_or_insert(now);
+
+        if !self.dormant && now.saturating_duration_since(since) >= timeout {
+            self.dormant = true;
+
+            return true;
+        }
+
+        false
+    }
+}
 
 enum ReceiverStatus {
     Udp(Vec<u8>),
     Websocket(VoiceEvent),
+    /// The voice gateway closed the WebSocket, with the given frame.
+    Closed(CloseFrame<'static>),
 }
 
 #[allow(dead_code)]
@@ -85,35 +180,76 @@ struct TaskItems {
 }
 
 pub struct Connection {
+    /// Whether the encoder falls back to mono at low bitrates.
+    adaptive_channels: bool,
     audio_timer: Timer,
-    cipher: XSalsa20Poly1305,
+    bitrate_ladder: BitrateLadder,
+    /// The volume of each bus of sources, where it is not 1.0.
+    bus_volumes: HashMap<String, f32>,
+    /// Whether a source panicking while read is ended, rather than taking
+    /// the task down with it.
+    catch_source_panics: bool,
+    complexity: ComplexityScaler,
+    opus: OpusConfig,
+    cipher: Box<dyn EncryptionBackend>,
     stream: SplitSink<WsStream, Message>,
     connection_info: ConnectionInfo,
     decoder_map: HashMap<(u32, Channels), OpusDecoder>,
     destination: SocketAddr,
+    /// The gain of sources ducked under an announcement.
+    ducker: Ducker,
     encoder: OpusEncoder,
+    /// The number of consecutive frames which have failed to encode.
+    encoder_failures: u8,
+    /// The number of times the encoder has been replaced by a new one.
+    encoder_rebuilds: u32,
     encoder_stereo: bool,
     keepalive_timer: Timer,
-    last_heartbeat_nonce: Option<u64>,
+    heartbeat_timeout: Option<u32>,
+    idle: IdleState,
+    /// The nonce of the heartbeat awaiting an ack, and when it was sent.
+    last_heartbeat: Option<(u64, Instant)>,
+    missed_heartbeats: u32,
+    /// Whether the encoder has fallen back to mono for a low bitrate.
+    mono_fallback: bool,
+    pacing: PacingMode,
     sequence: u16,
     silence_frames: u8,
+    limiter: Option<PeakLimiter>,
+    /// Whether sent packets carry an empty one-byte header extension.
+    rtp_header_extension: bool,
+    rtp_payload_type: u8,
     soft_clip: SoftClip,
     speaking: bool,
+    /// Whether changes in `speaking` are sent to Discord.
+    speaking_indicator: bool,
+    /// The speaking flag last sent to Discord.
+    indicated: bool,
     ssrc: u32,
+    shared: Arc<SharedState>,
+    ssrc_map: HashMap<u32, UserId>,
+    /// The last RTP sequence number received from each SSRC.
+    sequences: HashMap<u32, u16>,
+    /// The SSRCs whose audio is being concealed after failing to decode.
+    concealing: HashSet<u32>,
     task_items: TaskItems,
     timestamp: u32,
+    /// The bitrate asked for by the tracks playing, if any.
+    track_bitrate: Option<Bitrate>,
     udp: SendHalf,
+    /// The number of samples per channel over which gain changes are ramped.
+    volume_ramp: usize,
 }
 
 impl Connection {
-    pub async fn new(mut info: ConnectionInfo) -> Result<Connection> {
-        let url = generate_url(&mut info.endpoint)?;
-
-        #[cfg(all(feature = "rustls_backend", not(feature = "native_tls_backend")))]
-        let mut stream = create_rustls_client(url).await?;
-
-        #[cfg(feature = "native_tls_backend")]
-        let mut stream = create_native_tls_client(url).await?;
+    pub async fn new(
+        info: ConnectionInfo,
+        mode: EncryptionMode,
+        backend: Option<&EncryptionBackendFactory>,
+        shared: Arc<SharedState>,
+    ) -> Result<Connection> {
+        let url = generate_url(&info.endpoint)?;
+        let mut stream = open_ws(url, info.endpoint_resolver.as_ref()).await?;
 
         let mut hello = None;
         let mut ready = None;
@@ -149,7 +285,7 @@ impl Connection {
         let hello = hello.expect("[Voice] Hello packet expected in connection initialisation, but not found.");
         let ready = ready.expect("[Voice] Ready packet expected in connection initialisation, but not found.");
 
-        if !has_valid_mode(&ready.modes) {
+        if !has_valid_mode(&ready.modes, mode) {
             return Err(Error::Voice(VoiceError::VoiceModeUnavailable));
         }
 
@@ -166,16 +302,28 @@ impl Connection {
         //
         // The returned packet will be a null-terminated string of the IP, and
         // the port encoded in LE in the last two bytes of the packet.
-        let mut udp = UdpSocket::bind("0.0.0.0:0").await?;
+        let bind_address = match info.bind_address {
+            Some(address) if address.is_ipv4() != destination.is_ipv4() => {
+                return Err(Error::Voice(VoiceError::BindAddressFamily));
+            },
+            Some(address) => SocketAddr::new(address, 0),
+            None => SocketAddr::from(([0, 0, 0, 0], 0)),
+        };
+
+        let mut udp = UdpSocket::bind(bind_address).await?;
 
         {
             let mut bytes = [0; 70];
 
             (&mut bytes[..]).write_u32::<BigEndian>(ready.ssrc)?;
-            udp.send_to(&bytes, destination).await?;
+            let sent = udp.send_to(&bytes, destination).await?;
 
             let mut bytes = [0; 256];
             let (len, _addr) = udp.recv_from(&mut bytes).await?;
+            shared.update_stats(|stats| {
+                stats.bytes_sent += wire_len(sent);
+                stats.bytes_received += wire_len(len);
+            });
 
             // Find the position in the bytes that contains the first byte of 0,
             // indicating the "end of the address".
@@ -191,67 +339,371 @@ impl Connection {
             let port = (&bytes[port_pos..]).read_u16::<BigEndian>()?;
 
             stream
-                .send_json(&payload::build_select_protocol(addr, port)).await?;
+                .send_json(&payload::build_select_protocol(addr, port, mode)).await?;
         }
 
-        let cipher = init_cipher(&mut stream).await?;
+        let cipher = init_cipher(&mut stream, mode, backend).await?;
+
+        Connection::from_parts(info, stream, udp, destination, ready.ssrc, cipher, hello.heartbeat_interval, shared).await
+    }
 
+    /// Builds a connection over a WebSocket and UDP socket which have
+    /// completed their handshake with the voice server.
+    #[allow(clippy::too_many_arguments)]
+    async fn from_parts(
+        info: ConnectionInfo,
+        stream: WsStream,
+        udp: UdpSocket,
+        destination: SocketAddr,
+        ssrc: u32,
+        cipher: Box<dyn EncryptionBackend>,
+        heartbeat_interval: u64,
+        shared: Arc<SharedState>,
+    ) -> Result<Connection> {
         let (sink, stream) = stream.split();
         let (udp_recv_half, udp_send_half) = udp.split();
         let task_items = start_udp_task(stream, udp_recv_half).await?;
+        shared.reset_ws_sequence();
+        shared.update_speaking(SpeakingUsers::clear);
+        shared.update_ssrcs(ActiveSsrcs::clear);
+        shared.set_last_receive(None);
 
         info!("[Voice] Connected to: {}", info.endpoint);
 
         // Encode for Discord in Stereo, as required.
+        let opus = OpusConfig::default();
         let mut encoder = OpusEncoder::new(SAMPLE_RATE, Channels::Stereo, CodingMode::Audio)?;
-        encoder.set_bitrate(DEFAULT_BITRATE)?;
+        opus.apply(&mut encoder)?;
         let soft_clip = SoftClip::new(Channels::Stereo);
+        let (sequence, timestamp) = initial_rtp_state(info.rtp_seed);
 
         // Per discord dev team's current recommendations:
         // (https://discord.com/developers/docs/topics/voice-connections#heartbeating)
-        let temp_heartbeat = (hello.heartbeat_interval as f64 * 0.75) as u64;
+        let temp_heartbeat = (heartbeat_interval as f64 * 0.75) as u64;
         info!(
             "[Voice] WS heartbeat duration given as {}ms, adjusted to {}ms.",
-            hello.heartbeat_interval,
+            heartbeat_interval,
             temp_heartbeat,
         );
 
         Ok(Connection {
+            adaptive_channels: false,
             audio_timer: Timer::new(1000 * 60 * 4),
+            bitrate_ladder: BitrateLadder::default(),
+            bus_volumes: HashMap::new(),
+            catch_source_panics: false,
+            complexity: ComplexityScaler::new(opus.complexity),
             cipher,
             stream: sink,
             connection_info: info,
             decoder_map: HashMap::new(),
             destination,
+            ducker: Ducker::default(),
             encoder,
+            encoder_failures: 0,
+            encoder_rebuilds: 0,
             encoder_stereo: false,
             keepalive_timer: Timer::new(temp_heartbeat),
-            last_heartbeat_nonce: None,
+            heartbeat_timeout: None,
+            idle: IdleState::default(),
+            last_heartbeat: None,
+            missed_heartbeats: 0,
+            mono_fallback: false,
+            pacing: PacingMode::default(),
+            opus,
             udp: udp_send_half,
-            sequence: 0,
+            sequence,
             silence_frames: 0,
+            limiter: None,
+            rtp_header_extension: false,
+            rtp_payload_type: DEFAULT_RTP_PAYLOAD_TYPE,
             soft_clip,
             speaking: false,
-            ssrc: ready.ssrc,
+            speaking_indicator: true,
+            indicated: false,
+            ssrc,
+            shared,
+            ssrc_map: HashMap::new(),
+            sequences: HashMap::new(),
+            concealing: HashSet::new(),
             task_items,
-            timestamp: 0,
+            timestamp,
+            track_bitrate: None,
+            volume_ramp: ramp_len(DEFAULT_VOLUME_RAMP),
         })
     }
 
+    pub fn connection_info(&self) -> &ConnectionInfo {
+        &self.connection_info
+    }
+
+    #[cfg(test)]
+    pub(crate) fn encoder_rebuilds(&self) -> u32 {
+        self.encoder_rebuilds
+    }
+
+    /// Applies a new bitrate to the live encoder, taking effect from the next
+    /// packet.
+    pub fn set_bitrate(&mut self, bitrate: Bitrate) -> Result<()> {
+        self.opus.bitrate = bitrate;
+
+        self.apply_bitrate()
+    }
+
+    /// Sets the steps the bitrate moves through as packet loss rises and
+    /// falls, replacing the configured bitrate while any are set.
+    pub fn set_adaptive_bitrate(&mut self, steps: Vec<Bitrate>) -> Result<()> {
+        self.bitrate_ladder.set_steps(steps);
+
+        self.apply_bitrate()
+    }
+
+    /// The bitrate which should be in use, from the ladder if one is set,
+    /// or else as requested by the tracks playing.
+    fn bitrate(&self) -> Bitrate {
+        self.bitrate_ladder.current()
+            .or(self.track_bitrate)
+            .unwrap_or(self.opus.bitrate)
+    }
+
+    fn apply_bitrate(&mut self) -> Result<()> {
+        self.encoder.set_bitrate(self.bitrate())?;
+        self.update_channels();
+
+        self.publish_session_info()
+    }
+
+    /// Enables or disables falling back to a mono encoder while the bitrate
+    /// in use is too low for stereo.
+    pub fn set_adaptive_channels(&mut self, enabled: bool) {
+        self.adaptive_channels = enabled;
+
+        self.update_channels();
+    }
+
+    /// Decides whether the encoder should fall back to mono for the bitrate
+    /// in use, telling the handler of any change. The encoder itself is
+    /// rebuilt when the next frame is mixed.
+    fn update_channels(&mut self) {
+        let bitrate = self.bitrate();
+        let mono = self.adaptive_channels && adaptive::prefers_mono(bitrate);
+
+        if mono == self.mono_fallback {
+            return;
+        }
+
+        info!("[Voice] Bitrate is {:?}, switching to {} audio.", bitrate, if mono { "mono" } else { "stereo" });
+
+        self.mono_fallback = mono;
+        self.shared.emit(HandlerEvent::ChannelsChanged {
+            stereo: !mono,
+            bitrate,
+        });
+    }
+
+    /// Applies a full set of encoder settings to the live encoder, taking
+    /// effect from the next packet.
+    pub fn set_opus_config(&mut self, config: OpusConfig) -> Result<()> {
+        config.apply(&mut self.encoder)?;
+        self.complexity.set_max(config.complexity);
+        self.opus = config;
+
+        self.apply_bitrate()
+    }
+
+    /// Replaces the live encoder with a new one with every setting applied,
+    /// taking effect from the next packet, so that all of them change at
+    /// once. If any cannot be applied, the live encoder is left as it was.
+    pub fn reconfigure_encoder(&mut self, config: OpusConfig) -> Result<()> {
+        let previous = mem::replace(&mut self.opus, config);
+        let channels = if self.encoder_stereo {
+            Channels::Stereo
+        } else {
+            Channels::Mono
+        };
+
+        match build_encoder(channels, &self.opus, self.bitrate(), self.opus.complexity) {
+            Ok(encoder) => {
+                self.encoder = encoder;
+                self.encoder_rebuilds += 1;
+                self.complexity.set_max(self.opus.complexity);
+
+                self.publish_session_info()
+            },
+            Err(why) => {
+                self.opus = previous;
+
+                Err(why)
+            },
+        }
+    }
+
+    /// Sets the configured complexity on the live encoder, from which any
+    /// adaptive lowering starts again.
+    pub fn set_complexity(&mut self, complexity: u8) -> Result<()> {
+        self.complexity.set_max(complexity);
+        self.opus.complexity = complexity;
+
+        self.apply_complexity(complexity)
+    }
+
+    /// Enables or disables discontinuous transmission on the live encoder.
+    pub fn set_dtx(&mut self, dtx: bool) -> Result<()> {
+        self.encoder.set_encoder_ctl_request(ffi::OPUS_SET_DTX_REQUEST, i32::from(dtx))?;
+        self.opus.dtx = dtx;
+
+        self.publish_session_info()
+    }
+
+    /// Enables or disables lowering the encoder's complexity while encoding
+    /// is slow.
+    pub fn set_adaptive_complexity(&mut self, enabled: bool) -> Result<()> {
+        match self.complexity.set_enabled(enabled) {
+            Some(complexity) => self.apply_complexity(complexity),
+            None => Ok(()),
+        }
+    }
+
+    fn apply_complexity(&mut self, complexity: u8) -> Result<()> {
+        self.encoder.set_complexity(complexity)?;
+
+        self.publish_session_info()
+    }
+
+    /// Shares the encode time, and adjusts the complexity if it is adaptive.
+    fn record_encode_time(&mut self, took: Duration) -> Result<()> {
+        let previous = self.complexity.current();
+        let change = self.complexity.record(took);
+        let average = self.complexity.average();
+        self.shared.update_stats(|stats| stats.encode_time = average);
+
+        match change {
+            Some(complexity) => {
+                info!(
+                    "[Voice] Average encode time is {:?}, setting complexity to {}.",
+                    average,
+                    complexity,
+                );
+
+                self.apply_complexity(complexity)?;
+
+                if complexity < previous {
+                    self.emit_degraded();
+                }
+
+                Ok(())
+            },
+            None => Ok(()),
+        }
+    }
+
+    /// Tells the handler that the encoder's quality was lowered.
+    fn emit_degraded(&self) {
+        self.shared.emit(HandlerEvent::EncoderDegraded {
+            complexity: self.complexity.current(),
+            bitrate: self.bitrate(),
+        });
+    }
+
+    /// Reads back the settings in use, and shares them with the handler.
+    fn publish_session_info(&self) -> Result<()> {
+        let dtx = self.encoder.encoder_ctl_request(ffi::OPUS_GET_DTX_REQUEST)?;
+
+        let info = SessionInfo {
+            endpoint: self.connection_info.endpoint.clone(),
+            encryption_mode: self.cipher.mode().name().to_string(),
+            ssrc: self.ssrc,
+            bitrate: self.encoder.bitrate()?,
+            complexity: self.encoder.complexity()?,
+            dtx: dtx != 0,
+            inband_fec: self.encoder.inband_fec()?,
+            signal: self.encoder.signal()?,
+            vbr: self.encoder.vbr()?,
+        };
+
+        self.shared.set_session_info(Some(info));
+
+        Ok(())
+    }
+
+    /// Sets how many heartbeats in a row may go unacknowledged before the
+    /// connection is considered dead, or `None` to never consider it so.
+    pub fn set_heartbeat_timeout(&mut self, missed: Option<u32>) {
+        self.heartbeat_timeout = missed;
+    }
+
+    /// Advances the RTP timestamp over frames which were never sent, so that
+    /// listeners keep the timing of the audio in line with the wall clock.
+    pub fn skip_frames(&mut self, frames: u32) {
+        self.timestamp = self.timestamp.wrapping_add(960u32.wrapping_mul(frames));
+    }
+
+    /// Sets how long nothing may be playing before the send loop goes
+    /// dormant, or `None` to never do so.
+    pub fn set_playback_idle_timeout(&mut self, timeout: Option<Duration>) {
+        self.idle.timeout = timeout;
+
+        if timeout.is_none() {
+            self.idle.dormant = false;
+        }
+    }
+
+    /// Sets whether the speaking flag is sent to Discord, clearing or
+    /// raising it at once to match.
+    pub async fn set_speaking_indicator(&mut self, enabled: bool) -> Result<()> {
+        self.speaking_indicator = enabled;
+
+        self.indicate_speaking().await
+    }
+
+    /// Sets the payload type of sent RTP packets.
+    pub fn set_rtp_payload_type(&mut self, payload_type: u8) {
+        self.rtp_payload_type = payload_type;
+    }
+
+    /// Sets whether sent RTP packets carry an empty one-byte header
+    /// extension.
+    pub fn set_rtp_header_extension(&mut self, enabled: bool) {
+        self.rtp_header_extension = enabled;
+    }
+
+    /// Sets the limiter applied to the final mix before soft clipping, if
+    /// any.
+    pub fn set_limiter(&mut self, config: Option<&LimiterConfig>) {
+        self.limiter = config.map(PeakLimiter::new);
+    }
+
+    /// Sets the time over which the mixer ramps changes in each source's
+    /// gain.
+    pub fn set_volume_ramp(&mut self, ramp: Duration) {
+        self.volume_ramp = ramp_len(ramp);
+    }
+
+    /// Sets how frames are paced.
+    pub fn set_pacing_mode(&mut self, mode: PacingMode) {
+        self.pacing = mode;
+    }
+
+    /// Sets the volume of every source on a bus.
+    pub fn set_bus_volume(&mut self, bus: String, volume: f32) {
+        self.bus_volumes.insert(bus, volume);
+    }
+
+    /// Sets whether sources which panic while read are ended.
+    pub fn set_catch_source_panics(&mut self, catch: bool) {
+        self.catch_source_panics = catch;
+    }
+
     #[instrument(skip(self))]
     pub async fn reconnect(&mut self) -> Result<()> {
-        let url = generate_url(&mut self.connection_info.endpoint)?;
+        let url = generate_url(&self.connection_info.endpoint)?;
 
         // Task may have died, we want to send to prompt a clean exit
         // (if at all possible) and then proceed as normal.
         info!("[VOICE] Sending signal to close WebSocket Stream.");
         let _ = self.task_items.ws_close_sender.unbounded_send(0);
 
-        #[cfg(all(feature = "rustls_backend", not(feature = "native_tls_backend")))]
-        let mut stream = create_rustls_client(url).await?;
-
-        #[cfg(feature = "native_tls_backend")]
-        let mut stream = create_native_tls_client(url).await?;
+        let mut stream = open_ws(url, self.connection_info.endpoint_resolver.as_ref()).await?;
 
         stream.send_json(&payload::build_resume(&self.connection_info)).await?;
 
@@ -288,6 +740,9 @@ impl Connection {
         let hello = hello.expect("[Voice] Hello packet expected in connection initialisation, but not found.");
 
         self.keepalive_timer = Timer::new((hello.heartbeat_interval as f64 * 0.75) as u64);
+        self.last_heartbeat = None;
+        self.missed_heartbeats = 0;
+        self.shared.reset_ws_sequence();
 
         let (sink, stream) = stream.split();
         let (ws_close_sender, ws_task) = start_ws_task(stream, &self.task_items.tx).await?;
@@ -301,26 +756,75 @@ impl Connection {
     }
 
     #[inline]
-    #[instrument(skip(self, receiver, buffer))]
+    #[instrument(skip(self, receive, buffer))]
     async fn handle_received_udp(
         &mut self,
-        receiver: &mut Option<Arc<dyn AudioReceiver>>,
+        receive: &mut ReceiveState,
         buffer: &mut [i16; 1920],
         packet: &[u8],
-        nonce: &mut Nonce,
         ) -> Result<()> {
 
-        if let Some(receiver) = receiver.as_mut() {
-            let mut handle = &packet[2..];
-            let seq = handle.read_u16::<BigEndian>()?;
-            let timestamp = handle.read_u32::<BigEndian>()?;
-            let ssrc = handle.read_u32::<BigEndian>()?;
+        if rtcp::is_rtcp(packet) {
+            self.handle_received_rtcp(receive, packet).await;
+
+            return Ok(());
+        }
+
+        if packet.len() < HEADER_LEN {
+            return Ok(());
+        }
+
+        let mut handle = &packet[2..];
+        let seq = handle.read_u16::<BigEndian>()?;
+        let timestamp = handle.read_u32::<BigEndian>()?;
+        let ssrc = handle.read_u32::<BigEndian>()?;
+
+        // Only voice packets count towards loss, not RTCP.
+        if packet[1] & 0x7F == 0x78 {
+            let user_id = self.ssrc_map.get(&ssrc).copied();
+            self.shared.update_ssrcs(|ssrcs| ssrcs.heard(ssrc, user_id, Instant::now()));
+
+            let lost = match self.sequences.get(&ssrc) {
+                Some(&previous) => adaptive::lost_between(previous, seq),
+                None => Some(0),
+            };
+
+            // Duplicate and late packets are neither counted nor remembered.
+            if let Some(lost) = lost {
+                let lost = u64::from(lost);
+
+                self.sequences.insert(ssrc, seq);
+                self.bitrate_ladder.record(1, lost);
+                self.shared.update_stats(|stats| {
+                    stats.packets_received += 1;
+                    stats.packets_lost += lost;
+                });
+            }
+        }
+
+        if let Some(&user_id) = self.ssrc_map.get(&ssrc) {
+            self.shared.update_speaking(|speaking| speaking.heard(user_id, Instant::now()));
+        }
+
+        // Don't bother decrypting or decoding if nobody is listening.
+        if receive.is_listening() {
+            if !receive.accepts(self.ssrc_map.get(&ssrc)) {
+                return Ok(());
+            }
 
-            nonce[..HEADER_LEN]
-                .clone_from_slice(&packet[..HEADER_LEN]);
+            let (header, body) = packet.split_at(HEADER_LEN);
+
+            if let Some(mut decrypted) = self.cipher.decrypt(header, body) {
+                let channels = match opus_packet::nb_channels(&decrypted) {
+                    Ok(channels) => channels,
+                    Err(why) => {
+                        for receiver in &receive.receivers {
+                            receiver.decode_error(ssrc, &why).await;
+                        }
 
-            if let Ok(mut decrypted) = self.cipher.decrypt(&nonce, &packet[HEADER_LEN..]) {
-                let channels = opus_packet::nb_channels(&decrypted)?;
+                        return Ok(());
+                    },
+                };
 
                 let entry =
                     self.decoder_map.entry((ssrc, channels)).or_insert_with(
@@ -348,20 +852,108 @@ impl Connection {
                     decrypted = decrypted.split_off(offset);
                 }
 
-                let len = entry.decode(Some(&decrypted), &mut buffer[..], false)?;
+                let len = match entry.decode(Some(&decrypted), &mut buffer[..], false) {
+                    Ok(len) => {
+                        self.concealing.remove(&ssrc);
+
+                        len
+                    },
+                    Err(why) => {
+                        for receiver in &receive.receivers {
+                            receiver.decode_error(ssrc, &why).await;
+                        }
+
+                        if self.concealing.insert(ssrc) {
+                            self.shared.emit(HandlerEvent::DecoderConcealing {
+                                ssrc,
+                                user_id: self.ssrc_map.get(&ssrc).copied(),
+                            });
+                        }
+
+                        // Conceal the corrupt packet, so that consumers see a
+                        // continuous stream rather than a gap.
+                        match entry.decode(None::<&[u8]>, &mut buffer[..], false) {
+                            Ok(len) => len,
+                            Err(_) => return Ok(()),
+                        }
+                    },
+                };
 
                 let is_stereo = channels == Channels::Stereo;
 
                 let b = if is_stereo { len * 2 } else { len };
 
-                receiver
-                    .voice_packet(ssrc, seq, timestamp, is_stereo, &buffer[..b], decrypted.len()).await;
+                receive.apply_agc(ssrc, &mut buffer[..b]);
+
+                // The mix is made from the decoded audio itself, and only
+                // converted once it is delivered.
+                let (out_stereo, data) = receive.convert(is_stereo, &buffer[..b]);
+
+                // Receivers are called in order of registration, so that each
+                // sees packets in the order they arrived.
+                for receiver in &receive.receivers {
+                    receiver
+                        .voice_packet(ssrc, seq, timestamp, out_stereo, &data, decrypted.len()).await;
+                }
+
+                let user_id = self.ssrc_map.get(&ssrc).copied();
+
+                if !receive.receivers.is_empty() && receive.mixer.push(ssrc, user_id, is_stereo, &buffer[..b]) {
+                    self.shared.update_stats(|stats| stats.receive_frames_dropped += 1);
+                }
+
+                if receive.push_user_frame(ssrc, user_id, is_stereo, &buffer[..b]) {
+                    self.shared.update_stats(|stats| stats.receive_frames_dropped += 1);
+                }
+
+                receive.send_to_stream(DecodedPacket {
+                    ssrc,
+                    user_id,
+                    sequence: seq,
+                    timestamp,
+                    stereo: out_stereo,
+                    data: data.into_owned(),
+                    compressed_size: decrypted.len(),
+                });
             }
         }
 
         Ok(())
     }
 
+    /// Decrypts and parses an RTCP packet, passing any reports on to the
+    /// receivers, and sharing those on the handler's own audio as stats.
+    ///
+    /// Only the RTCP header is left unencrypted.
+    async fn handle_received_rtcp(&mut self, receive: &ReceiveState, packet: &[u8]) {
+        let (header, body) = packet.split_at(RTCP_HEADER_LEN);
+
+        let body = match self.cipher.decrypt(header, body) {
+            Some(body) => body,
+            None => {
+                debug!("[Voice] Failed to decrypt RTCP packet.");
+
+                return;
+            },
+        };
+
+        let mut data = packet[..RTCP_HEADER_LEN].to_vec();
+        data.extend_from_slice(&body);
+
+        for report in rtcp::parse_reports(&data) {
+            if let Some(block) = report.blocks.iter().find(|block| block.ssrc == self.ssrc) {
+                self.shared.update_stats(|stats| {
+                    stats.remote_jitter = Some(block.jitter);
+                    stats.remote_fraction_lost = Some(block.fraction_lost);
+                });
+            }
+
+            for receiver in &receive.receivers {
+                receiver.rtcp_report(&report).await;
+            }
+        }
+    }
+
     #[inline]
     #[instrument(skip(self))]
     async fn check_audio_timer(&mut self) -> Result<()> {
@@ -370,6 +962,7 @@ impl Connection {
             let mut bytes = [0; 4];
             (&mut bytes[..]).write_u32::<BigEndian>(self.ssrc)?;
             self.udp.send_to(&bytes, &self.destination).await?;
+            self.shared.update_stats(|stats| stats.bytes_sent += wire_len(bytes.len()));
             info!("[Voice] UDP keepalive sent");
         }
 
@@ -380,38 +973,110 @@ impl Connection {
     #[instrument(skip(self))]
     async fn check_keepalive_timer(&mut self) -> Result<()> {
         if self.keepalive_timer.check() {
-            info!("[Voice] WS keepalive");
-            let nonce = random::<u64>();
-            self.last_heartbeat_nonce = Some(nonce);
-            self.stream.send_json(&payload::build_heartbeat(nonce)).await?;
-            info!("[Voice] WS keepalive sent");
+
+            if self.last_heartbeat.is_some() {
+                self.missed_heartbeats += 1;
+
+                warn!("[Voice] Heartbeat ACK missed, {} in a row.", self.missed_heartbeats);
+
+                if let Some(limit) = self.heartbeat_timeout {
+                    if self.missed_heartbeats >= limit.max(1) {
+                        return Err(Error::Voice(VoiceError::HeartbeatTimeout));
+                    }
+                }
+            }
+
+            self.send_heartbeat().await?;
+        }
+
+        Ok(())
+    }
+
+    /// Closes the websocket with the given code, so that Discord ends the
+    /// session at once, rather than once it notices the socket has gone.
+    #[instrument(skip(self))]
+    pub async fn close(&mut self, code: u16) {
+        match timeout(CLOSE_TIMEOUT, send_close(&mut self.stream, code)).await {
+            Ok(Ok(())) => info!("[Voice] Closed websocket with code {}.", code),
+            Ok(Err(why)) => warn!("[Voice] Failed to close websocket: {:?}", why),
+            Err(_) => warn!("[Voice] Timed out closing websocket."),
         }
+    }
+
+    /// Sends a heartbeat immediately, regardless of the keepalive timer.
+    #[instrument(skip(self))]
+    pub async fn send_heartbeat(&mut self) -> Result<()> {
+        info!("[Voice] WS keepalive");
+        let nonce = random::<u64>();
+        self.last_heartbeat = Some((nonce, Instant::now()));
+        self.stream.send_json(&payload::build_heartbeat(nonce)).await?;
+        info!("[Voice] WS keepalive sent");
 
         Ok(())
     }
 
+    /// Sends a payload with the given opcode down the websocket as it is.
+    #[instrument(skip(self, data))]
+    pub async fn send_voice_op(&mut self, op: VoiceOpCode, data: Value) -> Result<()> {
+        self.stream.send_json(&payload::build_op(op, data)).await
+    }
+
     #[inline]
     #[instrument(skip(self, sources, buffer, mix_buffer))]
     async fn remove_unfinished_files(
         &mut self,
         sources: &mut Vec<LockedAudio>,
-        opus_frame: &[u8],
+        opus_frame: &mut Vec<u8>,
         buffer: &mut [i16; 1920],
         mut mix_buffer: &mut [f32; 1920],
     ) -> Result<usize> {
         let mut len = 0;
         let mut i = 0;
 
+        let mut stop_all = false;
+        let mut announcement: Option<DuckConfig> = None;
+        let mut requested_bitrate = None;
+
+        // Whether a playing source had no audio ready for this frame.
+        let mut starved = false;
+        let started = Instant::now();
+        let mut playing = 0;
+
         while i < sources.len() {
-            let mut finished = false;
+            let mut ended;
+            let mut failed = false;
+            let mut panicked = false;
 
             let aud_lock = (&sources[i]).clone();
             let mut aud = aud_lock.lock().await;
 
-            let vol = aud.volume;
+            let mut gains = [0f32; 960];
+            let bus_gain = aud.bus.as_ref()
+                .and_then(|bus| self.bus_volumes.get(bus))
+                .copied()
+                .unwrap_or(1.0);
+            aud.frame_gains(self.volume_ramp, self.ducker.gain(), bus_gain, &mut gains);
             let skip = !aud.playing;
 
+            // The first track playing which has asked for a bitrate gets it.
+            if !skip {
+                requested_bitrate = requested_bitrate.or(aud.bitrate);
+            }
+
+            // The deepest duck asked for by any announcement playing wins.
+            if let Some(config) = aud.announcement.filter(|_| !skip) {
+                if announcement.map_or(true, |current| config.level < current.level) {
+                    announcement = Some(config);
+                }
+            }
+            let seek_to = if aud.position_modified {
+                Some(aud.position)
+            } else {
+                None
+            };
+
             {
+                let muted = aud.is_muted();
                 let stream = &mut aud.source;
 
                 if skip {
@@ -420,11 +1085,28 @@ impl Connection {
                     continue;
                 }
 
-                // Assume this for now, at least.
-                // We'll be fusing streams, so we can either keep
-                // as stereo or downmix to mono.
-                let is_stereo = true;
-                let source_stereo = stream.is_stereo().await;
+                if let Some(position) = seek_to {
+                    if !stream.seek(position).await {
+                        warn!("[Voice] Unable to seek audio source to {:?}", position);
+                    }
+                }
+
+                let catch = self.catch_source_panics;
+
+                // A source is asked for its layout under the same guard as
+                // its reads, and is not read at all if that panics.
+                let layout = match guard_read(catch, stream.is_stereo()).await {
+                    Some(stereo) => guard_read(catch, stream.get_type()).await.map(|kind| (stereo, kind)),
+                    None => None,
+                };
+                let (source_stereo, kind) = match layout {
+                    Some((stereo, kind)) => (stereo, Some(kind)),
+                    None => (false, None),
+                };
+
+                // Streams are always mixed as stereo, and downmixed when
+                // encoding if the encoder has fallen back to mono.
+                let is_stereo = !self.mono_fallback;
 
                 if is_stereo != self.encoder_stereo {
                     let channels = if is_stereo {
@@ -432,80 +1114,250 @@ impl Connection {
                     } else {
                         Channels::Mono
                     };
-                    self.encoder = OpusEncoder::new(SAMPLE_RATE, channels, CodingMode::Audio)?;
+                    self.encoder = build_encoder(channels, &self.opus, self.bitrate(), self.complexity.current())?;
+                    self.encoder_rebuilds += 1;
                     self.encoder_stereo = is_stereo;
                 }
 
-                let temp_len = match stream.get_type().await {
-                    AudioType::Opus => if stream.decode_and_add_opus_frame(&mut mix_buffer, vol).await.is_some() {
-                            opus_frame.len()
-                        } else {
+                let temp_len = match kind {
+                    None => {
+                        panicked = true;
+
+                        0
+                    },
+                    Some(AudioType::Opus) => {
+                        let mut decoded = [0f32; 1920];
+
+                        match guard_read(catch, stream.decode_and_add_opus_frame(&mut decoded, 1.0)).await {
+                            Some(Some(count)) => {
+                                aud.equalize(&mut decoded, self.volume_ramp);
+                                combine_float_audio(&decoded, &mut mix_buffer, &gains);
+
+                                // Frames are always decoded to stereo.
+                                count * 2
+                            },
+                            Some(None) => {
+                                failed = true;
+
+                                0
+                            },
+                            None => {
+                                panicked = true;
+
+                                0
+                            },
+                        }
+                    },
+                    Some(AudioType::Passthrough) => match guard_read(catch, stream.read_opus_frame()).await {
+                        None => {
+                            panicked = true;
+
+                            0
+                        },
+                        Some(Some(frame)) if !frame.is_empty() => {
+                            // Only one frame can be sent, so the first
+                            // source playing takes the packet, and any
+                            // others are played unheard. A muted source
+                            // keeps its place but never takes the packet.
+                            if opus_frame.is_empty() && !muted {
+                                *opus_frame = frame;
+                            }
+
+                            buffer.len()
+                        },
+                        Some(Some(_)) => 0,
+                        Some(None) => {
+                            failed = true;
+
                             0
                         },
-                    AudioType::Pcm => {
+                    },
+                    Some(AudioType::Pcm) => {
                         let buffer_len = if source_stereo { 960 * 2 } else { 960 };
 
-                        match stream.read_pcm_frame(&mut buffer[..buffer_len]).await {
-                            Some(len) => len,
-                            None => 0,
+                        match guard_read(catch, stream.read_pcm_frame(&mut buffer[..buffer_len])).await {
+                            Some(Some(len)) => {
+                                let mut decoded = [0f32; 1920];
+
+                                // A partial frame leaves a gap, unless it was
+                                // the last the source had, which is only known
+                                // once the source has had another go.
+                                if aud.short_read && len > 0 {
+                                    starved = true;
+                                }
+                                aud.short_read = len < buffer_len;
+
+                                // May need to force interleave/copy.
+                                pcm_to_float(buffer, &mut decoded, source_stereo);
+                                aud.equalize(&mut decoded, self.volume_ramp);
+                                combine_float_audio(&decoded, &mut mix_buffer, &gains);
+
+                                len
+                            },
+                            Some(None) => {
+                                failed = true;
+
+                                0
+                            },
+                            None => {
+                                panicked = true;
+
+                                0
+                            },
                         }
                     },
                 };
 
-                // May need to force interleave/copy.
-                combine_audio(*buffer, &mut mix_buffer, source_stereo, vol);
+                if let Some(title) = aud.source.take_stream_title() {
+                    self.shared.emit(HandlerEvent::StreamMetadataUpdated {
+                        track_id: aud.id(),
+                        title,
+                    });
+                }
 
                 len = len.max(temp_len);
-                i += if temp_len > 0 {
-                    1
-                } else {
-                    sources.remove(i);
-                    finished = true;
+                ended = if panicked {
+                    aud.failure = Some(VoiceError::SourcePanicked);
 
-                    0
+                    Some(EndReason::Failed)
+                } else if temp_len == 0 && !failed {
+                    Some(EndReason::Finished)
+                } else {
+                    None
                 };
             }
 
-            aud.finished = finished;
+            if failed {
+                match aud.error_action(VoiceError::SourceRead) {
+                    ErrorAction::Retry => {
+                        warn!("[Voice] Audio source failed to read, retrying.");
+                        starved = true;
 
-            if !finished {
-                aud.step_frame();
+                        if aud.source.is_seekable() {
+                            aud.position_modified = true;
+                        }
+                    },
+                    ErrorAction::Stop => {
+                        ended = Some(EndReason::Failed);
+                        aud.failure = Some(VoiceError::SourceRead);
+                        stop_all = true;
+                    },
+                    ErrorAction::Skip => {
+                        ended = Some(EndReason::Failed);
+                        aud.failure = Some(VoiceError::SourceRead);
+                    },
+                }
             }
-        };
 
-        Ok(len)
-    }
+            // A track which has faded out is done, even if its source isn't.
+            if ended.is_none() && aud.step_fade() {
+                ended = Some(EndReason::Stopped);
+            }
 
-    #[allow(unused_variables)]
-    #[instrument(skip(self, sources, receiver))]
-    pub async fn cycle(
-        &mut self,
-        mut sources: &mut Vec<LockedAudio>,
-        mut receiver: &mut Option<Arc<dyn AudioReceiver>>,
-        audio_timer: &mut Timer,
-        bitrate: Bitrate,
-        muted: bool,
-    ) -> Result<()> {
-        // We need to actually reserve enough space for the desired bitrate.
-        let size = match bitrate {
-            // If user specified, we can calculate. 20ms means 50fps.
-            Bitrate::BitsPerSecond(b) => b / 50,
-            // Otherwise, just have a lot preallocated.
-            _ => 5120,
-        } + 16;
+            // As is a trimmed track once its last frame has been mixed.
+            if ended.is_none() && !failed && aud.trim_end_reached() {
+                ended = Some(EndReason::Finished);
+            }
 
-        let mut buffer = [0i16; 960 * 2];
-        let mut mix_buffer = [0f32; 960 * 2];
-        let mut packet = vec![0u8; size as usize].into_boxed_slice();
-        let mut nonce = Nonce::default();
+            aud.finished = ended.is_some();
 
-        while let Ok(Some(status)) = self.task_items.rx.try_next() {
-            match status {
-                ReceiverStatus::Udp(packet) => {
-                    self.handle_received_udp(&mut receiver, &mut buffer, &packet[..], &mut nonce).await?;
+            if let Some(reason) = ended {
+                sources.remove(i);
+                self.shared.end_track(&mut aud, reason);
+            } else {
+                i += 1;
+
+                // A retried frame produced no audio, so playback has not
+                // moved on.
+                if !failed {
+                    aud.mark_playing();
+                    aud.step_frame();
+                }
+
+                playing += 1;
+            }
+        };
+
+        // Reads which outlast the frame leave the listener with a gap, even
+        // if they produce audio.
+        let underrun = starved || started.elapsed() > FRAME_DURATION;
+
+        self.shared.update_stats(|stats| {
+            stats.tracks_playing = playing;
+
+            if underrun {
+                stats.underruns += 1;
+            }
+        });
+
+        self.ducker.advance(announcement.as_ref());
+
+        if requested_bitrate != self.track_bitrate {
+            let previous = mem::replace(&mut self.track_bitrate, requested_bitrate);
+
+            // A bitrate the encoder rejects is not worth reconnecting over,
+            // so the old one is kept.
+            if let Err(why) = self.apply_bitrate() {
+                warn!("[Voice] Failed to apply track bitrate {:?}: {:?}", requested_bitrate, why);
+
+                self.track_bitrate = previous;
+                self.apply_bitrate()?;
+            }
+        }
+
+        if stop_all {
+            let cleared = self.shared.queue.clear();
+
+            for source in sources.drain(..) {
+                self.shared.end_track(&mut *source.lock().await, EndReason::Cleared);
+            }
+
+            for track in cleared {
+                self.shared.end_track(&mut *track.audio().lock().await, EndReason::Cleared);
+            }
+        }
+
+        Ok(len)
+    }
+
+    #[allow(unused_variables)]
+    #[instrument(skip(self, sources, receive, tee, observer))]
+    pub async fn cycle(
+        &mut self,
+        mut sources: &mut Vec<LockedAudio>,
+        receive: &mut ReceiveState,
+        audio_timer: &mut Timer,
+        tee: &mut Option<OutputTee>,
+        observer: &Option<PacketObserver>,
+        muted: bool,
+    ) -> Result<()> {
+        // We need to actually reserve enough space for the desired bitrate.
+        let size = match self.bitrate() {
+            // If user specified, we can calculate. 20ms means 50fps.
+            Bitrate::BitsPerSecond(b) => b / 50,
+            // Otherwise, just have a lot preallocated.
+            _ => 5120,
+        } + self.cipher.overhead() as i32;
+
+        let mut buffer = [0i16; 960 * 2];
+        let mut mix_buffer = [0f32; 960 * 2];
+        let mut packet = vec![0u8; size as usize].into_boxed_slice();
+        while let Ok(Some(status)) = self.task_items.rx.try_next() {
+            if let ReceiverStatus::Websocket(_) = status {
+                self.shared.bump_ws_sequence();
+            }
+
+            match status {
+                ReceiverStatus::Udp(packet) => {
+                    self.shared.set_last_receive(Some(Instant::now()));
+                    self.shared.update_stats(|stats| stats.bytes_received += wire_len(packet.len()));
+                    self.handle_received_udp(receive, &mut buffer, &packet[..]).await?;
                 },
                 ReceiverStatus::Websocket(VoiceEvent::Speaking(ev)) => {
-                    if let Some(receiver) = receiver {
+                    self.ssrc_map.insert(ev.ssrc, ev.user_id);
+                    self.shared.update_speaking(|speaking| speaking.set_speaking(ev.user_id, ev.speaking));
+
+                    for receiver in &receive.receivers {
                         let receiver = Arc::clone(receiver);
 
                         tokio::spawn(async move {
@@ -514,7 +1366,9 @@ impl Connection {
                     }
                 },
                 ReceiverStatus::Websocket(VoiceEvent::ClientConnect(ev)) => {
-                    if let Some(receiver) = receiver {
+                    self.ssrc_map.insert(ev.audio_ssrc, ev.user_id);
+
+                    for receiver in &receive.receivers {
                         let receiver = Arc::clone(receiver);
 
                         tokio::spawn(async move {
@@ -523,7 +1377,23 @@ impl Connection {
                     }
                 },
                 ReceiverStatus::Websocket(VoiceEvent::ClientDisconnect(ev)) => {
-                    if let Some(receiver) = receiver {
+                    let sequences = &mut self.sequences;
+                    let concealing = &mut self.concealing;
+
+                    self.ssrc_map.retain(|ssrc, user_id| {
+                        let keep = *user_id != ev.user_id;
+
+                        if !keep {
+                            sequences.remove(ssrc);
+                            concealing.remove(ssrc);
+                            receive.forget_ssrc(*ssrc);
+                        }
+
+                        keep
+                    });
+                    self.shared.update_speaking(|speaking| speaking.set_speaking(ev.user_id, false));
+
+                    for receiver in &receive.receivers {
                         let receiver = Arc::clone(receiver);
 
                         tokio::spawn(async move {
@@ -532,40 +1402,84 @@ impl Connection {
                     }
                 },
                 ReceiverStatus::Websocket(VoiceEvent::HeartbeatAck(ev)) => {
-                    if let Some(nonce) = self.last_heartbeat_nonce {
+                    if let Some((nonce, sent)) = self.last_heartbeat {
 
                         if ev.nonce == nonce {
                             info!("[Voice] Heartbeat ACK received.");
+                            self.missed_heartbeats = 0;
+
+                            let latency = sent.elapsed();
+                            self.shared.update_stats(|stats| stats.heartbeat_latency = Some(latency));
                         } else {
                             warn!("[Voice] Heartbeat nonce mismatch! Expected {}, saw {}.", nonce, ev.nonce);
                         }
 
-                        self.last_heartbeat_nonce = None;
+                        self.last_heartbeat = None;
                     }
                 },
                 ReceiverStatus::Websocket(other) => {
                     info!("[Voice] Received other websocket data: {:?}", other);
                 },
+                ReceiverStatus::Closed(frame) => {
+                    return Err(Error::Gateway(GatewayError::Closed(Some(frame))));
+                },
             }
         }
 
+        if !receive.receivers.is_empty() {
+            let mixed = receive.mix();
+            let (_, mixed) = receive.convert(true, &mixed);
+
+            for receiver in &receive.receivers {
+                receiver.mixed_audio(&mixed).await;
+            }
+        }
+
+        receive.send_user_frames();
+
         // Send the voice websocket keepalive if it's time
         self.check_keepalive_timer().await?;
 
         // Send UDP keepalive if it's time
         self.check_audio_timer().await?;
 
-        // Reconfigure encoder bitrate.
-        // From my testing, it seemed like this needed to be set every cycle.
-        if let Err(e) = self.encoder.set_bitrate(bitrate) {
-            warn!("[Voice] Bitrate set unsuccessfully: {:?}", e);
+        let step = self.bitrate_ladder.step();
+
+        if let Some(bitrate) = self.bitrate_ladder.tick() {
+            info!("[Voice] Packet loss changed, setting bitrate to {:?}.", bitrate);
+
+            self.apply_bitrate()?;
+
+            if self.bitrate_ladder.step() > step {
+                self.emit_degraded();
+            }
+        }
+
+        // While dormant, nothing is mixed or sent until a source is added.
+        if self.idle.update(sources.is_empty(), Instant::now()) {
+            info!("[Voice] Nothing has played for {:?}, going dormant.", self.idle.timeout);
+
+            self.silence_frames = 0;
+            self.set_speaking(false).await?;
+        }
+
+        if self.idle.dormant {
+            self.shared.set_transmitting(false);
+            self.shared.set_output_level(0.0);
+            pace(self.pacing, audio_timer).await;
+
+            return Ok(());
         }
 
         let mut opus_frame = Vec::new();
 
         // Walk over all the audio files, removing those which have finished.
         // For this purpose, we need a while loop in Rust.
-        let mut len = self.remove_unfinished_files(&mut sources, &opus_frame, &mut buffer, &mut mix_buffer).await?;
+        let mut len = self.remove_unfinished_files(&mut sources, &mut opus_frame, &mut buffer, &mut mix_buffer).await?;
+
+        if let Some(limiter) = self.limiter.as_mut() {
+            limiter.apply(&mut mix_buffer[..]);
+        }
 
         self.soft_clip.apply(&mut mix_buffer[..])?;
 
@@ -573,22 +1487,50 @@ impl Connection {
             len = 0;
         }
 
+        // Once the playtime budget runs out, nothing plays until it is reset.
+        if self.shared.playtime_exhausted() {
+            for source in sources.drain(..) {
+                self.shared.end_track(&mut *source.lock().await, EndReason::Stopped);
+            }
+
+            // Clearing emits an event, so is only done when there is
+            // something to clear, rather than on every frame.
+            if !self.shared.queue.is_empty() {
+                for track in self.shared.queue.clear() {
+                    self.shared.end_track(&mut *track.audio().lock().await, EndReason::Stopped);
+                }
+            }
+
+            len = 0;
+        }
+
+        self.shared.set_transmitting(len > 0);
+
+        if len > 0 {
+            self.shared.spend_playtime(FRAME_DURATION);
+            self.shared.set_output_level(smoothed_level(self.shared.output_level(), &mix_buffer[..]));
+        } else {
+            self.shared.set_output_level(0.0);
+        }
+
         if len == 0 {
             if self.silence_frames > 0 {
                 self.silence_frames -= 1;
 
-                // Explicit "Silence" frame.
-                opus_frame.extend_from_slice(&[0xf8, 0xff, 0xfe]);
+                // Explicit "Silence" frame, in place of any passthrough
+                // frame muted.
+                opus_frame.clear();
+                opus_frame.extend_from_slice(&SILENCE_FRAME);
             } else {
-                // Per official guidelines, send 5x silence BEFORE we stop speaking.
+                // Per official guidelines, send silence BEFORE we stop speaking.
                 self.set_speaking(false).await?;
 
-                audio_timer.hold().await;
+                pace(self.pacing, audio_timer).await;
 
                 return Ok(());
             }
         } else {
-            self.silence_frames = 5;
+            self.silence_frames = SILENCE_FRAME_COUNT;
 
             for value in &mut buffer[len..] {
                 *value = 0;
@@ -597,11 +1539,46 @@ impl Connection {
 
         self.set_speaking(true).await?;
 
-        let index = self.prep_packet(&mut packet, mix_buffer, &opus_frame, nonce)?;
-        audio_timer.hold().await;
+        let index = self.prep_packet(&mut packet, mix_buffer, &opus_frame)?;
+
+        if let Some(output) = tee.as_mut() {
+            let written = if opus_frame.is_empty() {
+                output.write(&mix_buffer[..])
+            } else {
+                output.write_opus(&opus_frame, mix_buffer.len())
+            };
+
+            if !written {
+                *tee = None;
+            }
+        }
+
+        pace(self.pacing, audio_timer).await;
+
+        // Frames suppressed by DTX leave the speaking flag set, as the
+        // source is still playing, and only skip the packet itself.
+        match index {
+            Some(index) => {
+                if let Some(observer) = observer {
+                    let mut header = &packet[2..HEADER_LEN];
+
+                    observer(&RtpPacketView {
+                        sequence: header.read_u16::<BigEndian>()?,
+                        timestamp: header.read_u32::<BigEndian>()?,
+                        ssrc: header.read_u32::<BigEndian>()?,
+                        payload_len: index - HEADER_LEN,
+                    });
+                }
 
-        self.udp.send_to(&packet[..index], &self.destination).await?;
-        self.audio_timer.reset();
+                self.udp.send_to(&packet[..index], &self.destination).await?;
+                self.audio_timer.reset();
+                self.shared.update_stats(|stats| {
+                    stats.packets_sent += 1;
+                    stats.bytes_sent += wire_len(index);
+                });
+            },
+            None => self.shared.update_stats(|stats| stats.packets_suppressed += 1),
+        }
 
         Ok(())
     }
@@ -610,37 +1587,89 @@ impl Connection {
     fn prep_packet(&mut self,
                    packet: &mut [u8],
                    buffer: [f32; 1920],
-                   opus_frame: &[u8],
-                   mut nonce: Nonce)
-                   -> Result<usize> {
-        {
-            let mut cursor = &mut packet[..HEADER_LEN];
-            cursor.write_all(&[0x80, 0x78])?;
-            cursor.write_u16::<BigEndian>(self.sequence)?;
-            cursor.write_u32::<BigEndian>(self.timestamp)?;
-            cursor.write_u32::<BigEndian>(self.ssrc)?;
-        }
-
-        nonce[..HEADER_LEN]
-            .clone_from_slice(&packet[..HEADER_LEN]);
+                   opus_frame: &[u8])
+                   -> Result<Option<usize>> {
+        write_rtp_header(
+            &mut packet[..HEADER_LEN],
+            self.rtp_payload_type,
+            self.rtp_header_extension,
+            self.sequence,
+            self.timestamp,
+            self.ssrc,
+        )?;
+
+        let payload_at = if self.rtp_header_extension {
+            let payload_at = HEADER_LEN + EMPTY_HEADER_EXTENSION.len();
+            packet[HEADER_LEN..payload_at].clone_from_slice(&EMPTY_HEADER_EXTENSION);
+
+            payload_at
+        } else {
+            HEADER_LEN
+        };
 
-        let sl_index = packet.len() - 16;
-        let buffer_len = if self.encoder_stereo { 960 * 2 } else { 960 };
+        let sl_index = packet.len() - self.cipher.overhead();
 
         let len = if opus_frame.is_empty() {
-            self.encoder
-                .encode_float(&buffer[..buffer_len], &mut packet[HEADER_LEN..sl_index])?
+            let started = Instant::now();
+            let encoded = if self.encoder_stereo {
+                self.encoder.encode_float(&buffer[..], &mut packet[payload_at..sl_index])
+            } else {
+                self.encoder.encode_float(&downmix_to_mono(&buffer), &mut packet[payload_at..sl_index])
+            };
+            self.record_encode_time(started.elapsed())?;
+
+            match encoded {
+                Ok(len) if self.opus.dtx && len <= DTX_FRAME_LEN => {
+                    self.encoder_failures = 0;
+
+                    // The timestamp still advances over the skipped frame,
+                    // so that listeners keep the right timing.
+                    self.timestamp = self.timestamp.wrapping_add(960);
+
+                    return Ok(None);
+                },
+                Ok(len) => {
+                    self.encoder_failures = 0;
+
+                    len
+                },
+                Err(why) => {
+                    self.encoder_failures += 1;
+                    self.shared.update_stats(|stats| stats.encode_errors += 1);
+
+                    // A persistently failing encoder is treated as a broken
+                    // connection, rather than sending silence forever.
+                    if self.encoder_failures >= MAX_ENCODER_FAILURES {
+                        self.encoder_failures = 0;
+
+                        return Err(Error::Opus(why));
+                    }
+
+                    warn!("[Voice] Failed to encode frame, sending silence: {:?}", why);
+
+                    let len = SILENCE_FRAME.len();
+                    packet[payload_at..payload_at + len]
+                        .clone_from_slice(&SILENCE_FRAME);
+                    len
+                },
+            }
+        } else if payload_at + opus_frame.len() > sl_index {
+            warn!("[Voice] Dropping an Opus frame of {} bytes, too large for a packet.", opus_frame.len());
+
+            self.timestamp = self.timestamp.wrapping_add(960);
+
+            return Ok(None);
         } else {
             let len = opus_frame.len();
-            packet[HEADER_LEN..HEADER_LEN + len]
+            packet[payload_at..payload_at + len]
                 .clone_from_slice(opus_frame);
             len
         };
 
         let crypted = {
-            let slice = &packet[HEADER_LEN..HEADER_LEN + len];
-            self.cipher.encrypt(&nonce, slice)
-                .expect("[Voice] Couldn't encrypt packet.")
+            let (header, payload) = packet.split_at(HEADER_LEN);
+            self.cipher.encrypt(header, &payload[..payload_at - HEADER_LEN + len])
+                .map_err(Error::Voice)?
         };
         let index = HEADER_LEN + crypted.len();
         packet[HEADER_LEN..index].clone_from_slice(&crypted);
@@ -648,7 +1677,7 @@ impl Connection {
         self.sequence = self.sequence.wrapping_add(1);
         self.timestamp = self.timestamp.wrapping_add(960);
 
-        Ok(HEADER_LEN + crypted.len())
+        Ok(Some(HEADER_LEN + crypted.len()))
     }
 
     #[instrument(skip(self))]
@@ -659,6 +1688,20 @@ impl Connection {
 
         self.speaking = speaking;
 
+        self.indicate_speaking().await
+    }
+
+    /// Sends the speaking flag to Discord if it differs from what was last
+    /// sent, which is always unset while the indicator is disabled.
+    async fn indicate_speaking(&mut self) -> Result<()> {
+        let speaking = self.speaking && self.speaking_indicator;
+
+        if self.indicated == speaking {
+            return Ok(());
+        }
+
+        self.indicated = speaking;
+
         info!("[Voice] Speaking update: {}", speaking);
         let o = self
             .stream
@@ -678,35 +1721,175 @@ impl Drop for Connection {
     }
 }
 
+/// Makes a new encoder with every setting applied, overriding the configured
+/// bitrate and complexity with those given.
+fn build_encoder(channels: Channels, config: &OpusConfig, bitrate: Bitrate, complexity: u8) -> Result<OpusEncoder> {
+    let mut encoder = OpusEncoder::new(SAMPLE_RATE, channels, CodingMode::Audio)?;
+    config.apply(&mut encoder)?;
+    encoder.set_bitrate(bitrate)?;
+    encoder.set_complexity(complexity)?;
+
+    Ok(encoder)
+}
+
 #[inline]
-fn combine_audio(
-    raw_buffer: [i16; 1920],
-    float_buffer: &mut [f32; 1920],
-    true_stereo: bool,
-    volume: f32,
-) {
-    for (i, float_buffer_element) in float_buffer.iter_mut().enumerate().take(1920) {
+/// Converts a frame of PCM audio to floating point samples, upmixing mono
+/// audio to stereo.
+fn pcm_to_float(raw_buffer: &[i16; 1920], float_buffer: &mut [f32; 1920], true_stereo: bool) {
+    for (i, float_buffer_element) in float_buffer.iter_mut().enumerate() {
         let sample_index = if true_stereo { i } else { i / 2 };
-        let sample = f32::from(raw_buffer[sample_index]) / 32768.0;
 
-        *float_buffer_element += sample * volume;
+        *float_buffer_element = f32::from(raw_buffer[sample_index]) / 32768.0;
+    }
+}
+
+/// The length of a UDP datagram on the wire, with its header.
+fn wire_len(payload_len: usize) -> u64 {
+    (payload_len + UDP_HEADER_LEN) as u64
+}
+
+/// Averages each pair of samples in a stereo frame, for a mono encoder.
+fn downmix_to_mono(buffer: &[f32; 1920]) -> [f32; 960] {
+    let mut mono = [0f32; 960];
+
+    for (sample, pair) in mono.iter_mut().zip(buffer.chunks_exact(2)) {
+        *sample = (pair[0] + pair[1]) / 2.0;
+    }
+
+    mono
+}
+
+/// Mixes decoded stereo audio into a frame, scaling each sample by its gain.
+fn combine_float_audio(decoded: &[f32; 1920], float_buffer: &mut [f32; 1920], gains: &[f32; 960]) {
+    for (i, (float_buffer_element, sample)) in float_buffer.iter_mut().zip(decoded.iter()).enumerate() {
+        *float_buffer_element += sample * gains[i / 2];
+    }
+}
+
+/// The number of samples per channel in a ramp of the given length.
+fn ramp_len(ramp: Duration) -> usize {
+    (ramp.as_secs_f64() * f64::from(SAMPLE_RATE as i32)).round() as usize
+}
+
+/// Normalises a voice server endpoint sent by Discord into a bare hostname.
+///
+/// Discord has historically appended a port (usually `:80`) which must not be
+/// used, as the connection is always made over `wss` on the default port.
+/// IPv6 addresses are kept in their brackets.
+pub(crate) fn normalize_endpoint(endpoint: &str) -> StdResult<String, VoiceError> {
+    let mut host = endpoint.trim();
+
+    if host.starts_with("wss://") {
+        host = &host["wss://".len()..];
+    }
+
+    host = host.trim_end_matches('/');
+
+    // The colons of an IPv6 address are not a port separator, so the port
+    // can only follow its closing bracket.
+    let separator = if host.starts_with('[') {
+        match host.find(']') {
+            Some(end) if end + 1 < host.len() => Some(end + 1),
+            Some(_) => None,
+            None => return Err(VoiceError::EndpointUrl),
+        }
+    } else {
+        host.rfind(':')
+    };
+
+    if let Some(index) = separator {
+        let (name, port) = host.split_at(index);
+
+        if !port.starts_with(':') || port[1..].parse::<u16>().is_err() {
+            return Err(VoiceError::EndpointUrl);
+        }
+
+        host = name;
     }
+
+    match url::Host::parse(host) {
+        Ok(_) => Ok(host.to_string()),
+        Err(_) => Err(VoiceError::EndpointUrl),
+    }
+}
+
+/// Writes the fixed RTP header of a sent packet, marking whether a header
+/// extension follows it.
+fn write_rtp_header(
+    mut header: &mut [u8],
+    payload_type: u8,
+    extension: bool,
+    sequence: u16,
+    timestamp: u32,
+    ssrc: u32,
+) -> Result<()> {
+    let first = if extension { 0x90 } else { 0x80 };
+
+    header.write_all(&[first, payload_type & 0x7F])?;
+    header.write_u16::<BigEndian>(sequence)?;
+    header.write_u32::<BigEndian>(timestamp)?;
+    header.write_u32::<BigEndian>(ssrc)?;
+
+    Ok(())
 }
 
-fn generate_url(endpoint: &mut String) -> Result<Url> {
-    if endpoint.ends_with(":80") {
-        let len = endpoint.len();
+/// Picks the initial RTP sequence number and timestamp, from the seed if one
+/// is given.
+fn initial_rtp_state(seed: Option<u64>) -> (u16, u32) {
+    match seed {
+        Some(seed) => {
+            let mut rng = StdRng::seed_from_u64(seed);
 
-        endpoint.truncate(len - 3);
+            (rng.gen(), rng.gen())
+        },
+        None => (random(), random()),
     }
+}
+
+/// Opens a voice WebSocket, at the address given by the endpoint resolver
+/// for the URL's host if one is set.
+async fn open_ws(url: Url, resolver: Option<&EndpointResolver>) -> Result<WsStream> {
+    let address = match (resolver, url.host_str()) {
+        (Some(resolver), Some(host)) => Some(checked_endpoint_address(resolver(host))?),
+        _ => None,
+    };
+
+    #[cfg(all(feature = "rustls_backend", not(feature = "native_tls_backend")))]
+    let stream = match address {
+        Some(address) => create_rustls_client_to(url, address).await?,
+        None => create_rustls_client(url).await?,
+    };
+
+    #[cfg(feature = "native_tls_backend")]
+    let stream = match address {
+        Some(address) => create_native_tls_client_to(url, address).await?,
+        None => create_native_tls_client(url).await?,
+    };
+
+    Ok(stream)
+}
+
+/// Rejects resolved endpoint addresses which cannot be connected to.
+fn checked_endpoint_address(address: SocketAddr) -> StdResult<SocketAddr, VoiceError> {
+    if address.ip().is_unspecified() || address.ip().is_multicast() || address.port() == 0 {
+        return Err(VoiceError::InvalidEndpointAddress(address));
+    }
+
+    Ok(address)
+}
 
+fn generate_url(endpoint: &str) -> Result<Url> {
     Url::parse(&format!("wss://{}/?v={}", endpoint, VOICE_GATEWAY_VERSION))
         .or(Err(Error::Voice(VoiceError::EndpointUrl)))
 }
 
 #[inline]
-#[instrument(skip(stream))]
-async fn init_cipher(stream: &mut WsStream) -> Result<XSalsa20Poly1305> {
+#[instrument(skip(stream, backend))]
+async fn init_cipher(
+    stream: &mut WsStream,
+    mode: EncryptionMode,
+    backend: Option<&EncryptionBackendFactory>,
+) -> Result<Box<dyn EncryptionBackend>> {
     loop {
         let value = match stream.recv_json().await? {
             Some(value) => value,
@@ -715,12 +1898,21 @@ async fn init_cipher(stream: &mut WsStream) -> Result<XSalsa20Poly1305> {
 
         match VoiceEvent::deserialize(value)? {
             VoiceEvent::SessionDescription(desc) => {
-                if desc.mode != CRYPTO_MODE {
+                if desc.mode != mode.name() {
+                    return Err(Error::Voice(VoiceError::VoiceModeInvalid));
+                }
+
+                let cipher = match backend {
+                    Some(make) => make(&desc.secret_key),
+                    None => mode.backend(&desc.secret_key),
+                }.map_err(Error::Voice)?;
+
+                // A replacement must implement the mode which was agreed.
+                if cipher.mode() != mode {
                     return Err(Error::Voice(VoiceError::VoiceModeInvalid));
                 }
 
-                break XSalsa20Poly1305::new_varkey(&desc.secret_key)
-                    .map_err(|_| Error::Voice(VoiceError::KeyGen));
+                break Ok(cipher);
             },
             VoiceEvent::Unknown(op, value) => {
                 debug!(
@@ -734,12 +1926,65 @@ async fn init_cipher(stream: &mut WsStream) -> Result<XSalsa20Poly1305> {
     }
 }
 
+/// Sends a close frame with the given code, and no reason.
+async fn send_close<S>(sink: &mut S, code: u16) -> StdResult<(), S::Error>
+where S: Sink<Message> + Unpin
+{
+    let frame = CloseFrame {
+        code: CloseCode::from(code),
+        reason: "".into(),
+    };
+
+    sink.send(Message::Close(Some(frame))).await
+}
+
+/// Moves an output level, between 0 and 1, towards the peak of a mixed
+/// frame, so that meters built on it neither flicker nor lag far behind.
+fn smoothed_level(previous: f32, frame: &[f32]) -> f32 {
+    let peak = frame.iter().fold(0f32, |peak, sample| peak.max(sample.abs())).min(1.0);
+
+    previous + (peak - previous) * LEVEL_SMOOTHING
+}
+
+/// Awaits a read of an audio source, returning `None` if it panicked and
+/// `catch` is set, rather than unwinding through the task.
+async fn guard_read<F: Future>(catch: bool, read: F) -> Option<F::Output> {
+    if !catch {
+        return Some(read.await);
+    }
+
+    match AssertUnwindSafe(read).catch_unwind().await {
+        Ok(output) => Some(output),
+        Err(payload) => {
+            error!("[Voice] Audio source panicked while reading, ending it: {}", panic_message(&*payload));
+
+            None
+        },
+    }
+}
+
+/// The message a panic was raised with, if it was given one.
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    payload.downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("no message")
+}
+
+/// Waits until the next frame is due, according to the pacing mode.
+async fn pace(mode: PacingMode, timer: &mut Timer) {
+    match mode {
+        PacingMode::Precise => timer.hold_precise(PACING_MARGIN).await,
+        PacingMode::Timer => timer.hold().await,
+    }
+}
+
 #[inline]
-fn has_valid_mode<T, It> (modes: It) -> bool
+fn has_valid_mode<T, It> (modes: It, mode: EncryptionMode) -> bool
 where T: for<'a> PartialEq<&'a str>,
       It : IntoIterator<Item=T>
 {
-    modes.into_iter().any(|s| s == CRYPTO_MODE)
+    modes.into_iter().any(|s| s == mode.name())
 }
 
 #[inline]
@@ -799,7 +2044,19 @@ async fn start_ws_task(mut stream: SplitStream<WsStream>, tx: &Sender<ReceiverSt
             use crate::internal::ws_impl::convert_ws_message;
             use futures::stream::TryStreamExt;
 
-            while let Ok(Some(value)) = convert_ws_message(stream.try_next().await.ok().flatten()) {
+            loop {
+                let value = match convert_ws_message(stream.try_next().await.ok().flatten()) {
+                    Ok(Some(value)) => value,
+                    // The close code decides how the connection is restored,
+                    // and nothing more will be read from this stream.
+                    Err(Error::Gateway(GatewayError::Closed(Some(frame)))) => {
+                        let _ = tx_ws.unbounded_send(ReceiverStatus::Closed(frame));
+
+                        break 'outer;
+                    },
+                    _ => break,
+                };
+
                 let msg = match VoiceEvent::deserialize(value) {
                     Ok(msg) => msg,
                     Err(_) => break,
@@ -824,3 +2081,690 @@ async fn start_ws_task(mut stream: SplitStream<WsStream>, tx: &Sender<ReceiverSt
 
     Ok((ws_close_sender, ws_task))
 }
+
+#[cfg(test)]
+pub(crate) mod test {
+    use async_tungstenite::{tokio::{accept_async, connect_async, TokioAdapter}, WebSocketStream};
+    use crate::model::id::GuildId;
+    use super::*;
+    use super::super::{audio, AudioSource, TrackStatus};
+    use tokio::net::{TcpListener, TcpStream};
+
+    /// The voice server's end of a connection made by [`local_connection`].
+    ///
+    /// [`local_connection`]: fn.local_connection.html
+    pub(crate) struct LocalServer {
+        pub(crate) ws: WebSocketStream<TokioAdapter<TcpStream>>,
+        pub(crate) udp: UdpSocket,
+    }
+
+    /// Opens a WebSocket on the loopback interface, returning the client's
+    /// end and the server's.
+    async fn local_ws() -> (WsStream, WebSocketStream<TokioAdapter<TcpStream>>) {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        let (client, server) = futures::future::join(connect_async(url), async {
+            let (socket, _) = listener.accept().await.unwrap();
+
+            accept_async(socket).await.unwrap()
+        }).await;
+
+        (client.unwrap().0, server)
+    }
+
+    /// Makes a connection to a stand-in for a voice server on the loopback
+    /// interface, which has already completed its handshake.
+    pub(crate) async fn local_connection() -> (Connection, LocalServer) {
+        let (tx, _rx) = unbounded();
+        let shared = Arc::new(SharedState::new(GuildId(1), tx));
+        let (client, server) = local_ws().await;
+
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server_udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let destination = server_udp.local_addr().unwrap();
+
+        let info = ConnectionInfo {
+            bind_address: None,
+            endpoint: "127.0.0.1".to_string(),
+            endpoint_resolver: None,
+            guild_id: GuildId(1),
+            rtp_seed: Some(0),
+            session_id: String::new(),
+            token: String::new(),
+            user_id: UserId(2),
+        };
+        let cipher = EncryptionMode::default().backend(&[0; 32]).unwrap();
+
+        let connection = Connection::from_parts(info, client, udp, destination, 1, cipher, 41_250, shared)
+            .await
+            .unwrap();
+
+        (connection, LocalServer { ws: server, udp: server_udp })
+    }
+
+    /// Passes payloads through unencrypted, claiming to be `mode`.
+    struct PlainBackend(EncryptionMode);
+
+    impl EncryptionBackend for PlainBackend {
+        fn mode(&self) -> EncryptionMode {
+            self.0
+        }
+
+        fn overhead(&self) -> usize {
+            0
+        }
+
+        fn encrypt(&mut self, _header: &[u8], payload: &[u8]) -> StdResult<Vec<u8>, VoiceError> {
+            Ok(payload.to_vec())
+        }
+
+        fn decrypt(&self, _header: &[u8], body: &[u8]) -> Option<Vec<u8>> {
+            Some(body.to_vec())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_custom_encryption_backend() {
+        use futures::SinkExt;
+
+        for &claimed in &[EncryptionMode::Normal, EncryptionMode::Lite] {
+            let (mut client, mut server) = local_ws().await;
+            let description = serde_json::json!({
+                "op": VoiceOpCode::SessionDescription.num(),
+                "d": { "mode": EncryptionMode::Normal.name(), "secret_key": vec![0; 32] },
+            });
+            server.send(Message::Text(description.to_string())).await.unwrap();
+
+            let factory: EncryptionBackendFactory = Arc::new(move |key| {
+                assert_eq!(key, &[0; 32][..]);
+
+                Ok(Box::new(PlainBackend(claimed)))
+            });
+            let cipher = init_cipher(&mut client, EncryptionMode::Normal, Some(&factory)).await;
+
+            // A backend for a mode other than the one agreed is refused.
+            match cipher {
+                Ok(mut cipher) => {
+                    assert_eq!(claimed, EncryptionMode::Normal);
+                    assert_eq!(cipher.overhead(), 0);
+                    assert_eq!(cipher.encrypt(&[0; HEADER_LEN], &[1, 2, 3]).unwrap(), vec![1, 2, 3]);
+                },
+                Err(Error::Voice(VoiceError::VoiceModeInvalid)) => assert_eq!(claimed, EncryptionMode::Lite),
+                Err(why) => panic!("unexpected error: {:?}", why),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_send_close() {
+        let (mut tx, mut rx) = unbounded();
+        send_close(&mut tx, DEFAULT_CLOSE_CODE).await.unwrap();
+
+        match rx.try_next() {
+            Ok(Some(Message::Close(Some(frame)))) => {
+                assert_eq!(frame.code, CloseCode::Normal);
+                assert!(frame.reason.is_empty());
+            },
+            _ => panic!("expected a close frame"),
+        }
+    }
+
+    #[test]
+    fn test_dtx_suppresses_silence() {
+        let mut encoder = OpusEncoder::new(SAMPLE_RATE, Channels::Stereo, CodingMode::Audio).unwrap();
+        encoder.set_encoder_ctl_request(ffi::OPUS_SET_DTX_REQUEST, 1).unwrap();
+        let mut output = [0u8; 4000];
+
+        // DTX sets in after several frames of silence, then only sends an
+        // occasional frame to keep the decoder's noise estimate fresh.
+        let silence = [0f32; 1920];
+        let suppressed = (0..50)
+            .filter(|_| encoder.encode_float(&silence, &mut output).unwrap() <= DTX_FRAME_LEN)
+            .count();
+        assert!(suppressed > 30, "only {} of 50 silent frames suppressed", suppressed);
+
+        let mut tone = [0f32; 1920];
+        for (i, sample) in tone.iter_mut().enumerate() {
+            *sample = ((i / 2) as f32 * 440.0 * std::f32::consts::PI * 2.0 / 48_000.0).sin() * 0.5;
+        }
+
+        for _ in 0..5 {
+            assert!(encoder.encode_float(&tone, &mut output).unwrap() > DTX_FRAME_LEN);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dtx_skips_silent_packets() {
+        use super::super::{pcm, Audio};
+        use std::io::Cursor;
+        use tokio::sync::Mutex;
+
+        let (mut connection, _server) = local_connection().await;
+        connection.set_dtx(true).unwrap();
+
+        // A playing track of silence still has each frame mixed and encoded.
+        let silence = Arc::new(Mutex::new(Audio::new(pcm(true, Cursor::new(vec![0u8; 3840 * 50])))));
+        let mut sources = vec![silence];
+        let mut receive = ReceiveState::default();
+        let mut timer = Timer::new(20);
+
+        for _ in 0..50 {
+            connection.cycle(&mut sources, &mut receive, &mut timer, &mut None, &None, false).await.unwrap();
+        }
+
+        let stats = connection.shared.network_stats();
+        assert!(stats.packets_suppressed > 30, "only {} of 50 silent frames suppressed", stats.packets_suppressed);
+        assert_eq!(stats.packets_sent + stats.packets_suppressed, 50);
+
+        let mut packet = [0u8; 2000];
+        assert_eq!(connection.prep_packet(&mut packet, [0f32; 1920], &[]).unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_exhausted_budget_clears_queue_once() {
+        use super::super::{pcm, TrackMetadata};
+        use std::io::Cursor;
+
+        let (mut connection, _server) = local_connection().await;
+        connection.shared.queue.add(pcm(true, Cursor::new(vec![0u8; 3840 * 5])), TrackMetadata::default()).unwrap();
+        connection.shared.update_playtime_budget(|budget| budget.limit = Some(Duration::default()));
+
+        let mut events = connection.shared.subscribe();
+        let mut sources = Vec::new();
+        let mut receive = ReceiveState::default();
+        let mut timer = Timer::new(20);
+
+        for _ in 0..5 {
+            connection.cycle(&mut sources, &mut receive, &mut timer, &mut None, &None, false).await.unwrap();
+        }
+
+        assert!(connection.shared.queue.is_empty());
+
+        let mut changes = 0;
+
+        while let Ok(Some(event)) = events.try_next() {
+            if let HandlerEvent::QueueChanged = event {
+                changes += 1;
+            }
+        }
+
+        assert_eq!(changes, 1);
+    }
+
+    #[test]
+    fn test_normalize_endpoint() {
+        assert_eq!(normalize_endpoint("wss://voice.example.com:80/").unwrap(), "voice.example.com");
+        assert_eq!(normalize_endpoint("voice.example.com").unwrap(), "voice.example.com");
+        assert_eq!(normalize_endpoint("127.0.0.1:443").unwrap(), "127.0.0.1");
+        assert_eq!(normalize_endpoint("[::1]:80").unwrap(), "[::1]");
+        assert_eq!(normalize_endpoint("[::1]").unwrap(), "[::1]");
+
+        assert!(normalize_endpoint("voice.example.com:port").is_err());
+        assert!(normalize_endpoint("[::1]80").is_err());
+        assert!(normalize_endpoint("[::1").is_err());
+        assert!(normalize_endpoint("::1").is_err());
+    }
+
+    #[test]
+    fn test_write_rtp_header() {
+        let mut header = [0u8; HEADER_LEN];
+
+        write_rtp_header(&mut header, DEFAULT_RTP_PAYLOAD_TYPE, false, 1, 2, 3).unwrap();
+        assert_eq!(header, [0x80, 0x78, 0, 1, 0, 0, 0, 2, 0, 0, 0, 3]);
+
+        write_rtp_header(&mut header, 111, true, 1, 2, 3).unwrap();
+        assert_eq!(header[..2], [0x90, 111]);
+    }
+
+    #[test]
+    fn test_seeded_rtp_state() {
+        assert_eq!(initial_rtp_state(Some(7)), initial_rtp_state(Some(7)));
+        assert_ne!(initial_rtp_state(Some(7)), initial_rtp_state(Some(8)));
+    }
+
+    #[test]
+    fn test_idle_state() {
+        let start = Instant::now();
+        let mut idle = IdleState::default();
+
+        // Without a timeout, the loop never goes dormant.
+        assert!(!idle.update(true, start + Duration::from_secs(60)));
+
+        idle.timeout = Some(Duration::from_secs(5));
+        assert!(!idle.update(true, start));
+        assert!(!idle.update(true, start + Duration::from_secs(4)));
+        assert!(idle.update(true, start + Duration::from_secs(5)));
+        assert!(idle.dormant);
+        assert!(!idle.update(true, start + Duration::from_secs(6)));
+
+        // A new source wakes the loop at once, and restarts the timeout.
+        assert!(!idle.update(false, start + Duration::from_secs(7)));
+        assert!(!idle.dormant);
+        assert!(!idle.update(true, start + Duration::from_secs(8)));
+        assert!(!idle.update(true, start + Duration::from_secs(12)));
+        assert!(idle.update(true, start + Duration::from_secs(13)));
+    }
+
+    #[tokio::test]
+    async fn test_pacing() {
+        for &mode in &[PacingMode::Precise, PacingMode::Timer] {
+            let start = Instant::now();
+            let mut timer = Timer::new(5);
+
+            for frame in 1..=4 {
+                pace(mode, &mut timer).await;
+
+                // Frames are never sent early, and each is due a fixed time
+                // after the start rather than after the last was sent.
+                assert!(start.elapsed() >= Duration::from_millis(5 * frame));
+            }
+
+            assert!(timer.lateness() < Duration::from_millis(5));
+        }
+    }
+
+    // This depends on the machine's timers and load, so is only run by hand,
+    // as a benchmark, via `cargo test -- --ignored`.
+    #[tokio::test]
+    #[ignore]
+    async fn test_precise_pacing_jitter() {
+        // Measures how late each of a run of frames is sent, relative to
+        // when it was due.
+        async fn lateness(mode: PacingMode) -> Vec<Duration> {
+            let start = Instant::now();
+            let mut timer = Timer::new(5);
+
+            let mut lateness = Vec::new();
+            for frame in 1..=20 {
+                pace(mode, &mut timer).await;
+                lateness.push(start.elapsed() - Duration::from_millis(5 * frame));
+            }
+
+            lateness.sort();
+
+            lateness
+        }
+
+        let precise = lateness(PacingMode::Precise).await;
+        let timer = lateness(PacingMode::Timer).await;
+
+        // Precise pacing stops trusting the coarse timer shortly before each
+        // frame, so frames are sent closer to when they are due. Medians are
+        // compared, as either run may be held up now and then by other work
+        // on the machine.
+        assert!(precise[10] < timer[10], "{:?} against {:?}", precise, timer);
+        assert!(precise[10] < Duration::from_millis(1), "{:?}", precise);
+    }
+
+    #[test]
+    fn test_smoothed_level() {
+        let loud = [0.1, -0.8, 0.5];
+
+        let level = smoothed_level(0.0, &loud);
+        assert!(level > 0.0 && level < 0.8);
+        assert!(smoothed_level(level, &loud) > level);
+
+        // The level settles on a steady peak, and falls back with silence.
+        let settled = (0..50).fold(0.0, |level, _| smoothed_level(level, &loud));
+        assert!((settled - 0.8).abs() < 1e-3);
+        assert!(smoothed_level(settled, &[0.0; 4]) < settled);
+
+        assert!(smoothed_level(1.0, &[3.0, -2.0]) <= 1.0);
+    }
+
+    #[tokio::test]
+    async fn test_guard_read() {
+        assert_eq!(guard_read(true, async { Some(3) }).await, Some(Some(3)));
+        assert_eq!(guard_read(false, async { None::<usize> }).await, Some(None));
+
+        let panicking = async {
+            if true {
+                panic!("bad read");
+            }
+
+            Some(3)
+        };
+        assert_eq!(guard_read(true, panicking).await, None);
+
+        assert_eq!(panic_message(&"static"), "static");
+        assert_eq!(panic_message(&String::from("owned")), "owned");
+        assert_eq!(panic_message(&3), "no message");
+    }
+
+    #[test]
+    fn test_checked_endpoint_address() {
+        let proxy = SocketAddr::from(([10, 0, 0, 5], 443));
+        assert_eq!(checked_endpoint_address(proxy).unwrap(), proxy);
+
+        for &bad in &[
+            SocketAddr::from(([0, 0, 0, 0], 443)),
+            SocketAddr::from(([224, 0, 0, 1], 443)),
+            SocketAddr::from(([10, 0, 0, 5], 0)),
+        ] {
+            assert!(matches!(checked_endpoint_address(bad), Err(VoiceError::InvalidEndpointAddress(a)) if a == bad));
+        }
+    }
+
+    #[test]
+    fn test_build_encoder() {
+        let mut config = OpusConfig::default();
+        config.complexity(4).inband_fec(true).vbr(false).signal(audiopus::Signal::Voice);
+
+        let encoder = build_encoder(Channels::Stereo, &config, Bitrate::BitsPerSecond(64_000), 2).unwrap();
+        assert!(matches!(encoder.bitrate().unwrap(), Bitrate::BitsPerSecond(64_000)));
+        assert_eq!(encoder.complexity().unwrap(), 2);
+        assert!(encoder.inband_fec().unwrap());
+        assert!(!encoder.vbr().unwrap());
+        assert_eq!(encoder.signal().unwrap(), audiopus::Signal::Voice);
+    }
+
+    #[test]
+    fn test_downmix_to_mono() {
+        let mut buffer = [0f32; 1920];
+        buffer[0] = 0.5;
+        buffer[1] = 0.25;
+        buffer[1918] = -1.0;
+        buffer[1919] = 1.0;
+
+        let mono = downmix_to_mono(&buffer);
+        assert_eq!(mono[0], 0.375);
+        assert_eq!(mono[959], 0.0);
+        assert!(mono[1..959].iter().all(|&sample| sample == 0.0));
+    }
+
+    #[tokio::test]
+    async fn test_set_bitrate_applies_to_live_encoder() {
+        let (mut connection, _server) = local_connection().await;
+
+        connection.set_bitrate(Bitrate::BitsPerSecond(96_000)).unwrap();
+        assert_eq!(connection.encoder.bitrate().unwrap(), Bitrate::BitsPerSecond(96_000));
+
+        let clamped = audio::clamp_bitrate(Bitrate::BitsPerSecond(1_000_000)).unwrap();
+        connection.set_bitrate(clamped).unwrap();
+        assert_eq!(connection.encoder.bitrate().unwrap(), clamped);
+    }
+
+    #[tokio::test]
+    async fn test_rejected_track_bitrate_kept() {
+        use super::super::{pcm, Audio};
+        use std::io::Cursor;
+        use tokio::sync::Mutex;
+
+        let (mut connection, _server) = local_connection().await;
+        let before = connection.encoder.bitrate().unwrap();
+
+        // Track bitrates are normally clamped, so an invalid one is set
+        // directly.
+        let mut audio = Audio::new(pcm(true, Cursor::new(vec![0u8; 3840 * 2])));
+        audio.bitrate = Some(Bitrate::BitsPerSecond(-5));
+        let mut sources = vec![Arc::new(Mutex::new(audio))];
+        let mut opus_frame = Vec::new();
+        let mut buffer = [0i16; 1920];
+        let mut mix_buffer = [0f32; 1920];
+
+        assert!(connection.remove_unfinished_files(&mut sources, &mut opus_frame, &mut buffer, &mut mix_buffer).await.is_ok());
+        assert_eq!(connection.encoder.bitrate().unwrap(), before);
+        assert_eq!(connection.track_bitrate, None);
+    }
+
+    /// Reads the speaking flags sent to a [`LocalServer`] so far.
+    ///
+    /// [`LocalServer`]: struct.LocalServer.html
+    async fn speaking_updates(server: &mut LocalServer) -> Vec<bool> {
+        use futures::StreamExt;
+
+        let mut updates = Vec::new();
+
+        while let Ok(Some(Ok(Message::Text(text)))) = timeout(Duration::from_millis(50), server.ws.next()).await {
+            let value: Value = serde_json::from_str(&text).unwrap();
+
+            if value["op"] == VoiceOpCode::Speaking.num() {
+                updates.push(value["d"]["speaking"].as_bool().unwrap());
+            }
+        }
+
+        updates
+    }
+
+    #[tokio::test]
+    async fn test_speaking_indicator() {
+        let (mut connection, mut server) = local_connection().await;
+
+        // Audio is still sent while the indicator is off, without the flag.
+        connection.set_speaking_indicator(false).await.unwrap();
+        connection.set_speaking(true).await.unwrap();
+        assert!(speaking_updates(&mut server).await.is_empty());
+
+        // Turning it back on raises the flag for audio already being sent.
+        connection.set_speaking_indicator(true).await.unwrap();
+        assert_eq!(speaking_updates(&mut server).await, vec![true]);
+
+        connection.set_speaking_indicator(false).await.unwrap();
+        connection.set_speaking(false).await.unwrap();
+        assert_eq!(speaking_updates(&mut server).await, vec![false]);
+    }
+
+    /// Mixes a frame from an Opus source which fails to decode, handled by
+    /// `action`, alongside one which plays normally, returning the sources
+    /// left playing, the failing source and the other.
+    async fn mix_failing(action: ErrorAction) -> (Vec<LockedAudio>, LockedAudio, LockedAudio) {
+        use super::super::{opus, pcm, Audio};
+        use std::io::Cursor;
+        use tokio::sync::Mutex;
+
+        let (mut connection, _server) = local_connection().await;
+
+        // A code 3 packet claiming to hold no frames is invalid.
+        let failing = Arc::new(Mutex::new(Audio::new(opus(true, Cursor::new(vec![2u8, 0, 0xFF, 0x00])))));
+        failing.lock().await.on_error(Box::new(move |_| action));
+        let other = Arc::new(Mutex::new(Audio::new(pcm(true, Cursor::new(vec![1u8; 3840])))));
+
+        let mut sources = vec![Arc::clone(&failing), Arc::clone(&other)];
+        let mut opus_frame = Vec::new();
+        let mut buffer = [0i16; 1920];
+        let mut mix_buffer = [0f32; 1920];
+        connection.remove_unfinished_files(&mut sources, &mut opus_frame, &mut buffer, &mut mix_buffer).await.unwrap();
+
+        (sources, failing, other)
+    }
+
+    #[tokio::test]
+    async fn test_tracks_playing_skips_paused() {
+        use super::super::{pcm, Audio};
+        use std::io::Cursor;
+        use tokio::sync::Mutex;
+
+        let (mut connection, _server) = local_connection().await;
+        let track = || Arc::new(Mutex::new(Audio::new(pcm(true, Cursor::new(vec![1u8; 3840])))));
+        let paused = track();
+        paused.lock().await.pause();
+
+        let mut sources = vec![track(), paused, track()];
+        let mut opus_frame = Vec::new();
+        let mut buffer = [0i16; 1920];
+        let mut mix_buffer = [0f32; 1920];
+        connection.remove_unfinished_files(&mut sources, &mut opus_frame, &mut buffer, &mut mix_buffer).await.unwrap();
+
+        assert_eq!(sources.len(), 3);
+        assert_eq!(connection.shared.network_stats().tracks_playing, 2);
+    }
+
+    #[tokio::test]
+    async fn test_muted_passthrough_not_sent() {
+        use super::super::{opus_frames, Audio};
+        use tokio::sync::Mutex;
+
+        let (mut connection, _server) = local_connection().await;
+        let track = |frame: u8| Arc::new(Mutex::new(Audio::new(opus_frames(vec![vec![frame; 3]; 2]))));
+        let muted = track(1);
+        muted.lock().await.mute(true);
+
+        let mut sources = vec![muted, track(2)];
+        let mut opus_frame = Vec::new();
+        let mut buffer = [0i16; 1920];
+        let mut mix_buffer = [0f32; 1920];
+        connection.remove_unfinished_files(&mut sources, &mut opus_frame, &mut buffer, &mut mix_buffer).await.unwrap();
+
+        // The muted source is read, but the next one takes the packet.
+        assert_eq!(sources.len(), 2);
+        assert_eq!(opus_frame, vec![2; 3]);
+        assert_eq!(sources[0].lock().await.position, Duration::from_millis(20));
+    }
+
+    /// Reads frames of the given numbers of samples of stereo PCM.
+    struct Reads(Vec<usize>);
+
+    #[async_trait::async_trait]
+    impl AudioSource for Reads {
+        async fn is_stereo(&mut self) -> bool {
+            true
+        }
+
+        async fn get_type(&self) -> AudioType {
+            AudioType::Pcm
+        }
+
+        async fn read_pcm_frame(&mut self, _buffer: &mut [i16]) -> Option<usize> {
+            // An empty list stands in for a buggy source.
+            assert!(!self.0.is_empty(), "no reads left");
+
+            Some(self.0.remove(0))
+        }
+
+        async fn read_opus_frame(&mut self) -> Option<Vec<u8>> {
+            None
+        }
+
+        async fn decode_and_add_opus_frame(&mut self, _float_buffer: &mut [f32; 1920], _volume: f32) -> Option<usize> {
+            None
+        }
+    }
+
+    #[tokio::test]
+    async fn test_short_reads_underrun() {
+        use super::super::Audio;
+        use tokio::sync::Mutex;
+
+        let (mut connection, _server) = local_connection().await;
+        let mut sources = vec![Arc::new(Mutex::new(Audio::new(Box::new(Reads(vec![1920, 100, 1920, 100, 0])))))];
+        let mut opus_frame = Vec::new();
+        let mut buffer = [0i16; 1920];
+        let mut mix_buffer = [0f32; 1920];
+
+        let mut underruns = Vec::new();
+        for _ in 0..5 {
+            connection.remove_unfinished_files(&mut sources, &mut opus_frame, &mut buffer, &mut mix_buffer).await.unwrap();
+            underruns.push(connection.shared.network_stats().underruns);
+        }
+
+        // The partial frame the source went on from is a gap, but the one it
+        // ended on is not.
+        assert_eq!(underruns, vec![0, 0, 1, 1, 1]);
+        assert!(sources.is_empty());
+    }
+
+    fn status(audio: &LockedAudio) -> TrackStatus {
+        *audio.try_lock().unwrap().subscribe().borrow()
+    }
+
+    #[tokio::test]
+    async fn test_panicked_source_failure() {
+        use super::super::Audio;
+        use tokio::sync::Mutex;
+
+        let (mut connection, _server) = local_connection().await;
+        connection.catch_source_panics = true;
+        let panicking = Arc::new(Mutex::new(Audio::new(Box::new(Reads(Vec::new())))));
+
+        let mut sources = vec![Arc::clone(&panicking)];
+        let mut opus_frame = Vec::new();
+        let mut buffer = [0i16; 1920];
+        let mut mix_buffer = [0f32; 1920];
+        connection.remove_unfinished_files(&mut sources, &mut opus_frame, &mut buffer, &mut mix_buffer).await.unwrap();
+
+        assert!(sources.is_empty());
+        assert_eq!(status(&panicking), TrackStatus::Ended(EndReason::Failed));
+        assert!(matches!(panicking.try_lock().unwrap().failure, Some(VoiceError::SourcePanicked)));
+    }
+
+    /// Panics when asked whether it is stereo if set, or otherwise when asked
+    /// for its type.
+    struct PanickingLayout(bool);
+
+    #[async_trait::async_trait]
+    impl AudioSource for PanickingLayout {
+        async fn is_stereo(&mut self) -> bool {
+            assert!(!self.0, "no layout");
+
+            true
+        }
+
+        async fn get_type(&self) -> AudioType {
+            panic!("no type");
+        }
+
+        async fn read_pcm_frame(&mut self, _buffer: &mut [i16]) -> Option<usize> {
+            None
+        }
+
+        async fn read_opus_frame(&mut self) -> Option<Vec<u8>> {
+            None
+        }
+
+        async fn decode_and_add_opus_frame(&mut self, _float_buffer: &mut [f32; 1920], _volume: f32) -> Option<usize> {
+            None
+        }
+    }
+
+    #[tokio::test]
+    async fn test_panicked_layout_failure() {
+        use super::super::Audio;
+        use tokio::sync::Mutex;
+
+        let (mut connection, _server) = local_connection().await;
+        connection.catch_source_panics = true;
+        let track = |stereo| Arc::new(Mutex::new(Audio::new(Box::new(PanickingLayout(stereo)))));
+        let (stereo, kind) = (track(true), track(false));
+
+        let mut sources = vec![Arc::clone(&stereo), Arc::clone(&kind)];
+        let mut opus_frame = Vec::new();
+        let mut buffer = [0i16; 1920];
+        let mut mix_buffer = [0f32; 1920];
+        connection.remove_unfinished_files(&mut sources, &mut opus_frame, &mut buffer, &mut mix_buffer).await.unwrap();
+
+        assert!(sources.is_empty());
+
+        for track in &[stereo, kind] {
+            assert_eq!(status(track), TrackStatus::Ended(EndReason::Failed));
+            assert!(matches!(track.try_lock().unwrap().failure, Some(VoiceError::SourcePanicked)));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_decode_failure_skip() {
+        let (sources, failing, other) = mix_failing(ErrorAction::Skip).await;
+
+        assert_eq!(sources.len(), 1);
+        assert!(Arc::ptr_eq(&sources[0], &other));
+        assert_eq!(status(&failing), TrackStatus::Ended(EndReason::Failed));
+        assert!(matches!(failing.try_lock().unwrap().failure, Some(VoiceError::SourceRead)));
+        assert_eq!(status(&other), TrackStatus::Playing);
+    }
+
+    #[tokio::test]
+    async fn test_decode_failure_retry() {
+        let (sources, failing, _) = mix_failing(ErrorAction::Retry).await;
+
+        assert_eq!(sources.len(), 2);
+        assert_eq!(status(&failing), TrackStatus::Pending);
+    }
+
+    #[tokio::test]
+    async fn test_decode_failure_stop() {
+        let (sources, failing, other) = mix_failing(ErrorAction::Stop).await;
+
+        assert!(sources.is_empty());
+        assert_eq!(status(&failing), TrackStatus::Ended(EndReason::Failed));
+        assert_eq!(status(&other), TrackStatus::Ended(EndReason::Cleared));
+    }
+}
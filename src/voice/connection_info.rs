@@ -1,10 +1,24 @@
+use audiopus::{Bitrate, Signal};
 use crate::model::id::{GuildId, UserId};
-use std::fmt;
+use std::{
+    fmt,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+
+/// A function resolving the hostname of a voice server to the address to
+/// connect its WebSocket to, set via [`Handler::set_endpoint_resolver`].
+///
+/// [`Handler::set_endpoint_resolver`]: struct.Handler.html#method.set_endpoint_resolver
+pub type EndpointResolver = Arc<dyn Fn(&str) -> SocketAddr + Send + Sync>;
 
 #[derive(Clone)]
 pub struct ConnectionInfo {
+    pub bind_address: Option<IpAddr>,
     pub endpoint: String,
+    pub endpoint_resolver: Option<EndpointResolver>,
     pub guild_id: GuildId,
+    pub rtp_seed: Option<u64>,
     pub session_id: String,
     pub token: String,
     pub user_id: UserId,
@@ -13,10 +27,43 @@ pub struct ConnectionInfo {
 impl fmt::Debug for ConnectionInfo {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("ConnectionInfo")
+            .field("bind_address", &self.bind_address)
             .field("endpoint", &self.endpoint)
+            .field("endpoint_resolver", &self.endpoint_resolver.is_some())
             .field("guild_id", &self.guild_id)
+            .field("rtp_seed", &self.rtp_seed)
             .field("session_id", &self.session_id)
             .field("user_id", &self.user_id)
             .finish()
     }
 }
+
+/// The settings in use on a [`Handler`]'s live voice connection, as agreed
+/// with the voice server and read back from the Opus encoder.
+///
+/// Retrieved via [`Handler::connection_info`].
+///
+/// [`Handler`]: struct.Handler.html
+/// [`Handler::connection_info`]: struct.Handler.html#method.connection_info
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct SessionInfo {
+    /// The voice server connected to.
+    pub endpoint: String,
+    /// The encryption mode chosen by the voice server.
+    pub encryption_mode: String,
+    /// The RTP synchronisation source assigned to sent audio.
+    pub ssrc: u32,
+    /// The encoder's bitrate.
+    pub bitrate: Bitrate,
+    /// The encoder's complexity.
+    pub complexity: u8,
+    /// Whether discontinuous transmission is enabled.
+    pub dtx: bool,
+    /// Whether in-band forward error correction is enabled.
+    pub inband_fec: bool,
+    /// The encoder's signal hint.
+    pub signal: Signal,
+    /// Whether a variable bitrate is used.
+    pub vbr: bool,
+}
@@ -0,0 +1,229 @@
+use rand::random;
+use std::sync::Arc;
+use super::VoiceError;
+use xsalsa20poly1305::{
+    aead::{Aead, NewAead},
+    Nonce, XSalsa20Poly1305,
+};
+
+/// The length of the Poly1305 tag on each encrypted payload.
+const TAG_LEN: usize = 16;
+
+/// The length of the nonce appended by the `xsalsa20_poly1305_lite` mode.
+const LITE_NONCE_LEN: usize = 4;
+
+/// The ways in which voice packets can be encrypted, one of which is agreed
+/// with Discord when connecting.
+///
+/// Set via [`Handler::set_encryption_mode`].
+///
+/// [`Handler::set_encryption_mode`]: struct.Handler.html#method.set_encryption_mode
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[non_exhaustive]
+pub enum EncryptionMode {
+    /// `xsalsa20_poly1305`, where each packet's RTP header forms its nonce.
+    ///
+    /// This is the default.
+    Normal,
+    /// `xsalsa20_poly1305_suffix`, where a random 24-byte nonce is appended
+    /// to each packet.
+    Suffix,
+    /// `xsalsa20_poly1305_lite`, where an incrementing 4-byte nonce is
+    /// appended to each packet.
+    Lite,
+}
+
+impl Default for EncryptionMode {
+    fn default() -> Self {
+        EncryptionMode::Normal
+    }
+}
+
+impl EncryptionMode {
+    /// The name of the mode, as sent to Discord.
+    pub fn name(self) -> &'static str {
+        match self {
+            EncryptionMode::Normal => "xsalsa20_poly1305",
+            EncryptionMode::Suffix => "xsalsa20_poly1305_suffix",
+            EncryptionMode::Lite => "xsalsa20_poly1305_lite",
+        }
+    }
+
+    /// Makes a backend for the mode from the secret key Discord gave.
+    pub(crate) fn backend(self, key: &[u8]) -> Result<Box<dyn EncryptionBackend>, VoiceError> {
+        let cipher = XSalsa20Poly1305::new_varkey(key)
+            .map_err(|_| VoiceError::KeyGen)?;
+
+        Ok(match self {
+            EncryptionMode::Normal => Box::new(Normal(cipher)),
+            EncryptionMode::Suffix => Box::new(Suffix(cipher)),
+            EncryptionMode::Lite => Box::new(Lite {
+                cipher,
+                nonce: random(),
+            }),
+        })
+    }
+}
+
+/// Makes the [`EncryptionBackend`] for a connection from the secret key
+/// Discord gave, as set via [`Handler::set_encryption_backend`].
+///
+/// [`EncryptionBackend`]: trait.EncryptionBackend.html
+/// [`Handler::set_encryption_backend`]: struct.Handler.html#method.set_encryption_backend
+pub type EncryptionBackendFactory = Arc<dyn Fn(&[u8]) -> Result<Box<dyn EncryptionBackend>, VoiceError> + Send + Sync>;
+
+/// Encrypts and decrypts the payloads of voice packets, according to one
+/// [`EncryptionMode`].
+///
+/// Each payload follows a header which is left unencrypted, either of RTP
+/// or RTCP, which modes may use as the nonce.
+///
+/// Backends for each mode are built in, but another implementation of a
+/// mode can be used in their place via [`Handler::set_encryption_backend`].
+///
+/// [`EncryptionMode`]: enum.EncryptionMode.html
+/// [`Handler::set_encryption_backend`]: struct.Handler.html#method.set_encryption_backend
+pub trait EncryptionBackend: Send {
+    /// The mode implemented.
+    fn mode(&self) -> EncryptionMode;
+
+    /// The number of bytes encryption adds to a payload.
+    fn overhead(&self) -> usize;
+
+    /// Encrypts the payload following `header`, returning the bytes to send
+    /// after the header.
+    fn encrypt(&mut self, header: &[u8], payload: &[u8]) -> Result<Vec<u8>, VoiceError>;
+
+    /// Decrypts the bytes received after `header`, returning `None` if they
+    /// are not a valid payload.
+    fn decrypt(&self, header: &[u8], body: &[u8]) -> Option<Vec<u8>>;
+}
+
+/// Pads the start of a header or appended nonce out to a full nonce.
+fn nonce_from(bytes: &[u8]) -> Nonce {
+    let mut nonce = Nonce::default();
+    nonce[..bytes.len()].clone_from_slice(bytes);
+
+    nonce
+}
+
+/// Splits an appended nonce of `len` bytes off the end of a body.
+fn split_suffix(body: &[u8], len: usize) -> Option<(&[u8], &[u8])> {
+    if body.len() < TAG_LEN + len {
+        return None;
+    }
+
+    Some(body.split_at(body.len() - len))
+}
+
+struct Normal(XSalsa20Poly1305);
+
+impl EncryptionBackend for Normal {
+    fn mode(&self) -> EncryptionMode {
+        EncryptionMode::Normal
+    }
+
+    fn overhead(&self) -> usize {
+        TAG_LEN
+    }
+
+    fn encrypt(&mut self, header: &[u8], payload: &[u8]) -> Result<Vec<u8>, VoiceError> {
+        self.0.encrypt(&nonce_from(header), payload)
+            .map_err(|_| VoiceError::Encryption)
+    }
+
+    fn decrypt(&self, header: &[u8], body: &[u8]) -> Option<Vec<u8>> {
+        self.0.decrypt(&nonce_from(header), body).ok()
+    }
+}
+
+struct Suffix(XSalsa20Poly1305);
+
+impl EncryptionBackend for Suffix {
+    fn mode(&self) -> EncryptionMode {
+        EncryptionMode::Suffix
+    }
+
+    fn overhead(&self) -> usize {
+        TAG_LEN + Nonce::default().len()
+    }
+
+    fn encrypt(&mut self, _header: &[u8], payload: &[u8]) -> Result<Vec<u8>, VoiceError> {
+        let nonce = nonce_from(&random::<[u8; 24]>());
+        let mut body = self.0.encrypt(&nonce, payload)
+            .map_err(|_| VoiceError::Encryption)?;
+        body.extend_from_slice(&nonce);
+
+        Ok(body)
+    }
+
+    fn decrypt(&self, _header: &[u8], body: &[u8]) -> Option<Vec<u8>> {
+        let (body, nonce) = split_suffix(body, Nonce::default().len())?;
+
+        self.0.decrypt(&nonce_from(nonce), body).ok()
+    }
+}
+
+struct Lite {
+    cipher: XSalsa20Poly1305,
+    nonce: u32,
+}
+
+impl EncryptionBackend for Lite {
+    fn mode(&self) -> EncryptionMode {
+        EncryptionMode::Lite
+    }
+
+    fn overhead(&self) -> usize {
+        TAG_LEN + LITE_NONCE_LEN
+    }
+
+    fn encrypt(&mut self, _header: &[u8], payload: &[u8]) -> Result<Vec<u8>, VoiceError> {
+        let suffix = self.nonce.to_be_bytes();
+        self.nonce = self.nonce.wrapping_add(1);
+
+        let mut body = self.cipher.encrypt(&nonce_from(&suffix), payload)
+            .map_err(|_| VoiceError::Encryption)?;
+        body.extend_from_slice(&suffix);
+
+        Ok(body)
+    }
+
+    fn decrypt(&self, _header: &[u8], body: &[u8]) -> Option<Vec<u8>> {
+        let (body, nonce) = split_suffix(body, LITE_NONCE_LEN)?;
+
+        self.cipher.decrypt(&nonce_from(nonce), body).ok()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let header = [0x80, 0x78, 0, 1, 0, 0, 0, 2, 0, 0, 0, 3];
+        let payload = b"opus frame";
+
+        for &mode in &[EncryptionMode::Normal, EncryptionMode::Suffix, EncryptionMode::Lite] {
+            let mut backend = mode.backend(&[7; 32]).unwrap();
+            assert_eq!(backend.mode(), mode);
+
+            let first = backend.encrypt(&header, payload).unwrap();
+            let second = backend.encrypt(&header, payload).unwrap();
+            assert_eq!(first.len(), payload.len() + backend.overhead());
+            assert_eq!(backend.decrypt(&header, &first).as_deref(), Some(&payload[..]));
+            assert_eq!(backend.decrypt(&header, &second).as_deref(), Some(&payload[..]));
+
+            // Modes with an appended nonce never reuse one.
+            assert_eq!(first == second, mode == EncryptionMode::Normal);
+
+            let mut tampered = first.clone();
+            tampered[0] ^= 1;
+            assert_eq!(backend.decrypt(&header, &tampered), None);
+            assert_eq!(backend.decrypt(&header, &first[..4]), None);
+        }
+
+        assert!(EncryptionMode::Normal.backend(&[7; 16]).is_err());
+    }
+}
@@ -0,0 +1,447 @@
+use std::{f32::consts::PI, time::Duration};
+use super::{AgcConfig, DuckConfig, EqBand, LimiterConfig};
+
+/// The number of stereo samples per second of mixed audio.
+const SAMPLE_RATE: f32 = 48_000.0;
+
+/// How far an envelope moves towards its target each sample, for a time
+/// constant of the given length.
+fn coefficient(time: Duration) -> f32 {
+    let samples = time.as_secs_f32() * SAMPLE_RATE;
+
+    if samples <= 0.0 {
+        1.0
+    } else {
+        1.0 - (-1.0 / samples).exp()
+    }
+}
+
+/// Lowers the gain of the final mix while its peaks exceed a threshold, as
+/// configured by a [`LimiterConfig`].
+///
+/// [`LimiterConfig`]: struct.LimiterConfig.html
+#[derive(Debug)]
+pub(crate) struct PeakLimiter {
+    threshold: f32,
+    attack: f32,
+    release: f32,
+    /// The tracked peak level of recent samples.
+    envelope: f32,
+}
+
+impl PeakLimiter {
+    pub fn new(config: &LimiterConfig) -> Self {
+        Self {
+            threshold: config.threshold,
+            attack: coefficient(config.attack),
+            release: coefficient(config.release),
+            envelope: 0.0,
+        }
+    }
+
+    /// Limits a frame of interleaved stereo samples in place.
+    pub fn apply(&mut self, frame: &mut [f32]) {
+        for pair in frame.chunks_mut(2) {
+            let peak = pair.iter().fold(0f32, |peak, sample| peak.max(sample.abs()));
+            let rate = if peak > self.envelope { self.attack } else { self.release };
+
+            self.envelope += (peak - self.envelope) * rate;
+
+            if self.envelope > self.threshold {
+                let gain = self.threshold / self.envelope;
+
+                for sample in pair.iter_mut() {
+                    *sample *= gain;
+                }
+            }
+        }
+    }
+}
+
+/// The length of a frame of received audio.
+const FRAME: Duration = Duration::from_millis(20);
+
+/// The RMS level, as a fraction of full scale, below which a frame is taken
+/// to be silence or background noise, and so does not move the gain.
+const AGC_NOISE_FLOOR: f32 = 0.003;
+
+/// Moves the gain of one speaker's received audio towards that which brings
+/// them to a target level, as configured by an [`AgcConfig`].
+///
+/// The gain is judged from each frame's RMS level and changed once per
+/// frame, ramped across the frame so that no step is heard.
+///
+/// [`AgcConfig`]: struct.AgcConfig.html
+#[derive(Debug)]
+pub(crate) struct AutoGain {
+    target: f32,
+    max_gain: f32,
+    attack: f32,
+    release: f32,
+    gain: f32,
+}
+
+impl AutoGain {
+    pub fn new(config: &AgcConfig) -> Self {
+        // The gain is moved once per frame, so the time constants are
+        // turned into per-frame coefficients.
+        let per_frame = |time: Duration| {
+            let frames = time.as_secs_f32() / FRAME.as_secs_f32();
+
+            if frames <= 0.0 {
+                1.0
+            } else {
+                1.0 - (-1.0 / frames).exp()
+            }
+        };
+
+        Self {
+            target: config.target,
+            max_gain: config.max_gain,
+            attack: per_frame(config.attack),
+            release: per_frame(config.release),
+            gain: 1.0,
+        }
+    }
+
+    /// Applies the gain to a frame of samples in place, saturating at the
+    /// limits of an `i16`.
+    pub fn apply(&mut self, frame: &mut [i16]) {
+        if frame.is_empty() {
+            return;
+        }
+
+        let scale = f32::from(i16::MAX);
+        let power = frame.iter()
+            .map(|&sample| (f32::from(sample) / scale).powi(2))
+            .sum::<f32>() / frame.len() as f32;
+        let level = power.sqrt();
+        let previous = self.gain;
+
+        if level > AGC_NOISE_FLOOR {
+            let wanted = (self.target / level).min(self.max_gain);
+            let rate = if wanted < self.gain { self.attack } else { self.release };
+
+            self.gain += (wanted - self.gain) * rate;
+        }
+
+        let len = frame.len() as f32;
+
+        for (i, sample) in frame.iter_mut().enumerate() {
+            let gain = previous + (self.gain - previous) * (i + 1) as f32 / len;
+            let scaled = (f32::from(*sample) * gain).round();
+
+            *sample = scaled.max(f32::from(i16::MIN)).min(scale) as i16;
+        }
+    }
+}
+
+/// Moves the gain of sources ducked under an announcement towards the level
+/// its [`DuckConfig`] asks for, a frame at a time, and back up to full once
+/// no announcement is playing.
+///
+/// [`DuckConfig`]: struct.DuckConfig.html
+#[derive(Debug)]
+pub(crate) struct Ducker {
+    gain: f32,
+    /// How far the gain moves each frame, kept from the last announcement
+    /// so that the sources come back up as quickly as they went down.
+    step: f32,
+}
+
+impl Ducker {
+    /// The gain which ducked sources should be played at.
+    pub fn gain(&self) -> f32 {
+        self.gain
+    }
+
+    /// Advances by a frame, towards the level of the announcement playing
+    /// if there is one.
+    pub fn advance(&mut self, announcement: Option<&DuckConfig>) {
+        let target = match announcement {
+            Some(config) => {
+                let frames = config.fade.as_secs_f32() / FRAME.as_secs_f32();
+                self.step = if frames > 1.0 { (1.0 - config.level) / frames } else { 1.0 };
+
+                config.level
+            },
+            None => 1.0,
+        };
+
+        self.gain = if self.gain < target {
+            (self.gain + self.step).min(target)
+        } else {
+            (self.gain - self.step).max(target)
+        };
+    }
+}
+
+impl Default for Ducker {
+    fn default() -> Self {
+        Self {
+            gain: 1.0,
+            step: 1.0,
+        }
+    }
+}
+
+/// A peaking filter for one [`EqBand`], holding the state of each channel.
+///
+/// [`EqBand`]: struct.EqBand.html
+#[derive(Clone, Debug)]
+struct Biquad {
+    b0: f32,
+    b1: f32,
+    b2: f32,
+    a1: f32,
+    a2: f32,
+    state: [[f32; 2]; 2],
+}
+
+impl Biquad {
+    fn peaking(band: &EqBand) -> Self {
+        let a = 10f32.powf(band.gain / 40.0);
+        let w0 = 2.0 * PI * band.frequency / SAMPLE_RATE;
+        let alpha = w0.sin() / (2.0 * band.q);
+        let cos = w0.cos();
+        let a0 = 1.0 + alpha / a;
+
+        Self {
+            b0: (1.0 + alpha * a) / a0,
+            b1: -2.0 * cos / a0,
+            b2: (1.0 - alpha * a) / a0,
+            a1: -2.0 * cos / a0,
+            a2: (1.0 - alpha / a) / a0,
+            state: [[0.0; 2]; 2],
+        }
+    }
+
+    fn process(&mut self, channel: usize, x: f32) -> f32 {
+        let state = &mut self.state[channel];
+        let y = self.b0 * x + state[0];
+
+        state[0] = self.b1 * x - self.a1 * y + state[1];
+        state[1] = self.b2 * x - self.a2 * y;
+
+        y
+    }
+
+    fn apply(filters: &mut [Biquad], frame: &mut [f32]) {
+        for pair in frame.chunks_mut(2) {
+            for (channel, sample) in pair.iter_mut().enumerate() {
+                for filter in filters.iter_mut() {
+                    *sample = filter.process(channel, *sample);
+                }
+            }
+        }
+    }
+}
+
+/// Applies a track's [`EqBand`]s as cascaded biquad filters, crossfading
+/// from the previous bands whenever they change so that no click is heard.
+///
+/// [`EqBand`]: struct.EqBand.html
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Equalizer {
+    bands: Vec<EqBand>,
+    filters: Vec<Biquad>,
+    /// The filters being replaced, with how many samples of the crossfade
+    /// away from them have passed.
+    previous: Option<(Vec<Biquad>, usize)>,
+}
+
+impl Equalizer {
+    pub fn bands(&self) -> &[EqBand] {
+        &self.bands
+    }
+
+    /// Replaces the bands, crossfading to them over the next frames.
+    pub fn set_bands(&mut self, bands: Vec<EqBand>) {
+        let filters = bands.iter().map(Biquad::peaking).collect();
+        let previous = std::mem::replace(&mut self.filters, filters);
+
+        self.bands = bands;
+        self.previous = Some((previous, 0));
+    }
+
+    /// Equalizes a frame of interleaved stereo samples in place, completing
+    /// any crossfade over `ramp_len` samples per channel.
+    pub fn apply(&mut self, frame: &mut [f32], ramp_len: usize) {
+        let (mut previous, mut elapsed) = match self.previous.take() {
+            Some(previous) => previous,
+            None => {
+                Biquad::apply(&mut self.filters, frame);
+
+                return;
+            },
+        };
+
+        let mut old = frame.to_vec();
+        Biquad::apply(&mut previous, &mut old);
+        Biquad::apply(&mut self.filters, frame);
+
+        for (pair, old) in frame.chunks_mut(2).zip(old.chunks(2)) {
+            elapsed = elapsed.saturating_add(1);
+            let mix = (elapsed as f32 / ramp_len.max(1) as f32).min(1.0);
+
+            for (sample, old) in pair.iter_mut().zip(old) {
+                *sample = old + (*sample - old) * mix;
+            }
+        }
+
+        if elapsed < ramp_len {
+            self.previous = Some((previous, elapsed));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn tone(amplitude: f32) -> Vec<f32> {
+        (0..1920)
+            .map(|i| amplitude * ((i / 2) as f32 * 0.1).sin())
+            .collect()
+    }
+
+    #[test]
+    fn test_peak_limiter() {
+        let mut config = LimiterConfig::default();
+        config.threshold(0.5);
+        let mut limiter = PeakLimiter::new(&config);
+
+        // Quiet audio passes through untouched.
+        let mut frame = tone(0.25);
+        limiter.apply(&mut frame);
+        assert_eq!(frame, tone(0.25));
+
+        // Loud audio is brought down to the threshold once the attack has
+        // passed, give or take the peaks let through while it rises.
+        for _ in 0..5 {
+            frame = tone(2.0);
+            limiter.apply(&mut frame);
+        }
+
+        let peak = frame.iter().fold(0f32, |peak, sample| peak.max(sample.abs()));
+        assert!(peak < 0.6, "peak of {}", peak);
+
+        // Once the loud audio stops, the gain recovers over the release.
+        for _ in 0..50 {
+            frame = tone(0.25);
+            limiter.apply(&mut frame);
+        }
+
+        assert_eq!(frame, tone(0.25));
+    }
+
+    fn level(frame: &[i16]) -> f32 {
+        let power = frame.iter().map(|&sample| f32::from(sample).powi(2)).sum::<f32>();
+
+        (power / frame.len() as f32).sqrt() / f32::from(i16::MAX)
+    }
+
+    #[test]
+    fn test_auto_gain() {
+        let tone = |amplitude: f32| tone(amplitude)
+            .into_iter()
+            .map(|sample| (sample * f32::from(i16::MAX)) as i16)
+            .collect::<Vec<_>>();
+        let mut agc = AutoGain::new(&AgcConfig::default());
+
+        // Quiet speech is brought up to the target over the release.
+        let mut frame = Vec::new();
+        for _ in 0..250 {
+            frame = tone(0.02);
+            agc.apply(&mut frame);
+        }
+        assert!((level(&frame) - 0.1).abs() < 0.01, "level of {}", level(&frame));
+
+        // Loud speech is brought down far quicker.
+        for _ in 0..15 {
+            frame = tone(0.8);
+            agc.apply(&mut frame);
+        }
+        assert!(level(&frame) < 0.15, "level of {}", level(&frame));
+
+        // Silence holds the gain, rather than boosting noise.
+        let gain = agc.gain;
+        agc.apply(&mut vec![1; 1920]);
+        assert!((agc.gain - gain).abs() < f32::EPSILON);
+
+        // The gain never exceeds its maximum.
+        let mut agc = AutoGain::new(&AgcConfig::default());
+        for _ in 0..500 {
+            frame = tone(0.005);
+            agc.apply(&mut frame);
+        }
+        assert!(agc.gain <= 10.0);
+    }
+
+    fn sine(frequency: f32, frame: usize) -> Vec<f32> {
+        (0..1920)
+            .map(|i| (2.0 * PI * frequency * (frame * 960 + i / 2) as f32 / SAMPLE_RATE).sin() * 0.25)
+            .collect()
+    }
+
+    fn peak(frame: &[f32]) -> f32 {
+        frame.iter().fold(0f32, |peak, sample| peak.max(sample.abs()))
+    }
+
+    #[test]
+    fn test_equalizer() {
+        let mut equalizer = Equalizer::default();
+        equalizer.set_bands(vec![EqBand::new(1_000.0, 6.0, 1.0)]);
+
+        // Once settled, the band boosts its own frequency, but not others.
+        let mut boosted = Vec::new();
+        let mut untouched = Vec::new();
+
+        for frame in 0..10 {
+            boosted = sine(1_000.0, frame);
+            equalizer.apply(&mut boosted, 720);
+        }
+
+        let mut other = Equalizer::default();
+        other.set_bands(vec![EqBand::new(1_000.0, 6.0, 4.0)]);
+
+        for frame in 0..10 {
+            untouched = sine(12_000.0, frame);
+            other.apply(&mut untouched, 720);
+        }
+
+        assert!((peak(&boosted) / 0.25 - 2.0).abs() < 0.05, "peak of {}", peak(&boosted));
+        assert!((peak(&untouched) / 0.25 - 1.0).abs() < 0.05, "peak of {}", peak(&untouched));
+
+        // A change of bands fades in rather than jumping.
+        equalizer.set_bands(Vec::new());
+        let mut frame = sine(1_000.0, 10);
+        equalizer.apply(&mut frame, 1920);
+        let steps = frame.windows(2).map(|pair| (pair[1] - pair[0]).abs());
+        assert!(steps.fold(0f32, f32::max) < 0.1);
+        assert!(peak(&frame) > 0.25);
+    }
+
+    #[test]
+    fn test_ducker() {
+        let mut ducker = Ducker::default();
+        let mut config = DuckConfig::default();
+        config.level(0.5).fade(FRAME * 5);
+
+        ducker.advance(Some(&config));
+        assert!((ducker.gain() - 0.9).abs() < 1e-6);
+
+        for _ in 0..10 {
+            ducker.advance(Some(&config));
+        }
+        assert!((ducker.gain() - 0.5).abs() < 1e-6);
+
+        // Sources come back up at the same pace once the announcement ends.
+        ducker.advance(None);
+        assert!((ducker.gain() - 0.6).abs() < 1e-6);
+
+        config.fade(Duration::default());
+        ducker.advance(Some(&config));
+        assert!((ducker.gain() - 0.5).abs() < 1e-6);
+    }
+}
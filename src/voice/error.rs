@@ -1,7 +1,12 @@
+use crate::model::{id::ChannelId, Permissions};
+use super::EqBand;
 use serde_json::{Error as JsonError, Value};
 use std::{
+    ffi::OsString,
     io::Error as IoError,
-    process::Output
+    net::SocketAddr,
+    process::Output,
+    time::Duration,
 };
 
 /// An error returned from the voice module.
@@ -9,16 +14,172 @@ use std::{
 #[derive(Debug)]
 #[non_exhaustive]
 pub enum VoiceError {
+    /// An indicator that the guild already has a voice connection, in the
+    /// given channel, as a user can only be in one voice channel per guild.
+    ///
+    /// Returned by [`Manager::try_join`].
+    ///
+    /// [`Manager::try_join`]: struct.Manager.html#method.try_join
+    AlreadyInChannel(ChannelId),
+    /// An indicator that the address set via [`Handler::set_bind_address`]
+    /// is of a different family (IPv4 or IPv6) to the voice server's.
+    ///
+    /// [`Handler::set_bind_address`]: struct.Handler.html#method.set_bind_address
+    BindAddressFamily,
+    /// An indicator that a handler's background task is backed up, with as
+    /// many messages waiting as the limit set via
+    /// [`Handler::set_task_queue_limit`].
+    ///
+    /// [`Handler::set_task_queue_limit`]: struct.Handler.html#method.set_task_queue_limit
+    Busy,
+    /// An indicator that a voice packet could not be encrypted.
+    Encryption,
     /// An indicator that an endpoint URL was invalid.
     EndpointUrl,
     #[doc(hidden)] ExpectedHandshake,
     #[doc(hidden)] FindingByte,
+    /// An indicator that too many voice heartbeats in a row went
+    /// unacknowledged, as configured by [`Handler::set_heartbeat_timeout`].
+    ///
+    /// [`Handler::set_heartbeat_timeout`]: struct.Handler.html#method.set_heartbeat_timeout
+    HeartbeatTimeout,
     #[doc(hidden)] HostnameResolve,
+    /// An indicator that a bitrate of zero or fewer bits per second was
+    /// requested.
+    ///
+    /// The requested bitrate is given.
+    InvalidBitrate(i32),
+    /// An indicator that a receive buffer limit was requested which is too
+    /// small for any speaker to be mixed.
+    ///
+    /// The requested limit is given.
+    InvalidBufferLimit(usize),
+    /// An indicator that a websocket close code was requested which may not
+    /// be sent, such as one reserved for failures detected locally.
+    ///
+    /// The requested code is given.
+    InvalidCloseCode(u16),
+    /// An indicator that an Opus encoder complexity above 10 was requested.
+    ///
+    /// The requested complexity is given.
+    InvalidComplexity(u8),
+    /// An indicator that the resolver set via
+    /// [`Handler::set_endpoint_resolver`] gave an address which cannot be
+    /// connected to, as its IP is unspecified or multicast, or its port is 0.
+    ///
+    /// The address is given.
+    ///
+    /// [`Handler::set_endpoint_resolver`]: struct.Handler.html#method.set_endpoint_resolver
+    InvalidEndpointAddress(SocketAddr),
+    /// An indicator that an [`EqBand`] had a frequency outside of the range
+    /// which can be played at 48kHz, or a quality factor which was not
+    /// positive.
+    ///
+    /// The band is given.
+    ///
+    /// [`EqBand`]: struct.EqBand.html
+    InvalidEqBand(EqBand),
+    /// An indicator that an RTP payload type above 127 was requested, as it
+    /// must fit in 7 bits.
+    ///
+    /// The requested payload type is given.
+    InvalidPayloadType(u8),
+    /// An indicator that the [`TrackOptions`] of a track did not start
+    /// before they ended.
+    ///
+    /// [`TrackOptions`]: struct.TrackOptions.html
+    InvalidTrim,
     #[doc(hidden)] KeyGen,
-    /// An error occurred while checking if a path is stereo.
+    /// An indicator that a loop region did not start before it ended, or
+    /// extended past the end of its track.
+    LoopRegion,
+    /// An indicator that the current user lacks permissions needed to be
+    /// heard in a voice channel, as checked by [`Manager::join_checked`].
+    ///
+    /// The missing permissions are given.
+    ///
+    /// [`Manager::join_checked`]: struct.Manager.html#method.join_checked
+    MissingPermissions(Permissions),
+    /// An indicator that an operation required seeking, but the audio source
+    /// does not support it.
+    NotSeekable,
+    /// An indicator that a channel was expected to be a voice channel, but was
+    /// not.
+    NotVoiceChannel,
+    /// An indicator that there is no live voice connection, and none is being
+    /// established.
+    NotConnected,
+    /// An error occurred while reading the JSON output of `ffprobe`, such as
+    /// when it is not `ffprobe` at all.
+    ///
+    /// The error from parsing it is given.
+    ProbeOutput(JsonError),
+    /// An indicator that an external program needed to create an audio
+    /// source, such as `ffmpeg` or `youtube-dl`, is not installed or could
+    /// not be found.
+    ///
+    /// The program which was run is given, as set via
+    /// [`voice::set_ffmpeg_program`] or similar.
+    ///
+    /// [`voice::set_ffmpeg_program`]: fn.set_ffmpeg_program.html
+    ProgramNotFound(OsString),
+    /// An indicator that a [`TrackQueue`] held as many tracks as its limit,
+    /// and its policy did not allow any to be dropped to make room.
+    ///
+    /// [`TrackQueue`]: struct.TrackQueue.html
+    QueueFull,
+    /// An indicator that a file to be played through `ffmpeg` does not exist.
+    ///
+    /// The path is given.
+    SourceNotFound(OsString),
+    /// An indicator that an audio source panicked while being read, and was
+    /// ended as set via [`Handler::set_catch_source_panics`].
+    ///
+    /// [`Handler::set_catch_source_panics`]: struct.Handler.html#method.set_catch_source_panics
+    SourcePanicked,
+    /// An indicator that an audio source failed to read, rather than
+    /// reaching its end.
+    SourceRead,
+    /// An error occurred while probing the audio streams of a path.
     Streams,
-    #[doc(hidden)] VoiceModeInvalid,
-    #[doc(hidden)] VoiceModeUnavailable,
+    /// An operation did not complete within its allotted time, such as an
+    /// audio source producing its first output.
+    Timeout,
+    /// An indicator that a track was stopped or finished before any of it was
+    /// played.
+    TrackEnded,
+    /// An indicator that a track was longer than the maximum allowed by a
+    /// [`TrackQueue`].
+    ///
+    /// The length of the track is given.
+    ///
+    /// [`TrackQueue`]: struct.TrackQueue.html
+    TrackTooLong(Duration),
+    /// An indicator that a track of unknown length was rejected by a
+    /// [`TrackQueue`] with a maximum duration, or could not be seeked to a
+    /// fraction of its length via [`TrackHandle::seek_percent`].
+    ///
+    /// [`TrackHandle::seek_percent`]: struct.TrackHandle.html#method.seek_percent
+    /// [`TrackQueue`]: struct.TrackQueue.html
+    UnknownDuration,
+    /// An indicator that a channel could not be found in the cache.
+    UnknownChannel,
+    /// An indicator that the voice server agreed to a different encryption
+    /// mode than was asked for, or that a backend set via
+    /// [`Handler::set_encryption_backend`] does not implement the agreed one.
+    ///
+    /// [`Handler::set_encryption_backend`]: struct.Handler.html#method.set_encryption_backend
+    VoiceModeInvalid,
+    /// An indicator that the voice server does not offer the encryption
+    /// mode set via [`Handler::set_encryption_mode`].
+    ///
+    /// [`Handler::set_encryption_mode`]: struct.Handler.html#method.set_encryption_mode
+    VoiceModeUnavailable,
+    /// An indicator that a channel is not in the guild of the [`Handler`]
+    /// which was asked to join it.
+    ///
+    /// [`Handler`]: struct.Handler.html
+    WrongGuild,
     /// An error occurred while running `youtube-dl`.
     YouTubeDLRun(Output),
     /// An error occurred while processing the JSON output from `youtube-dl`.
@@ -40,3 +201,16 @@ pub enum DcaError {
     InvalidMetadata(JsonError),
     InvalidSize(i32),
 }
+
+/// An error returned when rearranging a [`TrackQueue`].
+///
+/// [`TrackQueue`]: struct.TrackQueue.html
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum QueueError {
+    /// The operation would have moved the currently playing track, which is
+    /// always at index 0.
+    CurrentTrack,
+    /// The given index was past the end of the queue.
+    OutOfBounds(usize),
+}
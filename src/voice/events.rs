@@ -0,0 +1,262 @@
+use futures::channel::mpsc::{
+    unbounded,
+    UnboundedReceiver as Receiver,
+    UnboundedSender as Sender,
+};
+use audiopus::Bitrate;
+use crate::error::Error;
+use crate::model::id::{ChannelId, GuildId, UserId};
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use super::{AfkPolicy, EndReason, ForceDisconnectPolicy, TrackMetadata};
+
+/// An event emitted by a [`Handler`]'s voice task, received via
+/// [`Handler::events`].
+///
+/// [`Handler`]: struct.Handler.html
+/// [`Handler::events`]: struct.Handler.html#method.events
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub enum HandlerEvent {
+    /// The voice WebSocket dropped, and the existing session was resumed
+    /// without repeating IP discovery or key exchange.
+    Resumed,
+    /// The voice WebSocket dropped and could not be resumed, so a fresh
+    /// connection was made.
+    Reconnected,
+    /// The voice gateway closed the connection with a code meaning its
+    /// session can no longer be used, such as after being kicked from the
+    /// channel.
+    ///
+    /// The connection is dropped rather than retried, and the handler's
+    /// session ID and token are cleared. To connect again, the voice state
+    /// must be sent to the gateway anew, such as via [`Handler::join`], so
+    /// that Discord issues fresh ones.
+    ///
+    /// [`Handler::join`]: struct.Handler.html#method.join
+    SessionInvalidated {
+        /// The close code sent by the voice gateway.
+        code: u16,
+    },
+    /// The order or contents of the handler's [`TrackQueue`] were changed by
+    /// a call such as [`TrackQueue::move_track`].
+    ///
+    /// [`TrackQueue`]: struct.TrackQueue.html
+    /// [`TrackQueue::move_track`]: struct.TrackQueue.html#method.move_track
+    QueueChanged,
+    /// The last track in the handler's [`TrackQueue`] ended, leaving nothing
+    /// to play, e.g. to start an idle timer before leaving the channel.
+    ///
+    /// By default, this is only emitted when the last track finishes or
+    /// fails by itself. Set [`TrackQueue::set_empty_on_stop`] to also emit it
+    /// when the track is stopped or the queue is cleared.
+    ///
+    /// [`TrackQueue`]: struct.TrackQueue.html
+    /// [`TrackQueue::set_empty_on_stop`]: struct.TrackQueue.html#method.set_empty_on_stop
+    QueueEmpty {
+        /// Why the last track ended.
+        reason: EndReason,
+    },
+    /// The current track in the handler's [`TrackQueue`] ended while
+    /// auto-advance was off, so the queue halted instead of starting its
+    /// next track.
+    ///
+    /// [`TrackQueue`]: struct.TrackQueue.html
+    QueuePaused {
+        /// The number of tracks left waiting in the queue.
+        pending: usize,
+    },
+    /// A track in the handler's [`TrackQueue`] failed, and the queue moved
+    /// past it by itself, rather than it being skipped by the application.
+    ///
+    /// This is emitted both for a lazily added track whose source could not
+    /// be created, such as a URL which `youtube-dl` cannot fetch, and for a
+    /// track which failed to read while playing and was skipped by its
+    /// [`ErrorAction`]. A track whose [`ErrorAction::Stop`] clears the queue
+    /// is not skipped.
+    ///
+    /// [`ErrorAction`]: enum.ErrorAction.html
+    /// [`ErrorAction::Stop`]: enum.ErrorAction.html#variant.Stop
+    /// [`TrackQueue`]: struct.TrackQueue.html
+    TrackAutoSkipped {
+        /// The error the track failed with, which is
+        /// [`VoiceError::SourceRead`] for one which failed to read while
+        /// playing, and [`VoiceError::SourcePanicked`] for one which
+        /// panicked.
+        ///
+        /// [`VoiceError::SourcePanicked`]: enum.VoiceError.html#variant.SourcePanicked
+        /// [`VoiceError::SourceRead`]: enum.VoiceError.html#variant.SourceRead
+        reason: Arc<Error>,
+        /// The metadata the track was queued with.
+        metadata: TrackMetadata,
+    },
+    /// A new session encryption key was agreed with Discord, on first
+    /// connecting or reconnecting, but not when resuming.
+    ///
+    /// The key itself is not exposed; this marks the boundaries of encrypted
+    /// sessions, e.g. for audit logs.
+    KeyNegotiated {
+        /// When the key was received.
+        at: SystemTime,
+    },
+    /// A track played by the handler stopped being played.
+    TrackEnded(TrackEvent),
+    /// The total duration of a track was found again via
+    /// [`TrackHandle::refresh_duration`], and has changed.
+    ///
+    /// [`TrackHandle::refresh_duration`]: struct.TrackHandle.html#method.refresh_duration
+    DurationUpdated {
+        /// The id of the track, as given by [`TrackHandle::id`].
+        ///
+        /// [`TrackHandle::id`]: struct.TrackHandle.html#method.id
+        track_id: u64,
+        /// The new duration, if it is still known.
+        duration: Option<Duration>,
+    },
+    /// The encoder's quality was lowered to cope with conditions, either
+    /// its complexity because encoding was slow, or its bitrate because of
+    /// packet loss. This is only emitted for adaptive settings which have
+    /// been enabled, and not when quality is raised again.
+    ///
+    /// Both fields hold the settings now in use.
+    EncoderDegraded {
+        /// The Opus complexity, from 0 to 10.
+        complexity: u8,
+        /// The bitrate.
+        bitrate: Bitrate,
+    },
+    /// The encoder switched between stereo and mono because the bitrate in
+    /// use crossed the threshold for stereo, while
+    /// [adaptive channels] are enabled.
+    ///
+    /// [adaptive channels]: struct.Handler.html#method.set_adaptive_channels
+    ChannelsChanged {
+        /// Whether audio is now sent in stereo, rather than mono.
+        stereo: bool,
+        /// The bitrate now in use.
+        bitrate: Bitrate,
+    },
+    /// Audio received from a user could not be decoded, and is being
+    /// concealed by the decoder until a packet decodes again.
+    ///
+    /// This is emitted once when concealment starts for each source, rather
+    /// than for every concealed packet.
+    DecoderConcealing {
+        /// The SSRC of the source of the audio.
+        ssrc: u32,
+        /// The user sending the audio, if known.
+        user_id: Option<UserId>,
+    },
+    /// The handler left its channel after sending no audio for the time set
+    /// via [`Handler::set_auto_disconnect`].
+    ///
+    /// [`Handler::set_auto_disconnect`]: struct.Handler.html#method.set_auto_disconnect
+    AutoDisconnected {
+        /// The channel the handler left.
+        channel_id: ChannelId,
+    },
+    /// The handler was disconnected from its channel by someone else, such
+    /// as a moderator, and has acted according to its
+    /// [`ForceDisconnectPolicy`].
+    ///
+    /// [`ForceDisconnectPolicy`]: enum.ForceDisconnectPolicy.html
+    ForceDisconnected {
+        /// The channel the handler was disconnected from.
+        channel_id: ChannelId,
+        /// What the handler did in response.
+        policy: ForceDisconnectPolicy,
+    },
+    /// The handler was moved into its guild's AFK channel, and has acted
+    /// according to its [`AfkPolicy`].
+    ///
+    /// [`AfkPolicy`]: enum.AfkPolicy.html
+    MovedToAfk {
+        /// The AFK channel.
+        channel_id: ChannelId,
+        /// What the handler did in response.
+        policy: AfkPolicy,
+    },
+    /// Discord moved the handler's voice connection to another voice server,
+    /// such as when migrating the guild to another region, and a connection
+    /// to the new server has been requested.
+    VoiceServerChanged {
+        /// The endpoint of the previous voice server.
+        old_endpoint: String,
+        /// The endpoint of the new voice server.
+        new_endpoint: String,
+    },
+    /// The title of a playing stream changed, such as when an internet
+    /// radio station played through [`http`] moves on to the next song.
+    ///
+    /// This is only emitted for sources which report titles via
+    /// [`AudioSource::take_stream_title`].
+    ///
+    /// [`AudioSource::take_stream_title`]: trait.AudioSource.html#method.take_stream_title
+    /// [`http`]: fn.http.html
+    StreamMetadataUpdated {
+        /// The id of the track playing the stream, as given by
+        /// [`TrackHandle::id`].
+        ///
+        /// [`TrackHandle::id`]: struct.TrackHandle.html#method.id
+        track_id: u64,
+        /// The new title.
+        title: String,
+    },
+    /// The handler transmitted audio for as long as its
+    /// [`Handler::set_playtime_budget`] allows, so its playback was stopped.
+    ///
+    /// [`Handler::set_playtime_budget`]: struct.Handler.html#method.set_playtime_budget
+    BudgetExhausted,
+}
+
+/// Details of a track which stopped being played, sent via
+/// [`HandlerEvent::TrackEnded`].
+///
+/// The guild and channel allow one listener to serve the handlers of many
+/// guilds.
+///
+/// [`HandlerEvent::TrackEnded`]: enum.HandlerEvent.html#variant.TrackEnded
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct TrackEvent {
+    /// The guild of the handler which played the track.
+    pub guild_id: GuildId,
+    /// The channel the handler was in when the track ended, if any.
+    pub channel_id: Option<ChannelId>,
+    /// Why the track ended.
+    pub reason: EndReason,
+    /// The user who queued the track, if it was queued with one in its
+    /// [`TrackMetadata`].
+    ///
+    /// [`TrackMetadata`]: struct.TrackMetadata.html
+    pub requester: Option<UserId>,
+}
+
+/// The listeners for a handler's events, shared between its state and queue.
+#[derive(Clone, Default)]
+pub(crate) struct EventSink {
+    listeners: Arc<Mutex<Vec<Sender<HandlerEvent>>>>,
+}
+
+impl EventSink {
+    /// Registers a new listener for events.
+    pub(crate) fn subscribe(&self) -> Receiver<HandlerEvent> {
+        let (tx, rx) = unbounded();
+
+        self.listeners.lock().expect("[Voice] Event listeners poisoned").push(tx);
+
+        rx
+    }
+
+    /// Sends an event to every listener, forgetting those which have been
+    /// dropped.
+    pub(crate) fn emit(&self, event: HandlerEvent) {
+        self.listeners
+            .lock()
+            .expect("[Voice] Event listeners poisoned")
+            .retain(|tx| tx.unbounded_send(event.clone()).is_ok());
+    }
+}
@@ -1,24 +1,96 @@
 use crate::constants::VoiceOpCode;
-use crate::gateway::InterMessage;
+use crate::error::{Error, Result as SerenityResult};
 use crate::model::{
+    channel::{ChannelType, GuildChannel},
     id::{
         ChannelId,
         GuildId,
         UserId
     },
+    event::{Event, GatewayEvent},
     voice::VoiceState
 };
-use tracing::instrument;
-use tokio::sync::Mutex;
-use std::sync::Arc;
-use futures::channel::mpsc::{
-    unbounded,
-    UnboundedSender as Sender,
+#[cfg(feature = "cache")]
+use crate::cache::Cache;
+use tracing::{info, info_span, instrument, warn, Span};
+use serde_json::Value;
+use std::{
+    collections::HashSet,
+    future::Future,
+    io::Write,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::time::{delay_for, timeout};
+use futures::{channel::{
+    mpsc::{
+        unbounded,
+        UnboundedReceiver as Receiver,
+        UnboundedSender as Sender,
+    },
+    oneshot,
+}, FutureExt};
+use super::config::MAX_COMPLEXITY;
+use super::connection_info::{ConnectionInfo, SessionInfo};
+use super::limiter::UpdateLimiter;
+use super::receive::JITTER_FRAMES;
+#[cfg(any(test, feature = "voice_mock"))]
+use super::mock::TaskMessage;
+use super::state::SharedState;
+use super::tee::OutputTee;
+use super::{
+    audio,
+    AfkPolicy,
+    AgcConfig,
+    Audio,
+    AudioReceiver,
+    AudioSource,
+    Bitrate,
+    Channels,
+    connection,
+    ConnectionState,
+    DecodedPacket,
+    DuckConfig,
+    EncryptionBackendFactory,
+    EncryptionMode,
+    EndpointResolver,
+    EndReason,
+    ForceDisconnectPolicy,
+    HandlerEvent,
+    LimiterConfig,
+    NetworkStats,
+    NowPlaying,
+    OpusConfig,
+    OpusSettings,
+    PacingMode,
+    PacketObserver,
+    ReceiverHandle,
+    SampleRate,
+    SsrcInfo,
+    Status as VoiceStatus,
+    streamer,
+    tasks,
+    TrackHandle,
+    TrackMetadata,
+    TrackOptions,
+    TrackQueue,
+    VoiceError,
 };
-use super::connection_info::ConnectionInfo;
-use super::{Audio, AudioReceiver, AudioSource, Bitrate, Status as VoiceStatus, tasks, LockedAudio};
 use serde_json::json;
 
+/// The longest time between checks of whether a handler has idled for long
+/// enough to leave, set via [`Handler::set_auto_disconnect`].
+///
+/// [`Handler::set_auto_disconnect`]: struct.Handler.html#method.set_auto_disconnect
+const AUTO_DISCONNECT_CHECK: Duration = Duration::from_secs(1);
+
+/// A function called with the voice states of users other than the current
+/// one, set via [`Handler::set_foreign_state_observer`].
+///
+/// [`Handler::set_foreign_state_observer`]: struct.Handler.html#method.set_foreign_state_observer
+pub type VoiceStateObserver = Arc<dyn Fn(&VoiceState) + Send + Sync>;
+
 /// The handler is responsible for "handling" a single voice connection, acting
 /// as a clean API above the inner connection.
 ///
@@ -49,6 +121,17 @@ use serde_json::json;
 /// [`Shard`]: ../gateway/struct.Shard.html
 #[derive(Clone)]
 pub struct Handler {
+    /// The guild's AFK channel, if known.
+    afk_channel_id: Option<ChannelId>,
+    /// What to do when moved into the guild's AFK channel.
+    afk_policy: AfkPolicy,
+    /// Whether to connect once the server and session are both known.
+    auto_connect: bool,
+    /// Keeps alive the background task leaving the channel once the handler
+    /// has idled, if one was started via `set_auto_disconnect`.
+    auto_disconnect: Option<Arc<()>>,
+    /// The local address to bind the voice UDP socket to, if any.
+    bind_address: Option<IpAddr>,
     /// The ChannelId to be connected to, if any.
     ///
     /// **Note**: This _must not_ be manually mutated. Call [`switch_to`] to
@@ -58,8 +141,27 @@ pub struct Handler {
     pub channel_id: Option<ChannelId>,
     /// The voice server endpoint.
     pub endpoint: Option<String>,
+    /// Resolves the endpoint's hostname in place of DNS, if set.
+    endpoint_resolver: Option<EndpointResolver>,
+    /// Called with the voice states of other users, if set.
+    foreign_state_observer: Option<VoiceStateObserver>,
+    /// The channel last left, if remembered via
+    /// [`set_remember_last_channel`].
+    ///
+    /// [`set_remember_last_channel`]: #method.set_remember_last_channel
+    last_channel: Option<ChannelId>,
+    /// The endpoint of a voice server which went away, until the next is
+    /// announced.
+    lost_endpoint: Option<String>,
+    /// What to do when disconnected from the channel by someone else.
+    force_disconnect: ForceDisconnectPolicy,
     /// The Id of the guild to be connected to.
     pub guild_id: GuildId,
+    /// Whether the channel left is remembered as the last channel.
+    remember_last_channel: bool,
+    /// The seed for the initial RTP sequence number and timestamp, if they
+    /// should not be random.
+    rtp_seed: Option<u64>,
     /// Whether the current handler is set to deafen voice connections.
     ///
     /// **Note**: This _must not_ be manually mutated. Call [`deafen`] to
@@ -74,14 +176,38 @@ pub struct Handler {
     ///
     /// [`mute`]: #method.mute
     pub self_mute: bool,
+    /// Whether the current user's camera is marked as being on.
+    ///
+    /// **Note**: This _must not_ be manually mutated. Call
+    /// [`set_self_video`] to mutate this value.
+    ///
+    /// [`set_self_video`]: #method.set_self_video
+    pub self_video: bool,
+    /// How long joining may take, as set via [`set_connect_timeout`].
+    ///
+    /// [`set_connect_timeout`]: #method.set_connect_timeout
+    connect_timeout: Duration,
+    /// The messages held back from the task while in a dry run, as set via
+    /// `set_dry_run`, shared between clones of the handler.
+    dry_run: Option<Arc<Mutex<Vec<VoiceStatus>>>>,
     /// The internal sender to the voice connection monitor thread.
     sender: Sender<VoiceStatus>,
+    /// State reported back by the voice connection monitor thread.
+    pub(crate) shared: Arc<SharedState>,
+    /// A name for the handler recorded in its tracing spans, as set via
+    /// [`set_span_label`].
+    ///
+    /// [`set_span_label`]: #method.set_span_label
+    span_label: Option<String>,
     /// The session Id of the current voice connection, if any.
     ///
     /// **Note**: This _should_ be set through an [`update_state`] call.
     ///
     /// [`update_state`]: #method.update_state
     pub session_id: Option<String>,
+    /// The most messages which may wait for the task before sources are
+    /// rejected by the `try_` methods, if limited.
+    task_queue_limit: Option<usize>,
     /// The token of the current voice connection, if any.
     ///
     /// **Note**: This _should_ be set through an [`update_server`] call.
@@ -100,18 +226,29 @@ pub struct Handler {
     ///
     /// When set via [`standalone`][`Handler::standalone`], it will not be
     /// present.
-    ws: Option<Sender<InterMessage>>,
+    ws: Option<Arc<UpdateLimiter>>,
 }
 
 impl Handler {
-    /// Creates a new Handler.
+    /// Creates a new Handler, which will be deafened and muted as given when
+    /// it first joins a channel.
     #[inline]
     pub(crate) fn new(
         guild_id: GuildId,
-        ws: Sender<InterMessage>,
+        ws: Arc<UpdateLimiter>,
         user_id: UserId,
+        self_deaf: bool,
+        self_mute: bool,
     ) -> Self {
-        Self::new_raw(guild_id, Some(ws), user_id)
+        let mut handler = Self::new_raw(guild_id, Some(ws), user_id);
+        handler.self_deaf = self_deaf;
+        handler.self_mute = self_mute;
+
+        if self_mute {
+            handler.send(VoiceStatus::Mute(true));
+        }
+
+        handler
     }
 
     /// Creates a new, standalone Handler which is not connected to the primary
@@ -138,15 +275,26 @@ impl Handler {
     /// is.
     ///
     /// This will automatically be called by [`update_server`] or
-    /// [`update_state`] when all three values become present.
+    /// [`update_state`] when all three values become present, unless
+    /// disabled via [`set_auto_connect`].
+    ///
+    /// After the voice gateway rejects a session, as announced by a
+    /// [`HandlerEvent::SessionInvalidated`], its session ID and token are
+    /// cleared rather than connected with again, until fresh ones arrive.
     ///
     /// [`endpoint`]: #structfield.endpoint
+    /// [`HandlerEvent::SessionInvalidated`]: enum.HandlerEvent.html#variant.SessionInvalidated
     /// [`session_id`]: #structfield.session_id
+    /// [`set_auto_connect`]: #method.set_auto_connect
     /// [`token`]: #structfield.token
     /// [`update_server`]: #method.update_server
     /// [`update_state`]: #method.update_state
     #[instrument(skip(self))]
     pub fn connect(&mut self) -> bool {
+        let _span = self.span().entered();
+
+        self.clear_invalid_session();
+
         if self.endpoint.is_none() || self.session_id.is_none() || self.token.is_none() {
             return false;
         }
@@ -157,10 +305,15 @@ impl Handler {
         let token = self.token.clone().unwrap();
         let user_id = self.user_id;
 
+        self.shared.set_connection_state(ConnectionState::Connecting);
+
         // Safe as all of these being present was already checked.
         self.send(VoiceStatus::Connect(ConnectionInfo {
+            bind_address: self.bind_address,
             endpoint,
+            endpoint_resolver: self.endpoint_resolver.clone(),
             guild_id,
+            rtp_seed: self.rtp_seed,
             session_id,
             token,
             user_id,
@@ -169,6 +322,258 @@ impl Handler {
         true
     }
 
+    /// Sets whether [`update_server`] and [`update_state`] connect by
+    /// themselves once the endpoint, session ID and token are all present.
+    /// Defaults to `true`.
+    ///
+    /// When disabled, the values are only stored, and no connection is made
+    /// until [`connect`] is called, such as by a standalone integration
+    /// driving the voice lifecycle itself. This includes reconnecting after
+    /// a move to another voice server, or rejoining via
+    /// [`ForceDisconnectPolicy::Rejoin`]. Enabling it again does not connect
+    /// by itself either.
+    ///
+    /// [`connect`]: #method.connect
+    /// [`ForceDisconnectPolicy::Rejoin`]: enum.ForceDisconnectPolicy.html#variant.Rejoin
+    /// [`update_server`]: #method.update_server
+    /// [`update_state`]: #method.update_state
+    #[instrument(skip(self))]
+    pub fn set_auto_connect(&mut self, auto_connect: bool) {
+        self.auto_connect = auto_connect;
+    }
+
+    /// Returns the state of the voice connection, as last reported by the
+    /// voice task.
+    ///
+    /// Audio played while this is [`ConnectionState::Disconnected`] will not
+    /// be heard until a connection is made.
+    ///
+    /// [`ConnectionState::Disconnected`]: enum.ConnectionState.html#variant.Disconnected
+    pub fn connection_state(&self) -> ConnectionState {
+        self.shared.connection_state()
+    }
+
+    /// Returns a stream of [`HandlerEvent`]s emitted by this handler's voice
+    /// task, such as notice of resumed or re-established connections.
+    ///
+    /// Each call creates a new, independent stream which receives every event
+    /// emitted after its creation. Dropping the stream unsubscribes it.
+    ///
+    /// [`HandlerEvent`]: enum.HandlerEvent.html
+    pub fn events(&self) -> Receiver<HandlerEvent> {
+        self.shared.subscribe()
+    }
+
+    /// Returns the number of messages received on the current voice
+    /// WebSocket, which is reset whenever a new WebSocket is opened, including
+    /// on resume.
+    ///
+    /// The voice gateway version used does not number its messages, so this
+    /// count stands in for a sequence number when debugging connection issues.
+    pub fn ws_sequence(&self) -> u64 {
+        self.shared.ws_sequence()
+    }
+
+    /// Sends a heartbeat over the voice WebSocket immediately, if connected.
+    ///
+    /// Regular heartbeats continue to be sent on their usual schedule.
+    #[instrument(skip(self))]
+    pub fn force_heartbeat(&mut self) {
+        self.send(VoiceStatus::ForceHeartbeat)
+    }
+
+    /// Sends a payload with the given opcode and data straight to the voice
+    /// gateway, as `{"op": op, "d": data}`, such as for an op which Discord
+    /// has added but which is not yet supported here.
+    ///
+    /// The payload is not checked, and nothing Discord replies with is
+    /// handled beyond what already is. Sending an op the handler manages
+    /// itself, such as [`VoiceOpCode::Identify`], may break the connection.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`VoiceError::NotConnected`] if there is no live connection,
+    /// in which case nothing is sent. A payload which reaches the task after
+    /// the connection is lost is dropped with a warning.
+    ///
+    /// [`VoiceError::NotConnected`]: enum.VoiceError.html#variant.NotConnected
+    /// [`VoiceOpCode::Identify`]: ../constants/enum.VoiceOpCode.html#variant.Identify
+    #[instrument(skip(self, data))]
+    pub fn send_voice_op(&mut self, op: VoiceOpCode, data: Value) -> Result<(), VoiceError> {
+        if self.connection_state() != ConnectionState::Connected {
+            return Err(VoiceError::NotConnected);
+        }
+
+        self.send(VoiceStatus::SendVoiceOp(op, data));
+
+        Ok(())
+    }
+
+    /// Tears down the current voice connection and makes a fresh one to the
+    /// same server, resolving once it is ready.
+    ///
+    /// This is a manual trigger for the reconnection made automatically when
+    /// a connection fails, for when a connection is known to be bad. Playing
+    /// sources carry on from where they were, and a
+    /// [`HandlerEvent::Reconnected`] is emitted on success.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`VoiceError::NotConnected`] if there is no live connection,
+    /// or the error from connecting if that fails, in which case the handler
+    /// is left disconnected.
+    ///
+    /// [`HandlerEvent::Reconnected`]: enum.HandlerEvent.html#variant.Reconnected
+    /// [`VoiceError::NotConnected`]: enum.VoiceError.html#variant.NotConnected
+    #[instrument(skip(self))]
+    pub async fn reconnect(&mut self) -> SerenityResult<()> {
+        let (tx, rx) = oneshot::channel();
+        self.span().in_scope(|| self.send(VoiceStatus::Reconnect(tx)));
+
+        rx.await.unwrap_or(Err(Error::Voice(VoiceError::NotConnected)))
+    }
+
+    /// Returns the settings in use on the live voice connection, as agreed
+    /// with the voice server and read back from the encoder, such as to check
+    /// that an [`OpusConfig`] was applied.
+    ///
+    /// This is `None` until the first connection is made, and after leaving.
+    ///
+    /// [`OpusConfig`]: struct.OpusConfig.html
+    pub fn connection_info(&self) -> Option<SessionInfo> {
+        self.shared.session_info()
+    }
+
+    /// Returns statistics about this handler's voice connections, accumulated
+    /// since it was created.
+    ///
+    /// Frames are sent on a 20ms schedule kept by a monotonic clock, so that
+    /// sending does not drift over long streams. Sources are read once per
+    /// frame, so they can never run ahead of the schedule, but a source which
+    /// is slow to read, or a stalled host, can leave the task behind it. A lag
+    /// of up to 100ms is made up by sending the late frames back to back. Past
+    /// that, the missed frames are skipped: the RTP timestamp jumps forward to
+    /// match the clock, while sources carry on from where they were, so none
+    /// of their audio is lost. No frames are ever inserted to hold a source
+    /// back, since none can get ahead. The lag is reported as
+    /// [`NetworkStats::drift`], and skipped frames are counted in
+    /// [`NetworkStats::frames_skipped`].
+    ///
+    /// [`NetworkStats::drift`]: struct.NetworkStats.html#structfield.drift
+    /// [`NetworkStats::frames_skipped`]: struct.NetworkStats.html#structfield.frames_skipped
+    pub fn network_stats(&self) -> NetworkStats {
+        self.shared.network_stats()
+    }
+
+    /// Returns the numbers of bytes sent and received over this handler's
+    /// voice connections, in that order, as they appear on the wire, since it
+    /// was created or this was last reset via [`reset_bytes_transferred`],
+    /// e.g. to estimate bandwidth costs.
+    ///
+    /// Every UDP datagram is counted with its RTP header, encryption overhead
+    /// and UDP header, including keepalives and IP discovery, but IP headers
+    /// and the voice WebSocket are not. These are the same as
+    /// [`NetworkStats::bytes_sent`] and [`NetworkStats::bytes_received`],
+    /// which [`Manager::aggregate_stats`] sums across handlers.
+    ///
+    /// [`Manager::aggregate_stats`]: struct.Manager.html#method.aggregate_stats
+    /// [`NetworkStats::bytes_received`]: struct.NetworkStats.html#structfield.bytes_received
+    /// [`NetworkStats::bytes_sent`]: struct.NetworkStats.html#structfield.bytes_sent
+    /// [`reset_bytes_transferred`]: #method.reset_bytes_transferred
+    pub fn bytes_transferred(&self) -> (u64, u64) {
+        let stats = self.shared.network_stats();
+
+        (stats.bytes_sent, stats.bytes_received)
+    }
+
+    /// Resets the counts returned by [`bytes_transferred`] to zero, such as at
+    /// the start of each billing period, leaving other statistics as they
+    /// are.
+    ///
+    /// [`bytes_transferred`]: #method.bytes_transferred
+    pub fn reset_bytes_transferred(&mut self) {
+        self.shared.update_stats(|stats| {
+            stats.bytes_sent = 0;
+            stats.bytes_received = 0;
+        });
+    }
+
+    /// Returns the number of frames for which the playing sources could not
+    /// supply audio fast enough, so that listeners heard a gap, such as from
+    /// a slow network stream. An alert can be raised when this climbs.
+    ///
+    /// This is the same as [`NetworkStats::underruns`].
+    ///
+    /// [`NetworkStats::underruns`]: struct.NetworkStats.html#structfield.underruns
+    pub fn underrun_count(&self) -> u64 {
+        self.shared.network_stats().underruns
+    }
+
+    /// Returns whether the handler is sending audio right now, i.e. whether a
+    /// source produced audio for the last frame, and the handler is neither
+    /// [muted] nor disconnected.
+    ///
+    /// Unlike [`connection_state`], or whether any source is playing, this is
+    /// `false` while every source is paused, and during the silence sent when
+    /// audio stops, making it suitable for an "on air" indicator.
+    ///
+    /// [`connection_state`]: #method.connection_state
+    /// [muted]: #method.mute
+    pub fn is_transmitting(&self) -> bool {
+        self.shared.is_transmitting()
+    }
+
+    /// Returns the recent level of the audio being sent, from 0.0 to 1.0,
+    /// for rendering a VU meter or driving reactive lights.
+    ///
+    /// This follows the peak of each frame of the final mix, smoothed over
+    /// the last few frames, and is 0.0 whenever [`is_transmitting`] is
+    /// `false`. Frames passed through without decoding are not measured.
+    ///
+    /// [`is_transmitting`]: #method.is_transmitting
+    pub fn output_level(&self) -> f32 {
+        self.shared.output_level()
+    }
+
+    /// Returns the users who are currently speaking in the channel.
+    ///
+    /// A user is included once they have announced that they are speaking,
+    /// for as long as their audio keeps arriving. This is tracked whether or
+    /// not any receiver is set, but ignores any [receive filter].
+    ///
+    /// [receive filter]: #method.set_receive_filter
+    pub fn speaking_users(&self) -> Vec<UserId> {
+        self.shared.speaking_users()
+    }
+
+    /// Returns every SSRC which voice packets have arrived from on the
+    /// current connection, and when, e.g. to find out why a user's audio is
+    /// not being received.
+    ///
+    /// This is tracked whether or not any receiver is set, and ignores any
+    /// [receive filter]. An SSRC is forgotten once nothing has arrived from
+    /// it for 30 seconds, and all are forgotten on reconnecting.
+    ///
+    /// [receive filter]: #method.set_receive_filter
+    pub fn active_ssrcs(&self) -> Vec<SsrcInfo> {
+        self.shared.active_ssrcs()
+    }
+
+    /// Returns how long it has been since any packet arrived on the current
+    /// connection's UDP socket, or `None` if none has, or there is no
+    /// connection.
+    ///
+    /// Unlike the [`last_packet`] of each SSRC, this covers the connection as
+    /// a whole. A value which keeps growing while users are speaking points
+    /// to a problem with the receive path, such as a firewall dropping
+    /// incoming audio. Packets are noted once per 20ms frame, so this is
+    /// accurate to within a frame.
+    ///
+    /// [`last_packet`]: struct.SsrcInfo.html#structfield.last_packet
+    pub fn last_receive_age(&self) -> Option<Duration> {
+        self.shared.last_receive_age()
+    }
+
     /// Sets whether the current connection to be deafened.
     ///
     /// If there is no live voice connection, then this only acts as a settings
@@ -194,32 +599,195 @@ impl Handler {
         }
     }
 
+    /// Sets whether the current user's camera is marked as being on, for bots
+    /// which track video presence. No video is sent.
+    ///
+    /// As with [`deafen`], this only acts as a settings update while not in a
+    /// channel.
+    ///
+    /// [`deafen`]: #method.deafen
+    #[instrument(skip(self))]
+    pub fn set_self_video(&mut self, video: bool) {
+        self.self_video = video;
+
+        if self.channel_id.is_some() {
+            self.update();
+        }
+    }
+
     /// Connect - or switch - to the given voice channel by its Id.
     #[instrument(skip(self))]
     pub fn join(&mut self, channel_id: ChannelId) {
-        self.channel_id = Some(channel_id);
+        let _span = self.span().entered();
+
+        self.set_channel_id(Some(channel_id));
 
         self.send_join();
     }
 
+    /// Sets the channel to connect to, sending it to the gateway along with
+    /// the current [`deafen`] and [`mute`] settings at once, but making no
+    /// voice connection until the gateway replies with fresh server and
+    /// session details, as passed to [`update_server`] and [`update_state`].
+    ///
+    /// Unlike [`join`] and [`switch_to`], the update is never coalesced with
+    /// others, and any server details kept from an earlier connection are
+    /// forgotten, so that one cannot be made with them before the reply. Set
+    /// [`deafen`] and [`mute`] before calling this, for them to be applied
+    /// from the moment of joining.
+    ///
+    /// Tracks may be played meanwhile, such as via [`play`], and are sent once
+    /// the connection is made.
+    ///
+    /// [`deafen`]: #method.deafen
+    /// [`join`]: #method.join
+    /// [`mute`]: #method.mute
+    /// [`play`]: #method.play
+    /// [`switch_to`]: #method.switch_to
+    /// [`update_server`]: #method.update_server
+    /// [`update_state`]: #method.update_state
+    #[instrument(skip(self))]
+    pub fn prepare(&mut self, channel_id: ChannelId) {
+        let _span = self.span().entered();
+
+        self.set_channel_id(Some(channel_id));
+        self.endpoint = None;
+        self.session_id = None;
+        self.token = None;
+
+        if !self.update() {
+            warn!(
+                "[Voice] Could not send voice state update to prepare channel {} in guild {}.",
+                channel_id,
+                self.guild_id,
+            );
+        }
+    }
+
+    /// Moves to the given voice channel, joining it if not in one, and
+    /// returns a future which resolves once the move is confirmed by a voice
+    /// connection being made in the new channel.
+    ///
+    /// This does nothing besides resolving at once if already connected in
+    /// the channel. Otherwise, the move is requested immediately, as with
+    /// [`join`], and confirmation arrives as the gateway's updates are passed
+    /// to [`update_state`] and [`update_server`]. The future does not borrow
+    /// the handler, so any lock on it, or on its [`Manager`], should be
+    /// released before awaiting the future, or those updates cannot be
+    /// delivered.
+    ///
+    /// # Errors
+    ///
+    /// Resolves with [`VoiceError::NotConnected`] if the voice connection in
+    /// the new channel fails, or [`VoiceError::Timeout`] if no connection is
+    /// made within the time set via [`set_connect_timeout`], of 10 seconds by
+    /// default.
+    ///
+    /// Discord ignores requests to join a channel which the bot lacks the
+    /// `CONNECT` permission for, or which is full, so such moves fail with
+    /// [`VoiceError::Timeout`], with the handler left set to the new channel
+    /// until it [`leave`]s or moves elsewhere. Check the bot's permissions in
+    /// the channel beforehand to fail sooner.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,ignore
+    /// let moved = {
+    ///     let mut manager = client.voice_manager.lock().await;
+    ///
+    ///     manager.get_mut(guild_id).map(|handler| handler.move_to(channel_id))
+    /// };
+    ///
+    /// if let Some(moved) = moved {
+    ///     moved.await?;
+    /// }
+    /// ```
+    ///
+    /// [`join`]: #method.join
+    /// [`leave`]: #method.leave
+    /// [`Manager`]: struct.Manager.html
+    /// [`set_connect_timeout`]: #method.set_connect_timeout
+    /// [`update_server`]: #method.update_server
+    /// [`update_state`]: #method.update_state
+    /// [`VoiceError::NotConnected`]: enum.VoiceError.html#variant.NotConnected
+    /// [`VoiceError::Timeout`]: enum.VoiceError.html#variant.Timeout
+    #[instrument(skip(self))]
+    pub fn move_to(&mut self, channel_id: ChannelId) -> impl Future<Output = Result<(), VoiceError>> + Send + 'static {
+        let _span = self.span().entered();
+
+        let already_connected = self.channel_id == Some(channel_id)
+            && self.connection_state() == ConnectionState::Connected;
+
+        // Only states reported after the move is requested confirm it, so the
+        // current one is consumed first.
+        let mut connection = self.shared.watch_connection_state();
+        let _ = connection.recv().now_or_never();
+
+        if !already_connected {
+            self.join(channel_id);
+        }
+
+        let connect_timeout = self.connect_timeout;
+
+        async move {
+            if already_connected {
+                return Ok(());
+            }
+
+            let confirmed = async {
+                while let Some(state) = connection.recv().await {
+                    match state {
+                        ConnectionState::Connected => return Ok(()),
+                        ConnectionState::Disconnected => return Err(VoiceError::NotConnected),
+                        ConnectionState::Connecting => {},
+                    }
+                }
+
+                Err(VoiceError::NotConnected)
+            };
+
+            timeout(connect_timeout, confirmed).await.unwrap_or(Err(VoiceError::Timeout))
+        }
+    }
+
     /// Leaves the current voice channel, disconnecting from it.
     ///
     /// This does _not_ forget settings, like whether to be self-deafened or
-    /// self-muted.
+    /// self-muted. The channel itself is forgotten, unless it is remembered
+    /// as the [`last_channel`] via [`set_remember_last_channel`].
+    ///
+    /// The gateway update is queued before the voice task is told to
+    /// disconnect, and the task is never restarted to do so, so this is safe
+    /// to call while shutting down, as happens when the `Handler` is dropped.
     ///
     /// **Note**: If the `Handler` was created via [`standalone`], then this
     /// will _only_ update whether the connection is internally connected to a
     /// voice channel.
     ///
+    /// [`last_channel`]: #method.last_channel
+    /// [`set_remember_last_channel`]: #method.set_remember_last_channel
     /// [`standalone`]: #method.standalone
     #[instrument(skip(self))]
     pub fn leave(&mut self) {
+        let _span = self.span().entered();
+
+        self.lost_endpoint = None;
+
         // Only send an update if we were in a voice channel.
         if self.channel_id.is_some() {
-            self.channel_id = None;
-            self.send(VoiceStatus::Disconnect);
+            self.remember_channel();
+            self.set_channel_id(None);
 
-            self.update();
+            if !self.update() {
+                warn!(
+                    "[Voice] Could not send voice state update to leave guild {}.",
+                    self.guild_id,
+                );
+            }
+
+            // A task which has stopped holds no connection, so there is no
+            // need to restart it as `send` would.
+            self.send_disconnect();
         }
     }
 
@@ -229,17 +797,209 @@ impl Handler {
     /// The `receiver` argument can be thought of as an "optional Option". You
     /// can pass in just a boxed receiver, and do not need to specify `Some`.
     ///
-    /// Pass `None` to drop the current receiver, if one exists.
+    /// This replaces _all_ receivers added via [`add_receiver`], and closes
+    /// any stream made by [`take_audio_stream`]. Pass `None` to drop every
+    /// current receiver.
+    ///
+    /// [`add_receiver`]: #method.add_receiver
+    /// [`take_audio_stream`]: #method.take_audio_stream
     #[instrument(skip(self, receiver))]
     pub fn listen(&mut self, receiver: Option<Arc<dyn AudioReceiver>>) {
         self.send(VoiceStatus::SetReceiver(receiver))
     }
 
+    /// Adds a receiver alongside any existing ones, so that several consumers
+    /// (e.g. a recorder and a transcriber) can each receive all audio.
+    ///
+    /// Voice packets are handed to each receiver in the order they were
+    /// added, and delivery of a packet waits for every receiver in turn. A
+    /// receiver performing slow work in [`AudioReceiver::voice_packet`] will
+    /// hold up the others, and should offload that work, e.g. via
+    /// `tokio::spawn`. Speaking and client connection updates are dispatched
+    /// to each receiver on their own task.
+    ///
+    /// This closes any stream made by [`take_audio_stream`].
+    ///
+    /// The receiver stays registered for as long as the returned
+    /// [`ReceiverHandle`] is held, and is removed, leaving all others, once
+    /// it is dropped. Use [`ReceiverHandle::detach`] to keep it registered
+    /// regardless.
+    ///
+    /// [`ReceiverHandle`]: struct.ReceiverHandle.html
+    /// [`ReceiverHandle::detach`]: struct.ReceiverHandle.html#method.detach
+    /// [`take_audio_stream`]: #method.take_audio_stream
+    /// [`AudioReceiver::voice_packet`]: trait.AudioReceiver.html#method.voice_packet
+    #[instrument(skip(self, receiver))]
+    pub fn add_receiver(&mut self, receiver: Arc<dyn AudioReceiver>) -> ReceiverHandle {
+        self.send(VoiceStatus::AddReceiver(Arc::clone(&receiver)));
+
+        ReceiverHandle::new(receiver, self.sender.clone(), Arc::clone(&self.shared))
+    }
+
+    /// Removes a receiver previously passed to [`add_receiver`] or [`listen`],
+    /// leaving all others registered.
+    ///
+    /// Receivers are compared by pointer, so this must be given a clone of the
+    /// same `Arc`.
+    ///
+    /// [`add_receiver`]: #method.add_receiver
+    /// [`listen`]: #method.listen
+    #[instrument(skip(self, receiver))]
+    pub fn remove_receiver(&mut self, receiver: &Arc<dyn AudioReceiver>) {
+        self.send(VoiceStatus::RemoveReceiver(Arc::clone(receiver)))
+    }
+
+    /// Returns a stream of decoded voice packets, as an alternative to
+    /// implementing [`AudioReceiver`].
+    ///
+    /// Only one means of receiving audio is active at a time: this removes all
+    /// receivers set via [`listen`] or [`add_receiver`], and setting a
+    /// receiver afterwards closes the stream. Calling this again closes the
+    /// previous stream.
+    ///
+    /// Speaking and client connection updates are not sent down the stream.
+    ///
+    /// [`AudioReceiver`]: trait.AudioReceiver.html
+    /// [`add_receiver`]: #method.add_receiver
+    /// [`listen`]: #method.listen
+    #[instrument(skip(self))]
+    pub fn take_audio_stream(&mut self) -> Receiver<DecodedPacket> {
+        let (tx, rx) = unbounded();
+        self.send(VoiceStatus::SetAudioStream(tx));
+
+        rx
+    }
+
+    /// Returns a stream of the audio received from one user, such as to
+    /// transcribe each speaker separately.
+    ///
+    /// Each item is a frame of 20ms of 48kHz audio, as 1920 samples of
+    /// interleaved stereo PCM, buffered against jitter and upmixed as for
+    /// [`AudioReceiver::mixed_audio`]. Audio is only buffered once Discord
+    /// has announced which SSRC the user speaks with, and nothing is sent
+    /// while they are silent.
+    ///
+    /// Any number of these streams may be open at once, including several
+    /// for the same user, alongside any receivers or [`take_audio_stream`].
+    /// Dropping a stream stops its user's audio being buffered for it.
+    ///
+    /// [`AudioReceiver::mixed_audio`]: trait.AudioReceiver.html#method.mixed_audio
+    /// [`take_audio_stream`]: #method.take_audio_stream
+    #[instrument(skip(self))]
+    pub fn user_audio_stream(&mut self, user_id: UserId) -> Receiver<Vec<i16>> {
+        let (tx, rx) = unbounded();
+        self.send(VoiceStatus::AddUserStream(user_id, tx));
+
+        rx
+    }
+
+    /// Restricts received audio to that of the given users, or removes the
+    /// restriction if `None`.
+    ///
+    /// Packets from other users are dropped before decoding, including those
+    /// from users whose SSRC has not yet been announced by Discord. This takes
+    /// effect from the next packet received.
+    #[instrument(skip(self))]
+    pub fn set_receive_filter(&mut self, users: Option<HashSet<UserId>>) {
+        self.send(VoiceStatus::SetReceiveFilter(users))
+    }
+
+    /// Sets automatic gain control of received audio, which turns each
+    /// speaker up or down towards a common level before their audio reaches
+    /// receivers, the mix, or an audio stream, or disables it if `None`,
+    /// which is the default.
+    ///
+    /// This evens out quiet and loud users, such as to help speech
+    /// recognition, at the cost of changing the audio: background noise is
+    /// raised along with quiet speakers, up to the configured maximum gain,
+    /// and the gain is judged from each frame as it arrives, so that no
+    /// latency is added but a sudden loud syllable is heard before the gain
+    /// falls to meet it. Silence leaves the gain where it was.
+    ///
+    /// Gains set via [`set_receive_gain`] are applied on top of this in the
+    /// mix. This takes effect from the next packet received, starting every
+    /// speaker from unity, and is kept across reconnects.
+    ///
+    /// [`set_receive_gain`]: #method.set_receive_gain
+    #[instrument(skip(self))]
+    pub fn set_receive_agc(&mut self, config: Option<AgcConfig>) {
+        self.send(VoiceStatus::SetReceiveAgc(config))
+    }
+
+    /// Sets the most frames of each speaker's audio, of 20ms each, which are
+    /// buffered to be mixed for [`AudioReceiver::mixed_audio`], which is 10
+    /// by default, and kept across reconnects.
+    ///
+    /// While receivers fall behind, or a speaker sends faster than real
+    /// time, the oldest frames beyond the limit are dropped, which bounds
+    /// both the memory used and the latency of the mix. Drops are counted
+    /// in [`NetworkStats::receive_frames_dropped`]. Lowering the limit drops
+    /// any buffered frames beyond it at once.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`VoiceError::InvalidBufferLimit`] if the limit is below 2,
+    /// as two frames are buffered before a speaker is first mixed.
+    ///
+    /// [`AudioReceiver::mixed_audio`]: trait.AudioReceiver.html#method.mixed_audio
+    /// [`NetworkStats::receive_frames_dropped`]: struct.NetworkStats.html#structfield.receive_frames_dropped
+    /// [`VoiceError::InvalidBufferLimit`]: enum.VoiceError.html#variant.InvalidBufferLimit
+    #[instrument(skip(self))]
+    pub fn set_receive_buffer_limit(&mut self, frames: usize) -> Result<(), VoiceError> {
+        if frames < JITTER_FRAMES {
+            return Err(VoiceError::InvalidBufferLimit(frames));
+        }
+
+        self.send(VoiceStatus::SetReceiveBufferLimit(frames));
+
+        Ok(())
+    }
+
+    /// Sets the sample rate and channels of received audio, as delivered to
+    /// receivers, down an audio stream, and down each user's stream, such as
+    /// 16kHz mono for speech recognition. The default is 48kHz stereo, which
+    /// is delivered as it is decoded. [`Channels::Auto`] keeps the channels
+    /// each user sends, which for the mix and user streams is stereo.
+    ///
+    /// Audio is still decoded and mixed at 48kHz stereo, and converted just
+    /// before it is delivered, by averaging samples down to the lower rate.
+    /// This costs a few additions per sample, little next to decoding, but
+    /// is done separately for each kind of delivery in use. This takes
+    /// effect from the next packet received, and is kept across reconnects.
+    ///
+    /// [`Channels::Auto`]: enum.Channels.html#variant.Auto
+    #[instrument(skip(self))]
+    pub fn set_receive_format(&mut self, rate: SampleRate, channels: Channels) {
+        self.send(VoiceStatus::SetReceiveFormat(rate, channels))
+    }
+
+    /// Sets the gain applied to a user's audio when speakers are mixed
+    /// together for [`AudioReceiver::mixed_audio`], such as to balance a
+    /// recording with quiet and loud speakers. Gains default to 1.0, which
+    /// leaves audio unchanged.
+    ///
+    /// This takes effect from the next mixed frame, including for audio
+    /// already buffered, and is kept across reconnects. The audio passed to
+    /// [`AudioReceiver::voice_packet`] or down an audio stream is unaffected.
+    /// Audio from users whose SSRC has not yet been announced by Discord is
+    /// mixed at unity gain.
+    ///
+    /// [`AudioReceiver::mixed_audio`]: trait.AudioReceiver.html#method.mixed_audio
+    /// [`AudioReceiver::voice_packet`]: trait.AudioReceiver.html#method.voice_packet
+    #[instrument(skip(self))]
+    pub fn set_receive_gain(&mut self, user_id: UserId, gain: f32) {
+        self.send(VoiceStatus::SetReceiveGain(user_id, gain))
+    }
+
     /// Sets whether the current connection is to be muted.
     ///
     /// If there is no live voice connection, then this only acts as a settings
     /// update for future connections.
     ///
+    /// When muted mid-playback, a few frames of silence are sent before
+    /// speaking stops, so that clients promptly stop showing the bot as
+    /// speaking. Sources carry on playing unheard.
+    ///
     /// **Note**: If the `Handler` was created via [`standalone`], then this
     /// will _only_ update whether the connection is internally muted.
     ///
@@ -248,9 +1008,12 @@ impl Handler {
     pub fn mute(&mut self, mute: bool) {
         self.self_mute = mute;
 
+        // The task must always know, so that audio is not sent on joining
+        // after muting while out of a channel.
+        self.send(VoiceStatus::Mute(mute));
+
         if self.channel_id.is_some() {
             self.update();
-            self.send(VoiceStatus::Mute(mute));
         }
     }
 
@@ -258,71 +1021,998 @@ impl Handler {
     ///
     /// This can be a source created via [`voice::ffmpeg`] or [`voice::ytdl`].
     ///
+    /// If there is no voice connection, and none is being established, the
+    /// audio will not be heard until one is made. Use [`play_returning`] and
+    /// [`TrackHandle::await_playing`] to find out whether it was played.
+    ///
     /// [`voice::ffmpeg`]: fn.ffmpeg.html
     /// [`voice::ytdl`]: fn.ytdl.html
+    /// [`play_returning`]: #method.play_returning
+    /// [`TrackHandle::await_playing`]: struct.TrackHandle.html#method.await_playing
     #[instrument(skip(self, source))]
     pub fn play(&mut self, source: Box<dyn AudioSource>) {
         self.play_returning(source);
     }
 
-    /// Plays audio from a source, returning the locked audio source.
+    /// Plays audio from a source, returning a handle to the track.
     #[instrument(skip(self, source))]
-    pub fn play_returning(&mut self, source: Box<dyn AudioSource>) -> LockedAudio {
-        let player = Arc::new(Mutex::new(Audio::new(source)));
-        self.send(VoiceStatus::AddSender(player.clone()));
+    pub fn play_returning(&mut self, source: Box<dyn AudioSource>) -> TrackHandle {
+        let track = self.new_track(Audio::new(source));
+        self.send(VoiceStatus::AddSender(track.audio().clone()));
 
-        player
+        track
     }
 
-    /// Plays audio from a source.
+    /// Plays audio from a source as with [`play_returning`], starting from
+    /// the given position, such as to resume a track where it was left off.
     ///
-    /// Unlike [`play`] or [`play_returning`], this stops all other sources attached
-    /// to the channel.
+    /// The source is seeked before its first frame is mixed, so the start of
+    /// the source is never heard.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`VoiceError::NotSeekable`] if the source cannot seek, in
+    /// which case it is not played.
     ///
-    /// [`play`]: #method.play
     /// [`play_returning`]: #method.play_returning
+    /// [`VoiceError::NotSeekable`]: enum.VoiceError.html#variant.NotSeekable
     #[instrument(skip(self, source))]
-    pub fn play_only(&mut self, source: Box<dyn AudioSource>) -> LockedAudio {
-        let player = Arc::new(Mutex::new(Audio::new(source)));
-        self.send(VoiceStatus::SetSender(Some(player.clone())));
+    pub fn play_from(&mut self, source: Box<dyn AudioSource>, position: Duration) -> Result<TrackHandle, VoiceError> {
+        if !source.is_seekable() {
+            return Err(VoiceError::NotSeekable);
+        }
 
-        player
-    }
+        let mut audio = Audio::new(source);
+        audio.position(position);
 
-    /// Sets the bitrate for encoding Opus packets sent along
-    /// the channel being managed.
-    ///
-    /// The default rate is 128 kbps.
-    /// Sensible values range between `Bits(512)` and `Bits(512_000)`
-    /// bits per second.
-    /// Alternatively, `Auto` and `Max` remain available.
-    #[instrument(skip(self))]
-    pub fn set_bitrate(&mut self, bitrate: Bitrate) {
-        self.send(VoiceStatus::SetBitrate(bitrate))
-    }
+        let track = self.new_track(audio);
+        self.send(VoiceStatus::AddSender(track.audio().clone()));
 
-    /// Stops playing audio from a source, if one is set.
-    #[instrument(skip(self))]
-    pub fn stop(&mut self) {
-        self.send(VoiceStatus::SetSender(None))
+        Ok(track)
     }
 
-    /// Switches the current connected voice channel to the given `channel_id`.
+    /// Plays audio from a source as with [`play_returning`], with the given
+    /// options, such as to play only a slice of a clip.
     ///
-    /// This has 3 separate behaviors:
+    /// # Errors
     ///
-    /// - if the given `channel_id` is equivalent to the current connected
-    ///   `channel_id`, then do nothing;
-    /// - if the given `channel_id` is _not_ equivalent to the current connected
-    ///   `channel_id`, then switch to the given `channel_id`;
-    /// - if not currently connected to a voice channel, connect to the given
-    ///   one.
+    /// Returns [`VoiceError::NotSeekable`] if the options start after the
+    /// beginning of a source which cannot seek, and
+    /// [`VoiceError::InvalidTrim`] if they do not start before they end. In
+    /// either case, the source is not played.
     ///
-    /// If you are dealing with switching from one group to another, then open
-    /// another handler, and optionally drop this one via [`Manager::remove`].
+    /// [`play_returning`]: #method.play_returning
+    /// [`VoiceError::InvalidTrim`]: enum.VoiceError.html#variant.InvalidTrim
+    /// [`VoiceError::NotSeekable`]: enum.VoiceError.html#variant.NotSeekable
+    #[instrument(skip(self, source))]
+    pub fn play_with_options(&mut self, source: Box<dyn AudioSource>, options: TrackOptions) -> Result<TrackHandle, VoiceError> {
+        let mut audio = Audio::new(source);
+        audio.apply_options(options)?;
+
+        let track = self.new_track(audio);
+        self.send(VoiceStatus::AddSender(track.audio().clone()));
+
+        Ok(track)
+    }
+
+    /// Plays audio from a source as with [`play_returning`], unless the
+    /// background task is backed up beyond the limit set via
+    /// [`set_task_queue_limit`].
     ///
-    /// **Note**: The given `channel_id`, if in a guild, _must_ be in the
-    /// current handler's associated guild.
+    /// # Errors
+    ///
+    /// Returns [`VoiceError::Busy`] if the limit has been reached, in which
+    /// case the source is dropped without being played.
+    ///
+    /// [`play_returning`]: #method.play_returning
+    /// [`set_task_queue_limit`]: #method.set_task_queue_limit
+    /// [`VoiceError::Busy`]: enum.VoiceError.html#variant.Busy
+    #[instrument(skip(self, source))]
+    pub fn try_play(&mut self, source: Box<dyn AudioSource>) -> Result<TrackHandle, VoiceError> {
+        self.check_task_queue()?;
+
+        Ok(self.play_returning(source))
+    }
+
+    /// Plays audio from a source alongside any others, resolving once it has
+    /// stopped being played with the reason why.
+    ///
+    /// As this borrows the handler until the source ends, a handler shared
+    /// behind a lock, such as one from the [`Manager`], should instead be used
+    /// to call [`play_returning`] so the lock can be released before awaiting
+    /// [`TrackHandle::ended`].
+    ///
+    /// # Errors
+    ///
+    /// Returns [`VoiceError::NotConnected`] if the handler is neither
+    /// connected nor connecting, or stops being so before the source ends.
+    ///
+    /// [`Manager`]: struct.Manager.html
+    /// [`play_returning`]: #method.play_returning
+    /// [`TrackHandle::ended`]: struct.TrackHandle.html#method.ended
+    /// [`VoiceError::NotConnected`]: enum.VoiceError.html#variant.NotConnected
+    #[instrument(skip(self, source))]
+    pub async fn play_blocking(&mut self, source: Box<dyn AudioSource>) -> Result<EndReason, VoiceError> {
+        self.play_returning(source).ended().await
+    }
+
+    /// Plays an announcement, such as text-to-speech, over the sources
+    /// already playing, returning a handle to its track.
+    ///
+    /// The other sources, including any started meanwhile, are ducked as
+    /// configured for as long as the announcement plays: they fade down as
+    /// it starts, and back up once it ends or is stopped, taking as long as
+    /// set via [`DuckConfig::fade`].
+    ///
+    /// [`DuckConfig::fade`]: struct.DuckConfig.html#method.fade
+    #[instrument(skip(self, source))]
+    pub fn announce_returning(&mut self, source: Box<dyn AudioSource>, config: DuckConfig) -> TrackHandle {
+        let mut audio = Audio::new(source);
+        audio.announcement = Some(config);
+
+        let track = self.new_track(audio);
+        self.send(VoiceStatus::AddSender(track.audio().clone()));
+
+        track
+    }
+
+    /// Plays an announcement as with [`announce_returning`], resolving once
+    /// it has stopped being played with the reason why.
+    ///
+    /// The ducking is tied to the announcement rather than to this future,
+    /// so the other sources are brought back up even if it is dropped early.
+    /// As with [`play_blocking`], a handler shared behind a lock should
+    /// instead be used to call [`announce_returning`], so the lock can be
+    /// released before awaiting [`TrackHandle::ended`].
+    ///
+    /// # Errors
+    ///
+    /// Returns [`VoiceError::NotConnected`] as [`play_blocking`] does.
+    ///
+    /// [`announce_returning`]: #method.announce_returning
+    /// [`play_blocking`]: #method.play_blocking
+    /// [`TrackHandle::ended`]: struct.TrackHandle.html#method.ended
+    /// [`VoiceError::NotConnected`]: enum.VoiceError.html#variant.NotConnected
+    #[instrument(skip(self, source))]
+    pub async fn announce(&mut self, source: Box<dyn AudioSource>, config: DuckConfig) -> Result<EndReason, VoiceError> {
+        self.announce_returning(source, config).ended().await
+    }
+
+    /// Plays audio from a source as with [`play_returning`], after reading
+    /// the given length of it ahead via [`voice::prebuffer`], so that its
+    /// start does not stutter.
+    ///
+    /// As this borrows the handler while reading ahead, a handler shared
+    /// behind a lock, such as one from the [`Manager`], should instead be
+    /// given a source already passed through [`voice::prebuffer`], so the
+    /// lock is not held meanwhile.
+    ///
+    /// [`Manager`]: struct.Manager.html
+    /// [`play_returning`]: #method.play_returning
+    /// [`voice::prebuffer`]: fn.prebuffer.html
+    #[instrument(skip(self, source))]
+    pub async fn play_with_prebuffer(&mut self, source: Box<dyn AudioSource>, prebuffer: Duration) -> TrackHandle {
+        let source = streamer::prebuffer(source, prebuffer).await;
+
+        self.play_returning(source)
+    }
+
+    /// Plays audio from a source, returning a handle to the track.
+    ///
+    /// Unlike [`play`] or [`play_returning`], this stops all other sources attached
+    /// to the channel.
+    ///
+    /// [`play`]: #method.play
+    /// [`play_returning`]: #method.play_returning
+    #[instrument(skip(self, source))]
+    pub fn play_only(&mut self, source: Box<dyn AudioSource>) -> TrackHandle {
+        let track = self.new_track(Audio::new(source));
+        self.send(VoiceStatus::SetSender(Some(track.audio().clone())));
+
+        track
+    }
+
+    /// Plays audio from a source as with [`play_only`], unless the background
+    /// task is backed up beyond the limit set via [`set_task_queue_limit`].
+    ///
+    /// # Errors
+    ///
+    /// Returns [`VoiceError::Busy`] if the limit has been reached, in which
+    /// case the source is dropped, and other sources are left playing.
+    ///
+    /// [`play_only`]: #method.play_only
+    /// [`set_task_queue_limit`]: #method.set_task_queue_limit
+    /// [`VoiceError::Busy`]: enum.VoiceError.html#variant.Busy
+    #[instrument(skip(self, source))]
+    pub fn try_play_only(&mut self, source: Box<dyn AudioSource>) -> Result<TrackHandle, VoiceError> {
+        self.check_task_queue()?;
+
+        Ok(self.play_only(source))
+    }
+
+    /// Adds a source to the end of this handler's [`TrackQueue`], returning a
+    /// handle to its track.
+    ///
+    /// This is a shortcut for calling [`TrackQueue::add`] on the result of
+    /// [`queue`], and fails in the same cases.
+    ///
+    /// [`TrackQueue`]: struct.TrackQueue.html
+    /// [`TrackQueue::add`]: struct.TrackQueue.html#method.add
+    /// [`queue`]: #method.queue
+    #[instrument(skip(self, source))]
+    pub fn enqueue(
+        &mut self,
+        source: Box<dyn AudioSource>,
+        metadata: TrackMetadata,
+    ) -> Result<TrackHandle, VoiceError> {
+        self.queue().add(source, metadata)
+    }
+
+    /// Returns the queue of tracks played one after another by this handler.
+    pub fn queue(&self) -> &TrackQueue {
+        &self.shared.queue
+    }
+
+    /// Returns a future which resolves once the [`queue`] is empty, e.g. to
+    /// leave after playing a few clips queued via [`enqueue`] or
+    /// [`Manager::join_and_play`]. It resolves at once if the queue is
+    /// already empty.
+    ///
+    /// As well as the last track finishing, stopping it or clearing the
+    /// queue, such as via [`stop_and_clear`], also resolves this. Unlike
+    /// [`HandlerEvent::QueueEmpty`], this does not depend on
+    /// [`TrackQueue::set_empty_on_stop`].
+    ///
+    /// The future does not borrow the handler, so the manager's lock can be
+    /// released while it is awaited.
+    ///
+    /// [`enqueue`]: #method.enqueue
+    /// [`HandlerEvent::QueueEmpty`]: enum.HandlerEvent.html#variant.QueueEmpty
+    /// [`Manager::join_and_play`]: struct.Manager.html#method.join_and_play
+    /// [`queue`]: #method.queue
+    /// [`stop_and_clear`]: #method.stop_and_clear
+    /// [`TrackQueue::set_empty_on_stop`]: struct.TrackQueue.html#method.set_empty_on_stop
+    pub fn wait_for_queue_empty(&self) -> impl Future<Output = ()> + Send + 'static {
+        self.queue().wait_until_empty()
+    }
+
+    /// Reads the metadata, position, length, volume, loop region and paused
+    /// state of the current track of the [`queue`] in one go.
+    ///
+    /// Refer to [`TrackQueue::now_playing`] for details.
+    ///
+    /// [`queue`]: #method.queue
+    /// [`TrackQueue::now_playing`]: struct.TrackQueue.html#method.now_playing
+    pub async fn now_playing(&self) -> Option<NowPlaying> {
+        self.queue().now_playing().await
+    }
+
+    /// Sets a function called with the current track of the [`queue`]
+    /// whenever it changes, such as to show it in the bot's presence: when a
+    /// track starts, and with `None` once the queue is emptied or stops
+    /// advancing. Skipping a track calls it once, with the next track.
+    ///
+    /// It is called from the voice task, so should not block, and is first
+    /// called once set, with whatever is playing then. Tracks played outside
+    /// of the queue do not call it.
+    ///
+    /// [`queue`]: #method.queue
+    #[instrument(skip(self, hook))]
+    #[allow(clippy::type_complexity)]
+    pub fn set_presence_hook(&mut self, hook: Box<dyn Fn(Option<&NowPlaying>) + Send + Sync>) {
+        self.queue().set_presence_hook(Some(Arc::from(hook)));
+    }
+
+    /// Removes any function set via [`set_presence_hook`].
+    ///
+    /// [`set_presence_hook`]: #method.set_presence_hook
+    #[instrument(skip(self))]
+    pub fn clear_presence_hook(&mut self) {
+        self.queue().set_presence_hook(None);
+    }
+
+    /// Plays audio from a source, crossfading from all other sources attached
+    /// to the channel.
+    ///
+    /// Like [`play_only`], this replaces all other sources. Rather than cutting
+    /// them off, they fade out while the new source fades in, both over
+    /// `duration`, so that there is never a silent gap. The whole transition
+    /// takes `duration`, after which the old sources are stopped.
+    ///
+    /// [`play_only`]: #method.play_only
+    #[instrument(skip(self, source))]
+    pub fn play_only_fade(&mut self, source: Box<dyn AudioSource>, duration: Duration) -> TrackHandle {
+        let track = self.new_track(Audio::new(source));
+        self.send(VoiceStatus::FadeToSender(track.audio().clone(), duration));
+
+        track
+    }
+
+    /// Sets the local IP address which the voice UDP socket is bound to, e.g.
+    /// to select a network interface on a multi-homed host.
+    ///
+    /// This also determines the address reported to Discord during IP
+    /// discovery. Pass `None` to bind to all interfaces, which is the default.
+    ///
+    /// This takes effect on the next connection. The address must be of the
+    /// same family as the voice server's, otherwise connecting fails with
+    /// [`VoiceError::BindAddressFamily`].
+    ///
+    /// [`VoiceError::BindAddressFamily`]: enum.VoiceError.html#variant.BindAddressFamily
+    #[instrument(skip(self))]
+    pub fn set_bind_address(&mut self, address: Option<IpAddr>) {
+        self.bind_address = address;
+    }
+
+    /// Sets a function which resolves the hostname of each voice server to
+    /// the address its WebSocket is connected to, in place of DNS, e.g. to
+    /// reach it through a proxy in a split-horizon network.
+    ///
+    /// The TLS certificate is still checked against the hostname, and the
+    /// UDP socket uses the address sent by the voice server, as before.
+    ///
+    /// This takes effect on the next connection or resume. If the address
+    /// returned has an unspecified or multicast IP, or a port of 0,
+    /// connecting fails with [`VoiceError::InvalidEndpointAddress`].
+    ///
+    /// [`VoiceError::InvalidEndpointAddress`]: enum.VoiceError.html#variant.InvalidEndpointAddress
+    #[instrument(skip(self, resolver))]
+    pub fn set_endpoint_resolver(&mut self, resolver: Box<dyn Fn(&str) -> SocketAddr + Send + Sync>) {
+        self.endpoint_resolver = Some(Arc::from(resolver));
+    }
+
+    /// Removes any resolver set via [`set_endpoint_resolver`], so that voice
+    /// servers are found via DNS again, which is the default.
+    ///
+    /// [`set_endpoint_resolver`]: #method.set_endpoint_resolver
+    #[instrument(skip(self))]
+    pub fn clear_endpoint_resolver(&mut self) {
+        self.endpoint_resolver = None;
+    }
+
+    /// Seeds the initial RTP sequence number and timestamp of sent audio,
+    /// which are otherwise random, so that the packets of a connection can be
+    /// reproduced, e.g. when comparing recorded packets in tests.
+    ///
+    /// This takes effect on the next connection. The SSRC is assigned by the
+    /// voice server, and is not affected.
+    ///
+    /// **Note**: Do not seed live connections without good reason. Packet
+    /// streams which reuse the sequence numbers, timestamps or SSRCs of
+    /// another are easily mistaken for it, and predictable values on a live
+    /// connection make forging its packets easier.
+    #[instrument(skip(self))]
+    pub fn set_rtp_seed(&mut self, seed: u64) {
+        self.rtp_seed = Some(seed);
+    }
+
+    /// Limits how many messages, such as sources to play or changes to
+    /// settings, may wait for the handler's background task before
+    /// [`try_play`] and [`try_play_only`] fail with [`VoiceError::Busy`].
+    /// Passing `None` removes the limit, which is the default.
+    ///
+    /// Messages are otherwise queued without bound, so a producer which adds
+    /// sources faster than the task takes them, such as while its runtime is
+    /// starved, can use unbounded memory. Applications which add sources in
+    /// response to untrusted input should set a limit and use the `try_`
+    /// methods.
+    ///
+    /// All other methods queue their messages regardless of the limit, so
+    /// that controls such as [`stop_and_clear`], which relieve a backed up
+    /// task, are never rejected.
+    ///
+    /// [`stop_and_clear`]: #method.stop_and_clear
+    /// [`try_play`]: #method.try_play
+    /// [`try_play_only`]: #method.try_play_only
+    /// [`VoiceError::Busy`]: enum.VoiceError.html#variant.Busy
+    #[instrument(skip(self))]
+    pub fn set_task_queue_limit(&mut self, limit: Option<usize>) {
+        self.task_queue_limit = limit;
+    }
+
+    /// Sets whether the handler records what it would ask of its voice task
+    /// instead of asking, so that code driving it can be checked without
+    /// connecting or playing anything. **This is for testing only.**
+    ///
+    /// While on, every message the handler would send to the task, such as
+    /// to connect or to play a source, is kept in a log which can be taken
+    /// via [`take_dry_run_log`]. Turning it off discards the log, and clones
+    /// of the handler share the setting as it was when cloned.
+    ///
+    /// Only the task is held back: voice state updates are still sent to
+    /// the gateway, so this is best used on a [`standalone`] handler, and
+    /// [`TrackHandle`]s and [`ReceiverHandle`]s still reach the task.
+    ///
+    /// This requires the `voice_mock` feature.
+    ///
+    /// [`ReceiverHandle`]: struct.ReceiverHandle.html
+    /// [`TrackHandle`]: struct.TrackHandle.html
+    /// [`standalone`]: #method.standalone
+    /// [`take_dry_run_log`]: #method.take_dry_run_log
+    #[cfg(any(test, feature = "voice_mock"))]
+    pub fn set_dry_run(&mut self, enabled: bool) {
+        self.dry_run = if enabled {
+            Some(self.dry_run.take().unwrap_or_default())
+        } else {
+            None
+        };
+    }
+
+    /// Takes the messages recorded by a dry run since it was started or
+    /// since the last call, which is empty if no dry run is in progress.
+    ///
+    /// Refer to [`set_dry_run`] for more information.
+    ///
+    /// This requires the `voice_mock` feature.
+    ///
+    /// [`set_dry_run`]: #method.set_dry_run
+    #[cfg(any(test, feature = "voice_mock"))]
+    pub fn take_dry_run_log(&self) -> Vec<TaskMessage> {
+        match self.dry_run.as_ref() {
+            Some(log) => log.lock()
+                .expect("[Voice] Dry run log poisoned")
+                .drain(..)
+                .map(TaskMessage::from)
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Sets the bitrate for encoding Opus packets sent along
+    /// the channel being managed.
+    ///
+    /// The default rate is 128 kbps. This applies to a live connection from
+    /// the next packet, without interrupting playback, and is kept across
+    /// reconnects.
+    ///
+    /// Values are clamped to the range supported by Opus, between
+    /// `BitsPerSecond(500)` and `BitsPerSecond(512_000)`.
+    /// Alternatively, `Auto` and `Max` remain available.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`VoiceError::InvalidBitrate`] if the bitrate is not positive.
+    ///
+    /// [`VoiceError::InvalidBitrate`]: enum.VoiceError.html#variant.InvalidBitrate
+    #[instrument(skip(self))]
+    pub fn set_bitrate(&mut self, bitrate: Bitrate) -> Result<(), VoiceError> {
+        let bitrate = audio::clamp_bitrate(bitrate)?;
+
+        self.shared.update_opus_config(|opus| opus.bitrate = bitrate);
+        self.send(VoiceStatus::SetBitrate(bitrate));
+
+        Ok(())
+    }
+
+    /// Sets every setting of the Opus encoder for sent audio at once,
+    /// replacing any bitrate set via [`set_bitrate`].
+    ///
+    /// As with the bitrate, this applies to a live connection from the next
+    /// packet, and is kept across reconnects.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`VoiceError::InvalidComplexity`] if the complexity is above
+    /// 10, or [`VoiceError::InvalidBitrate`] as with [`set_bitrate`]. In
+    /// either case, no setting is changed.
+    ///
+    /// [`set_bitrate`]: #method.set_bitrate
+    /// [`VoiceError::InvalidBitrate`]: enum.VoiceError.html#variant.InvalidBitrate
+    /// [`VoiceError::InvalidComplexity`]: enum.VoiceError.html#variant.InvalidComplexity
+    #[instrument(skip(self))]
+    pub fn set_opus_config(&mut self, config: OpusConfig) -> Result<(), VoiceError> {
+        let config = config.validate()?;
+
+        self.shared.update_opus_config(|opus| *opus = config.clone());
+        self.send(VoiceStatus::SetOpusConfig(config));
+
+        Ok(())
+    }
+
+    /// Sets every setting of the Opus encoder for sent audio at once as with
+    /// [`set_opus_config`], but by rebuilding the encoder with them all
+    /// applied, in a single step between two packets.
+    ///
+    /// Where [`set_opus_config`] and the individual setters change the live
+    /// encoder one setting at a time, this swaps it for a new one only once
+    /// every setting has been applied, so that either all change together
+    /// or, if one cannot be applied, none do. As the new encoder starts
+    /// afresh, prefer the others when changing a single setting. This is
+    /// kept across reconnects.
+    ///
+    /// # Errors
+    ///
+    /// Returns the same errors as [`set_opus_config`], in which case no
+    /// setting is changed. Should the encoder still fail to be rebuilt, the
+    /// old one is kept, and [`opus_settings`] reports its settings again
+    /// once the background task has handled the change.
+    ///
+    /// [`opus_settings`]: #method.opus_settings
+    /// [`set_opus_config`]: #method.set_opus_config
+    #[instrument(skip(self))]
+    pub fn reconfigure_encoder(&mut self, config: OpusConfig) -> Result<(), VoiceError> {
+        let config = config.validate()?;
+
+        self.shared.update_opus_config(|opus| *opus = config.clone());
+        self.send(VoiceStatus::ReconfigureEncoder(config));
+
+        Ok(())
+    }
+
+    /// Sets the computational complexity of the Opus encoder, from 0 to 10,
+    /// where higher values give better quality at more CPU cost, leaving
+    /// its other settings as they are. Defaults to 9.
+    ///
+    /// This is equivalent to setting [`OpusConfig::complexity`] via
+    /// [`set_opus_config`], applies to a live connection from the next
+    /// packet, and is kept across reconnects. With
+    /// [`set_adaptive_complexity`], this is the highest complexity used.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`VoiceError::InvalidComplexity`] if the complexity is above
+    /// 10.
+    ///
+    /// [`OpusConfig::complexity`]: struct.OpusConfig.html#method.complexity
+    /// [`set_adaptive_complexity`]: #method.set_adaptive_complexity
+    /// [`set_opus_config`]: #method.set_opus_config
+    /// [`VoiceError::InvalidComplexity`]: enum.VoiceError.html#variant.InvalidComplexity
+    #[instrument(skip(self))]
+    pub fn set_opus_complexity(&mut self, complexity: u8) -> Result<(), VoiceError> {
+        if complexity > MAX_COMPLEXITY {
+            return Err(VoiceError::InvalidComplexity(complexity));
+        }
+
+        self.shared.update_opus_config(|opus| opus.complexity = complexity);
+        self.send(VoiceStatus::SetOpusComplexity(complexity));
+
+        Ok(())
+    }
+
+    /// Returns the settings of the Opus encoder. While connected, these are
+    /// read back from the live encoder, and so show any adaptive changes to
+    /// the bitrate or complexity. Otherwise, they are the settings requested
+    /// for the next connection.
+    ///
+    /// [`OpusSettings::live`] tells the two apart.
+    ///
+    /// [`OpusSettings::live`]: struct.OpusSettings.html#structfield.live
+    pub fn opus_settings(&self) -> OpusSettings {
+        match self.shared.session_info() {
+            Some(info) => OpusSettings::from(&info),
+            None => OpusSettings::from(&self.shared.opus_config()),
+        }
+    }
+
+    /// Sets whether discontinuous transmission is used, so that frames of
+    /// silence or background noise within playing audio are not sent at all,
+    /// saving bandwidth. Off by default.
+    ///
+    /// The handler stays marked as speaking while frames are suppressed, and
+    /// the RTP timestamp advances over them, so listeners neither see the bot
+    /// stop speaking nor lose timing. Suppressed frames are counted in
+    /// [`NetworkStats::packets_suppressed`].
+    ///
+    /// This is equivalent to setting [`OpusConfig::dtx`] via
+    /// [`set_opus_config`], applies to a live connection from the next
+    /// packet, and is kept across reconnects.
+    ///
+    /// [`NetworkStats::packets_suppressed`]: struct.NetworkStats.html#structfield.packets_suppressed
+    /// [`OpusConfig::dtx`]: struct.OpusConfig.html#method.dtx
+    /// [`set_opus_config`]: #method.set_opus_config
+    #[instrument(skip(self))]
+    pub fn set_dtx(&mut self, dtx: bool) {
+        self.shared.update_opus_config(|opus| opus.dtx = dtx);
+        self.send(VoiceStatus::SetDtx(dtx));
+    }
+
+    /// Sets whether the Opus complexity is lowered automatically while
+    /// frames take too long to encode, such as on a host with many handlers
+    /// competing for CPU time. Off by default.
+    ///
+    /// While enabled, the average encode time reported in
+    /// [`NetworkStats::encode_time`] is checked each second. Above 5ms, a
+    /// quarter of each frame's budget, the complexity is lowered one step at a
+    /// time, as far as 0. Below 2ms, it is raised one step at a time, up to
+    /// the complexity set via [`set_opus_config`], which is never exceeded.
+    /// Disabling this restores the configured complexity. The complexity in
+    /// use is reported by [`connection_info`].
+    ///
+    /// This is kept across reconnects.
+    ///
+    /// [`connection_info`]: #method.connection_info
+    /// [`NetworkStats::encode_time`]: struct.NetworkStats.html#structfield.encode_time
+    /// [`set_opus_config`]: #method.set_opus_config
+    #[instrument(skip(self))]
+    pub fn set_adaptive_complexity(&mut self, adaptive: bool) {
+        self.send(VoiceStatus::SetAdaptiveComplexity(adaptive));
+    }
+
+    /// Sets a ladder of bitrates which sent audio steps through as network
+    /// conditions change, from the first step as the highest quality to the
+    /// last as the most robust. An empty ladder, the default, disables this
+    /// and restores the bitrate set via [`set_bitrate`].
+    ///
+    /// Discord does not report how much sent audio is lost, so loss of the
+    /// audio received from other users in the channel, as reported in
+    /// [`NetworkStats::packets_lost`], is used as a measure of the network.
+    ///
+    /// Loss is judged over windows of 5 seconds. To avoid oscillating between
+    /// steps, the ladder moves down a step after a single window with 5% or
+    /// more loss, but only moves up a step after 3 windows in a row with
+    /// under 1% loss. Windows with fewer than 50 packets, such as while
+    /// nobody else is speaking, are not judged, and neither count towards
+    /// nor interrupt a run of clean windows.
+    ///
+    /// Playback starts from the first step, and the step in use is reported
+    /// by [`connection_info`]. This is kept across reconnects, starting from
+    /// the first step again on each.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`VoiceError::InvalidBitrate`] if any step is not positive,
+    /// as with [`set_bitrate`], in which case nothing is changed. Steps are
+    /// otherwise clamped in the same way.
+    ///
+    /// [`connection_info`]: #method.connection_info
+    /// [`NetworkStats::packets_lost`]: struct.NetworkStats.html#structfield.packets_lost
+    /// [`set_bitrate`]: #method.set_bitrate
+    /// [`VoiceError::InvalidBitrate`]: enum.VoiceError.html#variant.InvalidBitrate
+    #[instrument(skip(self))]
+    pub fn set_adaptive_bitrate(&mut self, steps: Vec<Bitrate>) -> Result<(), VoiceError> {
+        let steps = steps
+            .into_iter()
+            .map(audio::clamp_bitrate)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        self.send(VoiceStatus::SetAdaptiveBitrate(steps));
+
+        Ok(())
+    }
+
+    /// Sets whether sent audio falls back to mono while the bitrate in use is
+    /// below 48kbps, where stereo would spend too few bits on each channel,
+    /// returning to stereo once the bitrate is raised again. Defaults to
+    /// `false`.
+    ///
+    /// This follows the bitrate chosen by [`set_adaptive_bitrate`] as packet
+    /// loss rises and falls, as well as any set via [`set_bitrate`] or
+    /// requested by tracks. Audio is still mixed in stereo, and only
+    /// downmixed when encoded, so any [output tee] is unaffected. Each switch
+    /// emits a [`HandlerEvent::ChannelsChanged`] event.
+    ///
+    /// This is kept across reconnects.
+    ///
+    /// [`HandlerEvent::ChannelsChanged`]: enum.HandlerEvent.html#variant.ChannelsChanged
+    /// [`set_adaptive_bitrate`]: #method.set_adaptive_bitrate
+    /// [`set_bitrate`]: #method.set_bitrate
+    /// [output tee]: #method.set_output_tee
+    #[instrument(skip(self))]
+    pub fn set_adaptive_channels(&mut self, adaptive: bool) {
+        self.send(VoiceStatus::SetAdaptiveChannels(adaptive));
+    }
+
+    /// Sets how long joining a channel may take before it is given up on.
+    ///
+    /// This bounds both the wait for Discord's replies to a join, within
+    /// which the futures returned by [`move_to`] and the [`Manager`]'s
+    /// `join_and_play` fail with [`VoiceError::Timeout`], and the first
+    /// handshake with the voice server, after which the voice task abandons
+    /// the connection and reports [`ConnectionState::Disconnected`], rather
+    /// than staying [`ConnectionState::Connecting`] on a bad endpoint.
+    ///
+    /// Reconnecting to a voice server after a connection drops is not
+    /// bounded by this. The default is 10 seconds.
+    ///
+    /// [`ConnectionState::Connecting`]: enum.ConnectionState.html#variant.Connecting
+    /// [`ConnectionState::Disconnected`]: enum.ConnectionState.html#variant.Disconnected
+    /// [`Manager`]: struct.Manager.html
+    /// [`move_to`]: #method.move_to
+    /// [`VoiceError::Timeout`]: enum.VoiceError.html#variant.Timeout
+    #[instrument(skip(self))]
+    pub fn set_connect_timeout(&mut self, timeout: Duration) {
+        self.connect_timeout = timeout;
+        self.send(VoiceStatus::SetConnectTimeout(timeout))
+    }
+
+    /// Sets how many voice heartbeats in a row may go unacknowledged before
+    /// the connection is considered dead, and is resumed or re-established
+    /// without waiting for a send to fail.
+    ///
+    /// The default is 3. Passing `None` disables the check, leaving only
+    /// socket errors to trigger a reconnect. A threshold of `Some(0)` is
+    /// treated as `Some(1)`.
+    #[instrument(skip(self))]
+    pub fn set_heartbeat_timeout(&mut self, missed: Option<u32>) {
+        self.send(VoiceStatus::SetHeartbeatTimeout(missed))
+    }
+
+    /// Sets a limiter which turns the final mix of sent audio down while its
+    /// peaks exceed a threshold, replacing any existing limiter. Passing
+    /// `None`, the default, removes it.
+    ///
+    /// Either way, the mix is soft clipped, so sources which together exceed
+    /// full scale are rounded off rather than distorting harshly. A limiter
+    /// additionally compresses loud mixes, such as of many sources or at high
+    /// volumes, towards its threshold. This is kept across reconnects.
+    ///
+    /// Refer to [`LimiterConfig`] for the available settings.
+    ///
+    /// [`LimiterConfig`]: struct.LimiterConfig.html
+    #[instrument(skip(self))]
+    pub fn set_limiter(&mut self, config: Option<LimiterConfig>) {
+        self.send(VoiceStatus::SetLimiter(config))
+    }
+
+    /// Sets how long the handler may have nothing to play before its voice
+    /// task goes dormant, or `None`, the default, to never do so.
+    ///
+    /// While dormant, the task stops mixing and sending audio, including the
+    /// silence frames which mark the end of speech, but keeps the voice
+    /// connection, its heartbeats, and any receiving alive. Playback resumes
+    /// on the next frame after a source is added, such as via [`play`] or
+    /// the [`queue`]. Sources which are paused still count as playing.
+    ///
+    /// Unlike [`leave`], this does not leave the channel, so the bot remains
+    /// visibly connected. This is kept across reconnects.
+    ///
+    /// [`leave`]: #method.leave
+    /// [`play`]: #method.play
+    /// [`queue`]: #method.queue
+    #[instrument(skip(self))]
+    pub fn set_playback_idle_timeout(&mut self, timeout: Option<Duration>) {
+        self.send(VoiceStatus::SetPlaybackIdleTimeout(timeout))
+    }
+
+    /// Sets the payload type of the RTP packets sent, for voice servers
+    /// which expect something other than Discord's. Defaults to Discord's,
+    /// `120`, and is kept across reconnects.
+    ///
+    /// This is a low-level setting for testing against compatible servers,
+    /// and Discord itself will not play audio of any other type.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`VoiceError::InvalidPayloadType`] if the payload type is
+    /// above 127.
+    ///
+    /// [`VoiceError::InvalidPayloadType`]: enum.VoiceError.html#variant.InvalidPayloadType
+    #[instrument(skip(self))]
+    pub fn set_rtp_payload_type(&mut self, payload_type: u8) -> Result<(), VoiceError> {
+        if payload_type > 0x7F {
+            return Err(VoiceError::InvalidPayloadType(payload_type));
+        }
+
+        self.send(VoiceStatus::SetRtpPayloadType(payload_type));
+
+        Ok(())
+    }
+
+    /// Sets whether the RTP packets sent carry an empty one-byte header
+    /// extension, for voice servers which expect one. Off by default, as
+    /// Discord does not need it, and kept across reconnects.
+    ///
+    /// As with the extensions in packets Discord sends, it is encrypted
+    /// along with the audio.
+    #[instrument(skip(self))]
+    pub fn set_rtp_header_extension(&mut self, enabled: bool) {
+        self.send(VoiceStatus::SetRtpHeaderExtension(enabled));
+    }
+
+    /// Sets how voice packets are encrypted, defaulting to
+    /// [`EncryptionMode::Normal`]. The mode is kept across reconnects.
+    ///
+    /// As the mode is agreed with Discord when connecting, a change only
+    /// takes effect from the next connection, and a voice server which does
+    /// not offer the mode fails that connection with
+    /// [`VoiceError::VoiceModeUnavailable`].
+    ///
+    /// [`EncryptionMode::Normal`]: enum.EncryptionMode.html#variant.Normal
+    /// [`VoiceError::VoiceModeUnavailable`]: enum.VoiceError.html#variant.VoiceModeUnavailable
+    #[instrument(skip(self))]
+    pub fn set_encryption_mode(&mut self, mode: EncryptionMode) {
+        self.send(VoiceStatus::SetEncryptionMode(mode));
+    }
+
+    /// Sets a custom implementation of the [encryption mode] to encrypt and
+    /// decrypt voice packets with, or `None`, the default, to use the one
+    /// built in. This is kept across reconnects.
+    ///
+    /// The factory is called with the secret key Discord gives once each
+    /// connection is made, so a change only takes effect from the next
+    /// connection. A backend whose [`mode`] is not the one agreed fails
+    /// that connection with [`VoiceError::VoiceModeInvalid`].
+    ///
+    /// [encryption mode]: #method.set_encryption_mode
+    /// [`mode`]: trait.EncryptionBackend.html#tymethod.mode
+    /// [`VoiceError::VoiceModeInvalid`]: enum.VoiceError.html#variant.VoiceModeInvalid
+    #[instrument(skip(self, backend))]
+    pub fn set_encryption_backend(&mut self, backend: Option<EncryptionBackendFactory>) {
+        self.send(VoiceStatus::SetEncryptionBackend(backend));
+    }
+
+    /// Sets the code the voice websocket is closed with when leaving, which
+    /// is kept across reconnects. The default of 1000, a normal closure,
+    /// tells Discord that the session is over, so that it stops showing the
+    /// user as connected at once.
+    ///
+    /// Only codes which a client may send are accepted: 1000 to 1003, 1007
+    /// to 1011, and 3000 to 4999.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`VoiceError::InvalidCloseCode`] for any other code.
+    ///
+    /// [`VoiceError::InvalidCloseCode`]: enum.VoiceError.html#variant.InvalidCloseCode
+    #[instrument(skip(self))]
+    pub fn set_close_code(&mut self, code: u16) -> Result<(), VoiceError> {
+        if !matches!(code, 1000..=1003 | 1007..=1011 | 3000..=4999) {
+            return Err(VoiceError::InvalidCloseCode(code));
+        }
+
+        self.send(VoiceStatus::SetCloseCode(code));
+
+        Ok(())
+    }
+
+    /// Sets whether Discord is told when the handler starts and stops
+    /// transmitting, which shows it as speaking in the channel. This is
+    /// enabled by default, and kept across reconnects.
+    ///
+    /// While disabled, audio is still sent as normal, but the speaking flag
+    /// is never raised, and is cleared at once if it was.
+    ///
+    /// **Note**: Discord may decline to relay audio from a client which has
+    /// not flagged itself as speaking, so whether others hear anything while
+    /// this is disabled is subject to Discord's behaviour.
+    #[instrument(skip(self))]
+    pub fn set_speaking_indicator(&mut self, enabled: bool) {
+        self.send(VoiceStatus::SetSpeakingIndicator(enabled))
+    }
+
+    /// Sets the time over which the mixer ramps each change in a source's
+    /// gain, whether from its [`volume`], a fade, or changes to either, so
+    /// that they do not click.
+    ///
+    /// The default is 15ms. Passing a zero duration applies changes at once,
+    /// at the start of the next frame. This is kept across reconnects.
+    ///
+    /// [`volume`]: struct.Audio.html#structfield.volume
+    #[instrument(skip(self))]
+    pub fn set_volume_ramp(&mut self, ramp: Duration) {
+        self.send(VoiceStatus::SetVolumeRamp(ramp))
+    }
+
+    /// Sets how the frames sent are paced, such as to compare the jitter of
+    /// each mode. The default is [`PacingMode::Timer`], while
+    /// [`PacingMode::Precise`] sends them more evenly spaced at the cost of
+    /// some CPU time. This is kept across reconnects.
+    ///
+    /// How late the send loop runs is reported in [`NetworkStats::drift`].
+    ///
+    /// [`NetworkStats::drift`]: struct.NetworkStats.html#structfield.drift
+    /// [`PacingMode::Precise`]: enum.PacingMode.html#variant.Precise
+    /// [`PacingMode::Timer`]: enum.PacingMode.html#variant.Timer
+    #[instrument(skip(self))]
+    pub fn set_pacing_mode(&mut self, mode: PacingMode) {
+        self.send(VoiceStatus::SetPacingMode(mode))
+    }
+
+    /// Sets whether a source which panics while being read, such as a buggy
+    /// custom [`AudioSource`], is ended with [`EndReason::Failed`] and
+    /// logged, rather than taking down the handler's audio task.
+    ///
+    /// Other sources carry on playing. The default is `false`. This is kept
+    /// across reconnects.
+    ///
+    /// [`AudioSource`]: trait.AudioSource.html
+    /// [`EndReason::Failed`]: enum.EndReason.html#variant.Failed
+    #[instrument(skip(self))]
+    pub fn set_catch_source_panics(&mut self, catch: bool) {
+        self.send(VoiceStatus::SetCatchSourcePanics(catch))
+    }
+
+    /// Sets the volume of every source on a bus, such as to turn music down
+    /// under sound effects without changing each track. Buses default to
+    /// 1.0, which leaves their sources unchanged, and negative volumes are
+    /// treated as 0.0.
+    ///
+    /// The volume applies on top of each source's own, and changes to it are
+    /// ramped as those are. It is also kept for sources added to the bus
+    /// later, and across reconnects.
+    ///
+    /// Sources are assigned to buses via [`TrackHandle::set_bus`].
+    ///
+    /// [`TrackHandle::set_bus`]: struct.TrackHandle.html#method.set_bus
+    #[instrument(skip(self))]
+    pub fn set_bus_volume(&mut self, bus: &str, volume: f32) {
+        self.send(VoiceStatus::SetBusVolume(bus.to_string(), volume.max(0.0)))
+    }
+
+    /// Stops every source playing on a bus, ending them with
+    /// [`EndReason::Stopped`], while sources on other buses, or on none, carry
+    /// on. Queued tracks which have yet to start are left in the queue.
+    ///
+    /// [`EndReason::Stopped`]: enum.EndReason.html#variant.Stopped
+    #[instrument(skip(self))]
+    pub fn stop_bus(&mut self, bus: &str) {
+        self.send(VoiceStatus::StopBus(bus.to_string()))
+    }
+
+    /// Sets a sink which receives a copy of all audio sent over the voice
+    /// connection, replacing any existing sink. Passing `None` stops copying.
+    ///
+    /// The final mix is written as 48kHz, 16-bit little-endian stereo PCM,
+    /// with a frame for every packet sent. Frames passed through from Opus
+    /// sources are decoded to be written. Silence frames, including those
+    /// sent while muted, are written as zeroes. Wrap the sink in a
+    /// [`WavWriter`] to produce a playable file.
+    ///
+    /// Writes take place on a separate thread, so a slow sink does not delay
+    /// sending. If the sink returns an error, it is dropped.
+    ///
+    /// [`WavWriter`]: struct.WavWriter.html
+    #[instrument(skip(self, sink))]
+    pub fn set_output_tee<W: Write + Send + 'static>(&mut self, sink: Option<W>) {
+        let tee = sink.map(|sink| OutputTee::new(Box::new(sink)));
+
+        self.send(VoiceStatus::SetOutputTee(tee))
+    }
+
+    /// Sets a function to be called with the header fields and payload length
+    /// of each voice packet, just before it is sent, replacing any existing
+    /// observer. Passing `None` removes the observer.
+    ///
+    /// This is intended for debugging, such as checking the pacing and
+    /// sequence continuity of sent audio. The packet cannot be changed.
+    ///
+    /// The observer is called from the voice task's send loop, so it must
+    /// return quickly; hand anything slow, such as logging to a file, off to
+    /// another thread or task.
+    #[instrument(skip(self, observer))]
+    pub fn set_packet_observer(&mut self, observer: Option<PacketObserver>) {
+        self.send(VoiceStatus::SetPacketObserver(observer))
+    }
+
+    /// Stops playing audio from a source, if one is set.
+    #[instrument(skip(self))]
+    pub fn stop(&mut self) {
+        self.send(VoiceStatus::SetSender(None))
+    }
+
+    /// Stops all playing audio and empties the [`queue`], so that no further
+    /// tracks are started.
+    ///
+    /// Unlike [`stop`], this also stops sources added via [`play`] alongside
+    /// the queue. Every affected track, including those which had yet to
+    /// start, ends with [`EndReason::Cleared`].
+    ///
+    /// [`EndReason::Cleared`]: enum.EndReason.html#variant.Cleared
+    /// [`play`]: #method.play
+    /// [`queue`]: #method.queue
+    /// [`stop`]: #method.stop
+    #[instrument(skip(self))]
+    pub fn stop_and_clear(&mut self) {
+        self.send(VoiceStatus::StopAndClear)
+    }
+
+    /// Switches the current connected voice channel to the given `channel_id`.
+    ///
+    /// This has 3 separate behaviors:
+    ///
+    /// - if the given `channel_id` is equivalent to the current connected
+    ///   `channel_id`, then do nothing;
+    /// - if the given `channel_id` is _not_ equivalent to the current connected
+    ///   `channel_id`, then switch to the given `channel_id`;
+    /// - if not currently connected to a voice channel, connect to the given
+    ///   one.
+    ///
+    /// If you are dealing with switching from one group to another, then open
+    /// another handler, and optionally drop this one via [`Manager::remove`].
+    ///
+    /// **Note**: The given `channel_id`, if in a guild, _must_ be in the
+    /// current handler's associated guild. This is _not_ checked; use
+    /// [`switch_to_channel`] to have it checked.
     ///
     /// **Note**: If the `Handler` was created via [`standalone`], then this
     /// will _only_ update whether the connection is internally switched to a
@@ -330,108 +2020,742 @@ impl Handler {
     ///
     /// [`Manager::remove`]: struct.Manager.html#method.remove
     /// [`standalone`]: #method.standalone
+    /// [`switch_to_channel`]: #method.switch_to_channel
     #[instrument(skip(self))]
     pub fn switch_to(&mut self, channel_id: ChannelId) {
+        let _span = self.span().entered();
+
         match self.channel_id {
             Some(current_id) if current_id == channel_id => {
                 // If already connected to the given channel, do nothing.
                 return;
             },
             _ => {
-                self.channel_id = Some(channel_id);
+                self.set_channel_id(Some(channel_id));
 
-                self.update();
+                self.update_coalesced();
             },
         }
     }
 
+    /// Sets how long after a channel switch further calls to [`join`] or
+    /// [`switch_to`] are coalesced, defaulting to 250ms.
+    ///
+    /// The first switch is sent to the gateway immediately. Any made within
+    /// the interval after it are held back, and only the latest is sent once
+    /// the interval ends, so that bursts of switches neither race nor hit
+    /// gateway rate limits. Other updates, such as [`deafen`] or [`leave`],
+    /// are always sent immediately. A zero interval disables coalescing.
+    ///
+    /// This applies to all clones of the handler.
+    ///
+    /// [`deafen`]: #method.deafen
+    /// [`join`]: #method.join
+    /// [`leave`]: #method.leave
+    /// [`switch_to`]: #method.switch_to
+    pub fn set_switch_debounce(&mut self, interval: Duration) {
+        self.shared.lock_switch().interval = interval;
+    }
+
+    /// Switches to the given channel as with [`switch_to`], after checking
+    /// that it is a voice channel in the handler's guild.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`VoiceError::WrongGuild`] if the channel is in another guild,
+    /// or [`VoiceError::NotVoiceChannel`] if it is not a voice channel. In
+    /// either case, no update is sent.
+    ///
+    /// [`switch_to`]: #method.switch_to
+    /// [`VoiceError::NotVoiceChannel`]: enum.VoiceError.html#variant.NotVoiceChannel
+    /// [`VoiceError::WrongGuild`]: enum.VoiceError.html#variant.WrongGuild
+    #[instrument(skip(self, channel))]
+    pub fn switch_to_channel(&mut self, channel: &GuildChannel) -> Result<(), VoiceError> {
+        if channel.guild_id != self.guild_id {
+            return Err(VoiceError::WrongGuild);
+        }
+
+        if channel.kind != ChannelType::Voice {
+            return Err(VoiceError::NotVoiceChannel);
+        }
+
+        self.switch_to(channel.id);
+
+        Ok(())
+    }
+
+    /// Switches to the given channel as with [`switch_to_channel`], looking
+    /// the channel up in the cache.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`VoiceError::UnknownChannel`] if the channel is not a cached
+    /// guild channel, along with the errors of [`switch_to_channel`].
+    ///
+    /// [`switch_to_channel`]: #method.switch_to_channel
+    /// [`VoiceError::UnknownChannel`]: enum.VoiceError.html#variant.UnknownChannel
+    #[cfg(feature = "cache")]
+    pub async fn switch_to_cached(
+        &mut self,
+        cache: impl AsRef<Cache>,
+        channel_id: ChannelId,
+    ) -> Result<(), VoiceError> {
+        let channel = cache.as_ref()
+            .guild_channel(channel_id)
+            .await
+            .ok_or(VoiceError::UnknownChannel)?;
+
+        self.switch_to_channel(&channel)
+    }
+
+    /// Counts the users other than bots, and the current user, in the
+    /// handler's channel, as held by the cache's voice states, e.g. to pause
+    /// playback while nobody is listening.
+    ///
+    /// As the cache is kept up to date by the gateway's voice state updates,
+    /// this can be called again as users join and leave to stay current.
+    ///
+    /// Returns `None` if the handler is not in a channel, or if the guild or
+    /// any member in the channel is missing from the cache, as bots cannot
+    /// then be told apart.
+    #[cfg(feature = "cache")]
+    pub async fn listener_count(&self, cache: impl AsRef<Cache>) -> Option<usize> {
+        let channel_id = self.channel_id?;
+        let user_id = self.user_id;
+
+        cache.as_ref()
+            .guild_field(self.guild_id, |guild| {
+                guild.voice_states
+                    .values()
+                    .filter(|state| state.channel_id == Some(channel_id) && state.user_id != user_id)
+                    .map(|state| guild.members.get(&state.user_id).map(|member| !member.user.bot))
+                    .try_fold(0, |count, listener| listener.map(|listener| count + usize::from(listener)))
+            })
+            .await
+            .flatten()
+    }
+
     /// Updates the voice server data.
     ///
     /// You should only need to use this if you initialized the `Handler` via
     /// [`standalone`].
     ///
     /// Refer to the documentation for [`connect`] for when this will
-    /// automatically connect to a voice channel.
+    /// automatically connect to a voice channel, and [`set_auto_connect`] to
+    /// prevent it.
+    ///
+    /// Any port on the endpoint, such as the `:80` historically appended by
+    /// Discord, is removed before it is stored.
+    ///
+    /// Discord sends a `None` endpoint when the voice server has gone away,
+    /// such as while moving the guild to another region, in which case the
+    /// voice connection is dropped but the channel is kept. When a different
+    /// endpoint arrives, a [`HandlerEvent::VoiceServerChanged`] is emitted and
+    /// a connection to the new server is made, with playing sources carrying
+    /// on from where they were.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`VoiceError::EndpointUrl`] if the endpoint is not a valid
+    /// hostname, in which case the stored voice server data is not changed.
     ///
     /// [`connect`]: #method.connect
+    /// [`HandlerEvent::VoiceServerChanged`]: enum.HandlerEvent.html#variant.VoiceServerChanged
+    /// [`set_auto_connect`]: #method.set_auto_connect
     /// [`standalone`]: #method.standalone
+    /// [`VoiceError::EndpointUrl`]: enum.VoiceError.html#variant.EndpointUrl
     #[instrument(skip(self, token))]
-    pub fn update_server(&mut self, endpoint: &Option<String>, token: &str) {
+    pub fn update_server(&mut self, endpoint: &Option<String>, token: &str) -> Result<(), VoiceError> {
+        let _span = self.span().entered();
+
+        let endpoint = match endpoint {
+            Some(endpoint) => Some(connection::normalize_endpoint(endpoint)?),
+            None => None,
+        };
+
+        self.clear_invalid_session();
         self.token = Some(token.to_string());
 
-        if let Some(endpoint) = endpoint.clone() {
-            self.endpoint = Some(endpoint);
+        if let Some(endpoint) = endpoint {
+            let old_endpoint = self.endpoint.take().or_else(|| self.lost_endpoint.take());
+            self.lost_endpoint = None;
+            self.endpoint = Some(endpoint.clone());
+
+            match old_endpoint {
+                Some(old_endpoint) if old_endpoint != endpoint => {
+                    info!(
+                        "[Voice] Voice server for guild {} moved from {} to {}.",
+                        self.guild_id,
+                        old_endpoint,
+                        endpoint,
+                    );
 
-            if self.session_id.is_some() {
+                    self.shared.emit(HandlerEvent::VoiceServerChanged {
+                        old_endpoint,
+                        new_endpoint: endpoint,
+                    });
+                },
+                _ => {},
+            }
+
+            if self.auto_connect && self.session_id.is_some() {
                 self.connect();
             }
+        } else if self.channel_id.is_some() {
+            // The server is being reallocated, so the connection to it is
+            // dropped until the next one is announced.
+            self.lost_endpoint = self.endpoint.take().or_else(|| self.lost_endpoint.take());
+            self.send(VoiceStatus::Disconnect);
+            self.shared.set_connection_state(ConnectionState::Disconnected);
         } else {
             self.leave();
         }
+
+        Ok(())
     }
 
     /// Updates the internal voice state of the current user.
     ///
     /// You should only need to use this if you initialized the `Handler` via
-    /// [`standalone`].
+    /// [`standalone`]. The states of other users in the guild are passed to
+    /// any observer set via [`set_foreign_state_observer`], and otherwise
+    /// ignored.
     ///
-    /// refer to the documentation for [`connect`] for when this will
-    /// automatically connect to a voice channel.
+    /// Refer to the documentation for [`connect`] for when this will
+    /// automatically connect to a voice channel, and [`set_auto_connect`] to
+    /// prevent it.
     ///
     /// [`connect`]: #method.connect
+    /// [`set_auto_connect`]: #method.set_auto_connect
+    /// [`set_foreign_state_observer`]: #method.set_foreign_state_observer
     /// [`standalone`]: #method.standalone
-    #[instrument(skip(self))]
+    #[instrument(skip(self, voice_state))]
     pub fn update_state(&mut self, voice_state: &VoiceState) {
+        let _span = self.span().entered();
+
         if self.user_id != voice_state.user_id.0 {
+            if let Some(observer) = self.foreign_state_observer.as_ref() {
+                observer(voice_state);
+            }
+
             return;
         }
 
-        self.channel_id = voice_state.channel_id;
+        self.self_video = voice_state.self_video;
+
+        match (voice_state.channel_id, self.channel_id) {
+            (Some(channel_id), previous) => {
+                self.set_channel_id(Some(channel_id));
+                self.clear_invalid_session();
+                self.session_id = Some(voice_state.session_id.clone());
+
+                if self.auto_connect && self.endpoint.is_some() && self.token.is_some() {
+                    self.connect();
+                }
+
+                // Joining sets the channel first, so only a move made by
+                // someone else arrives in a channel other than the last.
+                if previous != Some(channel_id) && self.afk_channel_id == Some(channel_id) {
+                    self.moved_to_afk(channel_id, previous);
+                }
+            },
+            // Leaving clears the channel before Discord confirms it, so the
+            // channel is only still set if someone else disconnected us, or
+            // the handler left after idling.
+            (None, Some(_)) if self.shared.take_auto_disconnected() => {
+                self.remember_channel();
+                self.set_channel_id(None);
+            },
+            (None, Some(channel_id)) => self.force_disconnected(channel_id),
+            (None, None) => {},
+        }
+    }
+
+    /// Passes a gateway event to the handler, returning whether it was a
+    /// voice server or voice state update meant for this handler.
+    ///
+    /// You should only need to use this if you initialized the `Handler` via
+    /// [`standalone`], in which case it can be given every event received
+    /// from the gateway instead of calling [`update_server`] and
+    /// [`update_state`] directly. Events for other guilds are ignored, and
+    /// voice states of other users are only passed to any observer set via
+    /// [`set_foreign_state_observer`].
+    ///
+    /// An invalid voice server endpoint is logged, and the event still
+    /// counts as consumed.
+    ///
+    /// [`set_foreign_state_observer`]: #method.set_foreign_state_observer
+    /// [`standalone`]: #method.standalone
+    /// [`update_server`]: #method.update_server
+    /// [`update_state`]: #method.update_state
+    #[instrument(skip(self, event))]
+    pub fn process_event(&mut self, event: &GatewayEvent) -> bool {
+        match event {
+            GatewayEvent::Dispatch(_, event) => self.process_dispatch(event),
+            _ => false,
+        }
+    }
+
+    /// Passes a dispatched event to the handler, as with [`process_event`].
+    ///
+    /// [`process_event`]: #method.process_event
+    pub(crate) fn process_dispatch(&mut self, event: &Event) -> bool {
+        match event {
+            Event::VoiceServerUpdate(event) if event.guild_id == Some(self.guild_id) => {
+                if let Err(why) = self.update_server(&event.endpoint, &event.token) {
+                    warn!(
+                        "[Voice] Invalid voice server endpoint {:?} for guild {}: {:?}",
+                        event.endpoint,
+                        self.guild_id,
+                        why,
+                    );
+                }
+
+                true
+            },
+            Event::VoiceStateUpdate(event) if event.guild_id == Some(self.guild_id) => {
+                self.update_state(&event.voice_state);
+
+                // Other users' states are only observed.
+                event.voice_state.user_id == self.user_id
+            },
+            _ => false,
+        }
+    }
+
+    /// Sets a function called with each voice state update of another user
+    /// in the guild which the handler receives, such as to keep track of who
+    /// is listening in its channel, without a separate event handler.
+    ///
+    /// These updates are otherwise ignored, and still are once observed. The
+    /// function is called as the update is passed to the handler, so should
+    /// not block. Only the most recently set function is called.
+    #[instrument(skip(self, observer))]
+    pub fn set_foreign_state_observer(&mut self, observer: Box<dyn Fn(&VoiceState) + Send + Sync>) {
+        self.foreign_state_observer = Some(Arc::from(observer));
+    }
+
+    /// Removes any function set via [`set_foreign_state_observer`].
+    ///
+    /// [`set_foreign_state_observer`]: #method.set_foreign_state_observer
+    #[instrument(skip(self))]
+    pub fn clear_foreign_state_observer(&mut self) {
+        self.foreign_state_observer = None;
+    }
+
+    /// Sets what the handler does when it is disconnected from its channel
+    /// by someone else, such as a moderator. Defaults to
+    /// [`ForceDisconnectPolicy::Leave`].
+    ///
+    /// [`ForceDisconnectPolicy::Leave`]: enum.ForceDisconnectPolicy.html#variant.Leave
+    pub fn set_on_force_disconnect(&mut self, policy: ForceDisconnectPolicy) {
+        self.force_disconnect = policy;
+    }
+
+    /// Sets how long the handler may send no audio while connected before it
+    /// leaves its channel, or `None`, the default, to stay indefinitely.
+    ///
+    /// Once it has idled for this long, a [`HandlerEvent::AutoDisconnected`]
+    /// is emitted and the channel is left as via [`leave`], from a background
+    /// task checking about once a second. Paused sources do not count as
+    /// sending audio. A standalone handler only drops its voice connection,
+    /// as it has no gateway to send the update over.
+    ///
+    /// [`HandlerEvent::AutoDisconnected`]: enum.HandlerEvent.html#variant.AutoDisconnected
+    /// [`leave`]: #method.leave
+    #[instrument(skip(self))]
+    pub fn set_auto_disconnect(&mut self, after: Option<Duration>) {
+        // Dropping the last handle to the old token stops its task.
+        self.auto_disconnect = None;
+
+        let after = match after {
+            Some(after) => after,
+            None => return,
+        };
+
+        let token = Arc::new(());
+        let alive = Arc::downgrade(&token);
+        self.auto_disconnect = Some(token);
+
+        let guild_id = self.guild_id;
+        let shared = Arc::clone(&self.shared);
+        let ws = self.ws.clone();
+        let check = after.min(AUTO_DISCONNECT_CHECK);
+
+        tokio::spawn(async move {
+            loop {
+                delay_for(check).await;
+
+                if alive.upgrade().is_none() {
+                    break;
+                }
+
+                let channel_id = match shared.channel_id() {
+                    Some(channel_id) if shared.idle_for(after) => channel_id,
+                    _ => continue,
+                };
+
+                // The task may not yet have dropped the connection.
+                if !shared.mark_auto_disconnected() {
+                    continue;
+                }
+
+                info!("[Voice] Leaving channel {} in guild {} after idling.", channel_id, guild_id);
+
+                if let Some(ws) = ws.as_ref() {
+                    ws.send_now(update_payload(guild_id, None, false, false, false));
+                }
+
+                shared.send_to_task(VoiceStatus::Disconnect);
+                shared.emit(HandlerEvent::AutoDisconnected { channel_id });
+            }
+        });
+    }
+
+    /// Sets what the handler does when someone else, or Discord itself after
+    /// a period of inactivity, moves it into its guild's AFK channel, where
+    /// nobody will hear it. Defaults to [`AfkPolicy::Ignore`].
+    ///
+    /// When joined via the [`Manager`] with the `cache` feature enabled, the
+    /// guild's AFK channel is read from the cache as voice state updates
+    /// arrive. Otherwise, it must be given via [`set_afk_channel`]. Joining
+    /// the AFK channel directly, such as via [`join`], is not treated as a
+    /// move.
+    ///
+    /// [`AfkPolicy::Ignore`]: enum.AfkPolicy.html#variant.Ignore
+    /// [`join`]: #method.join
+    /// [`Manager`]: struct.Manager.html
+    /// [`set_afk_channel`]: #method.set_afk_channel
+    #[instrument(skip(self))]
+    pub fn set_afk_policy(&mut self, policy: AfkPolicy) {
+        self.afk_policy = policy;
+    }
+
+    /// Sets the guild's AFK channel, as checked by [`set_afk_policy`] when
+    /// the handler is moved.
+    ///
+    /// You should only need to use this if the guild is not cached, such as
+    /// with a `Handler` initialized via [`standalone`].
+    ///
+    /// [`set_afk_policy`]: #method.set_afk_policy
+    /// [`standalone`]: #method.standalone
+    pub fn set_afk_channel(&mut self, channel_id: Option<ChannelId>) {
+        self.afk_channel_id = channel_id;
+    }
+
+    /// Sets whether the channel is remembered when leaving it, either via
+    /// [`leave`] or by being disconnected by someone else, so that it can be
+    /// found via [`last_channel`], such as to rejoin it later. Defaults to
+    /// `false`.
+    ///
+    /// Disabling this forgets any channel already remembered.
+    ///
+    /// [`last_channel`]: #method.last_channel
+    /// [`leave`]: #method.leave
+    pub fn set_remember_last_channel(&mut self, remember: bool) {
+        self.remember_last_channel = remember;
+
+        if !remember {
+            self.last_channel = None;
+        }
+    }
+
+    /// The channel most recently left, if remembered via
+    /// [`set_remember_last_channel`].
+    ///
+    /// # Examples
+    ///
+    /// Rejoin the channel left most recently:
+    ///
+    /// ```rust,ignore
+    /// if let Some(channel_id) = handler.last_channel() {
+    ///     handler.join(channel_id);
+    /// }
+    /// ```
+    ///
+    /// [`set_remember_last_channel`]: #method.set_remember_last_channel
+    pub fn last_channel(&self) -> Option<ChannelId> {
+        self.last_channel
+    }
+
+    /// Sets a name for the handler, such as that of its guild, recorded as
+    /// the `label` field of the `handler` tracing span, alongside its
+    /// `guild_id` and `channel_id` fields. The span is entered by the methods
+    /// which connect, move or leave, such as [`join`], so that what happens
+    /// to each handler can be told apart.
+    ///
+    /// This is kept by clones made after it is set. The voice token and
+    /// session ID are never recorded.
+    ///
+    /// [`join`]: #method.join
+    pub fn set_span_label<S: Into<String>>(&mut self, label: S) {
+        self.span_label = Some(label.into());
+    }
+
+    /// Limits how long the handler may transmit audio for, such as to cap
+    /// each guild's daily listening time, or removes the limit if `None`.
+    ///
+    /// Only frames of audio actually sent count towards the budget, so time
+    /// spent paused, muted or idle does not. Once it is used up, every
+    /// playing and queued track is stopped, a
+    /// [`HandlerEvent::BudgetExhausted`] event is emitted, and tracks played
+    /// afterwards are stopped at once, until the budget is raised, removed,
+    /// or reset via [`reset_playtime_budget`].
+    ///
+    /// The time already used is kept when the budget is changed, and is
+    /// shared by clones of the handler. Lowering the budget below the time
+    /// already used exhausts it at once.
+    ///
+    /// [`HandlerEvent::BudgetExhausted`]: enum.HandlerEvent.html#variant.BudgetExhausted
+    /// [`reset_playtime_budget`]: #method.reset_playtime_budget
+    #[instrument(skip(self))]
+    pub fn set_playtime_budget(&mut self, budget: Option<Duration>) {
+        self.shared.update_playtime_budget(|playtime| playtime.limit = budget);
+    }
+
+    /// Forgets the time counted against the handler's playtime budget, so
+    /// that the whole of it is available again.
+    ///
+    /// Refer to [`set_playtime_budget`] for more information.
+    ///
+    /// [`set_playtime_budget`]: #method.set_playtime_budget
+    #[instrument(skip(self))]
+    pub fn reset_playtime_budget(&mut self) {
+        self.shared.update_playtime_budget(|playtime| playtime.used = Duration::from_secs(0));
+    }
+
+    /// The name recorded in the handler's tracing spans, or an empty one if
+    /// none is set.
+    fn span_label(&self) -> &str {
+        self.span_label.as_deref().unwrap_or("")
+    }
+
+    /// A span recording which handler is in use, entered by the methods which
+    /// connect it, move it or leave.
+    fn span(&self) -> Span {
+        info_span!("handler", guild_id = %self.guild_id, channel_id = ?self.channel_id, label = self.span_label())
+    }
+
+    /// Remembers the current channel as the last channel, if enabled.
+    fn remember_channel(&mut self) {
+        if self.remember_last_channel && self.channel_id.is_some() {
+            self.last_channel = self.channel_id;
+        }
+    }
+
+    /// Forgets the session ID and token if the voice gateway rejected them,
+    /// so that a connection is only made once fresh ones have been sent.
+    fn clear_invalid_session(&mut self) {
+        if self.shared.take_session_invalid() {
+            self.session_id = None;
+            self.token = None;
+        }
+    }
+
+    fn moved_to_afk(&mut self, channel_id: ChannelId, previous: Option<ChannelId>) {
+        let policy = self.afk_policy;
+
+        if policy == AfkPolicy::Ignore {
+            return;
+        }
+
+        info!(
+            "[Voice] Moved to AFK channel {} in guild {}, applying {:?}.",
+            channel_id,
+            self.guild_id,
+            policy,
+        );
+
+        match (policy, previous) {
+            (AfkPolicy::Stop, _) => self.stop_and_clear(),
+            (AfkPolicy::Return, Some(previous)) => self.join(previous),
+            _ => {},
+        }
+
+        self.shared.emit(HandlerEvent::MovedToAfk { channel_id, policy });
+    }
+
+    fn force_disconnected(&mut self, channel_id: ChannelId) {
+        let policy = self.force_disconnect;
 
-        if voice_state.channel_id.is_some() {
-            self.session_id = Some(voice_state.session_id.clone());
+        info!(
+            "[Voice] Disconnected from channel {} in guild {}, applying {:?}.",
+            channel_id,
+            self.guild_id,
+            policy,
+        );
 
-            if self.endpoint.is_some() && self.token.is_some() {
-                self.connect();
-            }
-        } else {
-            self.leave();
+        self.remember_channel();
+        self.set_channel_id(None);
+
+        match policy {
+            ForceDisconnectPolicy::Leave => {
+                // Discord has already removed us, so only the voice
+                // connection needs dropping.
+                self.send_disconnect();
+            },
+            ForceDisconnectPolicy::Rejoin => {
+                self.send_disconnect();
+
+                // The old server details are no longer valid, so wait for
+                // those sent after rejoining.
+                self.endpoint = None;
+                self.token = None;
+                self.join(channel_id);
+            },
+            ForceDisconnectPolicy::Notify => {},
         }
+
+        self.shared.emit(HandlerEvent::ForceDisconnected { channel_id, policy });
     }
 
     fn new_raw(
         guild_id: GuildId,
-        ws: Option<Sender<InterMessage>>,
+        ws: Option<Arc<UpdateLimiter>>,
         user_id: UserId,
     ) -> Self {
         let (tx, rx) = unbounded();
-        tasks::start(guild_id, rx);
+        let handler = Self::with_task_sender(guild_id, ws, user_id, tx);
+        tasks::start(guild_id, rx, Arc::clone(&handler.shared));
+
+        handler
+    }
+
+    /// Creates a handler which sends messages for its task down the given
+    /// channel, without starting a task to receive them.
+    pub(crate) fn with_task_sender(
+        guild_id: GuildId,
+        ws: Option<Arc<UpdateLimiter>>,
+        user_id: UserId,
+        sender: Sender<VoiceStatus>,
+    ) -> Self {
+        let shared = Arc::new(SharedState::new(guild_id, sender.clone()));
 
         Handler {
+            afk_channel_id: None,
+            afk_policy: AfkPolicy::default(),
+            auto_connect: true,
+            auto_disconnect: None,
+            bind_address: None,
             channel_id: None,
+            connect_timeout: tasks::DEFAULT_CONNECT_TIMEOUT,
+            dry_run: None,
             endpoint: None,
+            endpoint_resolver: None,
+            force_disconnect: ForceDisconnectPolicy::default(),
+            foreign_state_observer: None,
             guild_id,
+            last_channel: None,
+            lost_endpoint: None,
+            remember_last_channel: false,
+            rtp_seed: None,
             self_deaf: false,
             self_mute: false,
-            sender: tx,
+            self_video: false,
+            sender,
+            shared,
             session_id: None,
+            span_label: None,
+            task_queue_limit: None,
             token: None,
             user_id,
             ws,
         }
     }
 
+    /// Sets the channel, sharing it with the task for its events.
+    fn set_channel_id(&mut self, channel_id: Option<ChannelId>) {
+        self.channel_id = channel_id;
+        self.shared.set_channel_id(channel_id);
+    }
+
+    /// Creates a handle for a new track, warning if it is unlikely to be heard.
+    fn new_track(&self, audio: Audio) -> TrackHandle {
+        if self.connection_state() == ConnectionState::Disconnected {
+            warn!(
+                "[Voice] Playing audio in guild {} without a voice connection.",
+                self.guild_id,
+            );
+        }
+
+        self.unplayed_track(audio)
+    }
+
+    /// Creates a track which is not yet passed to the task, to be started
+    /// later via [`TrackHandle::start`].
+    ///
+    /// [`TrackHandle::start`]: struct.TrackHandle.html#method.start
+    pub(crate) fn unplayed_track(&self, audio: Audio) -> TrackHandle {
+        TrackHandle::new(audio, self.shared.watch_connection_state(), self.shared.events())
+            .with_task(self.sender.clone(), Arc::clone(&self.shared))
+    }
+
+    /// Fails if as many messages are waiting for the task as the limit
+    /// allows.
+    fn check_task_queue(&self) -> Result<(), VoiceError> {
+        match self.task_queue_limit {
+            Some(limit) if self.shared.pending_messages() >= limit => Err(VoiceError::Busy),
+            _ => Ok(()),
+        }
+    }
+
     /// Sends a message to the task.
     #[instrument(skip(self, status))]
     fn send(&mut self, status: VoiceStatus) {
-        // Restart task if it errored.
-        if let Err(error) = self.sender.unbounded_send(status) {
-            let (tx, rx) = unbounded();
+        let status = match self.hold_back(status) {
+            Some(status) => status,
+            None => return,
+        };
 
-            self.sender = tx;
-            self.sender.unbounded_send(error.into_inner()).unwrap();
-            tasks::start(self.guild_id, rx);
-            self.update();
+        self.shared.message_sent();
+
+        let mut status = status;
+
+        // Restart task if it errored, unless another clone already has.
+        while let Err(error) = self.sender.unbounded_send(status) {
+            status = error.into_inner();
+
+            let guild_id = self.guild_id;
+            let shared = Arc::clone(&self.shared);
+            let (sender, restarted) = self.shared.task_sender(|| {
+                let (tx, rx) = unbounded();
+                tasks::start(guild_id, rx, shared);
+
+                tx
+            });
+            self.sender = sender;
+
+            if restarted {
+                // Anything still queued for the old task was lost with it.
+                self.shared.reset_pending();
+                self.shared.message_sent();
+
+                self.sender.unbounded_send(status).unwrap();
+                self.update();
+
+                break;
+            }
+        }
+    }
+
+    /// Tells the task to drop its connection, without restarting it if it
+    /// has stopped.
+    fn send_disconnect(&self) {
+        if let Some(status) = self.hold_back(VoiceStatus::Disconnect) {
+            let _ = self.sender.unbounded_send(status);
+        }
+    }
+
+    /// Records a message in the dry run log if one is in progress, or else
+    /// gives it back to be sent.
+    fn hold_back(&self, status: VoiceStatus) -> Option<VoiceStatus> {
+        match self.dry_run.as_ref() {
+            Some(log) => {
+                log.lock().expect("[Voice] Dry run log poisoned").push(status);
+
+                None
+            },
+            None => Some(status),
         }
     }
 
@@ -443,30 +2767,101 @@ impl Handler {
             return;
         }
 
-        self.update();
+        self.update_coalesced();
     }
 
-    /// Send an update for the current session over WS.
+    /// Send an update for the current session over WS, returning whether it
+    /// was queued.
     ///
-    /// Does nothing if initialized via [`standalone`].
+    /// Does nothing, successfully, if initialized via [`standalone`].
     ///
     /// [`standalone`]: #method.standalone
+    ///
+    /// This carries the latest state, so replaces any update deferred by
+    /// `update_coalesced`.
     #[instrument(skip(self))]
-    fn update(&self) {
+    fn update(&self) -> bool {
         if let Some(ref ws) = self.ws {
-            let map = json!({
-                "op": VoiceOpCode::SessionDescription.num(),
-                "d": {
-                    "channel_id": self.channel_id.map(|c| c.0),
-                    "guild_id": self.guild_id.0,
-                    "self_deaf": self.self_deaf,
-                    "self_mute": self.self_mute,
-                }
-            });
+            let mut switch = self.shared.lock_switch();
+            switch.pending = None;
+            switch.last_sent = Some(Instant::now());
+
+            // Leaves are sent at once, as those made while dropping, such as
+            // at shutdown, would be lost with a queue behind the limiter.
+            if self.channel_id.is_none() {
+                ws.send_now(self.update_payload())
+            } else {
+                ws.send(self.update_payload())
+            }
+        } else {
+            true
+        }
+    }
+
+    /// Sends an update as with `update`, unless one was sent within the
+    /// switch debounce interval. The update is then deferred to the end of
+    /// the interval, and replaced by any made in the meantime.
+    #[instrument(skip(self))]
+    fn update_coalesced(&self) {
+        let ws = match self.ws {
+            Some(ref ws) => Arc::clone(ws),
+            None => return,
+        };
+
+        let mut switch = self.shared.lock_switch();
+
+        if switch.pending.is_some() {
+            switch.pending = Some(self.update_payload());
+
+            return;
+        }
+
+        let wait = switch.last_sent
+            .and_then(|at| switch.interval.checked_sub(at.elapsed()))
+            .filter(|wait| *wait > Duration::default());
+
+        match wait {
+            Some(wait) => {
+                switch.pending = Some(self.update_payload());
 
-            let _ = ws.unbounded_send(InterMessage::Json(map));
+                let shared = Arc::clone(&self.shared);
+
+                tokio::spawn(async move {
+                    delay_for(wait).await;
+
+                    let mut switch = shared.lock_switch();
+
+                    if let Some(payload) = switch.pending.take() {
+                        switch.last_sent = Some(Instant::now());
+                        ws.send(payload);
+                    }
+                });
+            },
+            None => {
+                switch.last_sent = Some(Instant::now());
+                ws.send(self.update_payload());
+            },
         }
     }
+
+    fn update_payload(&self) -> Value {
+        update_payload(self.guild_id, self.channel_id, self.self_deaf, self.self_mute, self.self_video)
+    }
+}
+
+/// The voice state update sent to the gateway to join, move within or leave
+/// a guild's voice channels.
+fn update_payload(guild_id: GuildId, channel_id: Option<ChannelId>, deaf: bool, mute: bool, video: bool) -> Value {
+    json!({
+        "op": VoiceOpCode::SessionDescription.num(),
+        "d": {
+            "channel_id": channel_id.map(|c| c.0),
+            "guild_id": guild_id.0,
+            "self_deaf": deaf,
+            "self_mute": mute,
+            "self_video": video,
+        }
+    })
 }
 
 impl Drop for Handler {
@@ -474,3 +2869,755 @@ impl Drop for Handler {
     /// forgets all configurations relevant to this Handler.
     fn drop(&mut self) { self.leave(); }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::gateway::InterMessage;
+    use crate::voice::TrackStatus;
+    use futures::StreamExt;
+    use super::*;
+
+    #[tokio::test]
+    async fn test_drop_sends_leave_update() {
+        let (tx, mut rx) = unbounded();
+        let limiter = Arc::new(UpdateLimiter::new(tx));
+        let mut handler = Handler::new(GuildId(1), Arc::clone(&limiter), UserId(2), false, false);
+        handler.channel_id = Some(ChannelId(3));
+
+        // The leave is sent even once the limit is used up.
+        limiter.set_rate(1, Duration::from_secs(60));
+        assert!(limiter.send(json!({ "d": { "guild_id": 4 } })));
+        let _ = rx.try_next();
+
+        drop(handler);
+
+        match rx.try_next() {
+            Ok(Some(InterMessage::Json(value))) => {
+                assert_eq!(value["d"]["guild_id"], 1);
+                assert!(value["d"]["channel_id"].is_null());
+            },
+            _ => panic!("expected a voice state update"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_switches_coalesced() {
+        let (tx, mut rx) = unbounded();
+        let mut handler = Handler::new(GuildId(1), Arc::new(UpdateLimiter::new(tx)), UserId(2), false, false);
+        handler.set_switch_debounce(Duration::from_millis(50));
+
+        for channel in 3..6 {
+            handler.switch_to(ChannelId(channel));
+        }
+
+        let channel_of = |message| match message {
+            Ok(Some(InterMessage::Json(value))) => value["d"]["channel_id"].clone(),
+            _ => panic!("expected a voice state update"),
+        };
+
+        assert_eq!(channel_of(rx.try_next()), 3);
+        assert!(rx.try_next().is_err());
+
+        delay_for(Duration::from_millis(100)).await;
+
+        assert_eq!(channel_of(rx.try_next()), 5);
+        assert!(rx.try_next().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_task_queue_limit() {
+        let source = || crate::voice::pcm(true, std::io::Cursor::new(Vec::new()));
+        let (tx, mut rx) = unbounded();
+        let mut handler = Handler::with_task_sender(GuildId(1), None, UserId(2), tx);
+
+        handler.set_task_queue_limit(Some(2));
+        assert!(handler.try_play(source()).is_ok());
+        assert!(handler.try_play_only(source()).is_ok());
+        assert!(matches!(handler.try_play(source()), Err(VoiceError::Busy)));
+
+        // Controls are queued past the limit.
+        handler.stop_and_clear();
+        assert_eq!(handler.shared.pending_messages(), 3);
+
+        // Sources are accepted again once the task catches up.
+        while let Ok(Some(_)) = rx.try_next() {
+            handler.shared.message_received();
+        }
+
+        assert!(handler.try_play(source()).is_ok());
+        handler.set_task_queue_limit(None);
+        assert!(handler.try_play(source()).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_restart_ended_track() {
+        let (tx, mut rx) = unbounded();
+        let mut handler = Handler::with_task_sender(GuildId(1), None, UserId(2), tx);
+        let added = |message| matches!(message, Ok(Some(VoiceStatus::AddSender(_))));
+
+        let track = handler.play_returning(crate::voice::chain(Vec::new()));
+        assert!(added(rx.try_next()));
+
+        // A live track is rewound in place.
+        track.restart().await.unwrap();
+        assert!(rx.try_next().is_err());
+
+        handler.shared.end_track(&mut *track.audio().lock().await, EndReason::Finished);
+        track.restart().await.unwrap();
+        assert!(added(rx.try_next()));
+        assert_eq!(track.status(), TrackStatus::Pending);
+
+        let track = handler.play_returning(crate::voice::pcm(true, std::io::Cursor::new(Vec::new())));
+        assert!(matches!(track.restart().await, Err(VoiceError::NotSeekable)));
+    }
+
+    #[tokio::test]
+    async fn test_play_from() {
+        let (tx, mut rx) = unbounded();
+        let mut handler = Handler::with_task_sender(GuildId(1), None, UserId(2), tx);
+
+        let track = handler.play_from(crate::voice::chain(Vec::new()), Duration::from_secs(5)).unwrap();
+        assert!(matches!(rx.try_next(), Ok(Some(VoiceStatus::AddSender(_)))));
+
+        // The seek is applied by the task before the first frame is read.
+        let audio = track.audio().lock().await;
+        assert!(audio.position_modified);
+        assert_eq!(audio.position, Duration::from_secs(5));
+
+        let source = crate::voice::pcm(true, std::io::Cursor::new(Vec::new()));
+        assert!(matches!(handler.play_from(source, Duration::from_secs(5)), Err(VoiceError::NotSeekable)));
+        assert!(rx.try_next().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_move_to() {
+        let (tx, _rx) = unbounded();
+        let mut handler = Handler::with_task_sender(GuildId(1), None, UserId(2), tx);
+        handler.endpoint = Some("example.discord.media".to_string());
+        handler.token = Some("token".to_string());
+
+        // Joining is confirmed by the task once Discord's updates arrive.
+        let moved = handler.move_to(ChannelId(3));
+        handler.session_id = Some("session".to_string());
+        assert!(handler.connect());
+        handler.shared.set_connection_state(ConnectionState::Connected);
+        assert!(moved.await.is_ok());
+        assert_eq!(handler.channel_id, Some(ChannelId(3)));
+
+        handler.move_to(ChannelId(3)).await.unwrap();
+
+        let moved = handler.move_to(ChannelId(4));
+        handler.connect();
+        handler.shared.set_connection_state(ConnectionState::Disconnected);
+        assert!(matches!(moved.await, Err(VoiceError::NotConnected)));
+
+        // Without any reply from Discord, the move is given up on.
+        handler.set_connect_timeout(Duration::from_millis(10));
+        assert!(matches!(handler.move_to(ChannelId(5)).await, Err(VoiceError::Timeout)));
+    }
+
+    #[tokio::test]
+    async fn test_process_event() {
+        use crate::model::event::{VoiceServerUpdateEvent, VoiceStateUpdateEvent};
+
+        let (tx, _rx) = unbounded();
+        let mut handler = Handler::with_task_sender(GuildId(1), None, UserId(2), tx);
+        let server = |guild_id| GatewayEvent::Dispatch(1, Event::VoiceServerUpdate(VoiceServerUpdateEvent {
+            channel_id: None,
+            endpoint: Some("voice.example.com:80".to_string()),
+            guild_id: Some(GuildId(guild_id)),
+            token: "token".to_string(),
+            _nonexhaustive: (),
+        }));
+        let state = |user_id| GatewayEvent::Dispatch(2, Event::VoiceStateUpdate(VoiceStateUpdateEvent {
+            guild_id: Some(GuildId(1)),
+            voice_state: VoiceState {
+                channel_id: Some(ChannelId(3)),
+                deaf: false,
+                mute: false,
+                self_deaf: false,
+                self_mute: false,
+                self_stream: None,
+                self_video: false,
+                session_id: "session".to_string(),
+                suppress: false,
+                token: None,
+                user_id: UserId(user_id),
+                _nonexhaustive: (),
+            },
+            _nonexhaustive: (),
+        }));
+
+        assert!(!handler.process_event(&GatewayEvent::HeartbeatAck));
+        assert!(!handler.process_event(&server(4)));
+        assert!(handler.endpoint.is_none());
+
+        assert!(handler.process_event(&server(1)));
+        assert_eq!(handler.endpoint.as_deref(), Some("voice.example.com"));
+
+        let observed = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let sink = Arc::clone(&observed);
+        handler.set_foreign_state_observer(Box::new(move |state| sink.lock().unwrap().push(state.user_id)));
+
+        assert!(!handler.process_event(&state(5)));
+        assert!(handler.session_id.is_none());
+
+        assert!(handler.process_event(&state(2)));
+        assert_eq!(handler.session_id.as_deref(), Some("session"));
+        assert_eq!(handler.channel_id, Some(ChannelId(3)));
+        assert_eq!(*observed.lock().unwrap(), vec![UserId(5)]);
+
+        handler.clear_foreign_state_observer();
+        assert!(!handler.process_event(&state(6)));
+        assert_eq!(*observed.lock().unwrap(), vec![UserId(5)]);
+    }
+
+    #[tokio::test]
+    async fn test_invalid_session_cleared() {
+        let (tx, _rx) = unbounded();
+        let mut handler = Handler::with_task_sender(GuildId(1), None, UserId(2), tx);
+        handler.endpoint = Some("example.discord.media".to_string());
+        handler.session_id = Some("session".to_string());
+        handler.token = Some("token".to_string());
+
+        handler.shared.invalidate_session();
+        assert!(!handler.connect());
+        assert!(handler.session_id.is_none());
+        assert!(handler.token.is_none());
+
+        // Only the rejected details are forgotten, not fresh ones.
+        handler.shared.invalidate_session();
+        handler.update_server(&Some("example.discord.media".to_string()), "fresh").unwrap();
+        handler.session_id = Some("fresh".to_string());
+        assert!(handler.connect());
+        assert_eq!(handler.token.as_deref(), Some("fresh"));
+    }
+
+    #[test]
+    fn test_opus_settings() {
+        let (tx, _rx) = unbounded();
+        let mut handler = Handler::with_task_sender(GuildId(1), None, UserId(2), tx);
+        assert_eq!(handler.opus_settings().complexity, 9);
+
+        handler.set_opus_complexity(4).unwrap();
+        handler.set_dtx(true);
+        assert!(matches!(handler.set_opus_complexity(11), Err(VoiceError::InvalidComplexity(11))));
+
+        let settings = handler.opus_settings();
+        assert_eq!(settings.complexity, 4);
+        assert!(settings.dtx);
+        assert!(!settings.live);
+    }
+
+    #[test]
+    fn test_receiver_handle() {
+        struct Receiver;
+
+        #[async_trait::async_trait]
+        impl AudioReceiver for Receiver {}
+
+        let (tx, mut rx) = unbounded();
+        let mut handler = Handler::with_task_sender(GuildId(1), None, UserId(2), tx);
+        let first = handler.add_receiver(Arc::new(Receiver));
+        let second = handler.add_receiver(Arc::new(Receiver));
+        assert!(matches!(rx.try_next(), Ok(Some(VoiceStatus::AddReceiver(_)))));
+        assert!(matches!(rx.try_next(), Ok(Some(VoiceStatus::AddReceiver(_)))));
+
+        // Only the handle's own receiver is removed.
+        let kept = Arc::clone(second.receiver());
+        drop(second);
+        match rx.try_next() {
+            Ok(Some(VoiceStatus::RemoveReceiver(removed))) => assert!(Arc::ptr_eq(&removed, &kept)),
+            _ => panic!("expected the receiver to be removed"),
+        }
+
+        first.detach();
+        assert!(rx.try_next().is_err());
+    }
+
+    #[test]
+    fn test_dry_run() {
+        let (tx, mut rx) = unbounded();
+        let mut handler = Handler::with_task_sender(GuildId(1), None, UserId(2), tx);
+        handler.set_dry_run(true);
+
+        handler.mute(true);
+        handler.stop();
+        handler.channel_id = Some(ChannelId(3));
+        handler.leave();
+        assert_eq!(handler.take_dry_run_log(), vec![
+            TaskMessage::Mute(true),
+            TaskMessage::Stop,
+            TaskMessage::Disconnect,
+        ]);
+        assert!(handler.take_dry_run_log().is_empty());
+        assert!(rx.try_next().is_err());
+
+        handler.set_dry_run(false);
+        handler.stop();
+        assert!(matches!(rx.try_next(), Ok(Some(VoiceStatus::SetSender(None)))));
+    }
+
+    #[test]
+    fn test_set_close_code() {
+        let (tx, mut rx) = unbounded();
+        let mut handler = Handler::with_task_sender(GuildId(1), None, UserId(2), tx);
+
+        assert!(handler.set_close_code(4000).is_ok());
+        assert!(matches!(rx.try_next(), Ok(Some(VoiceStatus::SetCloseCode(4000)))));
+
+        for &code in &[999, 1005, 1015, 2000, 5000] {
+            assert!(matches!(handler.set_close_code(code), Err(VoiceError::InvalidCloseCode(c)) if c == code));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_announce() {
+        let (tx, mut rx) = unbounded();
+        let mut handler = Handler::with_task_sender(GuildId(1), None, UserId(2), tx);
+        let mut config = DuckConfig::default();
+        config.level(0.1);
+
+        let track = handler.announce_returning(crate::voice::pcm(true, std::io::Cursor::new(Vec::new())), config);
+
+        match rx.try_next() {
+            Ok(Some(VoiceStatus::AddSender(audio))) => {
+                assert!(Arc::ptr_eq(&audio, track.audio()));
+                assert_eq!(audio.lock().await.announcement, Some(config));
+            },
+            _ => panic!("expected the announcement to be played"),
+        }
+    }
+
+    #[test]
+    fn test_set_receive_buffer_limit() {
+        let (tx, mut rx) = unbounded();
+        let mut handler = Handler::with_task_sender(GuildId(1), None, UserId(2), tx);
+
+        assert!(matches!(handler.set_receive_buffer_limit(1), Err(VoiceError::InvalidBufferLimit(1))));
+        assert!(rx.try_next().is_err());
+
+        assert!(handler.set_receive_buffer_limit(JITTER_FRAMES).is_ok());
+        assert!(matches!(rx.try_next(), Ok(Some(VoiceStatus::SetReceiveBufferLimit(2)))));
+    }
+
+    #[test]
+    fn test_last_channel() {
+        let (tx, _rx) = unbounded();
+        let mut handler = Handler::with_task_sender(GuildId(1), None, UserId(2), tx);
+
+        handler.join(ChannelId(3));
+        handler.leave();
+        assert_eq!(handler.last_channel(), None);
+
+        handler.set_remember_last_channel(true);
+        handler.join(ChannelId(4));
+        handler.leave();
+        assert_eq!(handler.channel_id, None);
+        assert_eq!(handler.last_channel(), Some(ChannelId(4)));
+
+        // Leaving again while in no channel keeps it.
+        handler.leave();
+        assert_eq!(handler.last_channel(), Some(ChannelId(4)));
+
+        handler.set_remember_last_channel(false);
+        assert_eq!(handler.last_channel(), None);
+    }
+
+    #[tokio::test]
+    async fn test_prepare() {
+        let (tx, mut rx) = unbounded();
+        let mut handler = Handler::new(GuildId(1), Arc::new(UpdateLimiter::new(tx)), UserId(2), true, false);
+        handler.set_switch_debounce(Duration::from_secs(60));
+        handler.switch_to(ChannelId(3));
+        handler.endpoint = Some("example.discord.media".to_string());
+        handler.session_id = Some("session".to_string());
+        handler.token = Some("token".to_string());
+        let _ = rx.try_next();
+
+        // The update is sent at once, despite the debounce.
+        handler.prepare(ChannelId(4));
+
+        match rx.try_next() {
+            Ok(Some(InterMessage::Json(value))) => {
+                assert_eq!(value["d"]["channel_id"], 4);
+                assert_eq!(value["d"]["self_deaf"], true);
+            },
+            _ => panic!("expected a voice state update"),
+        }
+
+        // Stale details are not connected with.
+        handler.session_id = Some("fresh".to_string());
+        assert!(!handler.connect());
+        assert_eq!(handler.channel_id, Some(ChannelId(4)));
+    }
+
+    #[test]
+    fn test_playtime_budget() {
+        let (tx, _rx) = unbounded();
+        let mut handler = Handler::with_task_sender(GuildId(1), None, UserId(2), tx);
+        let mut events = handler.events();
+        let frame = Duration::from_millis(20);
+
+        // Without a budget, nothing is counted.
+        handler.shared.spend_playtime(frame);
+        handler.set_playtime_budget(Some(Duration::from_millis(40)));
+        handler.shared.spend_playtime(frame);
+        assert!(!handler.shared.playtime_exhausted());
+
+        handler.shared.spend_playtime(frame);
+        handler.shared.spend_playtime(frame);
+        assert!(handler.shared.playtime_exhausted());
+        assert!(matches!(events.try_next(), Ok(Some(HandlerEvent::BudgetExhausted))));
+        assert!(events.try_next().is_err());
+
+        handler.reset_playtime_budget();
+        assert!(!handler.shared.playtime_exhausted());
+
+        // Lowering the budget below the time already used exhausts it.
+        handler.shared.spend_playtime(frame);
+        handler.set_playtime_budget(Some(frame));
+        assert!(handler.shared.playtime_exhausted());
+        assert!(matches!(events.try_next(), Ok(Some(HandlerEvent::BudgetExhausted))));
+        handler.set_playtime_budget(Some(Duration::from_millis(10)));
+        assert!(events.try_next().is_err());
+
+        handler.set_playtime_budget(None);
+        assert!(!handler.shared.playtime_exhausted());
+    }
+
+    #[tokio::test]
+    async fn test_buses() {
+        let (tx, mut rx) = unbounded();
+        let mut handler = Handler::with_task_sender(GuildId(1), None, UserId(2), tx);
+
+        let track = handler.play_returning(crate::voice::pcm(true, std::io::Cursor::new(Vec::new())));
+        let _ = rx.try_next();
+        track.set_bus(Some("music")).await;
+        assert_eq!(track.bus().await.as_deref(), Some("music"));
+
+        handler.set_bus_volume("music", -1.0);
+        assert!(matches!(rx.try_next(), Ok(Some(VoiceStatus::SetBusVolume(ref b, v))) if b == "music" && v == 0.0));
+
+        handler.stop_bus("music");
+        assert!(matches!(rx.try_next(), Ok(Some(VoiceStatus::StopBus(ref b))) if b == "music"));
+    }
+
+    #[test]
+    fn test_last_receive_age() {
+        let (tx, _rx) = unbounded();
+        let handler = Handler::with_task_sender(GuildId(1), None, UserId(2), tx);
+        assert_eq!(handler.last_receive_age(), None);
+
+        handler.shared.set_last_receive(Some(Instant::now() - Duration::from_secs(5)));
+        assert!(handler.last_receive_age().unwrap() >= Duration::from_secs(5));
+
+        // The time is forgotten along with the connection.
+        handler.shared.set_connection_state(ConnectionState::Disconnected);
+        assert_eq!(handler.last_receive_age(), None);
+    }
+
+    #[tokio::test]
+    async fn test_single_task() {
+        let (tx, rx) = unbounded();
+        let mut first = Handler::with_task_sender(GuildId(1), None, UserId(2), tx);
+        let mut second = first.clone();
+
+        // The task stops, as if it had panicked mid-playback.
+        drop(rx);
+
+        // Both clones find it stopped, but only one new task is started.
+        first.set_receive_gain(UserId(3), 0.5);
+        second.set_receive_gain(UserId(3), 0.5);
+        assert!(first.sender.same_receiver(&second.sender));
+        assert!(first.shared.is_current_task(1));
+
+        // A task which has been replaced stops, rather than sending as well.
+        let (_tx, rx) = unbounded();
+        tasks::start(GuildId(1), rx, Arc::clone(&first.shared));
+        delay_for(Duration::from_millis(100)).await;
+
+        assert!(first.sender.is_closed());
+        assert!(first.shared.is_current_task(2));
+    }
+
+    #[test]
+    fn test_send_voice_op() {
+        let (tx, mut rx) = unbounded();
+        let mut handler = Handler::with_task_sender(GuildId(1), None, UserId(2), tx);
+
+        assert!(matches!(handler.send_voice_op(VoiceOpCode::Speaking, Value::Null), Err(VoiceError::NotConnected)));
+        assert!(rx.try_next().is_err());
+
+        handler.shared.set_connection_state(ConnectionState::Connected);
+        assert!(handler.send_voice_op(VoiceOpCode::Speaking, serde_json::json!({"speaking": 1})).is_ok());
+        assert!(matches!(
+            rx.try_next(),
+            Ok(Some(VoiceStatus::SendVoiceOp(VoiceOpCode::Speaking, ref data))) if data["speaking"] == 1,
+        ));
+    }
+
+    #[test]
+    fn test_reconfigure_encoder() {
+        let (tx, mut rx) = unbounded();
+        let mut handler = Handler::with_task_sender(GuildId(1), None, UserId(2), tx);
+
+        let mut config = OpusConfig::default();
+        config.complexity(11).inband_fec(true);
+        assert!(matches!(handler.reconfigure_encoder(config.clone()), Err(VoiceError::InvalidComplexity(11))));
+        assert!(rx.try_next().is_err());
+
+        // Several settings changed together reach the task as one rebuild.
+        config.complexity(5).bitrate(Bitrate::BitsPerSecond(96_000)).dtx(true);
+        assert!(handler.reconfigure_encoder(config).is_ok());
+        assert!(matches!(
+            rx.try_next(),
+            Ok(Some(VoiceStatus::ReconfigureEncoder(ref c))) if c.complexity == 5 && c.inband_fec && c.dtx,
+        ));
+        assert!(rx.try_next().is_err());
+        assert_eq!(handler.shared.opus_config().complexity, 5);
+    }
+
+    #[test]
+    fn test_bytes_transferred() {
+        let (tx, _rx) = unbounded();
+        let mut handler = Handler::with_task_sender(GuildId(1), None, UserId(2), tx);
+        assert_eq!(handler.bytes_transferred(), (0, 0));
+
+        handler.shared.update_stats(|stats| {
+            stats.packets_sent = 3;
+            stats.bytes_sent = 300;
+            stats.bytes_received = 120;
+        });
+        assert_eq!(handler.bytes_transferred(), (300, 120));
+
+        // Only the byte counts are reset.
+        handler.reset_bytes_transferred();
+        assert_eq!(handler.bytes_transferred(), (0, 0));
+        assert_eq!(handler.network_stats().packets_sent, 3);
+    }
+
+    #[tokio::test]
+    async fn test_auto_disconnect() {
+        let (ws, mut gateway) = unbounded();
+        let (tx, mut task) = unbounded();
+        let mut handler = Handler::with_task_sender(GuildId(1), Some(Arc::new(UpdateLimiter::new(ws))), UserId(2), tx);
+        let mut events = handler.events();
+        handler.set_channel_id(Some(ChannelId(3)));
+        handler.shared.set_connection_state(ConnectionState::Connected);
+
+        handler.set_auto_disconnect(Some(Duration::from_millis(20)));
+        delay_for(Duration::from_millis(100)).await;
+
+        match gateway.try_next() {
+            Ok(Some(InterMessage::Json(value))) => assert!(value["d"]["channel_id"].is_null()),
+            _ => panic!("expected a leave update"),
+        }
+        assert!(matches!(task.try_next(), Ok(Some(VoiceStatus::Disconnect))));
+        assert!(matches!(events.try_next(), Ok(Some(HandlerEvent::AutoDisconnected { channel_id: ChannelId(3) }))));
+
+        // Discord confirming the leave is not taken for a disconnect by
+        // someone else.
+        handler.update_state(&VoiceState {
+            channel_id: None,
+            deaf: false,
+            mute: false,
+            self_deaf: false,
+            self_mute: false,
+            self_stream: None,
+            self_video: false,
+            session_id: "session".to_string(),
+            suppress: false,
+            token: None,
+            user_id: UserId(2),
+            _nonexhaustive: (),
+        });
+        assert_eq!(handler.channel_id, None);
+        assert!(events.try_next().is_err());
+
+        // Nothing more is sent once stopped.
+        handler.set_channel_id(Some(ChannelId(3)));
+        handler.set_auto_disconnect(None);
+        delay_for(Duration::from_millis(100)).await;
+        assert!(gateway.try_next().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_reconnect_without_connection() {
+        // A running task with no connection has nothing to reconnect.
+        let mut handler = Handler::standalone(GuildId(1), UserId(2));
+        assert!(matches!(handler.reconnect().await, Err(Error::Voice(VoiceError::NotConnected))));
+        assert_eq!(handler.connection_state(), ConnectionState::Disconnected);
+
+        // Nor does a task which has gone without answering.
+        let (tx, mut rx) = unbounded();
+        let mut handler = Handler::with_task_sender(GuildId(1), None, UserId(2), tx);
+        let (result, _) = futures::future::join(handler.reconnect(), async {
+            match rx.next().await {
+                Some(VoiceStatus::Reconnect(done)) => drop(done),
+                _ => panic!("expected a reconnect message"),
+            }
+        }).await;
+        assert!(matches!(result, Err(Error::Voice(VoiceError::NotConnected))));
+    }
+
+    #[tokio::test]
+    async fn test_play_blocking_without_connection() {
+        // The source would never be played, so waiting for it fails.
+        let mut handler = Handler::standalone(GuildId(1), UserId(2));
+        let source = crate::voice::pcm(true, std::io::Cursor::new(vec![0u8; 3840]));
+        let ended = timeout(Duration::from_secs(1), handler.play_blocking(source)).await.unwrap();
+        assert!(matches!(ended, Err(VoiceError::NotConnected)));
+    }
+
+    #[tokio::test]
+    async fn test_track_ended_event() {
+        use crate::voice::TrackEvent;
+
+        let (tx, _rx) = unbounded();
+        let mut handler = Handler::with_task_sender(GuildId(1), None, UserId(2), tx);
+        let mut events = handler.events();
+        handler.set_channel_id(Some(ChannelId(3)));
+
+        let track = handler.play_returning(crate::voice::pcm(true, std::io::Cursor::new(Vec::new())));
+        handler.shared.end_track(&mut *track.audio().lock().await, EndReason::Finished);
+
+        match events.try_next() {
+            Ok(Some(HandlerEvent::TrackEnded(TrackEvent { guild_id, channel_id, reason, .. }))) => {
+                assert_eq!(guild_id, GuildId(1));
+                assert_eq!(channel_id, Some(ChannelId(3)));
+                assert_eq!(reason, EndReason::Finished);
+            },
+            other => panic!("expected a track end event, got {:?}", other),
+        }
+    }
+
+    #[cfg(feature = "cache")]
+    #[tokio::test]
+    async fn test_listener_count() {
+        use crate::model::{
+            event::GuildCreateEvent,
+            guild::{DefaultMessageNotificationLevel, ExplicitContentFilter, Guild, Member, MfaLevel, PremiumTier, VerificationLevel},
+            user::User,
+        };
+        use std::collections::HashMap;
+
+        let state = |user_id, channel_id| VoiceState {
+            channel_id: Some(ChannelId(channel_id)),
+            deaf: false,
+            mute: false,
+            self_deaf: false,
+            self_mute: false,
+            self_stream: None,
+            self_video: false,
+            session_id: String::new(),
+            suppress: false,
+            token: None,
+            user_id: UserId(user_id),
+            _nonexhaustive: (),
+        };
+        let member = |user_id, bot| Member {
+            deaf: false,
+            guild_id: GuildId(1),
+            joined_at: None,
+            mute: false,
+            nick: None,
+            roles: vec![],
+            user: User {
+                id: UserId(user_id),
+                avatar: None,
+                bot,
+                discriminator: 1,
+                name: String::new(),
+                _nonexhaustive: (),
+            },
+            _nonexhaustive: (),
+        };
+
+        // The handler's own user, a bot and two people are in the channel,
+        // and one more person is elsewhere.
+        let mut guild = Guild {
+            id: GuildId(1),
+            afk_channel_id: None,
+            afk_timeout: 0,
+            application_id: None,
+            default_message_notifications: DefaultMessageNotificationLevel::All,
+            emojis: HashMap::new(),
+            explicit_content_filter: ExplicitContentFilter::None,
+            features: vec![],
+            icon: None,
+            joined_at: chrono::Utc::now(),
+            large: false,
+            member_count: 0,
+            members: HashMap::new(),
+            mfa_level: MfaLevel::None,
+            name: String::new(),
+            owner_id: UserId(3),
+            presences: HashMap::new(),
+            region: String::new(),
+            roles: HashMap::new(),
+            splash: None,
+            system_channel_id: None,
+            verification_level: VerificationLevel::Low,
+            voice_states: HashMap::new(),
+            description: None,
+            premium_tier: PremiumTier::Tier0,
+            channels: HashMap::new(),
+            premium_subscription_count: 0,
+            banner: None,
+            vanity_url_code: None,
+            preferred_locale: String::new(),
+            _nonexhaustive: (),
+        };
+
+        for &(user_id, channel_id, bot) in &[(2, 3, true), (4, 3, true), (5, 3, false), (6, 3, false), (7, 8, false)] {
+            guild.voice_states.insert(UserId(user_id), state(user_id, channel_id));
+            guild.members.insert(UserId(user_id), member(user_id, bot));
+        }
+
+        let cache = Arc::new(Cache::default());
+        let (tx, _rx) = unbounded();
+        let mut handler = Handler::with_task_sender(GuildId(1), None, UserId(2), tx);
+        assert_eq!(handler.listener_count(&cache).await, None);
+
+        handler.channel_id = Some(ChannelId(3));
+        assert_eq!(handler.listener_count(&cache).await, None);
+
+        cache.update(&mut GuildCreateEvent { guild: guild.clone(), _nonexhaustive: () }).await;
+        assert_eq!(handler.listener_count(&cache).await, Some(2));
+
+        // Users whose membership is unknown cannot be told apart from bots.
+        guild.voice_states.insert(UserId(9), state(9, 3));
+        cache.update(&mut GuildCreateEvent { guild, _nonexhaustive: () }).await;
+        assert_eq!(handler.listener_count(&cache).await, None);
+    }
+
+    #[test]
+    fn test_region_migration() {
+        let (tx, _rx) = unbounded();
+        let mut handler = Handler::with_task_sender(GuildId(1), None, UserId(2), tx);
+        let mut events = handler.events();
+        let endpoint = |name: &str| Some(format!("{}.discord.media:80", name));
+
+        handler.update_server(&endpoint("old"), "token").unwrap();
+        assert!(events.try_next().is_err());
+
+        // While the guild moves region, Discord first takes the server away,
+        // then sends the new one.
+        handler.update_server(&None, "token").unwrap();
+        assert!(events.try_next().is_err());
+        handler.update_server(&endpoint("new"), "token").unwrap();
+
+        match events.try_next() {
+            Ok(Some(HandlerEvent::VoiceServerChanged { old_endpoint, new_endpoint })) => {
+                assert_eq!(old_endpoint, "old.discord.media");
+                assert_eq!(new_endpoint, "new.discord.media");
+            },
+            _ => panic!("expected the voice server to change"),
+        }
+
+        // The same server again is not a move.
+        handler.update_server(&endpoint("new"), "token").unwrap();
+        assert!(events.try_next().is_err());
+    }
+}
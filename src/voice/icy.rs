@@ -0,0 +1,144 @@
+/// Separates the metadata interleaved by ICY (Shoutcast) servers into an
+/// audio stream from the audio itself.
+///
+/// Servers asked for metadata via the `Icy-MetaData` request header send a
+/// block of it after every `icy-metaint` bytes of audio, prefixed by its
+/// length in units of 16 bytes.
+pub(crate) struct IcyParser {
+    interval: usize,
+    state: State,
+    metadata: Vec<u8>,
+    title: Option<String>,
+}
+
+enum State {
+    /// Reading audio, with the given number of bytes left before metadata.
+    Audio(usize),
+    /// Reading the length of the next metadata block.
+    Length,
+    /// Reading a metadata block, with the given number of bytes left.
+    Metadata(usize),
+}
+
+impl IcyParser {
+    /// Makes a parser for a stream with the given `icy-metaint`.
+    pub(crate) fn new(interval: usize) -> Self {
+        Self {
+            interval,
+            state: State::Audio(interval),
+            metadata: Vec::new(),
+            title: None,
+        }
+    }
+
+    /// Appends the audio in the next chunk of the stream to `audio`,
+    /// returning the stream's title if it changed within the chunk.
+    pub(crate) fn feed(&mut self, mut data: &[u8], audio: &mut Vec<u8>) -> Option<String> {
+        let mut updated = None;
+
+        while !data.is_empty() {
+            match self.state {
+                State::Audio(remaining) => {
+                    let len = remaining.min(data.len());
+                    audio.extend_from_slice(&data[..len]);
+                    data = &data[len..];
+
+                    self.state = if len == remaining {
+                        State::Length
+                    } else {
+                        State::Audio(remaining - len)
+                    };
+                },
+                State::Length => {
+                    let len = usize::from(data[0]) * 16;
+                    data = &data[1..];
+
+                    self.metadata.clear();
+                    self.state = if len == 0 {
+                        State::Audio(self.interval)
+                    } else {
+                        State::Metadata(len)
+                    };
+                },
+                State::Metadata(remaining) => {
+                    let len = remaining.min(data.len());
+                    self.metadata.extend_from_slice(&data[..len]);
+                    data = &data[len..];
+
+                    if len < remaining {
+                        self.state = State::Metadata(remaining - len);
+
+                        continue;
+                    }
+
+                    self.state = State::Audio(self.interval);
+
+                    // Servers may repeat the metadata while it is unchanged.
+                    match parse_stream_title(&self.metadata) {
+                        Some(title) if self.title.as_ref() != Some(&title) => {
+                            self.title = Some(title.clone());
+                            updated = Some(title);
+                        },
+                        _ => {},
+                    }
+                },
+            }
+        }
+
+        updated
+    }
+}
+
+/// Finds the `StreamTitle` in a block of ICY metadata, such as
+/// `StreamTitle='Artist - Song';StreamUrl='';`, if it is set.
+pub(crate) fn parse_stream_title(metadata: &[u8]) -> Option<String> {
+    let text = String::from_utf8_lossy(metadata);
+    let start = text.find("StreamTitle='")? + "StreamTitle='".len();
+    let rest = &text[start..];
+
+    // Titles may themselves contain quotes, so only a quote followed by the
+    // field separator ends one.
+    let end = rest.find("';").unwrap_or_else(|| rest.trim_end_matches('\0').trim_end_matches('\'').len());
+    let title = rest[..end].trim();
+
+    if title.is_empty() {
+        None
+    } else {
+        Some(title.to_string())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_icy_parser() {
+        let mut block = b"StreamTitle='Don't Stop';StreamUrl='';".to_vec();
+        block.resize(48, 0);
+
+        let mut stream = vec![1, 2, 3, 4, 3];
+        stream.extend_from_slice(&block);
+        stream.extend_from_slice(&[5, 6, 7, 8, 0, 9, 10]);
+
+        // The same title again is not reported.
+        let mut repeated = vec![11, 12, 3];
+        repeated.extend_from_slice(&block);
+
+        let mut parser = IcyParser::new(4);
+        let mut audio = Vec::new();
+        let mut titles = Vec::new();
+
+        // Chunks may split the stream anywhere.
+        for chunk in stream.chunks(3).chain(repeated.chunks(100)) {
+            titles.extend(parser.feed(chunk, &mut audio));
+        }
+
+        assert_eq!(audio, (1..=12).collect::<Vec<u8>>());
+        assert_eq!(titles, vec!["Don't Stop".to_string()]);
+
+        assert_eq!(parse_stream_title(b"StreamTitle='';"), None);
+        assert_eq!(parse_stream_title(b"StreamUrl='x';"), None);
+        assert_eq!(parse_stream_title(b"StreamTitle='Song'\0\0"), Some("Song".to_string()));
+    }
+}
@@ -0,0 +1,264 @@
+use crate::gateway::InterMessage;
+use futures::channel::mpsc::UnboundedSender as Sender;
+use serde_json::Value;
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+use tokio::{runtime::Handle, time::delay_for};
+
+/// The number of voice state updates a shard may send per
+/// `DEFAULT_UPDATE_PERIOD`, leaving half of the gateway's limit of 120
+/// messages a minute for heartbeats, presences and other commands.
+pub(crate) const DEFAULT_UPDATES: u32 = 60;
+
+/// The period over which `DEFAULT_UPDATES` may be sent.
+pub(crate) const DEFAULT_UPDATE_PERIOD: Duration = Duration::from_secs(60);
+
+/// A token bucket, holding up to `capacity` tokens and refilling at `capacity`
+/// tokens per `period`.
+#[derive(Debug)]
+struct Bucket {
+    capacity: f64,
+    period: Duration,
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl Bucket {
+    fn new(updates: u32, period: Duration, now: Instant) -> Self {
+        let capacity = f64::from(updates.max(1));
+
+        Self {
+            capacity,
+            period,
+            tokens: capacity,
+            refilled_at: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.refilled_at);
+        let per_sec = self.capacity / self.period.as_secs_f64().max(f64::EPSILON);
+
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * per_sec).min(self.capacity);
+        self.refilled_at = now;
+    }
+
+    /// Takes a token, or returns how long until one is available.
+    fn take(&mut self, now: Instant) -> Result<(), Duration> {
+        self.refill(now);
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+
+            Ok(())
+        } else {
+            let per_sec = self.capacity / self.period.as_secs_f64().max(f64::EPSILON);
+
+            Err(Duration::from_secs_f64((1.0 - self.tokens) / per_sec))
+        }
+    }
+}
+
+struct LimiterState {
+    bucket: Bucket,
+    queue: VecDeque<Value>,
+    draining: bool,
+}
+
+/// Paces the voice state updates of every [`Handler`] on a shard, so that
+/// joining or leaving many channels at once cannot trip the gateway's rate
+/// limit.
+///
+/// Updates within the limit are sent immediately, while bursts beyond it are
+/// queued and sent in order as the limit allows. Each guild has at most one
+/// update queued, as a newer one replaces it in its place. Leaves skip the
+/// queue.
+///
+/// [`Handler`]: struct.Handler.html
+pub(crate) struct UpdateLimiter {
+    ws: Sender<InterMessage>,
+    state: Mutex<LimiterState>,
+}
+
+impl UpdateLimiter {
+    pub(crate) fn new(ws: Sender<InterMessage>) -> Self {
+        Self {
+            ws,
+            state: Mutex::new(LimiterState {
+                bucket: Bucket::new(DEFAULT_UPDATES, DEFAULT_UPDATE_PERIOD, Instant::now()),
+                queue: VecDeque::new(),
+                draining: false,
+            }),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, LimiterState> {
+        self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Sets how many updates may be sent per period, starting with a full
+    /// allowance.
+    pub(crate) fn set_rate(&self, updates: u32, period: Duration) {
+        self.lock().bucket = Bucket::new(updates, period, Instant::now());
+    }
+
+    /// Sends an update now if the limit allows, or queues it otherwise,
+    /// returning whether the shard is still accepting messages.
+    pub(crate) fn send(self: &Arc<Self>, payload: Value) -> bool {
+        let mut state = self.lock();
+
+        if state.queue.is_empty() && state.bucket.take(Instant::now()).is_ok() {
+            return self.ws.unbounded_send(InterMessage::Json(payload)).is_ok();
+        }
+
+        // Only the latest state of a guild matters, so a newer update takes
+        // the place of any still queued for it.
+        let guild_id = &payload["d"]["guild_id"];
+
+        match state.queue.iter_mut().find(|queued| queued["d"]["guild_id"] == *guild_id) {
+            Some(queued) => *queued = payload,
+            None => state.queue.push_back(payload),
+        }
+
+        if !state.draining {
+            match Handle::try_current() {
+                Ok(runtime) => {
+                    state.draining = true;
+
+                    runtime.spawn(Arc::clone(self).drain());
+                },
+                // Without a runtime to pace them, such as when a handler is
+                // dropped after shutdown, queued updates are sent at once.
+                Err(_) => for payload in state.queue.drain(..) {
+                    let _ = self.ws.unbounded_send(InterMessage::Json(payload));
+                },
+            }
+        }
+
+        !self.ws.is_closed()
+    }
+
+    /// Sends an update at once, bypassing the limit, returning whether the
+    /// shard is still accepting messages.
+    ///
+    /// This is for leaving a channel, which must not wait behind a queue that
+    /// could be lost at shutdown. Any updates queued for the same guild are
+    /// dropped, as they would otherwise be sent after it.
+    pub(crate) fn send_now(&self, payload: Value) -> bool {
+        {
+            let mut state = self.lock();
+            let guild_id = &payload["d"]["guild_id"];
+
+            state.queue.retain(|queued| queued["d"]["guild_id"] != *guild_id);
+            let _ = state.bucket.take(Instant::now());
+        }
+
+        self.ws.unbounded_send(InterMessage::Json(payload)).is_ok()
+    }
+
+    async fn drain(self: Arc<Self>) {
+        loop {
+            let wait = {
+                let mut state = self.lock();
+
+                loop {
+                    if state.queue.is_empty() {
+                        state.draining = false;
+
+                        return;
+                    }
+
+                    match state.bucket.take(Instant::now()) {
+                        Ok(()) => if let Some(payload) = state.queue.pop_front() {
+                            let _ = self.ws.unbounded_send(InterMessage::Json(payload));
+                        },
+                        Err(wait) => break wait,
+                    }
+                }
+            };
+
+            delay_for(wait).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_bucket() {
+        let start = Instant::now();
+        let mut bucket = Bucket::new(2, Duration::from_secs(1), start);
+
+        assert!(bucket.take(start).is_ok());
+        assert!(bucket.take(start).is_ok());
+
+        let wait = bucket.take(start).unwrap_err();
+        assert!(wait > Duration::from_millis(490) && wait <= Duration::from_millis(500));
+
+        let refilled = start + wait + Duration::from_millis(1);
+        assert!(bucket.take(refilled).is_ok());
+        assert!(bucket.take(refilled).is_err());
+
+        // Idle time never builds up more than the capacity.
+        let later = start + Duration::from_secs(10);
+        assert!(bucket.take(later).is_ok());
+        assert!(bucket.take(later).is_ok());
+        assert!(bucket.take(later).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_send_now_bypasses_limit() {
+        use futures::channel::mpsc::unbounded;
+        use serde_json::json;
+
+        let (tx, mut rx) = unbounded();
+        let limiter = Arc::new(UpdateLimiter::new(tx));
+        limiter.set_rate(1, Duration::from_secs(60));
+
+        let update = |guild_id: u64, channel_id: Option<u64>| json!({
+            "d": { "channel_id": channel_id, "guild_id": guild_id },
+        });
+
+        assert!(limiter.send(update(1, Some(2))));
+        assert!(limiter.send(update(1, Some(3))));
+        assert!(limiter.send(update(4, Some(5))));
+        assert!(limiter.send_now(update(1, None)));
+
+        let mut sent = Vec::new();
+        while let Ok(Some(InterMessage::Json(value))) = rx.try_next() {
+            sent.push(value);
+        }
+
+        // The leave goes out at once, while the join it replaced is dropped,
+        // and the other guild's update stays queued.
+        assert_eq!(sent, vec![update(1, Some(2)), update(1, None)]);
+        assert_eq!(limiter.lock().queue, vec![update(4, Some(5))]);
+    }
+    #[tokio::test]
+    async fn test_queued_updates_coalesce() {
+        use futures::channel::mpsc::unbounded;
+        use serde_json::json;
+
+        let (tx, _rx) = unbounded();
+        let limiter = Arc::new(UpdateLimiter::new(tx));
+        limiter.set_rate(1, Duration::from_secs(60));
+
+        let update = |guild_id: u64, channel_id: u64| json!({
+            "d": { "channel_id": channel_id, "guild_id": guild_id },
+        });
+
+        assert!(limiter.send(update(1, 2)));
+        assert!(limiter.send(update(1, 3)));
+        assert!(limiter.send(update(4, 5)));
+        assert!(limiter.send(update(1, 6)));
+
+        // The newest update for the first guild keeps the place of the one
+        // it replaced.
+        assert_eq!(limiter.lock().queue, vec![update(1, 6), update(4, 5)]);
+    }
+}
@@ -1,8 +1,22 @@
+#[cfg(feature = "cache")]
+use crate::{cache::Cache, model::{channel::ChannelType, Permissions}};
 use crate::gateway::InterMessage;
 use crate::model::id::{ChannelId, GuildId, UserId};
-use std::collections::HashMap;
+use std::{collections::HashMap, future::Future, sync::Arc, time::Duration};
 use futures::channel::mpsc::UnboundedSender as Sender;
-use super::Handler;
+use super::limiter::UpdateLimiter;
+use super::streamer;
+use super::{
+    audio,
+    AggregateVoiceStats,
+    Audio,
+    AudioSource,
+    ConnectionState,
+    Handler,
+    HandlerConfig,
+    TrackHandle,
+    VoiceError,
+};
 
 /// A manager is a struct responsible for managing [`Handler`]s which belong to
 /// a single [`Shard`]. This is a fairly complex key-value store,
@@ -16,25 +30,139 @@ use super::Handler;
 /// If a `guild_id` is provided, then the target is the guild, as a user
 /// can not be connected to two channels within one guild simultaneously.
 ///
+/// As such, there is at most one [`Handler`] per guild, and joining another
+/// channel in the same guild moves its connection there rather than making a
+/// second one. Bots which need to be heard in several channels of one guild
+/// at once must do so through separate bot users. Use [`try_join`] to be told
+/// about an existing connection instead of moving it.
+///
 /// [`Handler`]: struct.Handler.html
+/// [`try_join`]: #method.try_join
 /// [guild's channel]: ../../model/channel/enum.ChannelType.html#variant.Voice
 /// [`Shard`]: ../gateway/struct.Shard.html
 #[derive(Clone)]
 pub struct Manager {
+    default_deaf: bool,
+    default_mute: bool,
     handlers: HashMap<GuildId, Handler>,
+    limiter: Arc<UpdateLimiter>,
+    task_queue_limit: Option<usize>,
     user_id: UserId,
-    ws: Sender<InterMessage>,
 }
 
 impl Manager {
     pub(crate) fn new(ws: Sender<InterMessage>, user_id: UserId) -> Manager {
         Manager {
+            default_deaf: false,
+            default_mute: false,
             handlers: HashMap::new(),
+            limiter: Arc::new(UpdateLimiter::new(ws)),
+            task_queue_limit: None,
             user_id,
-            ws,
         }
     }
 
+    /// Sets how many voice state updates the [`Handler`]s of this shard may
+    /// send to the gateway per `period`, together.
+    ///
+    /// Updates beyond this, such as when joining many channels at once, are
+    /// queued and sent as the limit allows rather than risking a disconnect
+    /// from the gateway. Defaults to 60 updates per minute, leaving room
+    /// within the gateway's limit of 120 messages per minute for other
+    /// commands.
+    ///
+    /// Leaving a channel is never queued, so that handlers dropped at
+    /// shutdown still leave, though it counts towards the limit.
+    ///
+    /// [`Handler`]: struct.Handler.html
+    pub fn set_update_rate(&mut self, updates: u32, period: Duration) {
+        self.limiter.set_rate(updates, period);
+    }
+
+    /// Limits how many sources backed by subprocesses, such as those made by
+    /// [`ffmpeg`], [`ytdl`] and [`http`], may run at once across every
+    /// [`Handler`] of every shard, so that a bot in many guilds cannot
+    /// exhaust its host with `ffmpeg` processes. Passing 0 removes the
+    /// limit, which is the default.
+    ///
+    /// Each source holds a permit from when its processes are spawned until
+    /// it is dropped, counting as one even where a `youtube-dl` process feeds
+    /// `ffmpeg` and another finds the track's duration, and keeps it when
+    /// restarted to seek. Finding a queued track's title via `youtube-dl`
+    /// takes a permit while it runs, but probing with `ffprobe` is not
+    /// counted.
+    ///
+    /// Once the limit is reached, creating another source waits, without
+    /// failing, until a running one is dropped, with waiting sources started
+    /// in the order they were created. Wrap the call in a timeout to give up
+    /// waiting instead. Sources already running when the limit is changed
+    /// are not counted against the new one.
+    ///
+    /// [`ffmpeg`]: fn.ffmpeg.html
+    /// [`Handler`]: struct.Handler.html
+    /// [`http`]: fn.http.html
+    /// [`ytdl`]: fn.ytdl.html
+    pub fn set_max_subprocesses(&mut self, max: usize) {
+        streamer::set_max_subprocesses(max);
+    }
+
+    /// Limits how many messages may wait for the background task of each
+    /// [`Handler`] of this shard, existing or created later, before sources
+    /// are rejected with [`VoiceError::Busy`]. Unlimited by default.
+    ///
+    /// Refer to [`Handler::set_task_queue_limit`] for details.
+    ///
+    /// [`Handler`]: struct.Handler.html
+    /// [`Handler::set_task_queue_limit`]: struct.Handler.html#method.set_task_queue_limit
+    /// [`VoiceError::Busy`]: enum.VoiceError.html#variant.Busy
+    pub fn set_task_queue_limit(&mut self, limit: Option<usize>) {
+        self.task_queue_limit = limit;
+
+        for handler in self.handlers.values_mut() {
+            handler.set_task_queue_limit(limit);
+        }
+    }
+
+    /// Whether newly created [`Handler`]s join deafened.
+    ///
+    /// [`Handler`]: struct.Handler.html
+    pub fn default_deaf(&self) -> bool {
+        self.default_deaf
+    }
+
+    /// Sets whether [`Handler`]s created by future calls to [`join`] start
+    /// deafened, so that they join their first channel without a separate
+    /// [`Handler::deafen`] call.
+    ///
+    /// Existing handlers are unaffected.
+    ///
+    /// [`Handler`]: struct.Handler.html
+    /// [`Handler::deafen`]: struct.Handler.html#method.deafen
+    /// [`join`]: #method.join
+    pub fn set_default_deaf(&mut self, deaf: bool) {
+        self.default_deaf = deaf;
+    }
+
+    /// Whether newly created [`Handler`]s join muted.
+    ///
+    /// [`Handler`]: struct.Handler.html
+    pub fn default_mute(&self) -> bool {
+        self.default_mute
+    }
+
+    /// Sets whether [`Handler`]s created by future calls to [`join`] start
+    /// muted, so that they join their first channel without a separate
+    /// [`Handler::mute`] call.
+    ///
+    /// Existing handlers are unaffected.
+    ///
+    /// [`Handler`]: struct.Handler.html
+    /// [`Handler::mute`]: struct.Handler.html#method.mute
+    /// [`join`]: #method.join
+    pub fn set_default_mute(&mut self, mute: bool) {
+        self.default_mute = mute;
+    }
+
     /// Retrieves an immutable handler for the given target, if one exists.
     #[inline]
     pub fn get<G: Into<GuildId>>(&self, guild_id: G) -> Option<&Handler> {
@@ -56,6 +184,74 @@ impl Manager {
         self.handlers.get_mut(&guild_id)
     }
 
+    /// Lists the guilds in which a voice connection is live or being made,
+    /// in order of their Ids.
+    ///
+    /// This is a snapshot of each [`Handler`]'s [`connection_state`], so
+    /// handlers which exist but have no connection, such as after leaving a
+    /// channel, are not included.
+    ///
+    /// [`Handler`]: struct.Handler.html
+    /// [`connection_state`]: struct.Handler.html#method.connection_state
+    pub fn connected_guilds(&self) -> Vec<GuildId> {
+        let mut guilds = self.handlers
+            .iter()
+            .filter(|(_, handler)| matches!(
+                handler.connection_state(),
+                ConnectionState::Connected | ConnectionState::Connecting,
+            ))
+            .map(|(guild_id, _)| *guild_id)
+            .collect::<Vec<_>>();
+        guilds.sort();
+
+        guilds
+    }
+
+    /// Sums the [`network_stats`] of every [`Handler`] whose connection is
+    /// live, for monitoring how much voice traffic the shard is carrying.
+    ///
+    /// Each handler's statistics are read as a single snapshot, and no
+    /// handler can be added or removed while the manager is borrowed, so
+    /// the totals never mix a handler's old and new figures. Handlers are
+    /// still read one after another, however, so figures from different
+    /// handlers may be a frame apart.
+    ///
+    /// [`Handler`]: struct.Handler.html
+    /// [`network_stats`]: struct.Handler.html#method.network_stats
+    pub fn aggregate_stats(&self) -> AggregateVoiceStats {
+        let mut aggregate = AggregateVoiceStats::default();
+        let mut latency = Duration::default();
+        let mut latencies = 0;
+
+        let live = self.handlers
+            .values()
+            .filter(|handler| handler.connection_state() == ConnectionState::Connected);
+
+        for handler in live {
+            let stats = handler.network_stats();
+
+            aggregate.connections += 1;
+            aggregate.tracks_playing += stats.tracks_playing;
+            aggregate.packets_sent += stats.packets_sent;
+            aggregate.packets_received += stats.packets_received;
+            aggregate.packets_lost += stats.packets_lost;
+            aggregate.bytes_sent += stats.bytes_sent;
+            aggregate.bytes_received += stats.bytes_received;
+            aggregate.packets_per_second += stats.packets_per_second;
+
+            if let Some(heartbeat) = stats.heartbeat_latency {
+                latency += heartbeat;
+                latencies += 1;
+            }
+        }
+
+        if latencies > 0 {
+            aggregate.average_latency = Some(latency / latencies);
+        }
+
+        aggregate
+    }
+
     /// Connects to a target by retrieving its relevant [`Handler`] and
     /// connecting, or creating the handler if required.
     ///
@@ -103,7 +299,14 @@ impl Manager {
             }
         }
 
-        let mut handler = Handler::new(guild_id, self.ws.clone(), self.user_id);
+        let mut handler = Handler::new(
+            guild_id,
+            Arc::clone(&self.limiter),
+            self.user_id,
+            self.default_deaf,
+            self.default_mute,
+        );
+        handler.set_task_queue_limit(self.task_queue_limit);
         handler.join(channel_id);
 
         self.handlers.insert(guild_id, handler);
@@ -112,6 +315,223 @@ impl Manager {
         self.handlers.get_mut(&guild_id).unwrap()
     }
 
+    /// Connects to a target as with [`join`], and plays a source once the
+    /// connection is live, resolving with a handle to its track.
+    ///
+    /// The source is only passed to the guild's [`Handler`] once the voice
+    /// connection in the channel is confirmed, as with
+    /// [`Handler::move_to`], so none of it is played into a connection which
+    /// is still being made, and it is never played if joining fails. If the
+    /// handler is already connected to the channel, the source is played
+    /// straight away, alongside anything else playing.
+    ///
+    /// The returned future does not borrow the manager, which must not be
+    /// locked while it is awaited, or the gateway's updates confirming the
+    /// connection cannot be delivered.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,ignore
+    /// use serenity::voice;
+    ///
+    /// let source = voice::ytdl(url).await?;
+    /// let joined = manager_lock.lock().await.join_and_play(guild_id, channel_id, source);
+    /// let track = joined.await?;
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Fails as with [`Handler::move_to`] if the connection is not made, and
+    /// with [`VoiceError::TrackEnded`] if the handler's task has stopped.
+    ///
+    /// [`Handler`]: struct.Handler.html
+    /// [`Handler::move_to`]: struct.Handler.html#method.move_to
+    /// [`join`]: #method.join
+    /// [`VoiceError::TrackEnded`]: enum.VoiceError.html#variant.TrackEnded
+    pub fn join_and_play<C, G>(
+        &mut self,
+        guild_id: G,
+        channel_id: C,
+        source: Box<dyn AudioSource>,
+    ) -> impl Future<Output = Result<TrackHandle, VoiceError>> + Send + 'static
+        where C: Into<ChannelId>, G: Into<GuildId> {
+        let guild_id = guild_id.into();
+
+        if !self.handlers.contains_key(&guild_id) {
+            let mut handler = Handler::new(
+                guild_id,
+                Arc::clone(&self.limiter),
+                self.user_id,
+                self.default_deaf,
+                self.default_mute,
+            );
+            handler.set_task_queue_limit(self.task_queue_limit);
+
+            self.handlers.insert(guild_id, handler);
+        }
+
+        // Actually safe, as the key either existed or was inserted above.
+        let handler = self.handlers.get_mut(&guild_id).unwrap();
+        let connected = handler.move_to(channel_id.into());
+        let track = handler.unplayed_track(Audio::new(source));
+
+        async move {
+            connected.await?;
+            track.start()?;
+
+            Ok(track)
+        }
+    }
+
+    /// Connects to a target as with [`join`], unless its guild is already
+    /// connected to a different channel.
+    ///
+    /// Joining the channel which the guild's handler is already in succeeds,
+    /// as does joining with a handler which has left its channel.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`VoiceError::AlreadyInChannel`] with the current channel if
+    /// the guild's handler is in another channel, which is left unchanged.
+    ///
+    /// [`join`]: #method.join
+    /// [`VoiceError::AlreadyInChannel`]: enum.VoiceError.html#variant.AlreadyInChannel
+    #[inline]
+    pub fn try_join<C, G>(&mut self, guild_id: G, channel_id: C) -> Result<&mut Handler, VoiceError>
+        where C: Into<ChannelId>, G: Into<GuildId> {
+        self._try_join(guild_id.into(), channel_id.into())
+    }
+
+    fn _try_join(
+        &mut self,
+        guild_id: GuildId,
+        channel_id: ChannelId,
+    ) -> Result<&mut Handler, VoiceError> {
+        let current = self.handlers.get(&guild_id).and_then(|handler| handler.channel_id);
+
+        match current {
+            Some(current) if current != channel_id => Err(VoiceError::AlreadyInChannel(current)),
+            _ => Ok(self._join(guild_id, channel_id)),
+        }
+    }
+
+    /// Connects to a channel as with [`join`], after checking in the cache
+    /// that the current user may connect and speak there, so that a join
+    /// which would leave the bot unheard fails with a clear error instead.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`VoiceError::UnknownChannel`] if the guild or channel is not
+    /// cached, [`VoiceError::NotVoiceChannel`] if the channel is not a voice
+    /// channel, and [`VoiceError::MissingPermissions`] with the permissions
+    /// lacking if the current user does not have both [Connect] and [Speak]
+    /// in the channel. In each case, no update is sent.
+    ///
+    /// [`join`]: #method.join
+    /// [`VoiceError::MissingPermissions`]: enum.VoiceError.html#variant.MissingPermissions
+    /// [`VoiceError::NotVoiceChannel`]: enum.VoiceError.html#variant.NotVoiceChannel
+    /// [`VoiceError::UnknownChannel`]: enum.VoiceError.html#variant.UnknownChannel
+    /// [Connect]: ../model/permissions/struct.Permissions.html#associatedconstant.CONNECT
+    /// [Speak]: ../model/permissions/struct.Permissions.html#associatedconstant.SPEAK
+    #[cfg(feature = "cache")]
+    pub async fn join_checked<C, G>(
+        &mut self,
+        cache: impl AsRef<Cache>,
+        guild_id: G,
+        channel_id: C,
+    ) -> Result<&mut Handler, VoiceError>
+        where C: Into<ChannelId>, G: Into<GuildId> {
+        let guild_id = guild_id.into();
+        let channel_id = channel_id.into();
+
+        let guild = cache.as_ref()
+            .guild(guild_id)
+            .await
+            .ok_or(VoiceError::UnknownChannel)?;
+        let channel = guild.channels.get(&channel_id).ok_or(VoiceError::UnknownChannel)?;
+
+        if channel.kind != ChannelType::Voice {
+            return Err(VoiceError::NotVoiceChannel);
+        }
+
+        let missing = missing_voice_permissions(guild.user_permissions_in(channel_id, self.user_id));
+
+        if !missing.is_empty() {
+            return Err(VoiceError::MissingPermissions(missing));
+        }
+
+        Ok(self._join(guild_id, channel_id))
+    }
+
+    /// Connects to a target as with [`join`], first applying the given
+    /// settings to its [`Handler`].
+    ///
+    /// If the handler is created by this call, every setting is in place
+    /// before it asks to join the channel, so it never briefly joins with the
+    /// wrong deafen or mute state. An existing handler has the settings
+    /// applied before switching to the channel.
+    ///
+    /// The [`default_deaf`] and [`default_mute`] of the manager are ignored.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`VoiceError::InvalidBitrate`] if the configured bitrate is not
+    /// positive, in which case no handler is created or changed.
+    ///
+    /// [`Handler`]: struct.Handler.html
+    /// [`default_deaf`]: #method.default_deaf
+    /// [`default_mute`]: #method.default_mute
+    /// [`join`]: #method.join
+    /// [`VoiceError::InvalidBitrate`]: enum.VoiceError.html#variant.InvalidBitrate
+    #[inline]
+    pub fn join_with<C, G>(
+        &mut self,
+        guild_id: G,
+        channel_id: C,
+        config: HandlerConfig,
+    ) -> Result<&mut Handler, VoiceError>
+        where C: Into<ChannelId>, G: Into<GuildId> {
+        self._join_with(guild_id.into(), channel_id.into(), config)
+    }
+
+    fn _join_with(
+        &mut self,
+        guild_id: GuildId,
+        channel_id: ChannelId,
+        config: HandlerConfig,
+    ) -> Result<&mut Handler, VoiceError> {
+        let bitrate = audio::clamp_bitrate(config.bitrate)?;
+
+        if let Some(handler) = self.handlers.get_mut(&guild_id) {
+            handler.set_auto_disconnect(config.auto_disconnect);
+            handler.set_bind_address(config.bind_address);
+            handler.set_bitrate(bitrate)?;
+            handler.set_encryption_mode(config.encryption_mode);
+            handler.deafen(config.deaf);
+            handler.mute(config.mute);
+            handler.switch_to(channel_id);
+        } else {
+            let mut handler = Handler::new(
+                guild_id,
+                Arc::clone(&self.limiter),
+                self.user_id,
+                config.deaf,
+                config.mute,
+            );
+            handler.set_task_queue_limit(self.task_queue_limit);
+            handler.set_auto_disconnect(config.auto_disconnect);
+            handler.set_bind_address(config.bind_address);
+            handler.set_bitrate(bitrate)?;
+            handler.set_encryption_mode(config.encryption_mode);
+            handler.join(channel_id);
+
+            self.handlers.insert(guild_id, handler);
+        }
+
+        // Actually safe, as the key either existed or was inserted above.
+        Ok(self.handlers.get_mut(&guild_id).unwrap())
+    }
+
     /// Retrieves the [handler][`Handler`] for the given target and leaves the
     /// associated voice channel, if connected.
     ///
@@ -151,3 +571,134 @@ impl Manager {
         self.handlers.remove(&guild_id);
     }
 }
+
+/// The permissions needed to be heard in a voice channel which are not
+/// among the given ones.
+#[cfg(feature = "cache")]
+fn missing_voice_permissions(permissions: Permissions) -> Permissions {
+    (Permissions::CONNECT | Permissions::SPEAK) - permissions
+}
+
+#[cfg(test)]
+mod test {
+    use futures::channel::mpsc::unbounded;
+    use super::*;
+
+    #[tokio::test]
+    async fn test_try_join() {
+        let (tx, _rx) = unbounded();
+        let mut manager = Manager::new(tx, UserId(1));
+
+        assert!(manager.try_join(GuildId(2), ChannelId(3)).is_ok());
+        assert!(manager.try_join(GuildId(2), ChannelId(3)).is_ok());
+        assert!(matches!(
+            manager.try_join(GuildId(2), ChannelId(4)),
+            Err(VoiceError::AlreadyInChannel(ChannelId(3))),
+        ));
+        assert_eq!(manager.get(GuildId(2)).unwrap().channel_id, Some(ChannelId(3)));
+
+        manager.leave(GuildId(2));
+        assert!(manager.try_join(GuildId(2), ChannelId(4)).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_join_and_play() {
+        use futures::FutureExt;
+        use std::io::Cursor;
+        use super::super::{pcm, TrackStatus};
+
+        let (tx, _rx) = unbounded();
+        let mut manager = Manager::new(tx, UserId(1));
+
+        // Nothing is played until the connection is confirmed.
+        let mut joined = manager.join_and_play(GuildId(2), ChannelId(3), pcm(true, Cursor::new(vec![0u8; 3840]))).boxed();
+        assert!((&mut joined).now_or_never().is_none());
+
+        manager.get(GuildId(2)).unwrap().shared.set_connection_state(ConnectionState::Connected);
+        let track = joined.await.unwrap();
+        assert_eq!(track.status(), TrackStatus::Pending);
+
+        // A failed connection never plays the source.
+        let failed = manager.join_and_play(GuildId(4), ChannelId(5), pcm(true, Cursor::new(Vec::new())));
+        manager.get(GuildId(4)).unwrap().shared.set_connection_state(ConnectionState::Connecting);
+        manager.get(GuildId(4)).unwrap().shared.set_connection_state(ConnectionState::Disconnected);
+        assert!(matches!(failed.await, Err(VoiceError::NotConnected)));
+    }
+
+    #[tokio::test]
+    async fn test_connected_guilds() {
+        let (tx, _rx) = unbounded();
+        let mut manager = Manager::new(tx, UserId(1));
+
+        // Handlers awaiting Discord's updates have made no connection yet.
+        manager.join(GuildId(4), ChannelId(5));
+        manager.join(GuildId(2), ChannelId(3));
+        manager.join(GuildId(6), ChannelId(7));
+        assert!(manager.connected_guilds().is_empty());
+
+        let set_state = |manager: &mut Manager, guild_id, state| {
+            manager.get(GuildId(guild_id)).unwrap().shared.set_connection_state(state);
+        };
+
+        set_state(&mut manager, 4, ConnectionState::Connected);
+        set_state(&mut manager, 2, ConnectionState::Connecting);
+        assert_eq!(manager.connected_guilds(), vec![GuildId(2), GuildId(4)]);
+
+        set_state(&mut manager, 4, ConnectionState::Disconnected);
+        assert_eq!(manager.connected_guilds(), vec![GuildId(2)]);
+    }
+
+    #[tokio::test]
+    async fn test_aggregate_stats() {
+        let (tx, _rx) = unbounded();
+        let mut manager = Manager::new(tx, UserId(1));
+
+        let stats = manager.aggregate_stats();
+        assert_eq!(stats.connections, 0);
+        assert_eq!(stats.average_latency, None);
+
+        // Handlers without a live connection contribute nothing.
+        manager.join(GuildId(2), ChannelId(3));
+        let stats = manager.aggregate_stats();
+        assert_eq!(stats.connections, 0);
+        assert_eq!(stats.packets_per_second, 0);
+
+        manager.join(GuildId(4), ChannelId(5));
+        manager.join(GuildId(6), ChannelId(7));
+
+        for &(guild_id, latency) in &[(2, 30), (4, 50), (6, 0)] {
+            let shared = &manager.get(GuildId(guild_id)).unwrap().shared;
+
+            // A handler which is not connected is still left out.
+            if latency > 0 {
+                shared.set_connection_state(ConnectionState::Connected);
+            }
+
+            shared.update_stats(|stats| {
+                stats.tracks_playing = 2;
+                stats.packets_per_second = 50;
+                stats.packets_sent = 1000;
+                stats.heartbeat_latency = Some(Duration::from_millis(latency));
+            });
+        }
+
+        let stats = manager.aggregate_stats();
+        assert_eq!(stats.connections, 2);
+        assert_eq!(stats.tracks_playing, 4);
+        assert_eq!(stats.packets_per_second, 100);
+        assert_eq!(stats.packets_sent, 2000);
+        assert_eq!(stats.average_latency, Some(Duration::from_millis(40)));
+    }
+
+    #[test]
+    #[cfg(feature = "cache")]
+    fn test_missing_voice_permissions() {
+        assert!(missing_voice_permissions(Permissions::CONNECT | Permissions::SPEAK).is_empty());
+        assert!(missing_voice_permissions(Permissions::all()).is_empty());
+        assert_eq!(missing_voice_permissions(Permissions::CONNECT), Permissions::SPEAK);
+        assert_eq!(
+            missing_voice_permissions(Permissions::SEND_MESSAGES),
+            Permissions::CONNECT | Permissions::SPEAK,
+        );
+    }
+}
@@ -0,0 +1,346 @@
+//! Tools for testing code which drives a [`Handler`], without a gateway or
+//! voice server.
+//!
+//! This module requires the `voice_mock` feature.
+//!
+//! # Examples
+//!
+//! Check that a bot joins and then leaves a channel:
+//!
+//! ```rust,ignore
+//! use serenity::model::id::{ChannelId, GuildId, UserId};
+//! use serenity::voice::mock::{MockHandler, TaskMessage};
+//!
+//! let mut mock = MockHandler::new(GuildId(1), UserId(2));
+//!
+//! mock.handler().join(ChannelId(3));
+//! mock.voice_state(Some(ChannelId(3)), "session");
+//! mock.voice_server("example.discord.media", "token").unwrap();
+//! assert!(matches!(mock.task_messages()[..], [TaskMessage::Connect { .. }]));
+//!
+//! mock.handler().leave();
+//! assert!(mock.gateway_updates()[1]["d"]["channel_id"].is_null());
+//! ```
+//!
+//! [`Handler`]: ../struct.Handler.html
+
+use crate::gateway::InterMessage;
+use crate::model::{
+    id::{ChannelId, GuildId, UserId},
+    voice::VoiceState,
+};
+use futures::channel::mpsc::{unbounded, UnboundedReceiver as Receiver};
+use serde_json::Value;
+use std::sync::Arc;
+use super::limiter::UpdateLimiter;
+use super::{Handler, Status, VoiceError};
+
+/// A message which a [`MockHandler`]'s handler sent to its voice task.
+///
+/// [`MockHandler`]: struct.MockHandler.html
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum TaskMessage {
+    /// Connect to a voice server.
+    Connect {
+        endpoint: String,
+        session_id: String,
+    },
+    /// Drop the voice connection.
+    Disconnect,
+    /// Start playing a source, alongside or in place of others.
+    Play,
+    /// Stop playing every source.
+    Stop,
+    /// Stop playing every source, and empty the queue.
+    StopAndClear,
+    /// Stop or start sending audio.
+    Mute(bool),
+    /// Any other message, given by name, such as a change of receiver.
+    Other(&'static str),
+}
+
+impl From<Status> for TaskMessage {
+    fn from(status: Status) -> Self {
+        match status {
+            Status::Connect(info) => TaskMessage::Connect {
+                endpoint: info.endpoint,
+                session_id: info.session_id,
+            },
+            Status::Disconnect => TaskMessage::Disconnect,
+            Status::SetSender(Some(_))
+            | Status::FadeToSender(..)
+            | Status::AddSender(_) => TaskMessage::Play,
+            Status::SetSender(None) => TaskMessage::Stop,
+            Status::StopAndClear => TaskMessage::StopAndClear,
+            Status::Mute(mute) => TaskMessage::Mute(mute),
+            Status::SetReceiver(_) => TaskMessage::Other("SetReceiver"),
+            Status::AddReceiver(_) => TaskMessage::Other("AddReceiver"),
+            Status::RemoveReceiver(_) => TaskMessage::Other("RemoveReceiver"),
+            Status::SetAudioStream(_) => TaskMessage::Other("SetAudioStream"),
+            Status::AddUserStream(..) => TaskMessage::Other("AddUserStream"),
+            Status::SetReceiveFilter(_) => TaskMessage::Other("SetReceiveFilter"),
+            Status::SetReceiveGain(..) => TaskMessage::Other("SetReceiveGain"),
+            Status::SetReceiveAgc(_) => TaskMessage::Other("SetReceiveAgc"),
+            Status::SetReceiveBufferLimit(_) => TaskMessage::Other("SetReceiveBufferLimit"),
+            Status::SetReceiveFormat(..) => TaskMessage::Other("SetReceiveFormat"),
+            Status::ForceHeartbeat => TaskMessage::Other("ForceHeartbeat"),
+            Status::SendVoiceOp(..) => TaskMessage::Other("SendVoiceOp"),
+            Status::Reconnect(_) => TaskMessage::Other("Reconnect"),
+            Status::SetBitrate(_) => TaskMessage::Other("SetBitrate"),
+            Status::SetOpusConfig(_) => TaskMessage::Other("SetOpusConfig"),
+            Status::ReconfigureEncoder(_) => TaskMessage::Other("ReconfigureEncoder"),
+            Status::SetOpusComplexity(_) => TaskMessage::Other("SetOpusComplexity"),
+            Status::SetAdaptiveComplexity(_) => TaskMessage::Other("SetAdaptiveComplexity"),
+            Status::SetAdaptiveBitrate(_) => TaskMessage::Other("SetAdaptiveBitrate"),
+            Status::SetAdaptiveChannels(_) => TaskMessage::Other("SetAdaptiveChannels"),
+            Status::SetDtx(_) => TaskMessage::Other("SetDtx"),
+            Status::SetVolumeRamp(_) => TaskMessage::Other("SetVolumeRamp"),
+            Status::SetPacingMode(_) => TaskMessage::Other("SetPacingMode"),
+            Status::SetCatchSourcePanics(_) => TaskMessage::Other("SetCatchSourcePanics"),
+            Status::SetBusVolume(..) => TaskMessage::Other("SetBusVolume"),
+            Status::StopBus(_) => TaskMessage::Other("StopBus"),
+            Status::SetPlaybackIdleTimeout(_) => TaskMessage::Other("SetPlaybackIdleTimeout"),
+            Status::SetSpeakingIndicator(_) => TaskMessage::Other("SetSpeakingIndicator"),
+            Status::SetRtpPayloadType(_) => TaskMessage::Other("SetRtpPayloadType"),
+            Status::SetRtpHeaderExtension(_) => TaskMessage::Other("SetRtpHeaderExtension"),
+            Status::SetEncryptionMode(_) => TaskMessage::Other("SetEncryptionMode"),
+            Status::SetEncryptionBackend(_) => TaskMessage::Other("SetEncryptionBackend"),
+            Status::SetCloseCode(_) => TaskMessage::Other("SetCloseCode"),
+            Status::SetLimiter(_) => TaskMessage::Other("SetLimiter"),
+            Status::SetHeartbeatTimeout(_) => TaskMessage::Other("SetHeartbeatTimeout"),
+            Status::SetConnectTimeout(_) => TaskMessage::Other("SetConnectTimeout"),
+            Status::SetOutputTee(_) => TaskMessage::Other("SetOutputTee"),
+            Status::SetPacketObserver(_) => TaskMessage::Other("SetPacketObserver"),
+        }
+    }
+}
+
+/// A [`Handler`] with no voice task, which records the gateway updates and
+/// task messages it sends instead of acting on them.
+///
+/// Voice state and server updates, which Discord would send after a join, can
+/// be fed in via [`voice_state`] and [`voice_server`].
+///
+/// [`Handler`]: ../struct.Handler.html
+/// [`voice_server`]: #method.voice_server
+/// [`voice_state`]: #method.voice_state
+pub struct MockHandler {
+    handler: Handler,
+    gateway: Receiver<InterMessage>,
+    task: Receiver<Status>,
+}
+
+impl MockHandler {
+    /// Creates a handler for the given guild, acting as the given user.
+    pub fn new(guild_id: GuildId, user_id: UserId) -> Self {
+        let (ws, gateway) = unbounded();
+        let (sender, task) = unbounded();
+
+        Self {
+            handler: Handler::with_task_sender(
+                guild_id,
+                Some(Arc::new(UpdateLimiter::new(ws))),
+                user_id,
+                sender,
+            ),
+            gateway,
+            task,
+        }
+    }
+
+    /// Returns the handler, to be driven by the code under test.
+    pub fn handler(&mut self) -> &mut Handler {
+        &mut self.handler
+    }
+
+    /// Feeds the handler a voice state update for its own user, as sent by
+    /// Discord after it joins or leaves a channel.
+    pub fn voice_state(&mut self, channel_id: Option<ChannelId>, session_id: &str) {
+        let state = VoiceState {
+            channel_id,
+            deaf: false,
+            mute: false,
+            self_deaf: self.handler.self_deaf,
+            self_mute: self.handler.self_mute,
+            self_stream: None,
+            self_video: self.handler.self_video,
+            session_id: session_id.to_string(),
+            suppress: false,
+            token: None,
+            user_id: self.handler.user_id,
+            _nonexhaustive: (),
+        };
+
+        self.handler.update_state(&state);
+    }
+
+    /// Feeds the handler a voice server update, as sent by Discord after it
+    /// joins a channel.
+    ///
+    /// # Errors
+    ///
+    /// Fails as with [`Handler::update_server`].
+    ///
+    /// [`Handler::update_server`]: ../struct.Handler.html#method.update_server
+    pub fn voice_server(&mut self, endpoint: &str, token: &str) -> Result<(), VoiceError> {
+        self.handler.update_server(&Some(endpoint.to_string()), token)
+    }
+
+    /// Takes the voice state updates sent to the gateway since the last call,
+    /// as their JSON payloads.
+    pub fn gateway_updates(&mut self) -> Vec<Value> {
+        let mut updates = Vec::new();
+
+        while let Ok(Some(message)) = self.gateway.try_next() {
+            if let InterMessage::Json(value) = message {
+                updates.push(value);
+            }
+        }
+
+        updates
+    }
+
+    /// Takes the messages sent to the voice task since the last call.
+    pub fn task_messages(&mut self) -> Vec<TaskMessage> {
+        let mut messages = Vec::new();
+
+        while let Ok(Some(status)) = self.task.try_next() {
+            messages.push(status.into());
+        }
+
+        messages
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::voice::{AfkPolicy, ForceDisconnectPolicy, HandlerEvent};
+    use super::*;
+
+    #[test]
+    fn test_join_and_leave() {
+        let mut mock = MockHandler::new(GuildId(1), UserId(2));
+        mock.handler().set_switch_debounce(Default::default());
+
+        mock.handler().join(ChannelId(3));
+        mock.voice_state(Some(ChannelId(3)), "session");
+        mock.voice_server("example.discord.media:80", "token").unwrap();
+
+        assert_eq!(mock.task_messages(), vec![TaskMessage::Connect {
+            endpoint: "example.discord.media".to_string(),
+            session_id: "session".to_string(),
+        }]);
+
+        mock.handler().mute(true);
+        mock.handler().leave();
+
+        let updates = mock.gateway_updates();
+        assert_eq!(updates.len(), 3);
+        assert_eq!(updates[0]["d"]["channel_id"], 3);
+        assert_eq!(updates[1]["d"]["self_mute"], true);
+        assert!(updates[2]["d"]["channel_id"].is_null());
+        assert_eq!(mock.task_messages(), vec![TaskMessage::Mute(true), TaskMessage::Disconnect]);
+    }
+
+    #[test]
+    fn test_force_disconnect_rejoin() {
+        let mut mock = MockHandler::new(GuildId(1), UserId(2));
+        mock.handler().set_switch_debounce(Default::default());
+        mock.handler().set_on_force_disconnect(ForceDisconnectPolicy::Rejoin);
+        let mut events = mock.handler().events();
+
+        mock.handler().join(ChannelId(3));
+        mock.voice_state(Some(ChannelId(3)), "session");
+        mock.voice_state(None, "session");
+
+        assert_eq!(mock.handler().channel_id, Some(ChannelId(3)));
+        assert_eq!(mock.task_messages(), vec![TaskMessage::Disconnect]);
+        assert_eq!(mock.gateway_updates()[1]["d"]["channel_id"], 3);
+        assert!(matches!(events.try_next(), Ok(Some(HandlerEvent::ForceDisconnected {
+            channel_id: ChannelId(3),
+            policy: ForceDisconnectPolicy::Rejoin,
+        }))));
+    }
+
+    #[test]
+    fn test_auto_connect_disabled() {
+        let mut mock = MockHandler::new(GuildId(1), UserId(2));
+        mock.handler().set_switch_debounce(Default::default());
+        mock.handler().set_auto_connect(false);
+
+        mock.handler().join(ChannelId(3));
+        mock.voice_state(Some(ChannelId(3)), "session");
+        mock.voice_server("example.discord.media:80", "token").unwrap();
+        assert!(mock.task_messages().is_empty());
+
+        // The stored values are used to connect once asked.
+        assert!(mock.handler().connect());
+        assert_eq!(mock.task_messages(), vec![TaskMessage::Connect {
+            endpoint: "example.discord.media".to_string(),
+            session_id: "session".to_string(),
+        }]);
+    }
+
+    #[test]
+    fn test_moved_to_afk() {
+        let mut mock = MockHandler::new(GuildId(1), UserId(2));
+        mock.handler().set_switch_debounce(Default::default());
+        mock.handler().set_afk_channel(Some(ChannelId(9)));
+        let mut events = mock.handler().events();
+
+        // Joining the AFK channel directly is not a move.
+        mock.handler().set_afk_policy(AfkPolicy::Stop);
+        mock.handler().join(ChannelId(9));
+        mock.voice_state(Some(ChannelId(9)), "session");
+        assert!(events.try_next().is_err());
+
+        mock.handler().join(ChannelId(3));
+        mock.voice_state(Some(ChannelId(3)), "session");
+        mock.task_messages();
+        mock.voice_state(Some(ChannelId(9)), "session");
+
+        assert_eq!(mock.task_messages(), vec![TaskMessage::StopAndClear]);
+        assert!(matches!(events.try_next(), Ok(Some(HandlerEvent::MovedToAfk {
+            channel_id: ChannelId(9),
+            policy: AfkPolicy::Stop,
+        }))));
+
+        mock.handler().set_afk_policy(AfkPolicy::Return);
+        mock.voice_state(Some(ChannelId(3)), "session");
+        mock.gateway_updates();
+        mock.voice_state(Some(ChannelId(9)), "session");
+
+        assert_eq!(mock.handler().channel_id, Some(ChannelId(3)));
+        assert_eq!(mock.gateway_updates()[0]["d"]["channel_id"], 3);
+        assert!(matches!(events.try_next(), Ok(Some(HandlerEvent::MovedToAfk {
+            policy: AfkPolicy::Return,
+            ..
+        }))));
+    }
+
+    #[test]
+    fn test_voice_server_migration() {
+        let mut mock = MockHandler::new(GuildId(1), UserId(2));
+        let mut events = mock.handler().events();
+
+        mock.handler().join(ChannelId(3));
+        mock.voice_state(Some(ChannelId(3)), "session");
+        mock.voice_server("old.discord.media", "token").unwrap();
+        mock.task_messages();
+
+        // A lost server drops the connection, but not the channel.
+        mock.handler().update_server(&None, "token").unwrap();
+        assert_eq!(mock.handler().channel_id, Some(ChannelId(3)));
+        assert_eq!(mock.task_messages(), vec![TaskMessage::Disconnect]);
+        assert_eq!(mock.gateway_updates().len(), 1);
+
+        mock.voice_server("new.discord.media", "token").unwrap();
+        assert!(matches!(mock.task_messages()[..], [TaskMessage::Connect { .. }]));
+        assert!(matches!(
+            events.try_next(),
+            Ok(Some(HandlerEvent::VoiceServerChanged { ref old_endpoint, ref new_endpoint }))
+                if old_endpoint == "old.discord.media" && new_endpoint == "new.discord.media"
+        ));
+    }
+}
@@ -1,45 +1,160 @@
 //! A module for connecting to voice channels.
 
+mod adaptive;
 mod audio;
+mod config;
 mod connection;
 mod connection_info;
+mod crypto;
 mod dca;
+mod dynamics;
 mod error;
+mod events;
 mod manager;
 mod handler;
+mod icy;
+mod limiter;
+#[cfg(any(test, feature = "voice_mock"))]
+pub mod mock;
 mod payload;
+mod probe;
+mod queue;
+mod receive;
+#[cfg(feature = "http")]
+mod regions;
+mod rtcp;
+mod state;
+mod stats;
 mod streamer;
 mod tasks;
+mod tee;
+mod track;
 
+// Deprecated constructors are still exported until they are removed.
+#[allow(deprecated)]
 pub use self::{
-    audio::{Audio, AudioReceiver, AudioSource, AudioType, LockedAudio},
+    audio::{
+        Audio,
+        AudioReceiver,
+        AudioSource,
+        AudioType,
+        EndReason,
+        ErrorAction,
+        ErrorHandler,
+        LockedAudio,
+        TrackStatus,
+    },
+    config::{AfkPolicy, AgcConfig, DuckConfig, EqBand, ForceDisconnectPolicy, HandlerConfig, LimiterConfig, OpusConfig, OpusSettings, PacingMode, ProcessOptions, TrackOptions, YtdlRetry},
+    connection_info::{EndpointResolver, SessionInfo},
+    crypto::{EncryptionBackend, EncryptionBackendFactory, EncryptionMode},
     dca::DcaMetadata,
-    error::{DcaError, VoiceError},
-    handler::Handler,
+    error::{DcaError, QueueError, VoiceError},
+    events::{HandlerEvent, TrackEvent},
+    handler::{Handler, VoiceStateObserver},
     manager::Manager,
+    probe::{probe, TrackInfo},
+    queue::{
+        resolve_many,
+        resolve_metadata_many,
+        AutoplayProvider,
+        NowPlaying,
+        PresenceHook,
+        QueueEntry,
+        QueueFullPolicy,
+        QueueSnapshot,
+        SnapshotTrack,
+        TrackMetadata,
+        TrackQueue,
+        TrackSource,
+    },
+    receive::{DecodedPacket, ReceiverHandle, SsrcInfo},
+    rtcp::{ReportBlock, RtcpReport, SenderInfo},
+    state::ConnectionState,
+    stats::{AggregateVoiceStats, NetworkStats, PacketObserver, RtpPacketView},
     streamer::{
+        async_reader,
+        chain,
         dca,
         ffmpeg,
         ffmpeg_optioned,
+        ffmpeg_with_options,
+        http,
         opus,
+        opus_frames,
         pcm,
+        prebuffer,
+        set_ffmpeg_program,
+        set_ffprobe_program,
+        set_ytdl_program,
+        with_volume,
         ytdl,
-        ytdl_search
-    }
+        ytdl_search,
+        ytdl_with_args,
+        ytdl_with_options,
+        ytdl_with_retry,
+        ytdl_with_timeout,
+        YtdlAttempt,
+    },
+    tee::WavWriter,
+    track::TrackHandle,
 };
-pub use audiopus::Bitrate;
+#[cfg(feature = "http")]
+pub use self::regions::{regions, set_channel_region, set_guild_region};
+pub use crate::model::voice::VoiceRegion;
+pub use audiopus::{Bitrate, Channels, SampleRate, Signal};
 
-use std::sync::Arc;
-use self::connection_info::ConnectionInfo;
-
-const CRYPTO_MODE: &str = "xsalsa20_poly1305";
+use crate::constants::VoiceOpCode;
+use crate::model::id::UserId;
+use crate::Result;
+use futures::channel::{mpsc::UnboundedSender as Sender, oneshot};
+use serde_json::Value;
+use std::{collections::HashSet, sync::Arc, time::Duration};
+use self::{connection_info::ConnectionInfo, tee::OutputTee};
 
 pub(crate) enum Status {
     Connect(ConnectionInfo),
     Disconnect,
     SetReceiver(Option<Arc<dyn AudioReceiver>>),
+    AddReceiver(Arc<dyn AudioReceiver>),
+    RemoveReceiver(Arc<dyn AudioReceiver>),
+    SetAudioStream(Sender<DecodedPacket>),
+    AddUserStream(UserId, Sender<Vec<i16>>),
+    SetReceiveFilter(Option<HashSet<UserId>>),
+    SetReceiveGain(UserId, f32),
+    SetReceiveAgc(Option<AgcConfig>),
+    SetReceiveBufferLimit(usize),
+    SetReceiveFormat(SampleRate, Channels),
     SetSender(Option<LockedAudio>),
+    FadeToSender(LockedAudio, Duration),
     AddSender(LockedAudio),
+    StopAndClear,
+    ForceHeartbeat,
+    SendVoiceOp(VoiceOpCode, Value),
+    Reconnect(oneshot::Sender<Result<()>>),
     SetBitrate(Bitrate),
+    SetOpusConfig(OpusConfig),
+    ReconfigureEncoder(OpusConfig),
+    SetOpusComplexity(u8),
+    SetAdaptiveComplexity(bool),
+    SetAdaptiveBitrate(Vec<Bitrate>),
+    SetAdaptiveChannels(bool),
+    SetDtx(bool),
+    SetHeartbeatTimeout(Option<u32>),
+    SetConnectTimeout(Duration),
+    SetVolumeRamp(Duration),
+    SetPacingMode(PacingMode),
+    SetCatchSourcePanics(bool),
+    SetBusVolume(String, f32),
+    StopBus(String),
+    SetPlaybackIdleTimeout(Option<Duration>),
+    SetSpeakingIndicator(bool),
+    SetRtpPayloadType(u8),
+    SetRtpHeaderExtension(bool),
+    SetEncryptionMode(EncryptionMode),
+    SetEncryptionBackend(Option<EncryptionBackendFactory>),
+    SetCloseCode(u16),
+    SetLimiter(Option<LimiterConfig>),
+    SetOutputTee(Option<OutputTee>),
+    SetPacketObserver(Option<PacketObserver>),
     Mute(bool),
 }
@@ -1,6 +1,7 @@
 use crate::constants::VoiceOpCode;
 use serde_json::{json, Value};
 use super::connection_info::ConnectionInfo;
+use super::crypto::EncryptionMode;
 
 #[inline]
 pub fn build_identify(info: &ConnectionInfo) -> Value {
@@ -36,20 +37,28 @@ pub fn build_resume(info: &ConnectionInfo) -> Value {
 }
 
 #[inline]
-pub fn build_select_protocol(address: ::std::borrow::Cow<'_, str>, port: u16) -> Value {
+pub fn build_select_protocol(address: ::std::borrow::Cow<'_, str>, port: u16, mode: EncryptionMode) -> Value {
     json!({
         "op": VoiceOpCode::SelectProtocol.num(),
         "d": {
             "protocol": "udp",
             "data": {
                 "address": address,
-                "mode": super::CRYPTO_MODE,
+                "mode": mode.name(),
                 "port": port,
             }
         }
     })
 }
 
+#[inline]
+pub fn build_op(op: VoiceOpCode, data: Value) -> Value {
+    json!({
+        "op": op.num(),
+        "d": data,
+    })
+}
+
 #[inline]
 pub fn build_speaking(speaking: bool) -> Value {
     json!({
@@ -0,0 +1,168 @@
+use crate::internal::prelude::*;
+use serde::Deserialize;
+use std::{ffi::OsStr, process::Stdio, time::Duration};
+use tokio::{process::Command, time};
+use tracing::instrument;
+use super::{streamer, ProcessOptions, VoiceError};
+
+/// Information about the audio of a file or URL, found via [`probe`].
+///
+/// [`probe`]: fn.probe.html
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct TrackInfo {
+    /// The length of the audio, if known.
+    ///
+    /// This is often unknown for live streams.
+    pub duration: Option<Duration>,
+    /// The name of the audio codec, such as `opus` or `mp3`.
+    pub codec: Option<String>,
+    /// The number of audio channels.
+    pub channels: Option<u8>,
+    /// The sample rate of the audio, in Hz.
+    pub sample_rate: Option<u32>,
+}
+
+impl TrackInfo {
+    /// Whether the audio has more than one channel.
+    pub fn is_stereo(&self) -> bool {
+        matches!(self.channels, Some(channels) if channels > 1)
+    }
+}
+
+#[derive(Deserialize)]
+struct ProbeOutput {
+    #[serde(default)]
+    streams: Vec<ProbeStream>,
+    format: Option<ProbeFormat>,
+}
+
+#[derive(Deserialize)]
+struct ProbeStream {
+    codec_type: Option<String>,
+    codec_name: Option<String>,
+    channels: Option<u8>,
+    sample_rate: Option<String>,
+    duration: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ProbeFormat {
+    duration: Option<String>,
+}
+
+/// Finds the duration and format of the first audio stream in a file or URL
+/// using `ffprobe`, without creating an audio source.
+///
+/// This can be used to reject tracks before they are played, such as those
+/// which are too long.
+///
+/// # Examples
+///
+/// Refuse to play tracks over ten minutes long:
+///
+/// ```rust,no_run
+/// use serenity::voice;
+/// use std::time::Duration;
+///
+/// # async fn run() -> serenity::Result<()> {
+/// let info = voice::probe("./some_file.mp3").await?;
+///
+/// if info.duration.map_or(true, |duration| duration > Duration::from_secs(600)) {
+///     println!("Track is too long, or of unknown length.");
+/// }
+/// # Ok(())
+/// # }
+/// ```
+///
+/// # Errors
+///
+/// Returns [`VoiceError::Streams`] if `ffprobe` finds no audio stream,
+/// [`VoiceError::ProbeOutput`] if its output cannot be read, and
+/// [`VoiceError::ProgramNotFound`] if `ffprobe` cannot be found.
+///
+/// [`VoiceError::ProbeOutput`]: enum.VoiceError.html#variant.ProbeOutput
+/// [`VoiceError::ProgramNotFound`]: enum.VoiceError.html#variant.ProgramNotFound
+/// [`VoiceError::Streams`]: enum.VoiceError.html#variant.Streams
+pub async fn probe<P: AsRef<OsStr>>(path: P) -> Result<TrackInfo> {
+    probe_with_options(path.as_ref(), &ProcessOptions::default()).await
+}
+
+/// Probes a file or URL as with [`probe`], spawning `ffprobe` with the given
+/// options.
+///
+/// [`probe`]: fn.probe.html
+#[instrument]
+pub(crate) async fn probe_with_options(path: &OsStr, options: &ProcessOptions) -> Result<TrackInfo> {
+    let args = ["-v", "quiet", "-of", "json", "-show_streams", "-show_format", "-i"];
+
+    let program = streamer::ffprobe_program();
+    let mut command = Command::new(&program);
+    options.apply(&mut command);
+
+    let output = command
+        .kill_on_drop(true)
+        .args(args.iter())
+        .arg(path)
+        .stdin(Stdio::null())
+        .output();
+
+    // The process is killed if it is dropped on timing out.
+    let out = match options.timeout {
+        Some(limit) => time::timeout(limit, output)
+            .await
+            .map_err(|_| Error::Voice(VoiceError::Timeout))?,
+        None => output.await,
+    }.map_err(streamer::spawn_error(program))?;
+
+    let output: ProbeOutput = serde_json::from_slice(&out.stdout)
+        .map_err(|why| Error::Voice(VoiceError::ProbeOutput(why)))?;
+
+    let stream = output.streams
+        .into_iter()
+        .find(|stream| stream.codec_type.as_deref() == Some("audio"))
+        .ok_or(Error::Voice(VoiceError::Streams))?;
+
+    // The container's duration is more reliable than that of its streams,
+    // which some formats leave unset.
+    let duration = output.format
+        .and_then(|format| format.duration)
+        .or(stream.duration)
+        .and_then(|duration| parse_duration(&duration));
+
+    Ok(TrackInfo {
+        duration,
+        codec: stream.codec_name,
+        channels: stream.channels,
+        sample_rate: stream.sample_rate.and_then(|rate| rate.parse().ok()),
+    })
+}
+
+/// Parses a duration in seconds as given by `ffprobe`, rejecting any which
+/// is negative, not a number or too long for a `Duration`.
+fn parse_duration(secs: &str) -> Option<Duration> {
+    let secs = secs.parse::<f64>().ok()?;
+
+    if secs.is_finite() && secs >= 0.0 && secs < u64::MAX as f64 {
+        Some(Duration::from_secs_f64(secs))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("12.5"), Some(Duration::from_millis(12_500)));
+        assert_eq!(parse_duration("0"), Some(Duration::from_secs(0)));
+
+        assert_eq!(parse_duration("N/A"), None);
+        assert_eq!(parse_duration("-1"), None);
+        assert_eq!(parse_duration("inf"), None);
+        assert_eq!(parse_duration("NaN"), None);
+        assert_eq!(parse_duration("1e30"), None);
+    }
+}
@@ -0,0 +1,1622 @@
+use async_trait::async_trait;
+use crate::internal::prelude::*;
+use crate::model::id::{ChannelId, GuildId, UserId};
+use futures::stream::{self, StreamExt};
+use std::{
+    collections::VecDeque,
+    future::Future,
+    mem,
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+        Mutex,
+        MutexGuard,
+    },
+    time::Duration,
+};
+use tokio::sync::watch;
+use tracing::{info, warn};
+use super::{
+    events::{EventSink, TrackEvent},
+    streamer::{self, ffmpeg, ytdl},
+    Audio,
+    AudioSource,
+    ConnectionState,
+    EndReason,
+    HandlerEvent,
+    LockedAudio,
+    QueueError,
+    TrackHandle,
+    TrackOptions,
+    TrackStatus,
+    VoiceError,
+};
+
+/// How many tracks in a row may fail before autoplay gives up, so that a
+/// provider whose tracks cannot be played is not asked for more forever.
+const MAX_AUTOPLAY_FAILURES: u32 = 3;
+
+/// Where the audio of a track came from, allowing it to be recreated.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[non_exhaustive]
+pub enum TrackSource {
+    /// A URL to be streamed via [`voice::ytdl`].
+    ///
+    /// [`voice::ytdl`]: fn.ytdl.html
+    Ytdl(String),
+    /// A file path or URL to be opened via [`voice::ffmpeg`].
+    ///
+    /// [`voice::ffmpeg`]: fn.ffmpeg.html
+    Ffmpeg(String),
+}
+
+impl TrackSource {
+    /// Creates a new audio source from this description.
+    pub async fn create(&self) -> Result<Box<dyn AudioSource>> {
+        match self {
+            TrackSource::Ytdl(url) => ytdl(url).await,
+            TrackSource::Ffmpeg(path) => ffmpeg(path).await,
+        }
+    }
+
+    /// Finds metadata for this source without creating it.
+    ///
+    /// The title of a `Ytdl` source is fetched via `youtube-dl`, while that
+    /// of an `Ffmpeg` source is its file name, without the extension.
+    pub async fn metadata(&self) -> Result<TrackMetadata> {
+        let title = match self {
+            TrackSource::Ytdl(url) => Some(streamer::ytdl_title(url).await?),
+            TrackSource::Ffmpeg(path) => Path::new(path)
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().into_owned()),
+        };
+
+        Ok(TrackMetadata {
+            title,
+            source: Some(self.clone()),
+            requester: None,
+        })
+    }
+}
+
+/// Creates the audio sources and metadata of many tracks at once, running at
+/// most `concurrency` at a time, such as when adding a playlist.
+///
+/// Results are returned in the same order as the given sources.
+///
+/// To avoid creating every source up front, use [`resolve_metadata_many`]
+/// and pass the results to [`TrackQueue::add_lazy`] instead.
+///
+/// [`resolve_metadata_many`]: fn.resolve_metadata_many.html
+/// [`TrackQueue::add_lazy`]: struct.TrackQueue.html#method.add_lazy
+pub async fn resolve_many<I>(sources: I, concurrency: usize) -> Vec<Result<(Box<dyn AudioSource>, TrackMetadata)>>
+where
+    I: IntoIterator<Item = TrackSource>,
+{
+    stream::iter(sources)
+        .map(|source| async move {
+            let metadata = source.metadata().await?;
+            let created = source.create().await?;
+
+            Ok((created, metadata))
+        })
+        .buffered(concurrency.max(1))
+        .collect()
+        .await
+}
+
+/// Finds the metadata of many tracks at once as with [`resolve_many`], but
+/// without creating their sources.
+///
+/// [`resolve_many`]: fn.resolve_many.html
+pub async fn resolve_metadata_many<I>(sources: I, concurrency: usize) -> Vec<Result<TrackMetadata>>
+where
+    I: IntoIterator<Item = TrackSource>,
+{
+    stream::iter(sources)
+        .map(|source| async move { source.metadata().await })
+        .buffered(concurrency.max(1))
+        .collect()
+        .await
+}
+
+/// Information about a track in a [`TrackQueue`].
+///
+/// [`TrackQueue`]: struct.TrackQueue.html
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct TrackMetadata {
+    /// A human-readable title for the track.
+    pub title: Option<String>,
+    /// Where the track's audio came from.
+    ///
+    /// Only tracks with a source can be recreated by [`TrackQueue::restore`].
+    ///
+    /// [`TrackQueue::restore`]: struct.TrackQueue.html#method.restore
+    pub source: Option<TrackSource>,
+    /// The user who queued the track, if known, such as to only let them
+    /// skip it.
+    ///
+    /// This is carried onto the track's [`TrackHandle::requester`] and the
+    /// [`TrackEvent`] emitted when it ends, but is not acted on otherwise.
+    ///
+    /// [`TrackEvent`]: struct.TrackEvent.html
+    /// [`TrackHandle::requester`]: struct.TrackHandle.html#method.requester
+    pub requester: Option<UserId>,
+}
+
+/// A serializable record of the contents of a [`TrackQueue`], created by
+/// [`TrackQueue::snapshot`].
+///
+/// [`TrackQueue`]: struct.TrackQueue.html
+/// [`TrackQueue::snapshot`]: struct.TrackQueue.html#method.snapshot
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct QueueSnapshot {
+    /// The queued tracks, with the currently playing track first.
+    pub tracks: Vec<SnapshotTrack>,
+}
+
+/// A single track within a [`QueueSnapshot`].
+///
+/// [`QueueSnapshot`]: struct.QueueSnapshot.html
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct SnapshotTrack {
+    /// The track's metadata, including its source.
+    pub metadata: TrackMetadata,
+    /// How far through the track playback had reached.
+    pub position: Duration,
+}
+
+/// The state of the current track of a [`TrackQueue`], read at a single point
+/// in time by [`TrackQueue::now_playing`].
+///
+/// [`TrackQueue`]: struct.TrackQueue.html
+/// [`TrackQueue::now_playing`]: struct.TrackQueue.html#method.now_playing
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct NowPlaying {
+    /// The track's metadata.
+    pub metadata: TrackMetadata,
+    /// How far through the track playback has reached.
+    pub position: Duration,
+    /// The total length of the track, if known.
+    pub duration: Option<Duration>,
+    /// The volume of the track.
+    pub volume: f32,
+    /// The region of the track being looped, if any.
+    pub loop_region: Option<(Duration, Duration)>,
+    /// Whether the track is paused.
+    pub paused: bool,
+}
+
+/// Chooses what a [`TrackQueue`] plays once it runs out of tracks, set via
+/// [`TrackQueue::set_autoplay`], such as a track related to the last one for
+/// a channel which plays music around the clock.
+///
+/// # Examples
+///
+/// Keep playing the next track of a radio station's endless playlist:
+///
+/// ```rust,ignore
+/// use serenity::voice::{AutoplayProvider, TrackMetadata, TrackSource};
+///
+/// struct Radio;
+///
+/// #[async_trait]
+/// impl AutoplayProvider for Radio {
+///     async fn next_track(&self, _previous: &TrackMetadata) -> Option<TrackMetadata> {
+///         let url = fetch_next_url().await?;
+///
+///         Some(TrackMetadata {
+///             title: None,
+///             source: Some(TrackSource::Ytdl(url)),
+///             requester: None,
+///         })
+///     }
+/// }
+///
+/// handler.queue().set_autoplay(Some(Box::new(Radio)));
+/// ```
+///
+/// [`TrackQueue`]: struct.TrackQueue.html
+/// [`TrackQueue::set_autoplay`]: struct.TrackQueue.html#method.set_autoplay
+#[async_trait]
+pub trait AutoplayProvider: Send + Sync {
+    /// Returns the next track to play after the given one, which was the
+    /// last in the queue, or `None` to let the queue empty.
+    ///
+    /// The track's source is created once it reaches the front of the queue,
+    /// as with [`TrackQueue::add_lazy`], so the metadata must have one.
+    ///
+    /// [`TrackQueue::add_lazy`]: struct.TrackQueue.html#method.add_lazy
+    async fn next_track(&self, previous: &TrackMetadata) -> Option<TrackMetadata>;
+}
+
+/// A function called with the current track of a handler's queue whenever it
+/// changes, set via [`Handler::set_presence_hook`].
+///
+/// [`Handler::set_presence_hook`]: struct.Handler.html#method.set_presence_hook
+pub type PresenceHook = Arc<dyn Fn(Option<&NowPlaying>) + Send + Sync>;
+
+/// An entry in a [`TrackQueue`], as listed by [`TrackQueue::tracklist`].
+///
+/// [`TrackQueue`]: struct.TrackQueue.html
+/// [`TrackQueue::tracklist`]: struct.TrackQueue.html#method.tracklist
+#[derive(Clone)]
+#[non_exhaustive]
+pub struct QueueEntry {
+    /// The position of the track in the queue, where 0 is the current track.
+    pub index: usize,
+    /// The track's metadata.
+    pub metadata: TrackMetadata,
+    /// A handle to the track, if its source has been created.
+    ///
+    /// This is `None` for tracks added by [`TrackQueue::restore`] which have
+    /// not yet reached the front of the queue.
+    ///
+    /// [`TrackQueue::restore`]: struct.TrackQueue.html#method.restore
+    pub track: Option<TrackHandle>,
+}
+
+/// What a [`TrackQueue`] does when a track is added while it holds as many
+/// tracks as allowed by [`TrackQueue::set_max_len`].
+///
+/// The current track is never dropped to make room.
+///
+/// [`TrackQueue`]: struct.TrackQueue.html
+/// [`TrackQueue::set_max_len`]: struct.TrackQueue.html#method.set_max_len
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum QueueFullPolicy {
+    /// Refuse the new track. This is the default.
+    Reject,
+    /// Drop the upcoming track which was queued first, i.e. the one due to
+    /// play next.
+    DropOldest,
+    /// Drop the upcoming track which was queued last, so that the new track
+    /// takes its place at the end of the queue.
+    DropNewest,
+}
+
+impl Default for QueueFullPolicy {
+    fn default() -> Self {
+        QueueFullPolicy::Reject
+    }
+}
+
+struct QueuedTrack {
+    id: u64,
+    metadata: TrackMetadata,
+    /// The live track, if its source has been created.
+    track: Option<TrackHandle>,
+    /// The position to resume playback from once the source is created.
+    resume_at: Duration,
+    /// Whether the source is currently being created.
+    resolving: bool,
+    /// Whether the track has been handed to the voice task's mixer.
+    sent: bool,
+}
+
+#[derive(Default)]
+struct QueueCore {
+    next_id: u64,
+    tracks: VecDeque<QueuedTrack>,
+    max_duration: Option<Duration>,
+    max_len: Option<usize>,
+    full_policy: QueueFullPolicy,
+    reject_unknown_duration: bool,
+    empty_on_stop: bool,
+    /// Whether auto-advance is off, so that no further track is started.
+    halted: bool,
+    presence_hook: Option<PresenceHook>,
+    autoplay: Option<Arc<dyn AutoplayProvider>>,
+    /// How many tracks in a row have failed, which stops autoplay once it
+    /// reaches `MAX_AUTOPLAY_FAILURES`.
+    failures: u32,
+    /// Whether the current track has changed since the presence hook was
+    /// last called.
+    current_changed: bool,
+}
+
+impl QueueCore {
+    /// Makes room for another track under the length limit as the policy
+    /// allows, returning the tracks which were dropped to do so.
+    fn make_room(&mut self) -> StdResult<Vec<QueuedTrack>, VoiceError> {
+        let excess = match self.max_len {
+            Some(max) if self.tracks.len() >= max => self.tracks.len() + 1 - max,
+            _ => return Ok(Vec::new()),
+        };
+
+        // Only upcoming tracks can be dropped, never the current one.
+        let upcoming = self.tracks.len().saturating_sub(1);
+
+        if self.full_policy == QueueFullPolicy::Reject || excess > upcoming {
+            return Err(VoiceError::QueueFull);
+        }
+
+        let dropped = match self.full_policy {
+            QueueFullPolicy::DropOldest => self.tracks.drain(1..=excess).collect(),
+            _ => {
+                let start = self.tracks.len() - excess;
+
+                self.tracks.drain(start..).collect()
+            },
+        };
+
+        Ok(dropped)
+    }
+
+    fn push(&mut self, metadata: TrackMetadata, track: Option<TrackHandle>, resume_at: Duration) {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        self.tracks.push_back(QueuedTrack {
+            id,
+            metadata,
+            track,
+            resume_at,
+            resolving: false,
+            sent: false,
+        });
+    }
+}
+
+/// A queue of tracks, played one after another by a [`Handler`].
+///
+/// The first track in the queue is the one currently playing. When it ends,
+/// whether naturally or by being stopped, it is removed and the next track is
+/// started. Tracks played outside of the queue, such as via
+/// [`Handler::play`], are mixed alongside the queue's current track.
+///
+/// The queue is shared between all clones of a [`Handler`], and is accessed
+/// via [`Handler::queue`].
+///
+/// [`Handler`]: struct.Handler.html
+/// [`Handler::play`]: struct.Handler.html#method.play
+/// [`Handler::queue`]: struct.Handler.html#method.queue
+#[derive(Clone)]
+pub struct TrackQueue {
+    inner: Arc<Mutex<QueueCore>>,
+    guild_id: GuildId,
+    /// The handler's current channel, or 0 if none.
+    channel_id: Arc<AtomicU64>,
+    connection: watch::Receiver<ConnectionState>,
+    /// Notified whenever the queue is emptied, however that happened.
+    emptied: Arc<watch::Sender<()>>,
+    emptied_rx: watch::Receiver<()>,
+    events: EventSink,
+}
+
+impl TrackQueue {
+    pub(crate) fn new(
+        guild_id: GuildId,
+        channel_id: Arc<AtomicU64>,
+        connection: watch::Receiver<ConnectionState>,
+        events: EventSink,
+    ) -> Self {
+        let (emptied, emptied_rx) = watch::channel(());
+
+        Self {
+            inner: Arc::new(Mutex::new(QueueCore::default())),
+            guild_id,
+            channel_id,
+            connection,
+            emptied: Arc::new(emptied),
+            emptied_rx,
+            events,
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, QueueCore> {
+        self.inner.lock().expect("[Voice] Track queue poisoned")
+    }
+
+    fn notify_emptied(&self) {
+        let _ = self.emptied.broadcast(());
+    }
+
+    /// Returns a future which resolves once the queue is empty, at once if it
+    /// already is.
+    ///
+    /// This resolves however the queue empties, whether its last track
+    /// finishes, fails or is stopped, or the queue is cleared, such as via
+    /// [`Handler::stop_and_clear`]. Tracks added before it resolves are
+    /// waited for too.
+    ///
+    /// As the future holds its own reference to the queue, it can be awaited
+    /// without borrowing the [`Handler`].
+    ///
+    /// [`Handler`]: struct.Handler.html
+    /// [`Handler::stop_and_clear`]: struct.Handler.html#method.stop_and_clear
+    pub fn wait_until_empty(&self) -> impl Future<Output = ()> + Send + 'static {
+        let queue = self.clone();
+        // A fresh receiver yields at once, so that emptying between checking
+        // the queue and waiting on it cannot be missed.
+        let mut emptied = self.emptied_rx.clone();
+
+        async move {
+            while !queue.is_empty() {
+                if emptied.recv().await.is_none() {
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Adds a source to the end of the queue, returning a handle to its track.
+    ///
+    /// The track starts playing once every track ahead of it has ended.
+    ///
+    /// If the queue is full, a track may first be dropped to make room, as
+    /// set via [`set_max_len`].
+    ///
+    /// # Errors
+    ///
+    /// If a maximum duration has been set via [`set_max_duration`], returns
+    /// [`VoiceError::TrackTooLong`] if the source is longer, and
+    /// [`VoiceError::UnknownDuration`] if its length is unknown and such
+    /// sources are rejected via [`set_reject_unknown_duration`].
+    ///
+    /// Returns [`VoiceError::QueueFull`] if the queue is full and no track
+    /// could be dropped to make room.
+    ///
+    /// [`set_max_duration`]: #method.set_max_duration
+    /// [`set_max_len`]: #method.set_max_len
+    /// [`set_reject_unknown_duration`]: #method.set_reject_unknown_duration
+    /// [`VoiceError::QueueFull`]: enum.VoiceError.html#variant.QueueFull
+    /// [`VoiceError::TrackTooLong`]: enum.VoiceError.html#variant.TrackTooLong
+    /// [`VoiceError::UnknownDuration`]: enum.VoiceError.html#variant.UnknownDuration
+    pub fn add(&self, source: Box<dyn AudioSource>, metadata: TrackMetadata) -> StdResult<TrackHandle, VoiceError> {
+        self.add_with_options(source, metadata, TrackOptions::default())
+    }
+
+    /// Adds a track to the end of the queue as with [`add`], with the given
+    /// options, such as to play only a slice of a clip.
+    ///
+    /// # Errors
+    ///
+    /// Returns the errors of [`add`], as well as [`VoiceError::NotSeekable`]
+    /// if the options start after the beginning of a source which cannot
+    /// seek, and [`VoiceError::InvalidTrim`] if they do not start before they
+    /// end.
+    ///
+    /// [`add`]: #method.add
+    /// [`VoiceError::InvalidTrim`]: enum.VoiceError.html#variant.InvalidTrim
+    /// [`VoiceError::NotSeekable`]: enum.VoiceError.html#variant.NotSeekable
+    pub fn add_with_options(
+        &self,
+        source: Box<dyn AudioSource>,
+        metadata: TrackMetadata,
+        options: TrackOptions,
+    ) -> StdResult<TrackHandle, VoiceError> {
+        let (track, dropped) = {
+            let mut core = self.lock();
+
+            if let Some(max) = core.max_duration {
+                match source.total_duration() {
+                    Some(length) if length > max => return Err(VoiceError::TrackTooLong(length)),
+                    None if core.reject_unknown_duration => return Err(VoiceError::UnknownDuration),
+                    _ => {},
+                }
+            }
+
+            let mut audio = Audio::new(source);
+            audio.apply_options(options)?;
+            audio.requester = metadata.requester;
+
+            let dropped = core.make_room()?;
+            let track = TrackHandle::new(audio, self.connection.clone(), self.events.clone());
+
+            core.push(metadata, Some(track.clone()), Duration::default());
+
+            (track, dropped)
+        };
+
+        self.dropped(dropped);
+
+        Ok(track)
+    }
+
+    /// Adds a track to the end of the queue without creating its source,
+    /// which is instead created from the metadata's [`TrackSource`] once the
+    /// track reaches the front of the queue.
+    ///
+    /// Returns `false`, adding nothing, if the metadata has no source, or if
+    /// the queue is full and no track could be dropped to make room, as with
+    /// [`add`]. As its length is unknown in advance, the track is not checked
+    /// against any maximum duration.
+    ///
+    /// [`add`]: #method.add
+    /// [`TrackSource`]: enum.TrackSource.html
+    pub fn add_lazy(&self, metadata: TrackMetadata) -> bool {
+        if metadata.source.is_none() {
+            return false;
+        }
+
+        let dropped = {
+            let mut core = self.lock();
+
+            match core.make_room() {
+                Ok(dropped) => {
+                    core.push(metadata, None, Duration::default());
+
+                    dropped
+                },
+                Err(_) => return false,
+            }
+        };
+
+        self.dropped(dropped);
+
+        true
+    }
+
+    /// Limits how many tracks the queue may hold, including the current one,
+    /// or removes the limit if `None`, which is the default.
+    ///
+    /// Tracks added beyond the limit are handled according to the policy set
+    /// via [`set_full_policy`], which rejects them by default. This applies
+    /// to every way of adding tracks, including lazily via [`add_lazy`] and
+    /// [`restore`], each track of which takes a place in the queue before its
+    /// source is created. Lowering the limit does not remove tracks already
+    /// in the queue.
+    ///
+    /// [`add_lazy`]: #method.add_lazy
+    /// [`restore`]: #method.restore
+    /// [`set_full_policy`]: #method.set_full_policy
+    pub fn set_max_len(&self, max: Option<usize>) {
+        self.lock().max_len = max;
+    }
+
+    /// Sets what happens when a track is added to a full queue.
+    ///
+    /// Tracks dropped to make room end with [`EndReason::Cleared`], without
+    /// being played, and a [`HandlerEvent::QueueChanged`] is emitted.
+    ///
+    /// [`EndReason::Cleared`]: enum.EndReason.html#variant.Cleared
+    /// [`HandlerEvent::QueueChanged`]: enum.HandlerEvent.html#variant.QueueChanged
+    pub fn set_full_policy(&self, policy: QueueFullPolicy) {
+        self.lock().full_policy = policy;
+    }
+
+    /// Ends the tracks dropped to make room for others, none of which had
+    /// been handed to the mixer.
+    ///
+    /// A track locked elsewhere, such as by its handle, is ended in the
+    /// background once it is free.
+    fn dropped(&self, dropped: Vec<QueuedTrack>) {
+        if dropped.is_empty() {
+            return;
+        }
+
+        for queued in dropped {
+            info!("[Voice] Dropped queued track to make room: {:?}", queued.metadata.title);
+
+            if let Some(track) = queued.track {
+                let ended = match track.audio().try_lock() {
+                    Ok(mut audio) => {
+                        self.end_dropped(&mut audio);
+
+                        true
+                    },
+                    Err(_) => false,
+                };
+
+                if !ended {
+                    let queue = self.clone();
+
+                    tokio::spawn(async move {
+                        queue.end_dropped(&mut *track.audio().lock().await);
+                    });
+                }
+            }
+        }
+
+        self.events.emit(HandlerEvent::QueueChanged);
+    }
+
+    /// Marks a dropped track as cleared, emitting an event if it had not
+    /// already ended.
+    fn end_dropped(&self, audio: &mut Audio) {
+        if !audio.mark_ended(EndReason::Cleared) {
+            return;
+        }
+
+        let channel_id = match self.channel_id.load(Ordering::Relaxed) {
+            0 => None,
+            id => Some(ChannelId(id)),
+        };
+
+        self.events.emit(HandlerEvent::TrackEnded(TrackEvent {
+            guild_id: self.guild_id,
+            channel_id,
+            reason: EndReason::Cleared,
+            requester: audio.requester,
+        }));
+    }
+
+    /// Sets the longest track which may be added to the queue, or removes the
+    /// limit if `None`.
+    ///
+    /// A source's length is known when it was created via [`voice::ffmpeg`]
+    /// or similar, which probe their input. Tracks already in the queue, and
+    /// those recreated by [`restore`], are not checked.
+    ///
+    /// [`restore`]: #method.restore
+    /// [`voice::ffmpeg`]: fn.ffmpeg.html
+    pub fn set_max_duration(&self, max: Option<Duration>) {
+        self.lock().max_duration = max;
+    }
+
+    /// Sets whether sources of unknown length, such as live streams, are
+    /// rejected while a maximum duration is set. They are allowed by default.
+    pub fn set_reject_unknown_duration(&self, reject: bool) {
+        self.lock().reject_unknown_duration = reject;
+    }
+
+    /// Sets whether [`HandlerEvent::QueueEmpty`] is also emitted when the
+    /// last track is stopped, or the queue is cleared via
+    /// [`Handler::stop_and_clear`]. By default, it is only emitted when the
+    /// last track ends by itself.
+    ///
+    /// [`Handler::stop_and_clear`]: struct.Handler.html#method.stop_and_clear
+    /// [`HandlerEvent::QueueEmpty`]: enum.HandlerEvent.html#variant.QueueEmpty
+    pub fn set_empty_on_stop(&self, enabled: bool) {
+        self.lock().empty_on_stop = enabled;
+    }
+
+    /// Sets whether the queue moves on to its next track when the current
+    /// one ends, which it does by default.
+    ///
+    /// While this is off, such as for a "stop after this" command, the
+    /// current track plays to its end, after which the queue halts, emitting
+    /// [`HandlerEvent::QueuePaused`], with the tracks behind it left in
+    /// place. No track is started until this is turned back on, which
+    /// starts the next one. Unlike pausing, this does not affect a track
+    /// which is already playing.
+    ///
+    /// [`HandlerEvent::QueuePaused`]: enum.HandlerEvent.html#variant.QueuePaused
+    pub fn set_auto_advance(&self, advance: bool) {
+        self.lock().halted = !advance;
+    }
+
+    /// Sets what to play once the last track in the queue finishes, or
+    /// removes it if `None`, which is the default.
+    ///
+    /// When the last track finishes or fails, the provider is asked for
+    /// another in the background, which is added as with [`add_lazy`] and
+    /// played once its source is created. The queue only counts as emptied,
+    /// emitting [`HandlerEvent::QueueEmpty`], if the provider returns
+    /// `None` or a track without a source, or once three tracks in a row
+    /// have failed, such as when the provider's tracks cannot be played.
+    /// Stopping the last track, or clearing the queue, stops playback as
+    /// usual.
+    ///
+    /// [`add_lazy`]: #method.add_lazy
+    /// [`HandlerEvent::QueueEmpty`]: enum.HandlerEvent.html#variant.QueueEmpty
+    pub fn set_autoplay(&self, provider: Option<Box<dyn AutoplayProvider>>) {
+        let mut core = self.lock();
+        core.autoplay = provider.map(Arc::from);
+        core.failures = 0;
+    }
+
+    /// Returns the number of tracks in the queue, including the current one.
+    pub fn len(&self) -> usize {
+        self.lock().tracks.len()
+    }
+
+    /// Returns whether the queue is empty.
+    pub fn is_empty(&self) -> bool {
+        self.lock().tracks.is_empty()
+    }
+
+    /// Returns a handle to the currently playing track, if its source has been
+    /// created.
+    pub fn current(&self) -> Option<TrackHandle> {
+        self.lock().tracks.front().and_then(|queued| queued.track.clone())
+    }
+
+    /// Reads the metadata and playback state of the current track together,
+    /// e.g. for a "now playing" message.
+    ///
+    /// Every field is read under a single lock of the track, so they are
+    /// consistent with each other even while it plays. Returns `None` if the
+    /// queue is empty, or the current track's source has yet to be created.
+    pub async fn now_playing(&self) -> Option<NowPlaying> {
+        let (metadata, track) = {
+            let core = self.lock();
+            let queued = core.tracks.front()?;
+
+            (queued.metadata.clone(), queued.track.clone()?)
+        };
+
+        let audio = track.audio().lock().await;
+
+        Some(NowPlaying {
+            metadata,
+            position: audio.position,
+            duration: audio.source.total_duration(),
+            volume: audio.volume,
+            loop_region: audio.loop_region(),
+            paused: !audio.playing,
+        })
+    }
+
+    /// Lists the tracks in the queue, with the current track first.
+    ///
+    /// The list is taken at a single point in time, so is consistent even if
+    /// tracks are added concurrently. Only handles and metadata are cloned,
+    /// not audio sources.
+    pub fn tracklist(&self) -> Vec<QueueEntry> {
+        self.lock()
+            .tracks
+            .iter()
+            .enumerate()
+            .map(|(index, queued)| QueueEntry {
+                index,
+                metadata: queued.metadata.clone(),
+                track: queued.track.clone(),
+            })
+            .collect()
+    }
+
+    /// Moves the track at index `from` to index `to`, shifting the tracks in
+    /// between.
+    ///
+    /// The current track, at index 0, cannot be moved, and no track can be
+    /// moved in front of it.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`QueueError::CurrentTrack`] if either index is 0, or
+    /// [`QueueError::OutOfBounds`] if either is past the end of the queue.
+    ///
+    /// [`QueueError::CurrentTrack`]: enum.QueueError.html#variant.CurrentTrack
+    /// [`QueueError::OutOfBounds`]: enum.QueueError.html#variant.OutOfBounds
+    pub fn move_track(&self, from: usize, to: usize) -> StdResult<(), QueueError> {
+        {
+            let mut core = self.lock();
+            check_upcoming(&core, from)?;
+            check_upcoming(&core, to)?;
+
+            if from == to {
+                return Ok(());
+            }
+
+            // Both indices were checked above.
+            let track = core.tracks.remove(from).unwrap();
+            core.tracks.insert(to, track);
+        }
+
+        self.events.emit(HandlerEvent::QueueChanged);
+
+        Ok(())
+    }
+
+    /// Swaps the tracks at indices `i` and `j`.
+    ///
+    /// As with [`move_track`], the current track cannot be swapped.
+    ///
+    /// # Errors
+    ///
+    /// Fails in the same cases as [`move_track`].
+    ///
+    /// [`move_track`]: #method.move_track
+    pub fn swap(&self, i: usize, j: usize) -> StdResult<(), QueueError> {
+        {
+            let mut core = self.lock();
+            check_upcoming(&core, i)?;
+            check_upcoming(&core, j)?;
+
+            if i == j {
+                return Ok(());
+            }
+
+            core.tracks.swap(i, j);
+        }
+
+        self.events.emit(HandlerEvent::QueueChanged);
+
+        Ok(())
+    }
+
+    /// Removes every track from the queue, returning the handles of those
+    /// whose sources had been created.
+    ///
+    /// The returned tracks are not marked as ended, which is left to the
+    /// voice task.
+    pub(crate) fn clear(&self) -> Vec<TrackHandle> {
+        let (cleared, emptied) = {
+            let mut core = self.lock();
+            let emptied = core.empty_on_stop && !core.tracks.is_empty();
+            let cleared = core.tracks
+                .drain(..)
+                .filter_map(|queued| queued.track)
+                .collect::<Vec<_>>();
+
+            core.current_changed |= !cleared.is_empty();
+
+            (cleared, emptied)
+        };
+
+        self.events.emit(HandlerEvent::QueueChanged);
+        self.notify_emptied();
+
+        if emptied {
+            self.events.emit(HandlerEvent::QueueEmpty {
+                reason: EndReason::Cleared,
+            });
+        }
+
+        cleared
+    }
+
+    /// Creates a serializable record of the queue's contents and playback
+    /// positions, which can later be passed to [`restore`], e.g. after a
+    /// process restart.
+    ///
+    /// Tracks without a [`TrackSource`] are included, but cannot be restored.
+    ///
+    /// [`restore`]: #method.restore
+    /// [`TrackSource`]: enum.TrackSource.html
+    pub async fn snapshot(&self) -> QueueSnapshot {
+        let entries = self.lock()
+            .tracks
+            .iter()
+            .map(|queued| (queued.metadata.clone(), queued.track.clone(), queued.resume_at))
+            .collect::<Vec<_>>();
+
+        let mut tracks = Vec::with_capacity(entries.len());
+
+        for (metadata, track, resume_at) in entries {
+            let position = match track {
+                Some(track) => track.audio().lock().await.position,
+                None => resume_at,
+            };
+
+            tracks.push(SnapshotTrack { metadata, position });
+        }
+
+        QueueSnapshot { tracks }
+    }
+
+    /// Appends the tracks recorded in a [`QueueSnapshot`] to the queue,
+    /// returning how many were restored.
+    ///
+    /// Sources are created lazily: each track's source is only recreated once
+    /// the track reaches the front of the queue. Tracks without a
+    /// [`TrackSource`], i.e. those which were played from in-memory sources,
+    /// are skipped. Each recreated track's recorded position is passed to
+    /// [`Audio::position`].
+    ///
+    /// Restored tracks count towards any limit set via [`set_max_len`]. Once
+    /// the queue is full, the rest of the snapshot is skipped if its policy
+    /// rejects new tracks, and otherwise restoring drops tracks as it goes.
+    ///
+    /// [`Audio::position`]: struct.Audio.html#method.position
+    /// [`QueueSnapshot`]: struct.QueueSnapshot.html
+    /// [`set_max_len`]: #method.set_max_len
+    /// [`TrackSource`]: enum.TrackSource.html
+    pub fn restore(&self, snapshot: QueueSnapshot) -> usize {
+        let mut dropped = Vec::new();
+        let mut restored = 0;
+
+        {
+            let mut core = self.lock();
+
+            for track in snapshot.tracks {
+                if track.metadata.source.is_none() {
+                    warn!("[Voice] Skipping restore of track without a source: {:?}", track.metadata.title);
+
+                    continue;
+                }
+
+                match core.make_room() {
+                    Ok(made) => dropped.extend(made),
+                    Err(_) => {
+                        warn!("[Voice] Queue is full, skipping the rest of the restore.");
+
+                        break;
+                    },
+                }
+
+                core.push(track.metadata, None, track.position);
+                restored += 1;
+            }
+        }
+
+        self.dropped(dropped);
+
+        restored
+    }
+
+    /// Advances the queue, called by the voice task on every cycle.
+    ///
+    /// Removes ended tracks from the front of the queue, and returns the new
+    /// current track if it has yet to be handed to the mixer.
+    ///
+    /// Emits [`HandlerEvent::QueueEmpty`] if this removes the last track, or
+    /// [`HandlerEvent::QueuePaused`] if auto-advance is off and others remain.
+    ///
+    /// [`HandlerEvent::QueueEmpty`]: enum.HandlerEvent.html#variant.QueueEmpty
+    /// [`HandlerEvent::QueuePaused`]: enum.HandlerEvent.html#variant.QueuePaused
+    pub(crate) fn poll(&self) -> Option<LockedAudio> {
+        let mut core = self.lock();
+        let mut last_ended = None;
+        let mut last_metadata = None;
+        let mut skipped = Vec::new();
+
+        while let Some(queued) = core.tracks.front() {
+            let reason = match queued.track {
+                Some(ref track) if queued.sent => match track.status() {
+                    TrackStatus::Ended(reason) => reason,
+                    _ => break,
+                },
+                _ => break,
+            };
+
+            let popped = core.tracks.pop_front();
+            core.current_changed = true;
+            last_ended = Some(reason);
+
+            if reason == EndReason::Failed {
+                if let Some(ref queued) = popped {
+                    skipped.push((failure(queued.track.as_ref()), queued.metadata.clone()));
+                }
+
+                core.failures += 1;
+            } else {
+                core.failures = 0;
+            }
+
+            last_metadata = popped.map(|queued| queued.metadata);
+        }
+
+        if !skipped.is_empty() {
+            drop(core);
+
+            for (reason, metadata) in skipped {
+                self.events.emit(HandlerEvent::TrackAutoSkipped {
+                    reason: Arc::new(Error::Voice(reason)),
+                    metadata,
+                });
+            }
+
+            core = self.lock();
+        }
+
+        if let Some(reason) = last_ended.filter(|_| core.tracks.is_empty()) {
+            let natural = matches!(reason, EndReason::Finished | EndReason::Failed);
+            let notify = natural || core.empty_on_stop;
+            let mut autoplay = core.autoplay.clone().filter(|_| natural);
+
+            if autoplay.is_some() && core.failures >= MAX_AUTOPLAY_FAILURES {
+                warn!("[Voice] {} tracks in a row failed, so autoplay is giving up.", core.failures);
+                autoplay = None;
+            }
+
+            drop(core);
+
+            match (autoplay, last_metadata) {
+                (Some(provider), Some(previous)) => self.autoplay(provider, previous, reason),
+                _ => self.emptied(reason, notify),
+            }
+
+            return None;
+        }
+
+        if core.halted {
+            if last_ended.is_some() {
+                let pending = core.tracks.len();
+                drop(core);
+                self.events.emit(HandlerEvent::QueuePaused { pending });
+            }
+
+            return None;
+        }
+
+        let queued = core.tracks.front_mut()?;
+
+        match queued.track {
+            Some(ref track) if !queued.sent => {
+                queued.sent = true;
+                let audio = track.audio().clone();
+                core.current_changed = true;
+
+                Some(audio)
+            },
+            None if !queued.resolving => {
+                queued.resolving = true;
+
+                if let Some(source) = queued.metadata.source.clone() {
+                    self.resolve(queued.id, source);
+                }
+
+                None
+            },
+            _ => None,
+        }
+    }
+
+    /// Sets the function called with the current track whenever it changes,
+    /// or removes it if `None`, calling it on the next cycle either way.
+    pub(crate) fn set_presence_hook(&self, hook: Option<PresenceHook>) {
+        let mut core = self.lock();
+        core.presence_hook = hook;
+        core.current_changed = true;
+    }
+
+    /// Calls the presence hook with the current track, if it has changed
+    /// since this was last called, called by the voice task after advancing
+    /// the queue.
+    pub(crate) async fn update_presence(&self) {
+        let (hook, playing) = {
+            let mut core = self.lock();
+
+            if !mem::replace(&mut core.current_changed, false) {
+                return;
+            }
+
+            // A track held back while auto-advance is off is not playing.
+            let playing = core.tracks.front().map_or(false, |queued| queued.sent);
+
+            match core.presence_hook.clone() {
+                Some(hook) => (hook, playing),
+                None => return,
+            }
+        };
+
+        let now_playing = if playing {
+            self.now_playing().await
+        } else {
+            None
+        };
+
+        hook(now_playing.as_ref());
+    }
+
+    /// Marks the queue as emptied, emitting an event if asked to.
+    fn emptied(&self, reason: EndReason, notify: bool) {
+        self.notify_emptied();
+
+        if notify {
+            self.events.emit(HandlerEvent::QueueEmpty { reason });
+        }
+    }
+
+    /// Asks an autoplay provider for the track to follow the last one in the
+    /// background, adding it to the queue or else marking the queue as
+    /// emptied.
+    fn autoplay(&self, provider: Arc<dyn AutoplayProvider>, previous: TrackMetadata, reason: EndReason) {
+        let queue = self.clone();
+
+        tokio::spawn(async move {
+            let added = match provider.next_track(&previous).await {
+                Some(next) => {
+                    info!("[Voice] Autoplaying track: {:?}", next.title);
+
+                    queue.add_lazy(next)
+                },
+                None => false,
+            };
+
+            if !added {
+                queue.emptied(reason, true);
+            }
+        });
+    }
+
+    /// Creates the source of a lazily restored track in the background.
+    fn resolve(&self, id: u64, source: TrackSource) {
+        let queue = self.clone();
+
+        tokio::spawn(async move {
+            let created = source.create().await;
+            let mut core = queue.lock();
+            let index = match core.tracks.iter().position(|queued| queued.id == id) {
+                Some(index) => index,
+                // Removed from the queue in the meantime.
+                None => return,
+            };
+
+            match created {
+                Ok(source) => {
+                    let queued = &mut core.tracks[index];
+                    let mut audio = Audio::new(source);
+                    audio.requester = queued.metadata.requester;
+
+                    if queued.resume_at > Duration::default() {
+                        audio.position(queued.resume_at);
+                    }
+
+                    info!("[Voice] Restored queued track: {:?}", queued.metadata.title);
+
+                    queued.track = Some(TrackHandle::new(audio, queue.connection.clone(), queue.events.clone()));
+                    queued.resolving = false;
+                },
+                Err(why) => {
+                    warn!("[Voice] Failed to restore queued track {:?}: {:?}", source, why);
+
+                    let skipped = core.tracks.remove(index);
+                    let emptied = core.tracks.is_empty();
+                    drop(core);
+
+                    if let Some(skipped) = skipped {
+                        queue.events.emit(HandlerEvent::TrackAutoSkipped {
+                            reason: Arc::new(why),
+                            metadata: skipped.metadata,
+                        });
+                    }
+
+                    if emptied {
+                        queue.notify_emptied();
+                    }
+                },
+            }
+        });
+    }
+}
+
+/// Takes the reason a track failed while playing, as recorded by the mixer.
+///
+/// A track whose audio is locked elsewhere, or which was ended as failed by
+/// hand, is reported as having failed to read.
+fn failure(track: Option<&TrackHandle>) -> VoiceError {
+    track
+        .and_then(|track| track.audio().try_lock().ok())
+        .and_then(|mut audio| audio.failure.take())
+        .unwrap_or(VoiceError::SourceRead)
+}
+
+/// Checks that an index refers to a track which is queued behind the current
+/// one.
+fn check_upcoming(core: &QueueCore, index: usize) -> StdResult<(), QueueError> {
+    if index == 0 {
+        Err(QueueError::CurrentTrack)
+    } else if index >= core.tracks.len() {
+        Err(QueueError::OutOfBounds(index))
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn queue_of(titles: &[&str]) -> TrackQueue {
+        let (_, connection) = watch::channel(ConnectionState::Disconnected);
+        let queue = TrackQueue::new(GuildId(1), Arc::default(), connection, EventSink::default());
+
+        let tracks = titles.iter().map(|title| SnapshotTrack {
+            metadata: TrackMetadata {
+                title: Some(title.to_string()),
+                source: Some(TrackSource::Ffmpeg(title.to_string())),
+                requester: None,
+            },
+            position: Duration::default(),
+        }).collect();
+
+        queue.restore(QueueSnapshot { tracks });
+
+        queue
+    }
+
+    fn titles(queue: &TrackQueue) -> Vec<String> {
+        queue.tracklist()
+            .into_iter()
+            .filter_map(|entry| entry.metadata.title)
+            .collect()
+    }
+
+    #[test]
+    fn test_move_track() {
+        let queue = queue_of(&["a", "b", "c", "d"]);
+
+        queue.move_track(3, 1).unwrap();
+        assert_eq!(titles(&queue), vec!["a", "d", "b", "c"]);
+
+        queue.move_track(1, 3).unwrap();
+        assert_eq!(titles(&queue), vec!["a", "b", "c", "d"]);
+
+        assert!(matches!(queue.move_track(0, 2), Err(QueueError::CurrentTrack)));
+        assert!(matches!(queue.move_track(2, 0), Err(QueueError::CurrentTrack)));
+        assert!(matches!(queue.move_track(1, 4), Err(QueueError::OutOfBounds(4))));
+    }
+
+    #[test]
+    fn test_swap() {
+        let queue = queue_of(&["a", "b", "c"]);
+
+        queue.swap(1, 2).unwrap();
+        assert_eq!(titles(&queue), vec!["a", "c", "b"]);
+
+        assert!(matches!(queue.swap(0, 1), Err(QueueError::CurrentTrack)));
+        assert!(matches!(queue.swap(1, 3), Err(QueueError::OutOfBounds(3))));
+    }
+
+    #[tokio::test]
+    async fn test_now_playing() {
+        let queue = queue_of(&[]);
+        assert!(queue.now_playing().await.is_none());
+
+        let metadata = TrackMetadata {
+            title: Some("a".to_string()),
+            source: None,
+            requester: Some(UserId(7)),
+        };
+        let track = queue.add(crate::voice::pcm(true, std::io::Cursor::new(Vec::new())), metadata).unwrap();
+        track.audio().lock().await.volume(0.5).pause();
+        assert_eq!(track.requester(), Some(UserId(7)));
+
+        let now_playing = queue.now_playing().await.unwrap();
+        assert_eq!(now_playing.metadata.title.as_deref(), Some("a"));
+        assert_eq!(now_playing.volume, 0.5);
+        assert!(now_playing.paused);
+        assert!(now_playing.duration.is_none());
+    }
+
+    #[test]
+    fn test_max_len() {
+        let queue = queue_of(&["a", "b", "c"]);
+        let mut events = queue.events.subscribe();
+        let lazy = |title: &str| TrackMetadata {
+            title: Some(title.to_string()),
+            source: Some(TrackSource::Ffmpeg(title.to_string())),
+            requester: None,
+        };
+
+        queue.set_max_len(Some(3));
+        assert!(!queue.add_lazy(lazy("d")));
+        assert!(events.try_next().is_err());
+
+        queue.set_full_policy(QueueFullPolicy::DropOldest);
+        assert!(queue.add_lazy(lazy("d")));
+        assert_eq!(titles(&queue), vec!["a", "c", "d"]);
+        assert!(matches!(events.try_next(), Ok(Some(HandlerEvent::QueueChanged))));
+
+        queue.set_full_policy(QueueFullPolicy::DropNewest);
+        assert!(queue.add_lazy(lazy("e")));
+        assert_eq!(titles(&queue), vec!["a", "c", "e"]);
+
+        // The current track is never dropped.
+        queue.set_max_len(Some(1));
+        let source = crate::voice::pcm(true, std::io::Cursor::new(Vec::new()));
+        assert!(matches!(queue.add(source, lazy("f")), Err(VoiceError::QueueFull)));
+        assert_eq!(queue.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_dropped_tracks_end() {
+        // Tracks are only waited on while connected.
+        let (_state, connection) = watch::channel(ConnectionState::Connected);
+        let queue = TrackQueue::new(GuildId(1), Arc::default(), connection, EventSink::default());
+        let mut events = queue.events.subscribe();
+        let add = |title: &str| {
+            let metadata = TrackMetadata {
+                title: Some(title.to_string()),
+                source: None,
+                requester: Some(UserId(7)),
+            };
+
+            queue.add(crate::voice::pcm(true, std::io::Cursor::new(Vec::new())), metadata).unwrap()
+        };
+
+        add("a");
+        let oldest = add("b");
+        while events.try_next().is_ok() {}
+
+        queue.set_max_len(Some(2));
+        queue.set_full_policy(QueueFullPolicy::DropOldest);
+        let newest = add("c");
+        assert_eq!(oldest.ended().await.unwrap(), EndReason::Cleared);
+
+        // A track locked by its handle is still ended once it is free.
+        queue.set_full_policy(QueueFullPolicy::DropNewest);
+        let guard = newest.audio().lock().await;
+        add("e");
+        assert_eq!(titles(&queue), vec!["a", "e"]);
+        drop(guard);
+        assert_eq!(newest.ended().await.unwrap(), EndReason::Cleared);
+
+        let mut ended = 0;
+        while let Ok(Some(event)) = events.try_next() {
+            if let HandlerEvent::TrackEnded(event) = event {
+                assert_eq!(event.guild_id, GuildId(1));
+                assert_eq!(event.reason, EndReason::Cleared);
+                assert_eq!(event.requester, Some(UserId(7)));
+                ended += 1;
+            }
+        }
+        assert_eq!(ended, 2);
+    }
+
+    #[tokio::test]
+    async fn test_queue_empty() {
+        let queue = queue_of(&[]);
+        let mut events = queue.events.subscribe();
+        let metadata = TrackMetadata::default();
+        let add = || {
+            let source = crate::voice::pcm(true, std::io::Cursor::new(Vec::new()));
+
+            queue.add(source, metadata.clone()).unwrap()
+        };
+
+        // Stopping the last track is not reported by default.
+        let track = add();
+        assert!(queue.poll().is_some());
+        track.audio().lock().await.mark_ended(EndReason::Stopped);
+        assert!(queue.poll().is_none());
+        assert!(events.try_next().is_err());
+
+        let track = add();
+        add();
+        queue.poll();
+        track.audio().lock().await.mark_ended(EndReason::Finished);
+        assert!(queue.poll().is_some());
+        assert!(events.try_next().is_err());
+
+        queue.current().unwrap().audio().lock().await.mark_ended(EndReason::Finished);
+        assert!(queue.poll().is_none());
+        assert!(matches!(
+            events.try_next(),
+            Ok(Some(HandlerEvent::QueueEmpty { reason: EndReason::Finished })),
+        ));
+
+        queue.set_empty_on_stop(true);
+        add();
+        queue.clear();
+        assert!(matches!(events.try_next(), Ok(Some(HandlerEvent::QueueChanged))));
+        assert!(matches!(
+            events.try_next(),
+            Ok(Some(HandlerEvent::QueueEmpty { reason: EndReason::Cleared })),
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_wait_until_empty() {
+        use futures::FutureExt;
+
+        let queue = queue_of(&[]);
+        assert!(queue.wait_until_empty().now_or_never().is_some());
+
+        let source = crate::voice::pcm(true, std::io::Cursor::new(Vec::new()));
+        let track = queue.add(source, TrackMetadata::default()).unwrap();
+        let mut emptied = Box::pin(queue.wait_until_empty());
+        assert!((&mut emptied).now_or_never().is_none());
+
+        queue.poll();
+        assert!((&mut emptied).now_or_never().is_none());
+
+        // Stopping counts as emptying the queue, even without an event.
+        track.audio().lock().await.mark_ended(EndReason::Stopped);
+        queue.poll();
+        assert!(emptied.now_or_never().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_auto_advance() {
+        let queue = queue_of(&[]);
+        let mut events = queue.events.subscribe();
+        let add = || {
+            let source = crate::voice::pcm(true, std::io::Cursor::new(Vec::new()));
+
+            queue.add(source, TrackMetadata::default()).unwrap()
+        };
+
+        let first = add();
+        let second = add();
+        assert!(queue.poll().is_some());
+
+        // The current track carries on, but the next is held back.
+        queue.set_auto_advance(false);
+        assert!(queue.poll().is_none());
+        first.audio().lock().await.mark_ended(EndReason::Finished);
+        assert!(queue.poll().is_none());
+        assert!(matches!(events.try_next(), Ok(Some(HandlerEvent::QueuePaused { pending: 1 }))));
+
+        assert!(queue.poll().is_none());
+        assert!(events.try_next().is_err());
+        assert_eq!(queue.len(), 1);
+
+        queue.set_auto_advance(true);
+        assert!(Arc::ptr_eq(&queue.poll().unwrap(), second.audio()));
+    }
+
+    #[tokio::test]
+    async fn test_presence_hook() {
+        let queue = queue_of(&[]);
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let hook_seen = Arc::clone(&seen);
+        queue.set_presence_hook(Some(Arc::new(move |now: Option<&NowPlaying>| {
+            let title = now.and_then(|now| now.metadata.title.clone());
+            hook_seen.lock().unwrap().push(title);
+        })));
+
+        let add = |title: &str| {
+            let source = crate::voice::pcm(true, std::io::Cursor::new(Vec::new()));
+            let metadata = TrackMetadata {
+                title: Some(title.to_string()),
+                ..Default::default()
+            };
+
+            queue.add(source, metadata).unwrap()
+        };
+
+        // Setting the hook reports the current state at once.
+        queue.update_presence().await;
+
+        let first = add("first");
+        add("second");
+        queue.poll();
+        queue.update_presence().await;
+        queue.update_presence().await;
+
+        first.audio().lock().await.mark_ended(EndReason::Finished);
+        queue.poll();
+        queue.update_presence().await;
+
+        // Nothing plays while the next track is held back.
+        let second = queue.current().unwrap();
+        add("third");
+        queue.set_auto_advance(false);
+        second.audio().lock().await.mark_ended(EndReason::Finished);
+        queue.poll();
+        queue.update_presence().await;
+
+        queue.clear();
+        queue.update_presence().await;
+
+        let title = |title: &str| Some(title.to_string());
+        assert_eq!(*seen.lock().unwrap(), vec![None, title("first"), title("second"), None, None]);
+    }
+
+    #[tokio::test]
+    async fn test_autoplay() {
+        struct Related;
+
+        #[async_trait]
+        impl AutoplayProvider for Related {
+            async fn next_track(&self, previous: &TrackMetadata) -> Option<TrackMetadata> {
+                match previous.title.as_deref() {
+                    Some("first") => Some(TrackMetadata {
+                        title: Some("related".to_string()),
+                        source: Some(TrackSource::Ffmpeg("related".to_string())),
+                        requester: None,
+                    }),
+                    _ => None,
+                }
+            }
+        }
+
+        let queue = queue_of(&[]);
+        let mut events = queue.events.subscribe();
+        queue.set_autoplay(Some(Box::new(Related)));
+
+        let source = crate::voice::pcm(true, std::io::Cursor::new(Vec::new()));
+        let metadata = TrackMetadata {
+            title: Some("first".to_string()),
+            ..Default::default()
+        };
+        let track = queue.add(source, metadata).unwrap();
+        queue.poll();
+
+        // The provider's track is queued in place of the queue emptying.
+        track.audio().lock().await.mark_ended(EndReason::Finished);
+        assert!(queue.poll().is_none());
+        tokio::task::yield_now().await;
+        assert_eq!(titles(&queue), vec!["related".to_string()]);
+        assert!(!matches!(events.try_next(), Ok(Some(HandlerEvent::QueueEmpty { .. }))));
+
+        // Once it has nothing to add, the queue empties as usual.
+        let source = crate::voice::pcm(true, std::io::Cursor::new(Vec::new()));
+        queue.clear();
+        let track = queue.add(source, TrackMetadata::default()).unwrap();
+        queue.poll();
+        track.audio().lock().await.mark_ended(EndReason::Finished);
+        queue.poll();
+        tokio::task::yield_now().await;
+        assert!(queue.is_empty());
+
+        let emptied = std::iter::from_fn(|| events.try_next().ok().flatten())
+            .any(|event| matches!(event, HandlerEvent::QueueEmpty { reason: EndReason::Finished }));
+        assert!(emptied);
+    }
+
+    #[tokio::test]
+    async fn test_autoplay_gives_up_on_failures() {
+        struct Broken;
+
+        #[async_trait]
+        impl AutoplayProvider for Broken {
+            async fn next_track(&self, _previous: &TrackMetadata) -> Option<TrackMetadata> {
+                Some(TrackMetadata {
+                    title: Some("broken".to_string()),
+                    source: Some(TrackSource::Ffmpeg("broken".to_string())),
+                    requester: None,
+                })
+            }
+        }
+
+        let queue = queue_of(&[]);
+        let mut events = queue.events.subscribe();
+        queue.set_autoplay(Some(Box::new(Broken)));
+
+        let source = crate::voice::pcm(true, std::io::Cursor::new(Vec::new()));
+        queue.add(source, TrackMetadata::default()).unwrap();
+
+        // Each track fails as soon as it plays, standing in for its source.
+        let mut autoplayed = 0;
+        loop {
+            let audio = {
+                let mut core = queue.lock();
+                let queued = match core.tracks.front_mut() {
+                    Some(queued) => queued,
+                    None => break,
+                };
+
+                if queued.track.is_none() {
+                    let source = crate::voice::pcm(true, std::io::Cursor::new(Vec::new()));
+                    queued.track = Some(TrackHandle::new(Audio::new(source), queue.connection.clone(), queue.events.clone()));
+                    autoplayed += 1;
+                }
+
+                queued.track.as_ref().unwrap().audio().clone()
+            };
+
+            queue.poll();
+            audio.lock().await.mark_ended(EndReason::Failed);
+            queue.poll();
+            tokio::task::yield_now().await;
+        }
+
+        assert_eq!(autoplayed, MAX_AUTOPLAY_FAILURES - 1);
+
+        let emptied = std::iter::from_fn(|| events.try_next().ok().flatten())
+            .any(|event| matches!(event, HandlerEvent::QueueEmpty { reason: EndReason::Failed }));
+        assert!(emptied);
+    }
+
+    #[tokio::test]
+    async fn test_track_auto_skipped() {
+        let queue = queue_of(&[]);
+        let mut events = queue.events.subscribe();
+
+        let source = crate::voice::pcm(true, std::io::Cursor::new(Vec::new()));
+        let metadata = TrackMetadata {
+            title: Some("failing".to_string()),
+            ..Default::default()
+        };
+        let track = queue.add(source, metadata).unwrap();
+        queue.add_lazy(TrackMetadata {
+            title: Some("missing".to_string()),
+            source: Some(TrackSource::Ffmpeg("/nonexistent/track.mp3".to_string())),
+            requester: None,
+        });
+        queue.poll();
+
+        // A track panicking while playing, then one failing to be created.
+        {
+            let mut audio = track.audio().lock().await;
+            audio.failure = Some(VoiceError::SourcePanicked);
+            audio.mark_ended(EndReason::Failed);
+        }
+        queue.poll();
+
+        let mut skipped = Vec::new();
+
+        while skipped.len() < 2 {
+            match tokio::time::timeout(Duration::from_secs(5), events.next()).await.unwrap() {
+                Some(HandlerEvent::TrackAutoSkipped { reason, metadata }) => skipped.push((reason, metadata.title)),
+                Some(_) => {},
+                None => panic!("events ended"),
+            }
+        }
+
+        assert!(matches!(*skipped[0].0, Error::Voice(VoiceError::SourcePanicked)));
+        assert_eq!(skipped[0].1.as_deref(), Some("failing"));
+        assert!(matches!(*skipped[1].0, Error::Voice(VoiceError::SourceNotFound(_))));
+        assert_eq!(skipped[1].1.as_deref(), Some("missing"));
+        assert!(queue.is_empty());
+    }
+}
@@ -0,0 +1,755 @@
+use audiopus::{Channels, SampleRate};
+use crate::model::id::UserId;
+use futures::channel::mpsc::UnboundedSender as Sender;
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet, VecDeque},
+    fmt,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use super::{audio::SAMPLE_RATE, dynamics::AutoGain, state::SharedState, AgcConfig, AudioReceiver, Status};
+
+/// How recently a user's audio must have arrived for them to be considered
+/// speaking.
+const SPEAKING_WINDOW: Duration = Duration::from_millis(250);
+
+/// How long after its last packet an SSRC is forgotten.
+const SSRC_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The number of samples in a frame of mixed audio: 20ms of 48kHz stereo.
+const MIXED_FRAME_LEN: usize = 1920;
+
+/// How many frames from a speaker are buffered before their audio is mixed,
+/// absorbing jitter in the arrival of their packets.
+pub(crate) const JITTER_FRAMES: usize = 2;
+
+/// The most frames buffered per speaker by default, beyond which the oldest
+/// are dropped so that a speaker sending too quickly cannot build up latency.
+const DEFAULT_BUFFER_LIMIT: usize = 10;
+
+/// A decoded voice packet, received via [`Handler::take_audio_stream`].
+///
+/// [`Handler::take_audio_stream`]: struct.Handler.html#method.take_audio_stream
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct DecodedPacket {
+    /// The RTP synchronisation source of the packet.
+    pub ssrc: u32,
+    /// The user who sent the packet, if their SSRC has been announced.
+    pub user_id: Option<UserId>,
+    /// The RTP sequence number of the packet.
+    pub sequence: u16,
+    /// The RTP timestamp of the packet.
+    pub timestamp: u32,
+    /// Whether `data` contains interleaved stereo samples.
+    pub stereo: bool,
+    /// Decoded PCM samples, at 48kHz unless another rate is set via
+    /// [`Handler::set_receive_format`].
+    ///
+    /// [`Handler::set_receive_format`]: struct.Handler.html#method.set_receive_format
+    pub data: Vec<i16>,
+    /// The size of the packet's Opus payload, in bytes.
+    pub compressed_size: usize,
+}
+
+/// A sender of audio heard recently on a voice connection, as listed by
+/// [`Handler::active_ssrcs`].
+///
+/// [`Handler::active_ssrcs`]: struct.Handler.html#method.active_ssrcs
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct SsrcInfo {
+    /// The RTP synchronisation source of the sender.
+    pub ssrc: u32,
+    /// The user who owns the SSRC, if it has been announced.
+    pub user_id: Option<UserId>,
+    /// When the last voice packet from the SSRC arrived.
+    pub last_packet: Instant,
+    /// The number of voice packets received from the SSRC.
+    pub packets: u64,
+}
+
+/// A receiver registered via [`Handler::add_receiver`], which is removed
+/// from the handler when this is dropped or [`remove`]d.
+///
+/// Each handle only removes its own receiver, so that independent parts of
+/// an application can manage their receivers without affecting each other's.
+/// Use [`detach`] to leave the receiver registered instead, until it is
+/// removed via [`Handler::remove_receiver`] or replaced via
+/// [`Handler::listen`].
+///
+/// [`detach`]: #method.detach
+/// [`Handler::add_receiver`]: struct.Handler.html#method.add_receiver
+/// [`Handler::listen`]: struct.Handler.html#method.listen
+/// [`Handler::remove_receiver`]: struct.Handler.html#method.remove_receiver
+/// [`remove`]: #method.remove
+#[must_use = "dropping a ReceiverHandle removes its receiver"]
+pub struct ReceiverHandle {
+    receiver: Option<Arc<dyn AudioReceiver>>,
+    sender: Sender<Status>,
+    shared: Arc<SharedState>,
+}
+
+impl ReceiverHandle {
+    pub(crate) fn new(receiver: Arc<dyn AudioReceiver>, sender: Sender<Status>, shared: Arc<SharedState>) -> Self {
+        Self {
+            receiver: Some(receiver),
+            sender,
+            shared,
+        }
+    }
+
+    /// Returns the registered receiver.
+    pub fn receiver(&self) -> &Arc<dyn AudioReceiver> {
+        self.receiver.as_ref().expect("[Voice] Receiver handle already removed")
+    }
+
+    /// Removes the receiver from the handler, from the next packet received.
+    /// This is the same as dropping the handle.
+    pub fn remove(self) {}
+
+    /// Leaves the receiver registered after the handle is gone, returning
+    /// it so that it can still be passed to [`Handler::remove_receiver`].
+    ///
+    /// [`Handler::remove_receiver`]: struct.Handler.html#method.remove_receiver
+    pub fn detach(mut self) -> Arc<dyn AudioReceiver> {
+        self.receiver.take().expect("[Voice] Receiver handle already removed")
+    }
+}
+
+impl Drop for ReceiverHandle {
+    fn drop(&mut self) {
+        if let Some(receiver) = self.receiver.take() {
+            self.shared.message_sent();
+
+            // A task which has stopped holds no receivers to remove.
+            if self.sender.unbounded_send(Status::RemoveReceiver(receiver)).is_err() {
+                self.shared.message_received();
+            }
+        }
+    }
+}
+
+impl fmt::Debug for ReceiverHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReceiverHandle")
+            .field("removed", &self.receiver.is_none())
+            .finish()
+    }
+}
+
+/// Where a voice task delivers received audio.
+///
+/// Audio is either passed to [`AudioReceiver`]s or sent down a stream, but
+/// never both.
+///
+/// [`AudioReceiver`]: trait.AudioReceiver.html
+#[derive(Default)]
+pub(crate) struct ReceiveState {
+    pub receivers: Vec<Arc<dyn AudioReceiver>>,
+    pub stream: Option<Sender<DecodedPacket>>,
+    /// The only users whose audio is decoded, if set.
+    pub filter: Option<HashSet<UserId>>,
+    /// The gain applied to each user's audio in the mix, where not unity.
+    pub gains: HashMap<UserId, f32>,
+    pub mixer: ReceiveMixer,
+    /// The automatic gain control applied to each SSRC's audio, if enabled.
+    agc: Option<AgcConfig>,
+    auto_gains: HashMap<u32, AutoGain>,
+    /// The streams of each user's own audio, made via
+    /// `Handler::user_audio_stream`.
+    user_streams: HashMap<UserId, Vec<Sender<Vec<i16>>>>,
+    /// Buffers the audio of users with streams, apart from `mixer`.
+    user_mixer: ReceiveMixer,
+    /// The format to which audio is converted before it is delivered, if
+    /// not 48kHz stereo.
+    format: Option<(SampleRate, Channels)>,
+}
+
+impl ReceiveState {
+    /// Whether anything is consuming received audio.
+    pub fn is_listening(&self) -> bool {
+        !self.receivers.is_empty() || self.stream.is_some() || !self.user_streams.is_empty()
+    }
+
+    /// Whether audio from the given user should be decoded, which is never
+    /// the case for unknown users while a filter is set, nor for users
+    /// without streams of their own while only those are listening.
+    pub fn accepts(&self, user_id: Option<&UserId>) -> bool {
+        let filtered = match (self.filter.as_ref(), user_id) {
+            (None, _) => true,
+            (Some(filter), Some(user_id)) => filter.contains(user_id),
+            (Some(_), None) => false,
+        };
+
+        if self.receivers.is_empty() && self.stream.is_none() {
+            filtered && user_id.map_or(false, |user_id| self.user_streams.contains_key(user_id))
+        } else {
+            filtered
+        }
+    }
+
+    pub fn set_receiver(&mut self, receiver: Option<Arc<dyn AudioReceiver>>) {
+        self.stream = None;
+        self.receivers.clear();
+        self.receivers.extend(receiver);
+        self.mixer.clear();
+    }
+
+    pub fn add_receiver(&mut self, receiver: Arc<dyn AudioReceiver>) {
+        self.stream = None;
+        self.receivers.push(receiver);
+    }
+
+    pub fn remove_receiver(&mut self, receiver: &Arc<dyn AudioReceiver>) {
+        self.receivers.retain(|existing| !Arc::ptr_eq(existing, receiver));
+    }
+
+    /// Sets the gain applied to a user's audio in the mix, forgetting it if
+    /// it is unity.
+    pub fn set_gain(&mut self, user_id: UserId, gain: f32) {
+        if (gain - 1.0).abs() < f32::EPSILON {
+            self.gains.remove(&user_id);
+        } else {
+            self.gains.insert(user_id, gain);
+        }
+    }
+
+    /// Enables or disables automatic gain control, starting every speaker
+    /// again from unity gain.
+    pub fn set_agc(&mut self, config: Option<AgcConfig>) {
+        self.agc = config;
+        self.auto_gains.clear();
+    }
+
+    /// Applies automatic gain control, if enabled, to a frame of audio
+    /// decoded from an SSRC.
+    pub fn apply_agc(&mut self, ssrc: u32, frame: &mut [i16]) {
+        if let Some(config) = self.agc.as_ref() {
+            self.auto_gains
+                .entry(ssrc)
+                .or_insert_with(|| AutoGain::new(config))
+                .apply(frame);
+        }
+    }
+
+    /// Sets the format to which audio is converted before it is delivered.
+    pub fn set_format(&mut self, rate: SampleRate, channels: Channels) {
+        self.format = if rate == SAMPLE_RATE && channels == Channels::Stereo {
+            None
+        } else {
+            Some((rate, channels))
+        };
+    }
+
+    /// Converts decoded audio to the set format, returning whether the
+    /// converted audio is stereo.
+    pub fn convert<'a>(&self, stereo: bool, data: &'a [i16]) -> (bool, Cow<'a, [i16]>) {
+        match self.format {
+            Some((rate, channels)) => {
+                let (stereo, data) = convert_format(data, stereo, rate, channels);
+
+                (stereo, Cow::Owned(data))
+            },
+            None => (stereo, Cow::Borrowed(data)),
+        }
+    }
+
+    /// Forgets the automatic gain of an SSRC which has gone away.
+    pub fn forget_ssrc(&mut self, ssrc: u32) {
+        self.auto_gains.remove(&ssrc);
+    }
+
+    /// Mixes the next frame of all speakers' audio.
+    pub fn mix(&mut self) -> Vec<i16> {
+        self.mixer.mix(&self.gains)
+    }
+
+    /// Sets the most frames buffered per speaker, for both the mix and the
+    /// streams of each user's audio, returning how many were dropped.
+    pub fn set_buffer_limit(&mut self, limit: usize) -> usize {
+        self.mixer.set_limit(limit) + self.user_mixer.set_limit(limit)
+    }
+
+    /// Adds a stream of a user's own audio, alongside any others.
+    pub fn add_user_stream(&mut self, user_id: UserId, stream: Sender<Vec<i16>>) {
+        self.user_streams.entry(user_id).or_default().push(stream);
+    }
+
+    /// Buffers a decoded frame for the streams of the user who sent it, if
+    /// they have any, returning whether an older frame was dropped.
+    pub fn push_user_frame(&mut self, ssrc: u32, user_id: Option<UserId>, stereo: bool, data: &[i16]) -> bool {
+        match user_id {
+            Some(user_id) if self.user_streams.contains_key(&user_id) => {
+                self.user_mixer.push(ssrc, Some(user_id), stereo, data)
+            },
+            _ => false,
+        }
+    }
+
+    /// Sends the next buffered frame of each user down their streams,
+    /// forgetting those whose receiving halves have been dropped, and so no
+    /// longer buffering the audio of users left without any.
+    pub fn send_user_frames(&mut self) {
+        for (user_id, frame) in self.user_mixer.next_frames() {
+            let user_id = match user_id {
+                Some(user_id) => user_id,
+                None => continue,
+            };
+
+            if let Some(streams) = self.user_streams.get_mut(&user_id) {
+                let frame = match self.format {
+                    Some((rate, channels)) => convert_format(&frame, true, rate, channels).1,
+                    None => frame,
+                };
+
+                streams.retain(|stream| stream.unbounded_send(frame.clone()).is_ok());
+
+                if streams.is_empty() {
+                    self.user_streams.remove(&user_id);
+                }
+            }
+        }
+    }
+
+    pub fn set_stream(&mut self, stream: Sender<DecodedPacket>) {
+        self.receivers.clear();
+        self.stream = Some(stream);
+    }
+
+    /// Sends a packet down the audio stream, if one is set, dropping the
+    /// stream if its receiving half has been dropped.
+    pub fn send_to_stream(&mut self, packet: DecodedPacket) {
+        if let Some(stream) = self.stream.as_ref() {
+            if stream.unbounded_send(packet).is_err() {
+                self.stream = None;
+            }
+        }
+    }
+}
+
+/// Converts 48kHz audio to the given sample rate and channels, returning
+/// whether the result is stereo. `Channels::Auto` keeps the audio's own
+/// channels.
+///
+/// Every supported rate divides 48kHz, so each output sample is the average
+/// of a whole number of input samples, which also filters out most of the
+/// frequencies too high for the lower rate. Downmixing averages the two
+/// channels, and upmixing duplicates the one.
+fn convert_format(data: &[i16], stereo: bool, rate: SampleRate, channels: Channels) -> (bool, Vec<i16>) {
+    let factor = (SAMPLE_RATE as i32 / rate as i32) as usize;
+    let in_channels = if stereo { 2 } else { 1 };
+    let out_stereo = match channels {
+        Channels::Mono => false,
+        Channels::Stereo => true,
+        Channels::Auto => stereo,
+    };
+
+    let out_len = data.len() / in_channels / factor * if out_stereo { 2 } else { 1 };
+    let mut out = Vec::with_capacity(out_len);
+
+    for group in data.chunks_exact(factor * in_channels) {
+        let mut left = 0i32;
+        let mut right = 0i32;
+
+        for frame in group.chunks_exact(in_channels) {
+            left += i32::from(frame[0]);
+            right += i32::from(frame[in_channels - 1]);
+        }
+
+        let left = left / factor as i32;
+        let right = right / factor as i32;
+
+        if out_stereo {
+            out.push(left as i16);
+            out.push(right as i16);
+        } else {
+            out.push(((left + right) / 2) as i16);
+        }
+    }
+
+    (out_stereo, out)
+}
+
+/// Buffers decoded audio per SSRC, to be summed into one frame every 20ms for
+/// [`AudioReceiver::mixed_audio`].
+///
+/// [`AudioReceiver::mixed_audio`]: trait.AudioReceiver.html#method.mixed_audio
+pub(crate) struct ReceiveMixer {
+    speakers: HashMap<u32, SpeakerBuffer>,
+    /// The most frames buffered per speaker.
+    limit: usize,
+}
+
+impl Default for ReceiveMixer {
+    fn default() -> Self {
+        Self {
+            speakers: HashMap::new(),
+            limit: DEFAULT_BUFFER_LIMIT,
+        }
+    }
+}
+
+#[derive(Default)]
+struct SpeakerBuffer {
+    /// The user who sent the audio, once their SSRC is known.
+    user_id: Option<UserId>,
+    frames: VecDeque<Vec<i16>>,
+    /// Whether enough frames have arrived for the speaker to be mixed.
+    primed: bool,
+}
+
+impl ReceiveMixer {
+    /// Drops all buffered audio.
+    pub fn clear(&mut self) {
+        self.speakers.clear();
+    }
+
+    /// Sets the most frames buffered per speaker, which must be at least
+    /// `JITTER_FRAMES`, returning how many frames were dropped to meet it.
+    pub fn set_limit(&mut self, limit: usize) -> usize {
+        self.limit = limit;
+
+        self.speakers
+            .values_mut()
+            .map(|speaker| {
+                let excess = speaker.frames.len().saturating_sub(limit);
+                speaker.frames.drain(..excess);
+
+                excess
+            })
+            .sum()
+    }
+
+    /// Buffers a decoded frame from the given SSRC, upmixing mono audio,
+    /// and returning whether the speaker's oldest frame was dropped to make
+    /// room.
+    pub fn push(&mut self, ssrc: u32, user_id: Option<UserId>, stereo: bool, data: &[i16]) -> bool {
+        let mut frame = Vec::with_capacity(MIXED_FRAME_LEN);
+
+        if stereo {
+            frame.extend_from_slice(data);
+        } else {
+            for &sample in data {
+                frame.push(sample);
+                frame.push(sample);
+            }
+        }
+
+        frame.resize(MIXED_FRAME_LEN, 0);
+
+        let speaker = self.speakers.entry(ssrc).or_default();
+        speaker.user_id = user_id.or(speaker.user_id);
+        speaker.frames.push_back(frame);
+
+        let dropped = speaker.frames.len() > self.limit;
+
+        if dropped {
+            speaker.frames.pop_front();
+        }
+
+        if speaker.frames.len() >= JITTER_FRAMES {
+            speaker.primed = true;
+        }
+
+        dropped
+    }
+
+    /// Takes the next buffered frame of every primed speaker, with the user
+    /// who sent it, if known.
+    pub fn next_frames(&mut self) -> Vec<(Option<UserId>, Vec<i16>)> {
+        let mut frames = Vec::new();
+
+        for speaker in self.speakers.values_mut() {
+            if !speaker.primed {
+                continue;
+            }
+
+            if let Some(frame) = speaker.frames.pop_front() {
+                frames.push((speaker.user_id, frame));
+            }
+
+            // A speaker who has run dry must build up a buffer again.
+            if speaker.frames.is_empty() {
+                speaker.primed = false;
+            }
+        }
+
+        self.speakers.retain(|_, speaker| !speaker.frames.is_empty());
+
+        frames
+    }
+
+    /// Sums the next buffered frame of every primed speaker, each scaled by
+    /// the gain of its user, saturating at the limits of an `i16`.
+    pub fn mix(&mut self, gains: &HashMap<UserId, f32>) -> Vec<i16> {
+        let mut mixed = vec![0i32; MIXED_FRAME_LEN];
+
+        for (user_id, frame) in self.next_frames() {
+            let gain = user_id
+                .and_then(|user_id| gains.get(&user_id))
+                .copied();
+
+            for (sum, sample) in mixed.iter_mut().zip(frame) {
+                *sum += match gain {
+                    Some(gain) => (f32::from(sample) * gain).round() as i32,
+                    None => i32::from(sample),
+                };
+            }
+        }
+
+        mixed
+            .into_iter()
+            .map(|sum| sum.max(i32::from(i16::MIN)).min(i32::from(i16::MAX)) as i16)
+            .collect()
+    }
+}
+
+/// Which users are speaking, according to both Speaking messages and the
+/// arrival of their audio.
+#[derive(Debug, Default)]
+pub(crate) struct SpeakingUsers {
+    /// Users who have announced that they are speaking, with when their audio
+    /// last arrived.
+    users: HashMap<UserId, Option<Instant>>,
+}
+
+impl SpeakingUsers {
+    pub fn set_speaking(&mut self, user_id: UserId, speaking: bool) {
+        if speaking {
+            self.users.entry(user_id).or_insert(None);
+        } else {
+            self.users.remove(&user_id);
+        }
+    }
+
+    /// Records that audio arrived from a user, if they have announced that
+    /// they are speaking.
+    pub fn heard(&mut self, user_id: UserId, at: Instant) {
+        if let Some(last_heard) = self.users.get_mut(&user_id) {
+            *last_heard = Some(at);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.users.clear();
+    }
+
+    /// Lists the users who are speaking, and have been heard recently.
+    pub fn current(&self, now: Instant) -> Vec<UserId> {
+        self.users
+            .iter()
+            .filter(|(_, last_heard)| matches!(
+                last_heard,
+                Some(at) if now.saturating_duration_since(*at) <= SPEAKING_WINDOW
+            ))
+            .map(|(user_id, _)| *user_id)
+            .collect()
+    }
+}
+
+/// The SSRCs which audio has been received from, forgetting those which
+/// have been silent for `SSRC_TIMEOUT`.
+#[derive(Debug, Default)]
+pub(crate) struct ActiveSsrcs {
+    ssrcs: HashMap<u32, SsrcInfo>,
+}
+
+impl ActiveSsrcs {
+    /// Records that a voice packet arrived from an SSRC.
+    pub fn heard(&mut self, ssrc: u32, user_id: Option<UserId>, at: Instant) {
+        if !self.ssrcs.contains_key(&ssrc) {
+            self.prune(at);
+        }
+
+        let info = self.ssrcs.entry(ssrc).or_insert(SsrcInfo {
+            ssrc,
+            user_id: None,
+            last_packet: at,
+            packets: 0,
+        });
+
+        info.user_id = user_id.or(info.user_id);
+        info.last_packet = at;
+        info.packets += 1;
+    }
+
+    pub fn clear(&mut self) {
+        self.ssrcs.clear();
+    }
+
+    fn prune(&mut self, now: Instant) {
+        self.ssrcs.retain(|_, info| now.saturating_duration_since(info.last_packet) < SSRC_TIMEOUT);
+    }
+
+    /// Lists the SSRCs heard recently, in order of SSRC.
+    pub fn current(&mut self, now: Instant) -> Vec<SsrcInfo> {
+        self.prune(now);
+
+        let mut ssrcs = self.ssrcs.values().cloned().collect::<Vec<_>>();
+        ssrcs.sort_by_key(|info| info.ssrc);
+
+        ssrcs
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_speaking_users() {
+        let start = Instant::now();
+        let (alice, bob) = (UserId(1), UserId(2));
+        let mut speaking = SpeakingUsers::default();
+
+        // Audio is ignored until a user announces they are speaking.
+        speaking.heard(alice, start);
+        assert!(speaking.current(start).is_empty());
+
+        speaking.set_speaking(alice, true);
+        speaking.set_speaking(bob, true);
+        assert!(speaking.current(start).is_empty());
+
+        speaking.heard(alice, start);
+        assert_eq!(speaking.current(start), vec![alice]);
+        assert!(speaking.current(start + SPEAKING_WINDOW * 2).is_empty());
+
+        speaking.set_speaking(alice, false);
+        assert!(speaking.current(start).is_empty());
+    }
+
+    #[test]
+    fn test_receive_mixer() {
+        let mut mixer = ReceiveMixer::default();
+
+        // A speaker is not mixed until their buffer is primed.
+        mixer.push(1, None, false, &[1000; 960]);
+        assert!(mixer.mix(&HashMap::new()).iter().all(|&sample| sample == 0));
+
+        mixer.push(1, None, false, &[1000; 960]);
+        mixer.push(2, None, true, &[i16::MAX; 1920]);
+        mixer.push(2, None, true, &[i16::MAX; 1920]);
+
+        let mixed = mixer.mix(&HashMap::new());
+        assert_eq!(mixed.len(), MIXED_FRAME_LEN);
+        assert!(mixed.iter().all(|&sample| sample == i16::MAX));
+
+        // The first speaker has run dry, leaving only the second.
+        assert!(mixer.mix(&HashMap::new()).iter().all(|&sample| sample == i16::MAX));
+        assert!(mixer.mix(&HashMap::new()).iter().all(|&sample| sample == 0));
+    }
+
+    #[test]
+    fn test_receive_buffer_limit() {
+        let mut mixer = ReceiveMixer::default();
+
+        for _ in 0..DEFAULT_BUFFER_LIMIT {
+            assert!(!mixer.push(1, None, true, &[0; 1920]));
+        }
+        assert!(mixer.push(1, None, true, &[0; 1920]));
+
+        // Lowering the limit drops the oldest frames at once.
+        assert_eq!(mixer.set_limit(JITTER_FRAMES), DEFAULT_BUFFER_LIMIT - JITTER_FRAMES);
+        mixer.push(1, None, true, &[1000; 1920]);
+        mixer.mix(&HashMap::new());
+        assert!(mixer.mix(&HashMap::new()).iter().all(|&sample| sample == 1000));
+        assert!(mixer.speakers.is_empty());
+    }
+
+    #[test]
+    fn test_user_streams() {
+        let mut receive = ReceiveState::default();
+        let (alice, bob) = (UserId(1), UserId(2));
+        let (first, mut first_rx) = futures::channel::mpsc::unbounded();
+        let (second, second_rx) = futures::channel::mpsc::unbounded();
+
+        assert!(!receive.is_listening());
+        receive.add_user_stream(alice, first);
+        receive.add_user_stream(alice, second);
+
+        // Only users with streams are decoded while nothing else listens.
+        assert!(receive.accepts(Some(&alice)));
+        assert!(!receive.accepts(Some(&bob)));
+        assert!(!receive.accepts(None));
+
+        for _ in 0..JITTER_FRAMES {
+            receive.push_user_frame(1, Some(alice), true, &[7; 1920]);
+            receive.push_user_frame(2, Some(bob), true, &[9; 1920]);
+        }
+
+        drop(second_rx);
+        receive.send_user_frames();
+        assert_eq!(first_rx.try_next().unwrap(), Some(vec![7; 1920]));
+        assert_eq!(receive.user_streams[&alice].len(), 1);
+
+        // Closing the last stream stops buffering for the user.
+        drop(first_rx);
+        receive.send_user_frames();
+        assert!(!receive.is_listening());
+        assert!(!receive.push_user_frame(1, Some(alice), true, &[7; 1920]));
+        assert!(receive.user_mixer.speakers.is_empty());
+    }
+
+    #[test]
+    fn test_receive_gain() {
+        let mut receive = ReceiveState::default();
+        let (alice, bob) = (UserId(1), UserId(2));
+
+        for _ in 0..JITTER_FRAMES + 1 {
+            receive.mixer.push(1, Some(alice), true, &[1000; 1920]);
+            receive.mixer.push(2, Some(bob), true, &[1000; 1920]);
+        }
+
+        receive.set_gain(alice, 0.5);
+        assert!(receive.mix().iter().all(|&sample| sample == 1500));
+
+        // Changes apply to audio which was already buffered.
+        receive.set_gain(alice, 1.0);
+        receive.set_gain(bob, 2.0);
+        assert!(receive.mix().iter().all(|&sample| sample == 3000));
+        assert_eq!(receive.gains.len(), 1);
+    }
+
+    #[test]
+    fn test_active_ssrcs() {
+        let start = Instant::now();
+        let mut ssrcs = ActiveSsrcs::default();
+
+        ssrcs.heard(2, None, start);
+        ssrcs.heard(1, None, start);
+        ssrcs.heard(2, Some(UserId(5)), start + Duration::from_secs(20));
+        ssrcs.heard(2, None, start + Duration::from_secs(21));
+
+        let current = ssrcs.current(start + Duration::from_secs(21));
+        assert_eq!(current.iter().map(|info| info.ssrc).collect::<Vec<_>>(), vec![1, 2]);
+        assert_eq!(current[1].user_id, Some(UserId(5)));
+        assert_eq!(current[1].packets, 3);
+
+        // The first SSRC has now been silent for too long.
+        let current = ssrcs.current(start + SSRC_TIMEOUT);
+        assert_eq!(current.len(), 1);
+        assert_eq!(current[0].ssrc, 2);
+    }
+
+    #[test]
+    fn test_convert_format() {
+        let stereo = [100, -100, 200, -200, 300, 300, 600, 0];
+
+        assert_eq!(convert_format(&stereo, true, SampleRate::Hz24000, Channels::Stereo), (true, vec![150, -150, 450, 150]));
+        assert_eq!(convert_format(&stereo, true, SampleRate::Hz24000, Channels::Mono), (false, vec![0, 300]));
+        assert_eq!(convert_format(&stereo, true, SampleRate::Hz48000, Channels::Auto), (true, stereo.to_vec()));
+        assert_eq!(convert_format(&[i16::MAX; 6], false, SampleRate::Hz16000, Channels::Stereo), (true, vec![i16::MAX; 4]));
+
+        // Audio matching the default format is passed through as it is.
+        let mut state = ReceiveState::default();
+        assert!(matches!(state.convert(true, &stereo), (true, Cow::Borrowed(_))));
+
+        state.set_format(SampleRate::Hz8000, Channels::Mono);
+        assert_eq!(state.convert(true, &[6; 1920]), (false, Cow::Owned(vec![6; 160])));
+
+        state.set_format(SampleRate::Hz48000, Channels::Stereo);
+        assert!(matches!(state.convert(true, &stereo), (true, Cow::Borrowed(_))));
+    }
+}
@@ -0,0 +1,102 @@
+use crate::http::Http;
+use crate::internal::prelude::*;
+use crate::model::{
+    id::{ChannelId, GuildId},
+    voice::VoiceRegion,
+};
+
+/// Gets the voice regions which a guild can use, including any VIP-only
+/// regions if the guild has the `VIP_REGIONS` feature, for offering as a
+/// choice to the guild's admins.
+///
+/// The [`id`] of one of these can be passed to [`set_guild_region`] or
+/// [`set_channel_region`].
+///
+/// # Errors
+///
+/// Returns an [`Error::Http`] if the request fails, or the current user is
+/// not in the guild.
+///
+/// [`Error::Http`]: ../enum.Error.html#variant.Http
+/// [`id`]: ../model/voice/struct.VoiceRegion.html#structfield.id
+/// [`set_channel_region`]: fn.set_channel_region.html
+/// [`set_guild_region`]: fn.set_guild_region.html
+pub async fn regions(http: impl AsRef<Http>, guild_id: GuildId) -> Result<Vec<VoiceRegion>> {
+    http.as_ref().get_guild_regions(guild_id.0).await
+}
+
+/// Sets the voice region of a guild, by the [`id`] of a [`VoiceRegion`].
+///
+/// Discord migrates any voice connections in the guild to the new region,
+/// which a connected [`Handler`] reports with a
+/// [`HandlerEvent::VoiceServerChanged`] event once it has been sent the new
+/// server, before moving to it.
+///
+/// **Note**: Requires the [Manage Guild] permission.
+///
+/// # Errors
+///
+/// Returns an [`Error::Http`] if the current user lacks permission, or the
+/// region is not one the guild can use.
+///
+/// [`Error::Http`]: ../enum.Error.html#variant.Http
+/// [`Handler`]: struct.Handler.html
+/// [`HandlerEvent::VoiceServerChanged`]: enum.HandlerEvent.html#variant.VoiceServerChanged
+/// [`id`]: ../model/voice/struct.VoiceRegion.html#structfield.id
+/// [`VoiceRegion`]: ../model/voice/struct.VoiceRegion.html
+/// [Manage Guild]: ../model/permissions/struct.Permissions.html#associatedconstant.MANAGE_GUILD
+pub async fn set_guild_region(http: impl AsRef<Http>, guild_id: GuildId, region_id: &str) -> Result<()> {
+    let map = region_map("region", Some(region_id));
+
+    http.as_ref().edit_guild(guild_id.0, &map).await.map(|_| ())
+}
+
+/// Sets the voice region of a single voice channel, by the [`id`] of a
+/// [`VoiceRegion`], overriding that of its guild. Passing `None` has
+/// Discord choose the region automatically again.
+///
+/// As with [`set_guild_region`], connected handlers emit a
+/// [`HandlerEvent::VoiceServerChanged`] event when they are migrated.
+///
+/// **Note**: Requires the [Manage Channels] permission.
+///
+/// # Errors
+///
+/// Returns an [`Error::Http`] if the current user lacks permission, the
+/// channel is not a voice channel, or the region is not one it can use.
+///
+/// [`Error::Http`]: ../enum.Error.html#variant.Http
+/// [`HandlerEvent::VoiceServerChanged`]: enum.HandlerEvent.html#variant.VoiceServerChanged
+/// [`id`]: ../model/voice/struct.VoiceRegion.html#structfield.id
+/// [`set_guild_region`]: fn.set_guild_region.html
+/// [`VoiceRegion`]: ../model/voice/struct.VoiceRegion.html
+/// [Manage Channels]: ../model/permissions/struct.Permissions.html#associatedconstant.MANAGE_CHANNELS
+pub async fn set_channel_region(http: impl AsRef<Http>, channel_id: ChannelId, region_id: Option<&str>) -> Result<()> {
+    let map = region_map("rtc_region", region_id);
+
+    http.as_ref().edit_channel(channel_id.0, &map).await.map(|_| ())
+}
+
+/// Builds the body of a request setting the region under `key`, where
+/// `None` is sent as `null`.
+fn region_map(key: &str, region_id: Option<&str>) -> JsonMap {
+    let mut map = JsonMap::new();
+    map.insert(key.to_string(), region_id.map_or(Value::Null, |id| Value::String(id.to_string())));
+
+    map
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_region_map() {
+        let map = region_map("region", Some("us-east"));
+        assert_eq!(map.len(), 1);
+        assert_eq!(map["region"], Value::String("us-east".to_string()));
+
+        // Clearing a channel's region has Discord pick one again.
+        assert_eq!(region_map("rtc_region", None)["rtc_region"], Value::Null);
+    }
+}
@@ -0,0 +1,189 @@
+use byteorder::{BigEndian, ByteOrder};
+use std::time::Duration;
+
+/// The length of the fixed header of each RTCP packet.
+pub(crate) const RTCP_HEADER_LEN: usize = 8;
+
+/// The RTCP packet type of a sender report.
+const SENDER_REPORT: u8 = 200;
+
+/// The RTCP packet type of a receiver report.
+const RECEIVER_REPORT: u8 = 201;
+
+/// The length of the sender information in a sender report.
+const SENDER_INFO_LEN: usize = 20;
+
+/// The length of each report block.
+const REPORT_BLOCK_LEN: usize = 24;
+
+/// Whether a received UDP packet is RTCP rather than RTP, judged by its
+/// second byte, which holds the RTCP packet type.
+pub(crate) fn is_rtcp(packet: &[u8]) -> bool {
+    packet.len() >= RTCP_HEADER_LEN && (SENDER_REPORT..=204).contains(&packet[1])
+}
+
+/// An RTCP sender or receiver report, passed to
+/// [`AudioReceiver::rtcp_report`].
+///
+/// [`AudioReceiver::rtcp_report`]: trait.AudioReceiver.html#method.rtcp_report
+#[derive(Clone, Debug, PartialEq)]
+#[non_exhaustive]
+pub struct RtcpReport {
+    /// The SSRC of the source which sent the report.
+    pub reporter: u32,
+    /// What the reporter has sent itself, if this is a sender report.
+    pub sender: Option<SenderInfo>,
+    /// The reporter's statistics about each source it receives from.
+    pub blocks: Vec<ReportBlock>,
+}
+
+/// The sending statistics in an RTCP sender report.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct SenderInfo {
+    /// The wallclock time at which the report was sent, in the 64-bit NTP
+    /// format.
+    pub ntp_timestamp: u64,
+    /// The RTP timestamp corresponding to `ntp_timestamp`.
+    pub rtp_timestamp: u32,
+    /// The number of RTP packets sent so far.
+    pub packet_count: u32,
+    /// The number of payload bytes sent so far.
+    pub octet_count: u32,
+}
+
+/// A reporter's statistics about one source it receives from, as held in
+/// RTCP sender and receiver reports.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[non_exhaustive]
+pub struct ReportBlock {
+    /// The SSRC of the source reported on.
+    pub ssrc: u32,
+    /// The fraction of the source's packets lost since the previous report,
+    /// from `0.0` to `1.0`.
+    pub fraction_lost: f32,
+    /// The total number of the source's packets lost, which may be negative
+    /// if duplicates were received.
+    pub cumulative_lost: i32,
+    /// The highest sequence number received from the source, extended with
+    /// the count of its wraparounds.
+    pub highest_sequence: u32,
+    /// The estimated jitter in the arrival of the source's packets.
+    pub jitter: Duration,
+    /// The middle 32 bits of the NTP timestamp of the last sender report
+    /// received from the source, or 0 if none was.
+    pub last_sender_report: u32,
+    /// The delay between receiving the last sender report from the source
+    /// and sending this report, in units of 1/65536 seconds.
+    pub delay_since_last_sender_report: u32,
+}
+
+/// Parses the sender and receiver reports in a decrypted compound RTCP
+/// packet, skipping packets of any other type, and stopping at the first
+/// which is malformed.
+pub(crate) fn parse_reports(mut data: &[u8]) -> Vec<RtcpReport> {
+    let mut reports = Vec::new();
+
+    while data.len() >= RTCP_HEADER_LEN {
+        let len = (usize::from(BigEndian::read_u16(&data[2..4])) + 1) * 4;
+
+        if len > data.len() || data[0] >> 6 != 2 {
+            break;
+        }
+
+        let (packet, rest) = data.split_at(len);
+        data = rest;
+
+        let blocks_at = match packet[1] {
+            SENDER_REPORT => RTCP_HEADER_LEN + SENDER_INFO_LEN,
+            RECEIVER_REPORT => RTCP_HEADER_LEN,
+            _ => continue,
+        };
+        let count = usize::from(packet[0] & 0x1F);
+
+        if packet.len() < blocks_at + count * REPORT_BLOCK_LEN {
+            break;
+        }
+
+        let sender = if packet[1] == SENDER_REPORT {
+            Some(SenderInfo {
+                ntp_timestamp: BigEndian::read_u64(&packet[8..16]),
+                rtp_timestamp: BigEndian::read_u32(&packet[16..20]),
+                packet_count: BigEndian::read_u32(&packet[20..24]),
+                octet_count: BigEndian::read_u32(&packet[24..28]),
+            })
+        } else {
+            None
+        };
+
+        let blocks = packet[blocks_at..blocks_at + count * REPORT_BLOCK_LEN]
+            .chunks(REPORT_BLOCK_LEN)
+            .map(parse_block)
+            .collect();
+
+        reports.push(RtcpReport {
+            reporter: BigEndian::read_u32(&packet[4..8]),
+            sender,
+            blocks,
+        });
+    }
+
+    reports
+}
+
+fn parse_block(block: &[u8]) -> ReportBlock {
+    // The cumulative loss is a signed 24-bit integer, sign-extended here.
+    let cumulative_lost = BigEndian::read_i32(&block[4..8]) << 8 >> 8;
+    let jitter = BigEndian::read_u32(&block[12..16]);
+
+    ReportBlock {
+        ssrc: BigEndian::read_u32(&block[0..4]),
+        fraction_lost: f32::from(block[4]) / 256.0,
+        cumulative_lost,
+        highest_sequence: BigEndian::read_u32(&block[8..12]),
+        // Jitter is measured in RTP timestamp units, of 48kHz for Opus.
+        jitter: Duration::from_secs_f64(f64::from(jitter) / 48_000.0),
+        last_sender_report: BigEndian::read_u32(&block[16..20]),
+        delay_since_last_sender_report: BigEndian::read_u32(&block[20..24]),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_reports() {
+        let mut packet = vec![0x81, RECEIVER_REPORT, 0, 7, 0, 0, 0, 1];
+        // A block on SSRC 2, with a quarter lost, and 3 lost in total.
+        packet.extend_from_slice(&[0, 0, 0, 2, 64, 0, 0, 3]);
+        packet.extend_from_slice(&[0, 1, 0, 10, 0, 0, 0x01, 0xE0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        // An unknown packet, skipped over.
+        packet.extend_from_slice(&[0x80, 203, 0, 1, 0, 0, 0, 9]);
+        // A sender report with no blocks.
+        packet.extend_from_slice(&[0x80, SENDER_REPORT, 0, 6, 0, 0, 0, 4]);
+        packet.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 5, 0, 0, 0, 6, 0, 0, 0, 7, 0, 0, 0, 8]);
+
+        assert!(is_rtcp(&packet));
+
+        let reports = parse_reports(&packet);
+        assert_eq!(reports.len(), 2);
+        assert_eq!(reports[0].reporter, 1);
+        assert_eq!(reports[0].sender, None);
+
+        let block = reports[0].blocks[0];
+        assert_eq!(block.ssrc, 2);
+        assert!((block.fraction_lost - 0.25).abs() < f32::EPSILON);
+        assert_eq!(block.cumulative_lost, 3);
+        assert_eq!(block.highest_sequence, 65_546);
+        assert_eq!(block.jitter, Duration::from_millis(10));
+
+        assert_eq!(reports[1].reporter, 4);
+        assert_eq!(reports[1].sender.map(|info| info.packet_count), Some(7));
+        assert!(reports[1].blocks.is_empty());
+
+        // A truncated packet yields nothing.
+        assert!(parse_reports(&packet[..20]).is_empty());
+        assert!(!is_rtcp(&[0x80, 0x78, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0]));
+    }
+}
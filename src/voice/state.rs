@@ -0,0 +1,459 @@
+use futures::channel::mpsc::{UnboundedReceiver as Receiver, UnboundedSender as Sender};
+use serde_json::Value;
+use crate::model::id::{ChannelId, GuildId, UserId};
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering},
+        Arc,
+        Mutex,
+        MutexGuard,
+    },
+    time::{Duration, Instant},
+};
+use tokio::sync::watch;
+use super::{
+    config::OpusConfig,
+    connection_info::SessionInfo,
+    events::{EventSink, TrackEvent},
+    Audio,
+    receive::{ActiveSsrcs, SpeakingUsers, SsrcInfo},
+    EndReason,
+    HandlerEvent,
+    NetworkStats,
+    Status,
+    TrackQueue,
+};
+
+/// The state of a [`Handler`]'s connection to a voice server, as last
+/// reported by its background task.
+///
+/// [`Handler`]: struct.Handler.html
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum ConnectionState {
+    /// There is no voice connection, and none is being established.
+    Disconnected,
+    /// Connection information has been passed to the task, but the voice
+    /// handshake has not yet completed.
+    Connecting,
+    /// A live voice connection exists.
+    Connected,
+}
+
+/// How long after a channel switch further switches are coalesced, by
+/// default.
+const DEFAULT_SWITCH_DEBOUNCE: Duration = Duration::from_millis(250);
+
+/// Gateway voice state updates held back so that rapid channel switches
+/// produce only one update for the final channel.
+pub(crate) struct SwitchDebounce {
+    pub interval: Duration,
+    /// When an update was last sent to the gateway.
+    pub last_sent: Option<Instant>,
+    /// The latest update, to be sent at the end of the interval.
+    pub pending: Option<Value>,
+}
+
+impl Default for SwitchDebounce {
+    fn default() -> Self {
+        Self {
+            interval: DEFAULT_SWITCH_DEBOUNCE,
+            last_sent: None,
+            pending: None,
+        }
+    }
+}
+
+/// A limit on how long a handler may transmit audio for, and how much of it
+/// has been used.
+#[derive(Default)]
+pub(crate) struct PlaytimeBudget {
+    pub limit: Option<Duration>,
+    pub used: Duration,
+}
+
+impl PlaytimeBudget {
+    pub(crate) fn is_exhausted(&self) -> bool {
+        self.limit.map_or(false, |limit| self.used >= limit)
+    }
+}
+
+/// State written by a handler's background task and read by the [`Handler`].
+///
+/// This is shared between all clones of a handler, and survives restarts of
+/// the task.
+///
+/// [`Handler`]: struct.Handler.html
+pub(crate) struct SharedState {
+    guild_id: GuildId,
+    /// Whether the handler left its channel after idling, and is yet to
+    /// hear so from Discord.
+    auto_disconnected: AtomicBool,
+    /// The handler's current channel, or 0 if none, shared with the queue to
+    /// tag the events of the tracks it drops.
+    channel_id: Arc<AtomicU64>,
+    connection: watch::Sender<ConnectionState>,
+    connection_rx: watch::Receiver<ConnectionState>,
+    events: EventSink,
+    /// When a frame holding audio was last sent, or the current connection
+    /// made if none has been.
+    last_audio: Mutex<Instant>,
+    /// When a UDP packet last arrived on the current connection.
+    last_receive: Mutex<Option<Instant>>,
+    /// The encoder settings requested of the task, for the next connection.
+    opus: Mutex<OpusConfig>,
+    /// The smoothed peak level of sent audio, as the bits of an `f32`.
+    output_level: AtomicU32,
+    /// The number of messages sent to the task which it has yet to receive.
+    pending: AtomicUsize,
+    /// Kept here, rather than by the task, so that restarts do not reset it.
+    playtime: Mutex<PlaytimeBudget>,
+    pub(crate) queue: TrackQueue,
+    session: Mutex<Option<SessionInfo>>,
+    /// Whether the voice gateway rejected the handler's session, which must
+    /// not be used to connect again.
+    session_invalid: AtomicBool,
+    speaking: Mutex<SpeakingUsers>,
+    ssrcs: Mutex<ActiveSsrcs>,
+    stats: Mutex<NetworkStats>,
+    switch: Mutex<SwitchDebounce>,
+    /// Counts the tasks started, so that each can tell whether it has been
+    /// replaced by a newer one.
+    task_generation: AtomicU64,
+    /// The sender to the current task, shared between all clones of the
+    /// handler, so that only one of them restarts it once it has stopped.
+    task_sender: Mutex<Sender<Status>>,
+    /// Whether the last frame sent held audio.
+    transmitting: AtomicBool,
+    ws_sequence: AtomicU64,
+}
+
+impl SharedState {
+    pub(crate) fn new(guild_id: GuildId, task_sender: Sender<Status>) -> Self {
+        let (connection, connection_rx) = watch::channel(ConnectionState::Disconnected);
+        let events = EventSink::default();
+        let channel_id = Arc::new(AtomicU64::new(0));
+
+        Self {
+            guild_id,
+            auto_disconnected: AtomicBool::new(false),
+            queue: TrackQueue::new(guild_id, Arc::clone(&channel_id), connection_rx.clone(), events.clone()),
+            channel_id,
+            connection,
+            connection_rx,
+            events,
+            last_audio: Mutex::new(Instant::now()),
+            last_receive: Mutex::new(None),
+            opus: Mutex::new(OpusConfig::default()),
+            output_level: AtomicU32::new(0),
+            pending: AtomicUsize::new(0),
+            playtime: Mutex::new(PlaytimeBudget::default()),
+            session: Mutex::new(None),
+            session_invalid: AtomicBool::new(false),
+            speaking: Mutex::new(SpeakingUsers::default()),
+            ssrcs: Mutex::new(ActiveSsrcs::default()),
+            stats: Mutex::new(NetworkStats::default()),
+            switch: Mutex::new(SwitchDebounce::default()),
+            task_generation: AtomicU64::new(0),
+            task_sender: Mutex::new(task_sender),
+            transmitting: AtomicBool::new(false),
+            ws_sequence: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn connection_state(&self) -> ConnectionState {
+        *self.connection_rx.borrow()
+    }
+
+    /// Returns a receiver which is notified whenever the connection state
+    /// changes.
+    pub(crate) fn watch_connection_state(&self) -> watch::Receiver<ConnectionState> {
+        self.connection_rx.clone()
+    }
+
+    pub(crate) fn set_connection_state(&self, state: ConnectionState) {
+        if state == ConnectionState::Disconnected {
+            self.set_session_info(None);
+            self.set_last_receive(None);
+        }
+
+        if state == ConnectionState::Connected && self.connection_state() != state {
+            *self.last_audio.lock().expect("[Voice] Last audio time poisoned") = Instant::now();
+            self.auto_disconnected.store(false, Ordering::Relaxed);
+        }
+
+        if state != ConnectionState::Connected {
+            self.set_transmitting(false);
+            self.set_output_level(0.0);
+        }
+
+        if self.connection_state() != state {
+            let _ = self.connection.broadcast(state);
+        }
+    }
+
+    pub(crate) fn is_transmitting(&self) -> bool {
+        self.transmitting.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn set_transmitting(&self, transmitting: bool) {
+        self.transmitting.store(transmitting, Ordering::Relaxed);
+
+        if transmitting {
+            *self.last_audio.lock().expect("[Voice] Last audio time poisoned") = Instant::now();
+        }
+    }
+
+    /// Whether the handler is connected, and has sent no audio for at least
+    /// the given time.
+    pub(crate) fn idle_for(&self, idle: Duration) -> bool {
+        self.connection_state() == ConnectionState::Connected
+            && !self.is_transmitting()
+            && self.last_audio.lock().expect("[Voice] Last audio time poisoned").elapsed() >= idle
+    }
+
+    /// Marks that the handler left its channel after idling, returning
+    /// `false` if it already had and is yet to hear so from Discord.
+    pub(crate) fn mark_auto_disconnected(&self) -> bool {
+        !self.auto_disconnected.swap(true, Ordering::Relaxed)
+    }
+
+    /// Returns whether the handler left its channel after idling since this
+    /// was last called.
+    pub(crate) fn take_auto_disconnected(&self) -> bool {
+        self.auto_disconnected.swap(false, Ordering::Relaxed)
+    }
+
+    /// The handler's current channel, if any.
+    pub(crate) fn channel_id(&self) -> Option<ChannelId> {
+        match self.channel_id.load(Ordering::Relaxed) {
+            0 => None,
+            id => Some(ChannelId(id)),
+        }
+    }
+
+    /// Sends a message to the current task, without restarting it if it has
+    /// stopped, returning whether it was sent.
+    pub(crate) fn send_to_task(&self, status: Status) -> bool {
+        let sent = self.task_sender.lock().expect("[Voice] Task sender poisoned")
+            .unbounded_send(status)
+            .is_ok();
+
+        if sent {
+            self.message_sent();
+        }
+
+        sent
+    }
+
+    pub(crate) fn update_playtime_budget(&self, update: impl FnOnce(&mut PlaytimeBudget)) {
+        let mut budget = self.playtime.lock().expect("[Voice] Playtime budget poisoned");
+        let exhausted = budget.is_exhausted();
+        update(&mut budget);
+
+        // Lowering the limit below the time already used exhausts it too.
+        if !exhausted && budget.is_exhausted() {
+            self.emit(HandlerEvent::BudgetExhausted);
+        }
+    }
+
+    pub(crate) fn playtime_exhausted(&self) -> bool {
+        self.playtime.lock().expect("[Voice] Playtime budget poisoned").is_exhausted()
+    }
+
+    /// Counts a frame of transmitted audio against the playtime budget,
+    /// emitting an event if this exhausts it.
+    pub(crate) fn spend_playtime(&self, frame: Duration) {
+        let mut budget = self.playtime.lock().expect("[Voice] Playtime budget poisoned");
+
+        if budget.limit.is_none() || budget.is_exhausted() {
+            return;
+        }
+
+        budget.used += frame;
+
+        if budget.is_exhausted() {
+            drop(budget);
+            self.emit(HandlerEvent::BudgetExhausted);
+        }
+    }
+
+    pub(crate) fn output_level(&self) -> f32 {
+        f32::from_bits(self.output_level.load(Ordering::Relaxed))
+    }
+
+    pub(crate) fn set_output_level(&self, level: f32) {
+        self.output_level.store(level.to_bits(), Ordering::Relaxed);
+    }
+
+    pub(crate) fn last_receive_age(&self) -> Option<Duration> {
+        self.last_receive.lock().expect("[Voice] Last receive poisoned").map(|at| at.elapsed())
+    }
+
+    pub(crate) fn set_last_receive(&self, at: Option<Instant>) {
+        *self.last_receive.lock().expect("[Voice] Last receive poisoned") = at;
+    }
+
+    pub(crate) fn network_stats(&self) -> NetworkStats {
+        self.stats.lock().expect("[Voice] Network stats poisoned").clone()
+    }
+
+    pub(crate) fn update_stats(&self, update: impl FnOnce(&mut NetworkStats)) {
+        update(&mut self.stats.lock().expect("[Voice] Network stats poisoned"));
+    }
+
+    pub(crate) fn opus_config(&self) -> OpusConfig {
+        self.opus.lock().expect("[Voice] Opus config poisoned").clone()
+    }
+
+    pub(crate) fn update_opus_config(&self, update: impl FnOnce(&mut OpusConfig)) {
+        update(&mut self.opus.lock().expect("[Voice] Opus config poisoned"));
+    }
+
+    pub(crate) fn session_info(&self) -> Option<SessionInfo> {
+        self.session.lock().expect("[Voice] Session info poisoned").clone()
+    }
+
+    pub(crate) fn set_session_info(&self, info: Option<SessionInfo>) {
+        *self.session.lock().expect("[Voice] Session info poisoned") = info;
+    }
+
+    pub(crate) fn invalidate_session(&self) {
+        self.session_invalid.store(true, Ordering::Relaxed);
+    }
+
+    /// Returns whether the session was invalidated since this was last
+    /// called.
+    pub(crate) fn take_session_invalid(&self) -> bool {
+        self.session_invalid.swap(false, Ordering::Relaxed)
+    }
+
+    pub(crate) fn speaking_users(&self) -> Vec<UserId> {
+        self.speaking.lock().expect("[Voice] Speaking users poisoned").current(Instant::now())
+    }
+
+    pub(crate) fn update_speaking(&self, update: impl FnOnce(&mut SpeakingUsers)) {
+        update(&mut self.speaking.lock().expect("[Voice] Speaking users poisoned"));
+    }
+
+    pub(crate) fn active_ssrcs(&self) -> Vec<SsrcInfo> {
+        self.ssrcs.lock().expect("[Voice] Active SSRCs poisoned").current(Instant::now())
+    }
+
+    pub(crate) fn update_ssrcs(&self, update: impl FnOnce(&mut ActiveSsrcs)) {
+        update(&mut self.ssrcs.lock().expect("[Voice] Active SSRCs poisoned"));
+    }
+
+    pub(crate) fn lock_switch(&self) -> MutexGuard<'_, SwitchDebounce> {
+        self.switch.lock().expect("[Voice] Switch debounce poisoned")
+    }
+
+    /// The number of messages received on the current voice WebSocket.
+    pub(crate) fn ws_sequence(&self) -> u64 {
+        self.ws_sequence.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn reset_ws_sequence(&self) {
+        self.ws_sequence.store(0, Ordering::Relaxed);
+    }
+
+    pub(crate) fn bump_ws_sequence(&self) {
+        self.ws_sequence.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The number of messages sent to the task which it has yet to receive.
+    pub(crate) fn pending_messages(&self) -> usize {
+        self.pending.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn message_sent(&self) {
+        self.pending.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn message_received(&self) {
+        let mut pending = self.pending.load(Ordering::Relaxed);
+
+        // The count never drops below zero, even if a message is somehow
+        // received twice.
+        while pending > 0 {
+            match self.pending.compare_exchange_weak(pending, pending - 1, Ordering::Relaxed, Ordering::Relaxed) {
+                Ok(_) => break,
+                Err(current) => pending = current,
+            }
+        }
+    }
+
+    /// Marks a new task as started, returning its generation, which replaces
+    /// any task still running.
+    pub(crate) fn start_task(&self) -> u64 {
+        self.task_generation.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    /// Whether the task of the given generation is the newest one.
+    pub(crate) fn is_current_task(&self, generation: u64) -> bool {
+        self.task_generation.load(Ordering::SeqCst) == generation
+    }
+
+    /// Returns the sender to the current task, first calling `restart` to
+    /// start a new one if it has stopped, along with whether it was restarted.
+    ///
+    /// A clone of the handler whose task has stopped may find that another
+    /// clone already restarted it, in which case the new task is shared,
+    /// rather than started twice and so playing everything twice.
+    pub(crate) fn task_sender(&self, restart: impl FnOnce() -> Sender<Status>) -> (Sender<Status>, bool) {
+        let mut sender = self.task_sender.lock().expect("[Voice] Task sender poisoned");
+
+        if sender.is_closed() {
+            *sender = restart();
+
+            (sender.clone(), true)
+        } else {
+            (sender.clone(), false)
+        }
+    }
+
+    /// Forgets all pending messages, which are lost along with a task which
+    /// has stopped.
+    pub(crate) fn reset_pending(&self) {
+        self.pending.store(0, Ordering::Relaxed);
+    }
+
+    pub(crate) fn set_channel_id(&self, channel_id: Option<ChannelId>) {
+        self.channel_id.store(channel_id.map_or(0, |id| id.0), Ordering::Relaxed);
+    }
+
+    /// Marks a track as ended, emitting an event if it had not already.
+    pub(crate) fn end_track(&self, audio: &mut Audio, reason: EndReason) {
+        if audio.mark_ended(reason) {
+            self.track_ended(reason, audio.requester);
+        }
+    }
+
+    /// Emits an event for a track which ended, tagged with the handler's
+    /// guild and current channel.
+    fn track_ended(&self, reason: EndReason, requester: Option<UserId>) {
+        let channel_id = self.channel_id();
+
+        self.emit(HandlerEvent::TrackEnded(TrackEvent {
+            guild_id: self.guild_id,
+            channel_id,
+            reason,
+            requester,
+        }));
+    }
+
+    /// Registers a new listener for events.
+    pub(crate) fn subscribe(&self) -> Receiver<HandlerEvent> {
+        self.events.subscribe()
+    }
+
+    /// Sends an event to every listener.
+    pub(crate) fn emit(&self, event: HandlerEvent) {
+        self.events.emit(event);
+    }
+
+    /// The listeners for events, for tracks to emit their own.
+    pub(crate) fn events(&self) -> EventSink {
+        self.events.clone()
+    }
+}
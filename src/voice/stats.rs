@@ -0,0 +1,192 @@
+use std::time::{Duration, Instant};
+
+/// How long packets are counted for to measure the rate they are sent at.
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+
+/// Statistics about a [`Handler`]'s voice connection, accumulated by its
+/// background task over the lifetime of the handler.
+///
+/// Retrieved via [`Handler::network_stats`].
+///
+/// [`Handler`]: struct.Handler.html
+/// [`Handler::network_stats`]: struct.Handler.html#method.network_stats
+#[derive(Clone, Debug, Default)]
+#[non_exhaustive]
+pub struct NetworkStats {
+    /// The number of voice packets sent.
+    pub packets_sent: u64,
+    /// The number of silent frames which were not sent, as discontinuous
+    /// transmission was enabled.
+    pub packets_suppressed: u64,
+    /// The number of frames which failed to encode, and were sent as silence
+    /// instead.
+    pub encode_errors: u64,
+    /// A running average of the time taken to encode each frame of mixed
+    /// audio, which must stay well within a frame's 20ms.
+    pub encode_time: Duration,
+    /// The number of voice packets received from other users.
+    pub packets_received: u64,
+    /// The number of voice packets from other users which never arrived, as
+    /// inferred from gaps in their sequence numbers.
+    pub packets_lost: u64,
+    /// How far the send loop was behind its 20ms schedule at the last frame.
+    pub drift: Duration,
+    /// The number of frames skipped over to catch back up with the schedule,
+    /// after falling too far behind.
+    pub frames_skipped: u64,
+    /// The number of frames for which a playing source could not supply
+    /// audio in time, as reading it failed and is being retried, gave only
+    /// part of a frame before carrying on, or took longer than the frame's
+    /// 20ms, causing audible stutter.
+    ///
+    /// Unlike `encode_time`, this measures only the sources themselves.
+    pub underruns: u64,
+    /// The number of received frames dropped from speakers' buffers before
+    /// they were mixed, as receivers were not keeping up, or a speaker sent
+    /// audio too quickly.
+    ///
+    /// The limit on each buffer is set via
+    /// [`Handler::set_receive_buffer_limit`].
+    ///
+    /// [`Handler::set_receive_buffer_limit`]: struct.Handler.html#method.set_receive_buffer_limit
+    pub receive_frames_dropped: u64,
+    /// The jitter in the arrival of the handler's audio, as last reported
+    /// by Discord over RTCP, if it has sent any reports.
+    pub remote_jitter: Option<Duration>,
+    /// The fraction of the handler's packets lost, from `0.0` to `1.0`, as
+    /// last reported by Discord over RTCP, if it has sent any reports.
+    ///
+    /// Unlike `packets_lost`, which only counts loss of received audio, this
+    /// measures whether the handler's own audio is arriving.
+    pub remote_fraction_lost: Option<f32>,
+    /// The time between sending the last acknowledged heartbeat and receiving
+    /// its ack, if one has been acknowledged.
+    pub heartbeat_latency: Option<Duration>,
+    /// The number of sources being mixed at the last frame, not counting any
+    /// which are paused.
+    pub tracks_playing: usize,
+    /// The number of voice packets sent over the last second of the
+    /// connection.
+    pub packets_per_second: u64,
+    /// The number of bytes sent over UDP, including RTP headers, encryption
+    /// overhead, keepalives and the UDP header of each datagram, but not IP
+    /// headers or the voice WebSocket.
+    ///
+    /// Unlike the other counts, this and `bytes_received` can be reset via
+    /// [`Handler::reset_bytes_transferred`].
+    ///
+    /// [`Handler::reset_bytes_transferred`]: struct.Handler.html#method.reset_bytes_transferred
+    pub bytes_sent: u64,
+    /// The number of bytes received over UDP, counted as with `bytes_sent`,
+    /// including packets which were not decoded as nobody was listening.
+    pub bytes_received: u64,
+}
+
+/// Statistics summed across every [`Handler`] of a [`Manager`].
+///
+/// Retrieved via [`Manager::aggregate_stats`].
+///
+/// [`Handler`]: struct.Handler.html
+/// [`Manager`]: struct.Manager.html
+/// [`Manager::aggregate_stats`]: struct.Manager.html#method.aggregate_stats
+#[derive(Clone, Debug, Default)]
+#[non_exhaustive]
+pub struct AggregateVoiceStats {
+    /// The number of handlers whose connection is live.
+    pub connections: usize,
+    /// The number of sources being mixed across every handler.
+    pub tracks_playing: usize,
+    /// The number of voice packets sent over the last second across every
+    /// handler, as counted by [`NetworkStats::packets_per_second`].
+    ///
+    /// [`NetworkStats::packets_per_second`]: struct.NetworkStats.html#structfield.packets_per_second
+    pub packets_per_second: u64,
+    /// The total number of voice packets sent.
+    pub packets_sent: u64,
+    /// The total number of voice packets received from other users.
+    pub packets_received: u64,
+    /// The total number of voice packets from other users which never
+    /// arrived.
+    pub packets_lost: u64,
+    /// The total number of bytes sent over UDP, as counted by
+    /// [`NetworkStats::bytes_sent`].
+    ///
+    /// [`NetworkStats::bytes_sent`]: struct.NetworkStats.html#structfield.bytes_sent
+    pub bytes_sent: u64,
+    /// The total number of bytes received over UDP.
+    pub bytes_received: u64,
+    /// The mean heartbeat latency of the live connections, if any has had a
+    /// heartbeat acknowledged.
+    pub average_latency: Option<Duration>,
+}
+
+/// Measures the rate at which packets are sent, from the running count of
+/// those sent, over windows of a second.
+pub(crate) struct PacketRate {
+    since: Instant,
+    sent: u64,
+}
+
+impl PacketRate {
+    pub(crate) fn new(now: Instant, sent: u64) -> Self {
+        Self { since: now, sent }
+    }
+
+    /// Takes the number of packets sent so far, returning the rate they
+    /// were sent at once a window has passed since the last.
+    pub(crate) fn sample(&mut self, now: Instant, sent: u64) -> Option<u64> {
+        let elapsed = now.saturating_duration_since(self.since);
+
+        if elapsed < RATE_WINDOW {
+            return None;
+        }
+
+        let rate = sent.saturating_sub(self.sent) * 1000 / elapsed.as_millis() as u64;
+        self.since = now;
+        self.sent = sent;
+
+        Some(rate)
+    }
+}
+
+/// A read-only view of an outgoing RTP packet, passed to the observer set via
+/// [`Handler::set_packet_observer`] just before the packet is sent.
+///
+/// [`Handler::set_packet_observer`]: struct.Handler.html#method.set_packet_observer
+#[derive(Clone, Copy, Debug)]
+#[non_exhaustive]
+pub struct RtpPacketView {
+    /// The RTP sequence number of the packet.
+    pub sequence: u16,
+    /// The RTP timestamp of the packet.
+    pub timestamp: u32,
+    /// The RTP synchronisation source of the packet.
+    pub ssrc: u32,
+    /// The length of the packet's encrypted payload, in bytes, excluding the
+    /// RTP header.
+    pub payload_len: usize,
+}
+
+/// A function called with each outgoing RTP packet, as set via
+/// [`Handler::set_packet_observer`].
+///
+/// [`Handler::set_packet_observer`]: struct.Handler.html#method.set_packet_observer
+pub type PacketObserver = Box<dyn Fn(&RtpPacketView) + Send + Sync>;
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_packet_rate() {
+        let start = Instant::now();
+        let mut rate = PacketRate::new(start, 10);
+
+        assert_eq!(rate.sample(start + Duration::from_millis(500), 35), None);
+        assert_eq!(rate.sample(start + Duration::from_secs(1), 60), Some(50));
+
+        // Each window is measured from the end of the last.
+        assert_eq!(rate.sample(start + Duration::from_secs(3), 110), Some(25));
+        assert_eq!(rate.sample(start + Duration::from_secs(4), 110), Some(0));
+    }
+}
@@ -1,31 +1,161 @@
 use byteorder::{ByteOrder, LittleEndian};
 use crate::internal::prelude::*;
 use audiopus::{
+    Application,
     Channels,
-    coder::Decoder as OpusDecoder,
+    coder::{Decoder as OpusDecoder, Encoder as OpusEncoder},
     Result as OpusResult,
 };
 use tokio::fs::File;
-use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
 use tokio::process::{Child, Command};
-use tokio::sync::Mutex;
+use tokio::runtime::Handle;
+use tokio::sync::{Mutex, OwnedSemaphorePermit, Semaphore};
+use tokio::time;
 use serde_json;
 use std::{
-    ffi::OsStr,
-    io::ErrorKind as IoErrorKind,
+    collections::VecDeque,
+    ffi::{OsStr, OsString},
+    future::Future,
+    io::{Cursor, Error as IoError, ErrorKind as IoErrorKind},
     marker::Unpin,
     pin::Pin,
-    process::Stdio,
+    process::{Child as StdChild, Stdio},
     result::Result as StdResult,
-    sync::Arc,
+    sync::{Arc, Mutex as StdMutex, RwLock},
     task::{Context, Poll},
+    time::Duration,
 };
-use super::{AudioSource, AudioType, DcaError, DcaMetadata, VoiceError, audio};
+use super::icy::IcyParser;
+use super::probe::probe_with_options;
+use super::{AudioSource, AudioType, DcaError, DcaMetadata, ProcessOptions, TrackInfo, VoiceError, YtdlRetry, audio};
 use tracing::{debug, warn, instrument};
 use crate::prelude::SerenityError;
 use async_trait::async_trait;
+use futures::future::BoxFuture;
+use lazy_static::lazy_static;
 
-struct ChildContainer(Child);
+/// How often a finished `youtube-dl` process is checked for, to be reaped.
+const REAP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How much audio is read ahead by default from sources fetched over a
+/// network, whose decoding may not keep up at first.
+const DEFAULT_NETWORK_PREBUFFER: Duration = Duration::from_millis(200);
+
+lazy_static! {
+    static ref FFMPEG_PROGRAM: RwLock<Option<OsString>> = RwLock::new(None);
+    static ref FFPROBE_PROGRAM: RwLock<Option<OsString>> = RwLock::new(None);
+    static ref YTDL_PROGRAM: RwLock<Option<OsString>> = RwLock::new(None);
+    /// Permits for the subprocesses behind sources, if they are limited.
+    static ref SUBPROCESS_LIMIT: StdMutex<Option<Arc<Semaphore>>> = StdMutex::new(None);
+}
+
+fn set_program(program: &RwLock<Option<OsString>>, path: &OsStr) {
+    *program.write().expect("[Voice] Program path poisoned") = Some(path.to_os_string());
+}
+
+fn program(program: &RwLock<Option<OsString>>, default: &str) -> OsString {
+    program.read()
+        .expect("[Voice] Program path poisoned")
+        .clone()
+        .unwrap_or_else(|| default.into())
+}
+
+/// Describes a failure to run an external program, naming the program if it
+/// could not be found.
+pub(crate) fn spawn_error(program: OsString) -> impl FnOnce(IoError) -> Error {
+    move |why| match why.kind() {
+        IoErrorKind::NotFound => Error::Voice(VoiceError::ProgramNotFound(program)),
+        _ => Error::Io(why),
+    }
+}
+
+/// Sets the program run in place of `ffmpeg` by all audio sources created
+/// after this call, such as a path to a binary outside of `PATH`.
+///
+/// Defaults to `ffmpeg`.
+pub fn set_ffmpeg_program<P: AsRef<OsStr>>(program: P) {
+    set_program(&FFMPEG_PROGRAM, program.as_ref());
+}
+
+/// Sets the program run in place of `ffprobe` when probing audio, such as by
+/// [`probe`] and [`ffmpeg`].
+///
+/// Defaults to `ffprobe`.
+///
+/// [`ffmpeg`]: fn.ffmpeg.html
+/// [`probe`]: fn.probe.html
+pub fn set_ffprobe_program<P: AsRef<OsStr>>(program: P) {
+    set_program(&FFPROBE_PROGRAM, program.as_ref());
+}
+
+/// Sets the program run in place of `youtube-dl` by [`ytdl`] and similar
+/// functions, such as `yt-dlp`.
+///
+/// Defaults to `youtube-dl`.
+///
+/// [`ytdl`]: fn.ytdl.html
+pub fn set_ytdl_program<P: AsRef<OsStr>>(program: P) {
+    set_program(&YTDL_PROGRAM, program.as_ref());
+}
+
+/// Limits how many sources may run subprocesses at once, or removes the
+/// limit if `max` is 0.
+///
+/// Sources already running keep the permits of the old limit, and are not
+/// counted against the new one.
+pub(crate) fn set_max_subprocesses(max: usize) {
+    *SUBPROCESS_LIMIT.lock().unwrap_or_else(|poisoned| poisoned.into_inner()) = subprocess_limit(max);
+}
+
+fn subprocess_limit(max: usize) -> Option<Arc<Semaphore>> {
+    if max == 0 {
+        None
+    } else {
+        Some(Arc::new(Semaphore::new(max)))
+    }
+}
+
+/// Waits for a permit to run a source's subprocesses, if they are limited,
+/// which is held until the source is dropped. Waiting sources are given
+/// permits in the order they asked.
+async fn subprocess_permit() -> Option<OwnedSemaphorePermit> {
+    let limit = SUBPROCESS_LIMIT.lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .clone();
+
+    permit_of(limit).await
+}
+
+async fn permit_of(limit: Option<Arc<Semaphore>>) -> Option<OwnedSemaphorePermit> {
+    match limit {
+        Some(limit) => Some(limit.acquire_owned().await),
+        None => None,
+    }
+}
+
+pub(crate) fn ffmpeg_program() -> OsString {
+    program(&FFMPEG_PROGRAM, "ffmpeg")
+}
+
+pub(crate) fn ffprobe_program() -> OsString {
+    program(&FFPROBE_PROGRAM, "ffprobe")
+}
+
+fn ytdl_program() -> OsString {
+    program(&YTDL_PROGRAM, "youtube-dl")
+}
+
+/// A child process read from, with the permit it was spawned under.
+///
+/// The child is only taken when dropped, to be reaped.
+struct ChildContainer(Option<Child>, Option<OwnedSemaphorePermit>);
+
+impl ChildContainer {
+    fn new(child: Child, permit: Option<OwnedSemaphorePermit>) -> Self {
+        Self(Some(child), permit)
+    }
+}
 
 impl AsyncRead for ChildContainer {
     #[instrument(skip(self))]
@@ -35,7 +165,7 @@ impl AsyncRead for ChildContainer {
         buffer: &mut [u8]
     ) -> Poll<tokio::io::Result<usize>> {
         let stdout = unsafe {
-            self.map_unchecked_mut(|s| { s.0.stdout.as_mut().unwrap() })
+            self.map_unchecked_mut(|s| { s.0.as_mut().and_then(|child| child.stdout.as_mut()).unwrap() })
         };
         stdout.poll_read(cx, buffer)
     }
@@ -43,9 +173,60 @@ impl AsyncRead for ChildContainer {
 
 impl Drop for ChildContainer {
     fn drop (&mut self) {
-        if let Err(e) = self.0.kill() {
-            debug!("[Voice] Error awaiting child process: {:?}", e);
+        let mut child = match self.0.take() {
+            Some(child) => child,
+            None => return,
+        };
+
+        if let Err(e) = child.kill() {
+            debug!("[Voice] Error killing child process: {:?}", e);
+        }
+
+        // Wait for the killed process in the background, so that it does not
+        // linger as a zombie, keeping the permit until it has exited.
+        let permit = self.1.take();
+
+        if let Ok(handle) = Handle::try_current() {
+            handle.spawn(async move {
+                if let Err(e) = child.await {
+                    debug!("[Voice] Error awaiting child process: {:?}", e);
+                }
+
+                drop(permit);
+            });
+        }
+    }
+}
+
+/// Waits for a process spawned through the standard library, such as the
+/// `youtube-dl` feeding `ffmpeg`, without blocking, so that it does not linger
+/// as a zombie once it exits.
+fn reap(mut child: StdChild) {
+    tokio::spawn(async move {
+        while let Ok(None) = child.try_wait() {
+            time::delay_for(REAP_INTERVAL).await;
         }
+    });
+}
+
+/// Makes a reader which is only `Send` usable as a source, which must also be
+/// `Sync`.
+///
+/// The lock is never contended, as reads always have exclusive access.
+struct SyncReader<R>(StdMutex<R>);
+
+impl<R: AsyncRead + Unpin> AsyncRead for SyncReader<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buffer: &mut [u8]
+    ) -> Poll<tokio::io::Result<usize>> {
+        let reader = match self.get_mut().0.get_mut() {
+            Ok(reader) => reader,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+
+        Pin::new(reader).poll_read(cx, buffer)
     }
 }
 
@@ -68,6 +249,7 @@ struct InputSource<R: AsyncRead + Unpin + Send + Sync + 'static> {
     reader: R,
     kind: AudioType,
     decoder: Option<Arc<Mutex<SendDecoder>>>,
+    duration: Option<Duration>,
 }
 
 #[async_trait]
@@ -137,6 +319,12 @@ impl<R: AsyncRead + Unpin + Send + Sync> AudioSource for InputSource<R> {
     async fn decode_and_add_opus_frame(&mut self, float_buffer: &mut [f32; 1920], volume: f32) -> Option<usize> {
         let decoder_lock = self.decoder.as_mut()?.clone();
         let frame = self.read_opus_frame().await?;
+
+        // The stream has ended.
+        if frame.is_empty() {
+            return Some(0);
+        }
+
         let mut local_buf = [0f32; 960 * 2];
 
         let count = {
@@ -151,17 +339,165 @@ impl<R: AsyncRead + Unpin + Send + Sync> AudioSource for InputSource<R> {
 
         Some(count)
     }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.duration
+    }
+}
+
+/// An audio source read from an `ffmpeg` process, which seeks by restarting
+/// the process from the new position.
+struct FfmpegSource {
+    path: OsString,
+    args: Vec<String>,
+    /// The duration found by `ffprobe`, shared with any search for it again.
+    duration: Arc<StdMutex<Option<Duration>>>,
+    options: ProcessOptions,
+    inner: InputSource<ChildContainer>,
+}
+
+impl FfmpegSource {
+    fn spawn(path: &OsStr, args: &[&str], start: Duration, options: &ProcessOptions) -> Result<Child> {
+        let program = ffmpeg_program();
+        let mut command = Command::new(&program);
+        command.kill_on_drop(true);
+        options.apply(&mut command);
+
+        if start > Duration::default() {
+            command.arg("-ss").arg(format!("{}.{:03}", start.as_secs(), start.subsec_millis()));
+        }
+
+        command
+            .arg("-i")
+            .arg(path)
+            .args(args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .spawn()
+            .map_err(spawn_error(program))
+    }
+}
+
+#[async_trait]
+impl AudioSource for FfmpegSource {
+    async fn is_stereo(&mut self) -> bool {
+        self.inner.stereo
+    }
+
+    async fn get_type(&self) -> AudioType {
+        self.inner.kind
+    }
+
+    async fn read_pcm_frame(&mut self, buffer: &mut [i16]) -> Option<usize> {
+        self.inner.read_pcm_frame(buffer).await
+    }
+
+    async fn read_opus_frame(&mut self) -> Option<Vec<u8>> {
+        self.inner.read_opus_frame().await
+    }
+
+    async fn decode_and_add_opus_frame(&mut self, float_buffer: &mut [f32; 1920], volume: f32) -> Option<usize> {
+        self.inner.decode_and_add_opus_frame(float_buffer, volume).await
+    }
+
+    fn is_seekable(&self) -> bool {
+        true
+    }
+
+    #[instrument(skip(self))]
+    async fn seek(&mut self, position: Duration) -> bool {
+        let args = self.args.iter().map(String::as_str).collect::<Vec<_>>();
+
+        match FfmpegSource::spawn(&self.path, &args, position, &self.options) {
+            Ok(child) => {
+                // The restarted process replaces the old under its permit.
+                let permit = self.inner.reader.1.take();
+                self.inner.reader = ChildContainer::new(child, permit);
+
+                true
+            },
+            Err(why) => {
+                warn!("[Voice] Failed to restart ffmpeg for seek: {:?}", why);
+
+                false
+            },
+        }
+    }
+
+    fn refresh_duration(&self) -> Option<BoxFuture<'static, Option<Duration>>> {
+        let path = self.path.clone();
+        let args = self.args.clone();
+        let options = self.options.clone();
+        let duration = Arc::clone(&self.duration);
+
+        Some(Box::pin(async move {
+            let probed = probe_with_options(&path, &options).await;
+            let mut duration = duration.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+
+            // A failed probe says nothing of the length, so the last one
+            // found is kept.
+            match probed {
+                Ok(info) => *duration = info.duration.map(|found| trimmed_duration(found, &args)),
+                Err(why) => warn!("[Voice] Failed to probe duration of {:?}: {:?}", path, why),
+            }
+
+            *duration
+        }))
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        *self.duration.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
 }
 
 /// Opens an audio file through `ffmpeg` and creates an audio source.
+///
+/// The source is seekable, via [`Audio::position`].
+///
+/// # Errors
+///
+/// Returns [`VoiceError::SourceNotFound`] if the path is neither a URL, a
+/// pipe such as `pipe:0`, nor an existing file, and [`VoiceError::Streams`]
+/// if the file holds no audio which `ffprobe` can read. Returns
+/// [`VoiceError::ProgramNotFound`] if `ffmpeg` cannot be found.
+///
+/// [`Audio::position`]: struct.Audio.html#method.position
+/// [`VoiceError::ProgramNotFound`]: enum.VoiceError.html#variant.ProgramNotFound
+/// [`VoiceError::SourceNotFound`]: enum.VoiceError.html#variant.SourceNotFound
+/// [`VoiceError::Streams`]: enum.VoiceError.html#variant.Streams
 pub async fn ffmpeg<P: AsRef<OsStr>>(path: P) -> Result<Box<dyn AudioSource>> {
-    _ffmpeg(path.as_ref()).await
+    _ffmpeg(path.as_ref(), ProcessOptions::default()).await
+}
+
+/// Opens an audio file through `ffmpeg` as with [`ffmpeg`], spawning `ffmpeg`
+/// and `ffprobe` with the given environment, working directory, stderr
+/// handling and timeout.
+///
+/// The options are used again each time the source is seeked, except for the
+/// timeout.
+///
+/// [`ffmpeg`]: fn.ffmpeg.html
+pub async fn ffmpeg_with_options<P: AsRef<OsStr>>(
+    path: P,
+    options: &ProcessOptions,
+) -> Result<Box<dyn AudioSource>> {
+    _ffmpeg(path.as_ref(), options.clone()).await
 }
 
 #[instrument]
-async fn _ffmpeg(path: &OsStr) -> Result<Box<dyn AudioSource>> {
+async fn _ffmpeg(path: &OsStr, options: ProcessOptions) -> Result<Box<dyn AudioSource>> {
+    let local = check_local_file(path).await?;
+
     // Will fail if the path is not to a file on the fs. Likely a YouTube URI.
-    let is_stereo = is_stereo(path).await.unwrap_or(false);
+    let probed = match probe_with_options(path, &options).await {
+        Ok(info) => Some(info),
+        // A local file which cannot be probed cannot be played either.
+        Err(Error::Voice(VoiceError::Streams)) | Err(Error::Voice(VoiceError::ProbeOutput(_))) if local => {
+            return Err(Error::Voice(VoiceError::Streams));
+        },
+        Err(_) => None,
+    };
+    let is_stereo = matches!(probed, Some(ref info) if info.is_stereo());
     let stereo_val = if is_stereo { "2" } else { "1" };
 
     _ffmpeg_optioned(path, &[
@@ -174,7 +510,25 @@ async fn _ffmpeg(path: &OsStr) -> Result<Box<dyn AudioSource>> {
         "-acodec",
         "pcm_s16le",
         "-",
-    ], Some(is_stereo)).await
+    ], probed, options).await
+}
+
+/// Checks that a path which is neither a URL nor one of ffmpeg's pipes, such
+/// as `pipe:0` or `-`, is to an existing file, returning whether it is local.
+async fn check_local_file(path: &OsStr) -> Result<bool> {
+    let lossy = path.to_string_lossy();
+
+    if lossy.contains("://") || lossy.starts_with("pipe:") || lossy == "-" {
+        return Ok(false);
+    }
+
+    match tokio::fs::metadata(path).await {
+        Ok(_) => Ok(true),
+        Err(ref why) if why.kind() == IoErrorKind::NotFound => {
+            Err(Error::Voice(VoiceError::SourceNotFound(path.to_os_string())))
+        },
+        Err(why) => Err(Error::Io(why)),
+    }
 }
 
 /// Opens an audio file through `ffmpeg` and creates an audio source, with
@@ -183,6 +537,10 @@ async fn _ffmpeg(path: &OsStr) -> Result<Box<dyn AudioSource>> {
 /// Note that this does _not_ build on the arguments passed by the [`ffmpeg`]
 /// function.
 ///
+/// As with [`ffmpeg`], the source is seekable. The given arguments are passed
+/// again each time it is seeked. Its [`AudioSource::total_duration`] is that
+/// of the file as trimmed by any `-ss`, `-t` or `-to` among them.
+///
 /// # Examples
 ///
 /// Pass options to create a custom ffmpeg streamer:
@@ -211,28 +569,56 @@ pub async fn ffmpeg_optioned<P: AsRef<OsStr>>(
     path: P,
     args: &[&str],
 ) -> Result<Box<dyn AudioSource>> {
-    _ffmpeg_optioned(path.as_ref(), args, None).await
+    _ffmpeg_optioned(path.as_ref(), args, None, ProcessOptions::default()).await
 }
 
 #[instrument]
-async fn _ffmpeg_optioned(path: &OsStr, args: &[&str], is_stereo_known: Option<bool>) -> Result<Box<dyn AudioSource>> {
-    let is_stereo_known = match is_stereo_known {
-        None => is_stereo(path).await.ok(),
+async fn _ffmpeg_optioned(
+    path: &OsStr,
+    args: &[&str],
+    probed: Option<TrackInfo>,
+    options: ProcessOptions,
+) -> Result<Box<dyn AudioSource>> {
+    let probed = match probed {
+        None => probe_with_options(path, &options).await.ok(),
         others => others,
     };
-    let is_stereo = is_stereo_known.unwrap_or(false);
+    let is_stereo = matches!(probed, Some(ref info) if info.is_stereo());
+    let duration = probed.and_then(|info| info.duration).map(|duration| trimmed_duration(duration, args));
 
-    let command = Command::new("ffmpeg")
-        .kill_on_drop(true)
-        .arg("-i")
-        .arg(path)
-        .args(args)
-        .stderr(Stdio::null())
-        .stdin(Stdio::null())
-        .stdout(Stdio::piped())
-        .spawn()?;
+    let permit = subprocess_permit().await;
+    let reader = ChildContainer::new(FfmpegSource::spawn(path, args, Duration::default(), &options)?, permit);
+    let timeout = options.timeout;
+    let ahead = options.prebuffer.unwrap_or_else(|| {
+        if path.to_string_lossy().contains("://") {
+            DEFAULT_NETWORK_PREBUFFER
+        } else {
+            Duration::default()
+        }
+    });
+
+    let source = Box::new(FfmpegSource {
+        path: path.to_os_string(),
+        args: args.iter().map(|arg| arg.to_string()).collect(),
+        duration: Arc::new(StdMutex::new(duration)),
+        options,
+        inner: InputSource {
+            stereo: is_stereo,
+            reader,
+            kind: AudioType::Pcm,
+            decoder: None,
+            duration: None,
+        },
+    });
 
-    Ok(pcm(is_stereo, ChildContainer(command)))
+    // With a timeout, at least the first frame is read ahead, so that a
+    // process which never produces one is killed here, as it is dropped.
+    match timeout {
+        Some(limit) => time::timeout(limit, prebuffer(source, ahead.max(Duration::from_millis(20))))
+            .await
+            .map_err(|_| Error::Voice(VoiceError::Timeout)),
+        None => Ok(prebuffer(source, ahead).await),
+    }
 }
 
 /// Creates a streamed audio source from a DCA file.
@@ -285,6 +671,7 @@ async fn _dca(path: &OsStr) -> StdResult<Box<dyn AudioSource>, DcaError> {
 ///
 /// If you want to decode a `.opus` file, use [`ffmpeg`]
 ///
+/// [`AudioSource::total_duration`]: trait.AudioSource.html#method.total_duration
 /// [`ffmpeg`]: fn.ffmpeg.html
 #[instrument(skip(reader))]
 pub fn opus<R: AsyncRead + Unpin + Send + Sync + 'static>(is_stereo: bool, reader: R) -> Box<dyn AudioSource> {
@@ -298,6 +685,72 @@ pub fn opus<R: AsyncRead + Unpin + Send + Sync + 'static>(is_stereo: bool, reade
                 SendDecoder(OpusDecoder::new(audio::SAMPLE_RATE, Channels::Stereo).unwrap())
             ))
         ),
+        duration: None,
+    })
+}
+
+/// Sends ready Opus frames from an iterator, as [`opus_frames`].
+struct OpusFramesSource<I> {
+    /// Locked only so that the source is `Sync`, and so never contended.
+    frames: StdMutex<I>,
+}
+
+#[async_trait]
+impl<I: Iterator<Item = Vec<u8>> + Send + 'static> AudioSource for OpusFramesSource<I> {
+    async fn is_stereo(&mut self) -> bool {
+        true
+    }
+
+    async fn get_type(&self) -> AudioType {
+        AudioType::Passthrough
+    }
+
+    async fn read_pcm_frame(&mut self, _buffer: &mut [i16]) -> Option<usize> {
+        None
+    }
+
+    async fn read_opus_frame(&mut self) -> Option<Vec<u8>> {
+        let frames = match self.frames.get_mut() {
+            Ok(frames) => frames,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+
+        Some(frames.next().unwrap_or_default())
+    }
+
+    async fn decode_and_add_opus_frame(&mut self, _float_buffer: &mut [f32; 1920], _volume: f32) -> Option<usize> {
+        None
+    }
+}
+
+/// Creates a source which sends each of the given Opus frames as it is,
+/// without decoding, mixing, or encoding it again, such as for audio encoded
+/// ahead of time. This is the cheapest way to play audio.
+///
+/// Each frame must hold 20ms of 48kHz audio, as Discord expects, and the
+/// source ends at the first empty frame, or when the iterator does.
+///
+/// As the frames are never decoded, a track's [`volume`], fades, equalizer
+/// and ducking do not apply to them, nor do its bus's volume or the
+/// handler's limiter and encoder settings. Muting the track via [`mute`]
+/// does silence it, as its frames are then read but not sent. While the
+/// source plays, it is sent in place of the mix of any other sources, which
+/// play on unheard; if several such sources play at once, only the first
+/// added which is not muted is heard.
+///
+/// Unlike [`opus`], the frames are not length-prefixed, so need not be read
+/// from a DCA-style stream.
+///
+/// [`mute`]: struct.Audio.html#method.mute
+/// [`opus`]: fn.opus.html
+/// [`volume`]: struct.Audio.html#structfield.volume
+pub fn opus_frames<I>(frames: I) -> Box<dyn AudioSource>
+where
+    I: IntoIterator<Item = Vec<u8>>,
+    I::IntoIter: Send + 'static,
+{
+    Box::new(OpusFramesSource {
+        frames: StdMutex::new(frames.into_iter()),
     })
 }
 
@@ -309,132 +762,1351 @@ pub fn pcm<R: AsyncRead + Unpin + Send + Sync + 'static>(is_stereo: bool, reader
         reader,
         kind: AudioType::Pcm,
         decoder: None,
+        duration: None,
     })
 }
 
-/// Creates a streamed audio source with `youtube-dl` and `ffmpeg`.
-#[instrument]
-pub async fn ytdl(uri: &str) -> Result<Box<dyn AudioSource>> {
-    let ytdl_args = [
-        "-f",
-        "webm[abr>0]/bestaudio/best",
-        "-R",
-        "infinite",
-        "--no-playlist",
-        "--ignore-config",
-        uri,
-        "-o",
-        "-"
-    ];
+/// Creates a source of 48kHz 16-bit little-endian stereo PCM from any async
+/// reader, such as the body of an HTTP response, without a blocking thread.
+///
+/// Unlike [`pcm`], the reader need not be `Sync`. Reads which return fewer
+/// bytes than requested are continued, and the source ends at EOF, playing
+/// any final partial frame.
+///
+/// [`pcm`]: fn.pcm.html
+#[instrument(skip(reader))]
+pub fn async_reader<R: AsyncRead + Unpin + Send + 'static>(reader: R) -> Box<dyn AudioSource> {
+    pcm(true, SyncReader(StdMutex::new(reader)))
+}
 
-    let ffmpeg_args = [
-        "-f",
-        "s16le",
-        "-ac",
-        "2",
-        "-ar",
-        "48000",
-        "-acodec",
-        "pcm_s16le",
-        "-",
-    ];
+/// Plays several sources one after another, as [`chain`].
+struct ChainSource {
+    sources: Vec<Box<dyn AudioSource>>,
+    current: usize,
+    /// Decodes passthrough frames read in another type, created on first
+    /// use.
+    decoder: Option<Arc<Mutex<SendDecoder>>>,
+    /// Encodes PCM frames read as passthrough, created on first use.
+    encoder: Option<Arc<Mutex<OpusEncoder>>>,
+}
 
-    let youtube_dl = std::process::Command::new("youtube-dl")
-        .args(&ytdl_args)
-        .stdin(Stdio::null())
-        .stderr(Stdio::null())
-        .stdout(Stdio::piped())
-        .spawn()?;
+impl ChainSource {
+    /// Decodes the next frame of the current source to stereo, whatever its
+    /// type, and adds it to `float_buffer` at the given volume. Returns the
+    /// number of samples per channel, as Opus decoding does.
+    async fn decode_current(&mut self, float_buffer: &mut [f32; 1920], volume: f32) -> Option<usize> {
+        let source = &mut self.sources[self.current];
 
-    let ffmpeg = Command::new("ffmpeg")
-        .kill_on_drop(true)
-        .arg("-re")
-        .arg("-i")
-        .arg("-")
-        .args(&ffmpeg_args)
-        .stdin(youtube_dl.stdout.ok_or(SerenityError::Other("Failed to open youtube-dl stdout"))?)
-        .stderr(Stdio::null())
-        .stdout(Stdio::piped())
-        .spawn()?;
+        match source.get_type().await {
+            AudioType::Opus => source.decode_and_add_opus_frame(float_buffer, volume).await,
+            AudioType::Passthrough => {
+                let frame = source.read_opus_frame().await?;
 
-    Ok(pcm(true, ChildContainer(ffmpeg)))
-}
+                if frame.is_empty() {
+                    return Some(0);
+                }
 
-/// Creates a streamed audio source from YouTube search results with `youtube-dl`,`ffmpeg`, and `ytsearch`.
-/// Takes the first video listed from the YouTube search.
-#[instrument]
-pub async fn ytdl_search(name: &str) -> Result<Box<dyn AudioSource>> {
-    let ytdl_args = [
-        "-f",
-        "webm[abr>0]/bestaudio/best",
-        "-R",
-        "infinite",
-        "--no-playlist",
-        "--ignore-config",
-        &format!("ytsearch1:{}",name),
-        "-o",
-        "-"
-    ];
+                let decoder = self.decoder.get_or_insert_with(|| Arc::new(Mutex::new(
+                    SendDecoder(OpusDecoder::new(audio::SAMPLE_RATE, Channels::Stereo).unwrap())
+                )));
+                let mut decoded = [0f32; 1920];
+                let count = decoder.lock().await.decode_float(&frame, &mut decoded, false).ok()?;
 
-    let ffmpeg_args = [
-        "-f",
-        "s16le",
-        "-ac",
-        "2",
-        "-ar",
-        "48000",
-        "-acodec",
-        "pcm_s16le",
-        "-",
-    ];
+                for (value, &sample) in float_buffer.iter_mut().zip(&decoded[..count * 2]) {
+                    *value += sample * volume;
+                }
 
-    let youtube_dl = std::process::Command::new("youtube-dl")
-        .args(&ytdl_args)
-        .stdin(Stdio::null())
-        .stderr(Stdio::null())
-        .stdout(Stdio::piped())
-        .spawn()?;
+                Some(count)
+            },
+            AudioType::Pcm => {
+                let mut pcm = [0i16; 1920];
+                let len = read_pcm_as(source, &mut pcm, true).await?;
 
-    let ffmpeg = Command::new("ffmpeg")
-        .kill_on_drop(true)
-        .arg("-re")
-        .arg("-i")
-        .arg("-")
-        .args(&ffmpeg_args)
-        .stdin(youtube_dl.stdout.ok_or(SerenityError::Other("Failed to open youtube-dl stdout"))?)
-        .stderr(Stdio::null())
-        .stdout(Stdio::piped())
-        .spawn()?;
+                for (value, &sample) in float_buffer.iter_mut().zip(&pcm[..len]) {
+                    *value += f32::from(sample) / f32::from(i16::MAX) * volume;
+                }
 
-    Ok(pcm(true, ChildContainer(ffmpeg)))
-}
+                Some(len / 2)
+            },
+        }
+    }
 
-#[instrument]
-async fn is_stereo(path: &OsStr) -> Result<bool> {
-    let args = ["-v", "quiet", "-of", "json", "-show-streams", "-i"];
+    /// Encodes the next frame of the current source, which is PCM, so that
+    /// it can be passed through. A final partial frame is padded with
+    /// silence.
+    async fn encode_current(&mut self) -> Option<Vec<u8>> {
+        let mut pcm = [0i16; 1920];
+        let len = read_pcm_as(&mut self.sources[self.current], &mut pcm, true).await?;
 
-    let out = Command::new("ffprobe")
-        .kill_on_drop(true)
-        .args(&args)
-        .arg(path)
-        .stdin(Stdio::null())
-        .output().await?;
+        if len == 0 {
+            return Some(Vec::new());
+        }
 
-    let value: Value = serde_json::from_reader(&out.stdout[..])?;
+        let encoder = self.encoder.get_or_insert_with(|| Arc::new(Mutex::new(
+            OpusEncoder::new(audio::SAMPLE_RATE, Channels::Stereo, Application::Audio).unwrap()
+        )));
+        let mut frame = vec![0u8; 4000];
+        let size = encoder.lock().await.encode(&pcm, &mut frame).ok()?;
+        frame.truncate(size);
 
-    let streams = value
-        .as_object()
-        .and_then(|m| m.get("streams"))
-        .and_then(|v| v.as_array())
-        .ok_or(Error::Voice(VoiceError::Streams))?;
+        Some(frame)
+    }
 
-    let check = streams.iter().any(|stream| {
-        let channels = stream
-            .as_object()
-            .and_then(|m| m.get("channels").and_then(|v| v.as_i64()));
+    /// Reads the next frame of the current source, which is not PCM, decoded
+    /// to PCM in the given layout. Only whole frames can be decoded, so
+    /// these can only start a frame.
+    async fn decode_frame(&mut self, buffer: &mut [i16], stereo: bool) -> Option<usize> {
+        let mut decoded = [0f32; 1920];
+        let count = self.decode_current(&mut decoded, 1.0).await?;
 
-        channels == Some(2)
-    });
+        // Frames are always decoded to stereo.
+        let decoded = &decoded[..count * 2];
 
-    Ok(check)
+        Some(if stereo {
+            for (sample, &value) in buffer.iter_mut().zip(decoded) {
+                *sample = float_to_pcm(value);
+            }
+
+            decoded.len().min(buffer.len())
+        } else {
+            for (sample, pair) in buffer.iter_mut().zip(decoded.chunks_exact(2)) {
+                *sample = float_to_pcm((pair[0] + pair[1]) / 2.0);
+            }
+
+            count.min(buffer.len())
+        })
+    }
+}
+
+/// Converts a float sample to 16-bit PCM, clipping it to the range of `i16`.
+fn float_to_pcm(value: f32) -> i16 {
+    (value * f32::from(i16::MAX)).max(f32::from(i16::MIN)).min(f32::from(i16::MAX)) as i16
+}
+
+/// Reads a PCM frame from a source into `buffer` in the given layout,
+/// converting from the source's own if it differs.
+async fn read_pcm_as(source: &mut Box<dyn AudioSource>, buffer: &mut [i16], stereo: bool) -> Option<usize> {
+    let source_stereo = source.is_stereo().await;
+
+    if source_stereo == stereo {
+        return source.read_pcm_frame(buffer).await;
+    }
+
+    if stereo {
+        // Each mono sample is played on both channels.
+        let mut mono = vec![0i16; buffer.len() / 2];
+        let len = source.read_pcm_frame(&mut mono).await?;
+
+        for (pair, &value) in buffer.chunks_exact_mut(2).zip(&mono[..len]) {
+            pair[0] = value;
+            pair[1] = value;
+        }
+
+        Some(len * 2)
+    } else {
+        let mut pairs = vec![0i16; buffer.len() * 2];
+        let len = source.read_pcm_frame(&mut pairs).await?;
+
+        for (sample, pair) in buffer.iter_mut().zip(pairs[..len].chunks_exact(2)) {
+            *sample = ((i32::from(pair[0]) + i32::from(pair[1])) / 2) as i16;
+        }
+
+        Some(len / 2)
+    }
+}
+
+#[async_trait]
+impl AudioSource for ChainSource {
+    async fn is_stereo(&mut self) -> bool {
+        match self.sources.get_mut(self.current) {
+            Some(source) => source.is_stereo().await,
+            None => true,
+        }
+    }
+
+    async fn get_type(&self) -> AudioType {
+        match self.sources.get(self.current) {
+            Some(source) => source.get_type().await,
+            None => AudioType::Pcm,
+        }
+    }
+
+    #[instrument(skip(self, buffer))]
+    async fn read_pcm_frame(&mut self, buffer: &mut [i16]) -> Option<usize> {
+        // The frame is laid out as the source it starts in, as the mixer
+        // expects, and the rest is converted to match.
+        let stereo = match self.sources.get_mut(self.current) {
+            Some(source) => source.is_stereo().await,
+            None => return Some(0),
+        };
+        let mut filled = 0;
+
+        while filled < buffer.len() {
+            let source = match self.sources.get_mut(self.current) {
+                Some(source) => source,
+                None => break,
+            };
+
+            if let AudioType::Pcm = source.get_type().await {
+                filled += read_pcm_as(source, &mut buffer[filled..], stereo).await?;
+
+                if filled < buffer.len() {
+                    self.current += 1;
+                }
+            } else if filled == 0 {
+                // A source which ends on a frame boundary is only found to
+                // have ended on the next read, so that is given the first
+                // frame of the next source, even if it is not PCM.
+                match self.decode_frame(buffer, stereo).await? {
+                    0 => self.current += 1,
+                    read => return Some(read),
+                }
+            } else {
+                break;
+            }
+        }
+
+        Some(filled)
+    }
+
+    async fn read_opus_frame(&mut self) -> Option<Vec<u8>> {
+        // Once a source ends, the frame is taken from the next, which is
+        // encoded if it is PCM. Opus sources are read as their frames.
+        while let Some(source) = self.sources.get_mut(self.current) {
+            let frame = match source.get_type().await {
+                AudioType::Pcm => self.encode_current().await,
+                _ => source.read_opus_frame().await,
+            };
+
+            match frame {
+                Some(frame) if !frame.is_empty() => return Some(frame),
+                _ => self.current += 1,
+            }
+        }
+
+        Some(Vec::new())
+    }
+
+    async fn decode_and_add_opus_frame(&mut self, float_buffer: &mut [f32; 1920], volume: f32) -> Option<usize> {
+        // Once a source ends, the frame is taken from the next, decoded or
+        // converted from its own type.
+        while self.current < self.sources.len() {
+            match self.decode_current(float_buffer, volume).await? {
+                0 => self.current += 1,
+                count => return Some(count),
+            }
+        }
+
+        Some(0)
+    }
+
+    fn is_seekable(&self) -> bool {
+        self.sources.iter().all(|source| source.is_seekable() && source.total_duration().is_some())
+    }
+
+    #[instrument(skip(self))]
+    async fn seek(&mut self, position: Duration) -> bool {
+        if !self.is_seekable() {
+            return false;
+        }
+
+        let mut start = Duration::default();
+        let mut target = self.sources.len();
+
+        for (i, source) in self.sources.iter().enumerate() {
+            let length = source.total_duration().unwrap_or_default();
+
+            if position < start + length {
+                target = i;
+
+                break;
+            }
+
+            start += length;
+        }
+
+        // Sources after the target may have been partly played already.
+        for (i, source) in self.sources.iter_mut().enumerate().skip(target) {
+            let offset = if i == target { position - start } else { Duration::default() };
+
+            if !source.seek(offset).await {
+                return false;
+            }
+        }
+
+        self.current = target;
+
+        true
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.sources.iter().map(|source| source.total_duration()).sum()
+    }
+
+    fn take_stream_title(&mut self) -> Option<String> {
+        self.sources.get_mut(self.current)?.take_stream_title()
+    }
+}
+
+/// Creates a source which plays each of the given sources in order, as one
+/// track, such as to stitch an intro, loop, and outro together.
+///
+/// Each source starts as soon as the one before it ends, without a gap. PCM
+/// sources are joined within a single frame, converting between mono and
+/// stereo as needed, so that not even a partial frame of silence is heard
+/// between them. A PCM source is followed by an Opus one after a partial
+/// frame of silence, if it ends partway through a frame. Sources of other
+/// types are decoded or encoded to match the type the chain is read as.
+///
+/// The [`total_duration`] of the chain is known when those of all its sources are,
+/// and it is seekable if they all are too.
+///
+/// This is lighter than a [`TrackQueue`] for short effects, but the sources
+/// are all opened up front, and the chain is controlled as a single track.
+///
+/// [`total_duration`]: trait.AudioSource.html#method.total_duration
+/// [`TrackQueue`]: struct.TrackQueue.html
+pub fn chain(sources: Vec<Box<dyn AudioSource>>) -> Box<dyn AudioSource> {
+    Box::new(ChainSource {
+        sources,
+        current: 0,
+        decoder: None,
+        encoder: None,
+    })
+}
+
+/// Scales the audio of another source by a fixed gain, as [`with_volume`].
+struct GainSource {
+    inner: Box<dyn AudioSource>,
+    gain: f32,
+}
+
+#[async_trait]
+impl AudioSource for GainSource {
+    async fn is_stereo(&mut self) -> bool {
+        self.inner.is_stereo().await
+    }
+
+    async fn get_type(&self) -> AudioType {
+        self.inner.get_type().await
+    }
+
+    async fn read_pcm_frame(&mut self, buffer: &mut [i16]) -> Option<usize> {
+        let len = self.inner.read_pcm_frame(buffer).await?;
+
+        for sample in buffer[..len].iter_mut() {
+            let scaled = f32::from(*sample) * self.gain;
+
+            *sample = scaled.max(f32::from(i16::MIN)).min(f32::from(i16::MAX)) as i16;
+        }
+
+        Some(len)
+    }
+
+    async fn read_opus_frame(&mut self) -> Option<Vec<u8>> {
+        self.inner.read_opus_frame().await
+    }
+
+    async fn decode_and_add_opus_frame(&mut self, float_buffer: &mut [f32; 1920], volume: f32) -> Option<usize> {
+        self.inner.decode_and_add_opus_frame(float_buffer, volume * self.gain).await
+    }
+
+    fn is_seekable(&self) -> bool {
+        self.inner.is_seekable()
+    }
+
+    async fn seek(&mut self, position: Duration) -> bool {
+        self.inner.seek(position).await
+    }
+
+    fn refresh_duration(&self) -> Option<BoxFuture<'static, Option<Duration>>> {
+        self.inner.refresh_duration()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.inner.total_duration()
+    }
+
+    fn take_stream_title(&mut self) -> Option<String> {
+        self.inner.take_stream_title()
+    }
+}
+
+/// Creates a source which plays another at a fixed gain, such as a sound
+/// effect which is always played at half volume, without needing its
+/// [`TrackHandle`].
+///
+/// The gain is applied as the source is read, on top of any [`volume`] set
+/// on its track later. A gain of `1.0` leaves the audio unchanged, and PCM
+/// samples pushed beyond full scale by gains above it are clipped. Raw Opus
+/// frames read via [`read_opus_frame`] cannot be scaled, and are passed
+/// through as they are.
+///
+/// This can wrap any source, including those made via [`chain`], and be
+/// chained itself.
+///
+/// [`chain`]: fn.chain.html
+/// [`read_opus_frame`]: trait.AudioSource.html#tymethod.read_opus_frame
+/// [`TrackHandle`]: struct.TrackHandle.html
+/// [`volume`]: struct.Audio.html#structfield.volume
+pub fn with_volume(source: Box<dyn AudioSource>, gain: f32) -> Box<dyn AudioSource> {
+    Box::new(GainSource {
+        inner: source,
+        gain,
+    })
+}
+
+/// Plays frames read ahead from another source before reading on from it,
+/// as [`prebuffer`].
+struct PrebufferedSource {
+    inner: Box<dyn AudioSource>,
+    frames: VecDeque<Vec<i16>>,
+}
+
+#[async_trait]
+impl AudioSource for PrebufferedSource {
+    async fn is_stereo(&mut self) -> bool {
+        self.inner.is_stereo().await
+    }
+
+    async fn get_type(&self) -> AudioType {
+        self.inner.get_type().await
+    }
+
+    async fn read_pcm_frame(&mut self, buffer: &mut [i16]) -> Option<usize> {
+        match self.frames.pop_front() {
+            Some(frame) => {
+                let len = frame.len().min(buffer.len());
+                buffer[..len].copy_from_slice(&frame[..len]);
+
+                Some(len)
+            },
+            None => self.inner.read_pcm_frame(buffer).await,
+        }
+    }
+
+    async fn read_opus_frame(&mut self) -> Option<Vec<u8>> {
+        self.inner.read_opus_frame().await
+    }
+
+    async fn decode_and_add_opus_frame(&mut self, float_buffer: &mut [f32; 1920], volume: f32) -> Option<usize> {
+        self.inner.decode_and_add_opus_frame(float_buffer, volume).await
+    }
+
+    fn is_seekable(&self) -> bool {
+        self.inner.is_seekable()
+    }
+
+    async fn seek(&mut self, position: Duration) -> bool {
+        self.frames.clear();
+
+        self.inner.seek(position).await
+    }
+
+    fn refresh_duration(&self) -> Option<BoxFuture<'static, Option<Duration>>> {
+        self.inner.refresh_duration()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.inner.total_duration()
+    }
+
+    fn take_stream_title(&mut self) -> Option<String> {
+        self.inner.take_stream_title()
+    }
+}
+
+/// Reads the given length of audio ahead from a source, returning a source
+/// which plays it before reading on from the original.
+///
+/// Sources fetched over a network may stutter for their first moments while
+/// decoding fills its pipeline. Reading ahead before playback rather than
+/// during it gives the decoder a head start, at the cost of waiting that
+/// long to start. The [`ffmpeg`] and [`ytdl`] sources do this by default,
+/// as set via [`ProcessOptions::prebuffer`].
+///
+/// Only PCM sources are read ahead, and others are returned as they are. If
+/// the source ends before the length is read, what was read is still played.
+///
+/// [`ffmpeg`]: fn.ffmpeg.html
+/// [`ProcessOptions::prebuffer`]: struct.ProcessOptions.html#method.prebuffer
+/// [`ytdl`]: fn.ytdl.html
+pub async fn prebuffer(mut source: Box<dyn AudioSource>, ahead: Duration) -> Box<dyn AudioSource> {
+    let count = (ahead.as_secs_f64() * 50.0).ceil() as usize;
+
+    if count == 0 || !matches!(source.get_type().await, AudioType::Pcm) {
+        return source;
+    }
+
+    let samples = if source.is_stereo().await { 960 * 2 } else { 960 };
+    let mut frames = VecDeque::new();
+
+    for _ in 0..count {
+        let mut frame = vec![0i16; samples];
+
+        match source.read_pcm_frame(&mut frame).await {
+            Some(len) if len > 0 => {
+                frame.truncate(len);
+                frames.push_back(frame);
+            },
+            _ => break,
+        }
+    }
+
+    Box::new(PrebufferedSource {
+        inner: source,
+        frames,
+    })
+}
+
+/// Creates a streamed audio source with `youtube-dl` and `ffmpeg`.
+///
+/// This waits for the first audio to be produced, so that a URL which cannot
+/// be played fails here rather than during playback. Meanwhile, the length
+/// of the audio is asked of `youtube-dl`, for the source's
+/// [`AudioSource::total_duration`]; live streams have none.
+///
+/// # Errors
+///
+/// Returns [`VoiceError::ProgramNotFound`] if `youtube-dl` or `ffmpeg`
+/// cannot be found, and [`VoiceError::YouTubeDLRun`] if `youtube-dl` fails,
+/// such as for an invalid or unsupported URL. Returns [`VoiceError::Streams`]
+/// if `youtube-dl` succeeds, but `ffmpeg` produces no audio from its output.
+///
+/// [`AudioSource::total_duration`]: trait.AudioSource.html#method.total_duration
+/// [`VoiceError::ProgramNotFound`]: enum.VoiceError.html#variant.ProgramNotFound
+/// [`VoiceError::Streams`]: enum.VoiceError.html#variant.Streams
+/// [`VoiceError::YouTubeDLRun`]: enum.VoiceError.html#variant.YouTubeDLRun
+#[instrument]
+pub async fn ytdl(uri: &str) -> Result<Box<dyn AudioSource>> {
+    _ytdl(uri, &[], &ProcessOptions::default()).await
+}
+
+/// Creates a streamed audio source with `youtube-dl` and `ffmpeg`, passing
+/// extra arguments to `youtube-dl`, such as `--cookies` or a `-f` format
+/// selector.
+///
+/// This is the same as setting [`ProcessOptions::ytdl_args`] for
+/// [`ytdl_with_options`].
+///
+/// [`ProcessOptions::ytdl_args`]: struct.ProcessOptions.html#method.ytdl_args
+/// [`ytdl_with_options`]: fn.ytdl_with_options.html
+#[deprecated(since = "0.9.0", note = "please use `ytdl_with_options` with `ProcessOptions::ytdl_args` instead")]
+#[instrument]
+pub async fn ytdl_with_args(uri: &str, extra_args: &[&str]) -> Result<Box<dyn AudioSource>> {
+    let mut options = ProcessOptions::default();
+    options.ytdl_args(extra_args.iter().copied());
+
+    ytdl_with_options(uri, &options).await
+}
+
+/// Creates a streamed audio source with `youtube-dl` and `ffmpeg`, failing
+/// with [`VoiceError::Timeout`] if no audio has been produced within the given
+/// duration.
+///
+/// Both child processes are killed on timeout, so that a URL which
+/// `youtube-dl` hangs while resolving does not wedge playback. This is the
+/// same as setting [`ProcessOptions::timeout`] for [`ytdl_with_options`].
+///
+/// [`ProcessOptions::timeout`]: struct.ProcessOptions.html#method.timeout
+/// [`VoiceError::Timeout`]: enum.VoiceError.html#variant.Timeout
+/// [`ytdl_with_options`]: fn.ytdl_with_options.html
+#[deprecated(since = "0.9.0", note = "please use `ytdl_with_options` with `ProcessOptions::timeout` instead")]
+#[instrument]
+pub async fn ytdl_with_timeout(uri: &str, timeout: Duration) -> Result<Box<dyn AudioSource>> {
+    let mut options = ProcessOptions::default();
+    options.timeout(timeout);
+
+    ytdl_with_options(uri, &options).await
+}
+
+/// Creates a streamed audio source with `youtube-dl` and `ffmpeg` as with
+/// [`ytdl`], spawning both with the given environment, working directory,
+/// stderr handling, timeout and extra `youtube-dl` arguments, such as to
+/// fetch through a proxy.
+///
+/// [`ytdl`]: fn.ytdl.html
+#[instrument]
+pub async fn ytdl_with_options(uri: &str, options: &ProcessOptions) -> Result<Box<dyn AudioSource>> {
+    _ytdl(uri, &[], options).await
+}
+
+/// Which attempt of [`ytdl_with_retry`] created a source, such as for logging
+/// how reliably URLs resolve.
+///
+/// [`ytdl_with_retry`]: fn.ytdl_with_retry.html
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct YtdlAttempt {
+    /// The set of arguments which succeeded: 0 for the defaults, or the
+    /// position of a fallback from 1.
+    pub args: usize,
+    /// How many times those arguments were retried before succeeding.
+    pub retry: usize,
+    /// The total number of attempts made, including the one which succeeded.
+    pub attempts: usize,
+}
+
+/// Creates a streamed audio source with `youtube-dl` and `ffmpeg` as with
+/// [`ytdl`], retrying and then falling back to other arguments as set by the
+/// given [`YtdlRetry`] when it fails, and returning which attempt succeeded.
+///
+/// # Errors
+///
+/// Returns the error of the last attempt, as for [`ytdl`], if every attempt
+/// fails. [`VoiceError::ProgramNotFound`] is returned at once, without
+/// retrying.
+///
+/// [`VoiceError::ProgramNotFound`]: enum.VoiceError.html#variant.ProgramNotFound
+/// [`ytdl`]: fn.ytdl.html
+/// [`YtdlRetry`]: struct.YtdlRetry.html
+#[instrument]
+pub async fn ytdl_with_retry(uri: &str, retry: &YtdlRetry) -> Result<(Box<dyn AudioSource>, YtdlAttempt)> {
+    let options = ProcessOptions::default();
+    let fallbacks = retry.fallbacks
+        .iter()
+        .map(|args| args.iter().map(String::as_str).collect::<Vec<_>>())
+        .collect::<Vec<_>>();
+
+    with_retries(retry, |args| {
+        let extra_args: &[&str] = match args {
+            0 => &[],
+            n => &fallbacks[n - 1],
+        };
+
+        _ytdl(uri, extra_args, &options)
+    }).await
+}
+
+/// Makes the attempts set by a [`YtdlRetry`], passing each the index of its
+/// set of arguments, until one succeeds.
+async fn with_retries<T, F, Fut>(retry: &YtdlRetry, mut attempt: F) -> Result<(T, YtdlAttempt)>
+    where F: FnMut(usize) -> Fut, Fut: Future<Output = Result<T>> {
+    let mut attempts = 0;
+    let mut last_error = None;
+
+    for args in 0..=retry.fallbacks.len() {
+        for tried in 0..=retry.retries {
+            if attempts > 0 {
+                time::delay_for(retry.backoff).await;
+            }
+
+            attempts += 1;
+
+            match attempt(args).await {
+                Ok(value) => return Ok((value, YtdlAttempt {
+                    args,
+                    retry: tried,
+                    attempts,
+                })),
+                // Retrying cannot make a missing program appear.
+                Err(why @ Error::Voice(VoiceError::ProgramNotFound(_))) => return Err(why),
+                Err(why) => {
+                    warn!("[Voice] youtube-dl attempt {} failed: {:?}", attempts, why);
+
+                    last_error = Some(why);
+                },
+            }
+        }
+    }
+
+    Err(last_error.unwrap_or(Error::Voice(VoiceError::Streams)))
+}
+
+/// Creates a streamed audio source from YouTube search results with `youtube-dl`,`ffmpeg`, and `ytsearch`.
+/// Takes the first video listed from the YouTube search.
+#[instrument]
+pub async fn ytdl_search(name: &str) -> Result<Box<dyn AudioSource>> {
+    _ytdl(&format!("ytsearch1:{}", name), &[], &ProcessOptions::default()).await
+}
+
+/// The arguments passed to `youtube-dl` for a URI, with any extra arguments
+/// after the defaults so that they take precedence. The default format
+/// selector is left out if the extra arguments give their own.
+fn ytdl_args<'a>(uri: &'a str, extra_args: &[&'a str]) -> Vec<&'a str> {
+    let mut args = Vec::with_capacity(extra_args.len() + 9);
+
+    if !extra_args.iter().any(|&arg| arg == "-f" || arg == "--format") {
+        args.extend_from_slice(&["-f", "webm[abr>0]/bestaudio/best"]);
+    }
+
+    args.extend_from_slice(&["-R", "infinite", "--no-playlist", "--ignore-config"]);
+    args.extend_from_slice(extra_args);
+    args.extend_from_slice(&[uri, "-o", "-"]);
+
+    args
+}
+
+async fn _ytdl(
+    uri: &str,
+    extra_args: &[&str],
+    options: &ProcessOptions,
+) -> Result<Box<dyn AudioSource>> {
+    let timeout = options.timeout;
+    let extra_args = options.ytdl_args
+        .iter()
+        .map(String::as_str)
+        .chain(extra_args.iter().copied())
+        .collect::<Vec<_>>();
+    let ytdl_args = ytdl_args(uri, &extra_args);
+
+    let ffmpeg_args = [
+        "-f",
+        "s16le",
+        "-ac",
+        "2",
+        "-ar",
+        "48000",
+        "-acodec",
+        "pcm_s16le",
+        "-",
+    ];
+
+    let permit = subprocess_permit().await;
+    let program = ytdl_program();
+    let mut youtube_dl = std::process::Command::new(&program);
+    options.apply_std(&mut youtube_dl);
+
+    let mut youtube_dl = youtube_dl
+        .args(&ytdl_args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(spawn_error(program))?;
+
+    let program = ffmpeg_program();
+    let mut ffmpeg = Command::new(&program);
+    options.apply(&mut ffmpeg);
+
+    let stdout = youtube_dl.stdout.take().ok_or(SerenityError::Other("Failed to open youtube-dl stdout"));
+    let ffmpeg = stdout.and_then(|stdout| ffmpeg
+        .kill_on_drop(true)
+        .arg("-re")
+        .arg("-i")
+        .arg("-")
+        .args(&ffmpeg_args)
+        .stdin(stdout)
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(spawn_error(program)));
+
+    // The duration is found under the source's own permit, as waiting for
+    // another while holding it could wait forever.
+    let first_output = async {
+        match ffmpeg {
+            Ok(ffmpeg) => await_first_output(ChildContainer::new(ffmpeg, permit), timeout).await,
+            Err(why) => Err(why),
+        }
+    };
+    let duration = async {
+        let found = match timeout {
+            Some(limit) => time::timeout(limit, ytdl_duration(uri, options))
+                .await
+                .unwrap_or_else(|_| Err(Error::Voice(VoiceError::Timeout))),
+            None => ytdl_duration(uri, options).await,
+        };
+
+        // The audio can still be played without knowing its length.
+        found.unwrap_or_else(|why| {
+            warn!("[Voice] Failed to find the duration of {}: {:?}", uri, why);
+
+            None
+        })
+    };
+    let (first_output, duration) = tokio::join!(first_output, duration);
+
+    match first_output {
+        Ok(reader) => {
+            let source = Box::new(InputSource {
+                stereo: true,
+                reader,
+                kind: AudioType::Pcm,
+                decoder: None,
+                duration,
+            });
+            let ahead = options.prebuffer.unwrap_or(DEFAULT_NETWORK_PREBUFFER);
+
+            // youtube-dl exits once ffmpeg stops reading from it.
+            reap(youtube_dl);
+
+            Ok(prebuffer(source, ahead).await)
+        },
+        // Nothing came out of ffmpeg, so find out whether youtube-dl failed.
+        Err(Error::Voice(VoiceError::Streams)) => {
+            let output = tokio::task::spawn_blocking(move || youtube_dl.wait_with_output())
+                .await
+                .map_err(|_| SerenityError::Other("Failed to wait for youtube-dl"))??;
+
+            if output.status.success() {
+                Err(Error::Voice(VoiceError::Streams))
+            } else {
+                Err(Error::Voice(VoiceError::YouTubeDLRun(output)))
+            }
+        },
+        Err(why) => {
+            if let Err(e) = youtube_dl.kill() {
+                debug!("[Voice] Error killing youtube-dl: {:?}", e);
+            }
+
+            reap(youtube_dl);
+
+            Err(why)
+        },
+    }
+}
+
+/// Reports the titles found in a stream's ICY metadata on top of another
+/// source, as [`http`].
+struct IcySource {
+    inner: Box<dyn AudioSource>,
+    title: Arc<StdMutex<Option<String>>>,
+}
+
+#[async_trait]
+impl AudioSource for IcySource {
+    async fn is_stereo(&mut self) -> bool {
+        self.inner.is_stereo().await
+    }
+
+    async fn get_type(&self) -> AudioType {
+        self.inner.get_type().await
+    }
+
+    async fn read_pcm_frame(&mut self, buffer: &mut [i16]) -> Option<usize> {
+        self.inner.read_pcm_frame(buffer).await
+    }
+
+    async fn read_opus_frame(&mut self) -> Option<Vec<u8>> {
+        self.inner.read_opus_frame().await
+    }
+
+    async fn decode_and_add_opus_frame(&mut self, float_buffer: &mut [f32; 1920], volume: f32) -> Option<usize> {
+        self.inner.decode_and_add_opus_frame(float_buffer, volume).await
+    }
+
+    fn take_stream_title(&mut self) -> Option<String> {
+        match self.title.lock() {
+            Ok(mut title) => title.take(),
+            Err(poisoned) => poisoned.into_inner().take(),
+        }
+    }
+}
+
+/// Creates a streamed audio source from an HTTP URL with `ffmpeg`, such as
+/// an internet radio station.
+///
+/// The stream is fetched here rather than by `ffmpeg`, asking the server
+/// for ICY (Shoutcast) metadata. If the server sends it, the metadata is
+/// removed from the audio before decoding, and each new stream title is
+/// emitted as a [`HandlerEvent::StreamMetadataUpdated`] while the source
+/// plays. Streams without metadata play as they are.
+///
+/// The source cannot be seeked, and has no [`total_duration`].
+///
+/// # Errors
+///
+/// Returns an [`Error::Http`] if the request fails or the server responds
+/// with an error status, and [`VoiceError::ProgramNotFound`] if `ffmpeg`
+/// cannot be found.
+///
+/// [`Error::Http`]: ../enum.Error.html#variant.Http
+/// [`HandlerEvent::StreamMetadataUpdated`]: enum.HandlerEvent.html#variant.StreamMetadataUpdated
+/// [`total_duration`]: trait.AudioSource.html#method.total_duration
+/// [`VoiceError::ProgramNotFound`]: enum.VoiceError.html#variant.ProgramNotFound
+#[instrument]
+pub async fn http(url: &str) -> Result<Box<dyn AudioSource>> {
+    let mut response = reqwest::Client::new()
+        .get(url)
+        .header("Icy-MetaData", "1")
+        .send()
+        .await?
+        .error_for_status()?;
+
+    let interval = response.headers()
+        .get("icy-metaint")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse::<usize>().ok())
+        .filter(|&interval| interval > 0);
+
+    let permit = subprocess_permit().await;
+    let program = ffmpeg_program();
+    let mut child = Command::new(&program)
+        .kill_on_drop(true)
+        .arg("-i")
+        .arg("-")
+        .args(&["-f", "s16le", "-ac", "2", "-ar", "48000", "-acodec", "pcm_s16le", "-"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(spawn_error(program))?;
+
+    let mut stdin = child.stdin.take().ok_or(SerenityError::Other("Failed to open ffmpeg stdin"))?;
+    let title = Arc::new(StdMutex::new(None));
+    let found = Arc::clone(&title);
+
+    // Writing fails once the source, and so ffmpeg, is dropped.
+    tokio::spawn(async move {
+        let mut parser = interval.map(IcyParser::new);
+        let mut audio = Vec::new();
+
+        while let Ok(Some(chunk)) = response.chunk().await {
+            let data = match parser {
+                Some(ref mut parser) => {
+                    audio.clear();
+
+                    if let Some(new) = parser.feed(&chunk, &mut audio) {
+                        debug!("[Voice] Stream title changed to {:?}", new);
+
+                        match found.lock() {
+                            Ok(mut title) => *title = Some(new),
+                            Err(poisoned) => *poisoned.into_inner() = Some(new),
+                        }
+                    }
+
+                    &audio[..]
+                },
+                None => &chunk[..],
+            };
+
+            if stdin.write_all(data).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    let source = Box::new(InputSource {
+        stereo: true,
+        reader: ChildContainer::new(child, permit),
+        kind: AudioType::Pcm,
+        decoder: None,
+        duration: None,
+    });
+
+    Ok(Box::new(IcySource {
+        inner: prebuffer(source, DEFAULT_NETWORK_PREBUFFER).await,
+        title,
+    }))
+}
+
+/// Finds the title of a URL with `youtube-dl`, without downloading its audio,
+/// under a subprocess permit of its own.
+pub(crate) async fn ytdl_title(uri: &str) -> Result<String> {
+    let args = ["--get-title", "--no-playlist", "--ignore-config"];
+
+    let _permit = subprocess_permit().await;
+    let out = Command::new(ytdl_program())
+        .kill_on_drop(true)
+        .args(args.iter())
+        .arg(uri)
+        .stdin(Stdio::null())
+        .output().await?;
+
+    if !out.status.success() {
+        return Err(Error::Voice(VoiceError::YouTubeDLRun(out)));
+    }
+
+    Ok(String::from_utf8_lossy(&out.stdout).trim().to_string())
+}
+
+/// Finds the duration of a URL with `youtube-dl`, without downloading its
+/// audio, if it is known. Live streams have none.
+///
+/// This takes no subprocess permit, as it only runs alongside the source the
+/// duration is for, under that source's permit.
+async fn ytdl_duration(uri: &str, options: &ProcessOptions) -> Result<Option<Duration>> {
+    let args = ["--get-duration", "--no-playlist", "--ignore-config"];
+
+    let program = ytdl_program();
+    let mut command = Command::new(&program);
+    options.apply(&mut command);
+
+    let out = command
+        .kill_on_drop(true)
+        .args(args.iter())
+        .arg(uri)
+        .stdin(Stdio::null())
+        .output().await
+        .map_err(spawn_error(program))?;
+
+    if !out.status.success() {
+        return Err(Error::Voice(VoiceError::YouTubeDLRun(out)));
+    }
+
+    Ok(parse_ytdl_duration(String::from_utf8_lossy(&out.stdout).trim()))
+}
+
+/// Trims the duration of an input to that which `ffmpeg` outputs, given the
+/// output `-ss`, `-t` and `-to` options among its arguments. As with
+/// `ffmpeg`, the last of each is used, and `-t` takes precedence over `-to`.
+fn trimmed_duration<S: AsRef<str>>(duration: Duration, args: &[S]) -> Duration {
+    let (mut start, mut length, mut end) = (None, None, None);
+
+    for pair in args.windows(2) {
+        let value = parse_ffmpeg_time(pair[1].as_ref());
+
+        match pair[0].as_ref() {
+            "-ss" => start = value.or(start),
+            "-t" => length = value.or(length),
+            "-to" => end = value.or(end),
+            _ => {},
+        }
+    }
+
+    let start = start.unwrap_or_default();
+    let end = match (length, end) {
+        (Some(length), _) => start + length,
+        (None, Some(end)) => end,
+        (None, None) => duration,
+    };
+
+    end.min(duration).checked_sub(start).unwrap_or_default()
+}
+
+/// Parses a time as given to `ffmpeg`, either as seconds such as `90.5` or as
+/// `[HH:]MM:SS[.m...]`. Negative times and unit suffixes are not supported.
+fn parse_ffmpeg_time(text: &str) -> Option<Duration> {
+    let mut secs = 0f64;
+    let mut parts = 0;
+
+    for part in text.split(':') {
+        parts += 1;
+
+        if parts > 3 || part.is_empty() || !part.chars().all(|c| c.is_ascii_digit() || c == '.') {
+            return None;
+        }
+
+        secs = secs * 60.0 + part.parse::<f64>().ok()?;
+    }
+
+    if secs < u64::MAX as f64 {
+        Some(Duration::from_secs_f64(secs))
+    } else {
+        None
+    }
+}
+
+/// Parses a duration as printed by `youtube-dl`, such as `1:02:03` or `45`.
+fn parse_ytdl_duration(text: &str) -> Option<Duration> {
+    let mut secs = 0u64;
+
+    for part in text.split(':') {
+        secs = secs.checked_mul(60)?.checked_add(part.parse().ok()?)?;
+    }
+
+    Some(Duration::from_secs(secs))
+}
+
+/// Waits for a child process to produce its first output, failing with
+/// [`VoiceError::Timeout`] if it does not do so within any `limit`, and with
+/// [`VoiceError::Streams`] if it exits without any.
+///
+/// The returned reader yields the already-read output before the remainder of
+/// the process' output. On failure, the process is killed.
+///
+/// [`VoiceError::Streams`]: enum.VoiceError.html#variant.Streams
+/// [`VoiceError::Timeout`]: enum.VoiceError.html#variant.Timeout
+async fn await_first_output(
+    mut reader: ChildContainer,
+    limit: Option<Duration>,
+) -> Result<impl AsyncRead + Unpin + Send + Sync> {
+    let mut first = vec![0u8; 4096];
+
+    let len = match limit {
+        Some(limit) => match time::timeout(limit, reader.read(&mut first)).await {
+            Ok(len) => len?,
+            Err(_) => return Err(Error::Voice(VoiceError::Timeout)),
+        },
+        None => reader.read(&mut first).await?,
+    };
+
+    if len == 0 {
+        return Err(Error::Voice(VoiceError::Streams));
+    }
+
+    first.truncate(len);
+
+    Ok(Cursor::new(first).chain(reader))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_subprocess_limit() {
+        // The limit is tested locally, as the global one is shared by other
+        // tests running at the same time.
+        let limit = subprocess_limit(1);
+        let held = permit_of(limit.clone()).await;
+        assert!(held.is_some());
+
+        // A second source waits for the running one to be dropped, rather
+        // than spawning its process or failing.
+        let mut waiting = tokio::spawn(permit_of(limit.clone()));
+        assert!(time::timeout(Duration::from_millis(200), &mut waiting).await.is_err());
+
+        drop(held);
+        let permit = time::timeout(Duration::from_secs(5), waiting).await.unwrap().unwrap();
+        assert!(permit.is_some());
+
+        // Without a limit, no permit is needed.
+        assert!(subprocess_limit(0).is_none());
+        assert!(permit_of(None).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_async_reader_partial_frame() {
+        // Two whole samples, then half of a third.
+        let reader = Cursor::new(vec![1u8, 0, 2, 0, 3]);
+        let mut source = async_reader(reader);
+        let mut buffer = [0i16; 4];
+
+        assert_eq!(source.read_pcm_frame(&mut buffer).await, Some(2));
+        assert_eq!(&buffer[..2], &[1, 2]);
+        assert_eq!(source.read_pcm_frame(&mut buffer).await, Some(0));
+    }
+
+    #[tokio::test]
+    async fn test_chain_joins_frames() {
+        let first = pcm(true, Cursor::new(vec![1u8, 0, 2, 0, 3, 0]));
+        let second = pcm(true, Cursor::new(vec![4u8, 0, 5, 0]));
+        let mut source = chain(vec![first, second]);
+        let mut buffer = [0i16; 4];
+
+        assert_eq!(source.read_pcm_frame(&mut buffer).await, Some(4));
+        assert_eq!(buffer, [1, 2, 3, 4]);
+        assert_eq!(source.read_pcm_frame(&mut buffer).await, Some(1));
+        assert_eq!(buffer[0], 5);
+        assert_eq!(source.read_pcm_frame(&mut buffer).await, Some(0));
+    }
+
+    #[tokio::test]
+    async fn test_chain_frame_boundary() {
+        use audiopus::{Application, coder::Encoder};
+
+        let encoder = Encoder::new(audio::SAMPLE_RATE, Channels::Stereo, Application::Audio).unwrap();
+        let mut frame = [0u8; 4000];
+        let len = encoder.encode_float(&[0.5f32; 1920], &mut frame).unwrap();
+
+        // Each source ends exactly on a frame boundary.
+        let stereo = (0..1920).flat_map(|_| vec![1u8, 0]).collect::<Vec<_>>();
+        let mono = (0..960).flat_map(|_| vec![2u8, 0]).collect::<Vec<_>>();
+        let passthrough = opus_frames(vec![frame[..len].to_vec()]);
+        let mut source = chain(vec![pcm(true, Cursor::new(stereo)), pcm(false, Cursor::new(mono)), passthrough]);
+        let mut buffer = [0i16; 1920];
+
+        assert_eq!(source.read_pcm_frame(&mut buffer).await, Some(1920));
+        assert!(buffer.iter().all(|&sample| sample == 1));
+        assert_eq!(source.read_pcm_frame(&mut buffer).await, Some(1920));
+        assert!(buffer.iter().all(|&sample| sample == 2));
+
+        // The frame is laid out as the mono source it starts in, which has
+        // ended, so the Opus frame is downmixed.
+        assert!(!source.is_stereo().await);
+        assert_eq!(source.read_pcm_frame(&mut buffer[..960]).await, Some(960));
+        assert!(buffer[..960].iter().any(|&sample| sample != 0));
+        assert_eq!(source.read_pcm_frame(&mut buffer).await, Some(0));
+    }
+
+    #[tokio::test]
+    async fn test_chain_opus_then_pcm() {
+        use audiopus::coder::Encoder;
+
+        let encoder = Encoder::new(audio::SAMPLE_RATE, Channels::Stereo, Application::Audio).unwrap();
+        let mut frame = [0u8; 4000];
+        let len = encoder.encode_float(&[0.5f32; 1920], &mut frame).unwrap();
+
+        // Opus sources are read as frames prefixed by their length.
+        let mut data = (len as i16).to_le_bytes().to_vec();
+        data.extend_from_slice(&frame[..len]);
+        let pcm_data = (0..1920).flat_map(|_| vec![0u8, 64]).collect::<Vec<_>>();
+        let mut source = chain(vec![opus(true, Cursor::new(data)), pcm(true, Cursor::new(pcm_data))]);
+        let mut decoded = [0f32; 1920];
+
+        assert!(matches!(source.get_type().await, AudioType::Opus));
+        assert_eq!(source.decode_and_add_opus_frame(&mut decoded, 1.0).await, Some(960));
+
+        // The PCM source is converted, though the chain is still read as the
+        // Opus source it started in.
+        let mut decoded = [0f32; 1920];
+        assert_eq!(source.decode_and_add_opus_frame(&mut decoded, 1.0).await, Some(960));
+        assert!(decoded.iter().all(|&value| (value - 0.5).abs() < 0.01));
+        assert_eq!(source.decode_and_add_opus_frame(&mut decoded, 1.0).await, Some(0));
+    }
+
+    #[tokio::test]
+    async fn test_chain_passthrough_then_pcm() {
+        let pcm_data = (0..960).flat_map(|_| vec![0u8, 64]).collect::<Vec<_>>();
+        let mut source = chain(vec![opus_frames(vec![vec![1u8, 2]]), pcm(true, Cursor::new(pcm_data))]);
+
+        assert_eq!(source.read_opus_frame().await, Some(vec![1, 2]));
+
+        // The partial PCM frame is encoded, rather than read as Opus.
+        let frame = source.read_opus_frame().await.unwrap();
+        let mut decoder = SendDecoder(OpusDecoder::new(audio::SAMPLE_RATE, Channels::Stereo).unwrap());
+        let mut decoded = [0f32; 1920];
+        assert_eq!(decoder.decode_float(&frame, &mut decoded, false).ok(), Some(960));
+        assert_eq!(source.read_opus_frame().await, Some(Vec::new()));
+    }
+
+    #[tokio::test]
+    async fn test_with_volume() {
+        let quiet = with_volume(pcm(true, Cursor::new(vec![100u8, 0, 156, 255])), 0.5);
+        let loud = with_volume(pcm(true, Cursor::new(vec![0u8, 64])), 4.0);
+        let mut source = chain(vec![quiet, loud]);
+        let mut buffer = [0i16; 4];
+
+        assert_eq!(source.read_pcm_frame(&mut buffer).await, Some(3));
+        assert_eq!(&buffer[..3], &[50, -50, i16::MAX]);
+    }
+
+    #[tokio::test]
+    async fn test_opus_frames() {
+        let mut source = prebuffer(opus_frames(vec![vec![1u8, 2], vec![3]]), Duration::from_millis(100)).await;
+
+        // Frames are passed through untouched, and never read ahead.
+        assert!(matches!(source.get_type().await, AudioType::Passthrough));
+        assert_eq!(source.read_pcm_frame(&mut [0i16; 4]).await, None);
+        assert_eq!(source.read_opus_frame().await, Some(vec![1, 2]));
+        assert_eq!(source.read_opus_frame().await, Some(vec![3]));
+        assert_eq!(source.read_opus_frame().await, Some(Vec::new()));
+    }
+
+    #[tokio::test]
+    async fn test_prebuffer() {
+        let samples = (0..2400).map(|i| i as i16).collect::<Vec<_>>();
+        let bytes = samples.iter().flat_map(|sample| sample.to_le_bytes().to_vec()).collect::<Vec<u8>>();
+        let mut source = prebuffer(pcm(true, Cursor::new(bytes)), Duration::from_millis(100)).await;
+        let mut buffer = [0i16; 1920];
+
+        // The whole source fit in the read-ahead, ending with a partial frame.
+        assert_eq!(source.read_pcm_frame(&mut buffer).await, Some(1920));
+        assert_eq!(buffer[1919], 1919);
+        assert_eq!(source.read_pcm_frame(&mut buffer).await, Some(480));
+        assert_eq!(&buffer[..2], &[1920, 1921]);
+    }
+
+    #[tokio::test]
+    async fn test_construction_errors() {
+        let missing = spawn_error("no-such-ffmpeg".into())(IoError::from(IoErrorKind::NotFound));
+        assert!(matches!(missing, Error::Voice(VoiceError::ProgramNotFound(ref program)) if program == "no-such-ffmpeg"));
+
+        let denied = spawn_error("ffmpeg".into())(IoError::from(IoErrorKind::PermissionDenied));
+        assert!(matches!(denied, Error::Io(_)));
+
+        let path = "./no/such/track.mp3";
+        let source = ffmpeg(path).await;
+        assert!(matches!(source, Err(Error::Voice(VoiceError::SourceNotFound(ref missing))) if missing == path));
+    }
+
+    #[tokio::test]
+    async fn test_check_local_file() {
+        assert!(check_local_file("Cargo.toml".as_ref()).await.unwrap());
+
+        for path in &["https://example.com/track.mp3", "pipe:0", "pipe:", "-"] {
+            assert!(!check_local_file(path.as_ref()).await.unwrap(), "{} is not local", path);
+        }
+
+        let missing = check_local_file("pipe0".as_ref()).await;
+        assert!(matches!(missing, Err(Error::Voice(VoiceError::SourceNotFound(_)))));
+    }
+
+    #[test]
+    fn test_parse_ytdl_duration() {
+        assert_eq!(parse_ytdl_duration("45"), Some(Duration::from_secs(45)));
+        assert_eq!(parse_ytdl_duration("3:07"), Some(Duration::from_secs(187)));
+        assert_eq!(parse_ytdl_duration("1:02:03"), Some(Duration::from_secs(3723)));
+        assert_eq!(parse_ytdl_duration(""), None);
+        assert_eq!(parse_ytdl_duration("live"), None);
+    }
+
+    #[test]
+    fn test_trimmed_duration() {
+        let full = Duration::from_secs(300);
+        let trimmed = |args: &[&str]| trimmed_duration(full, args);
+
+        assert_eq!(trimmed(&["-f", "s16le", "-"]), full);
+        assert_eq!(trimmed(&["-ss", "60"]), Duration::from_secs(240));
+        assert_eq!(trimmed(&["-ss", "1:00", "-t", "30.5"]), Duration::from_millis(30_500));
+        assert_eq!(trimmed(&["-ss", "60", "-to", "00:02:00"]), Duration::from_secs(60));
+        assert_eq!(trimmed(&["-t", "20", "-to", "100"]), Duration::from_secs(20));
+        assert_eq!(trimmed(&["-t", "600"]), full);
+        assert_eq!(trimmed(&["-ss", "400"]), Duration::default());
+        assert_eq!(trimmed(&["-ss", "later"]), full);
+
+        assert_eq!(parse_ffmpeg_time("1:02:03.5"), Some(Duration::from_millis(3_723_500)));
+        assert_eq!(parse_ffmpeg_time("-5"), None);
+        assert_eq!(parse_ffmpeg_time("1::2"), None);
+    }
+
+    #[test]
+    fn test_ytdl_args() {
+        let uri = "https://example.com/watch";
+        let defaults = ["-R", "infinite", "--no-playlist", "--ignore-config"];
+        let args = |before: &[&'static str], extra: &[&'static str]| {
+            let mut args = before.to_vec();
+            args.extend_from_slice(&defaults);
+            args.extend_from_slice(extra);
+            args.extend_from_slice(&[uri, "-o", "-"]);
+
+            args
+        };
+        let format = ["-f", "webm[abr>0]/bestaudio/best"];
+
+        assert_eq!(ytdl_args(uri, &[]), args(&format, &[]));
+        assert_eq!(ytdl_args(uri, &["--cookies", "cookies.txt"]), args(&format, &["--cookies", "cookies.txt"]));
+
+        // A format given by the caller, or by a fallback, replaces the default.
+        assert_eq!(ytdl_args(uri, &["--format", "worstaudio"]), args(&[], &["--format", "worstaudio"]));
+
+        let mut retry = YtdlRetry::default();
+        retry.fallback(vec!["-f", "bestaudio"]);
+        let fallback = retry.fallbacks[0].iter().map(String::as_str).collect::<Vec<_>>();
+        assert_eq!(ytdl_args(uri, &fallback), args(&[], &["-f", "bestaudio"]));
+    }
+
+    #[tokio::test]
+    async fn test_with_retries() {
+        let mut retry = YtdlRetry::default();
+        retry.retries(1).fallback(vec!["-a"]).fallback(vec!["-b"]);
+
+        // Both tries with the defaults fail, then the first fallback fails
+        // once before succeeding.
+        let mut calls = Vec::new();
+        let result = with_retries(&retry, |args| {
+            calls.push(args);
+            let failed = calls.len() <= 3;
+
+            async move {
+                if failed {
+                    Err(Error::Voice(VoiceError::Streams))
+                } else {
+                    Ok(args)
+                }
+            }
+        }).await.unwrap();
+
+        assert_eq!(result, (1, YtdlAttempt { args: 1, retry: 1, attempts: 4 }));
+        assert_eq!(calls, vec![0, 0, 1, 1]);
+
+        let mut calls = 0;
+        let result = with_retries(&retry, |_| {
+            calls += 1;
+
+            async { Err::<(), _>(Error::Voice(VoiceError::Timeout)) }
+        }).await;
+
+        assert!(matches!(result, Err(Error::Voice(VoiceError::Timeout))));
+        assert_eq!(calls, 6);
+
+        let mut calls = 0;
+        let result = with_retries(&retry, |_| {
+            calls += 1;
+
+            async { Err::<(), _>(Error::Voice(VoiceError::ProgramNotFound("youtube-dl".into()))) }
+        }).await;
+
+        assert!(matches!(result, Err(Error::Voice(VoiceError::ProgramNotFound(_)))));
+        assert_eq!(calls, 1);
+    }
 }
@@ -1,62 +1,362 @@
+use crate::constants::voice_close_codes;
+use crate::gateway::GatewayError;
+use crate::internal::prelude::*;
 use crate::internal::Timer;
 use crate::model::id::GuildId;
-use futures::channel::mpsc::UnboundedReceiver as Receiver;
+use futures::{channel::mpsc::UnboundedReceiver as Receiver, Future};
+use std::{collections::HashMap, sync::Arc, time::{Duration, Instant, SystemTime}};
 use super::{
-    connection::Connection,
+    audio::{EndReason, DEFAULT_VOLUME_RAMP},
+    connection::{Connection, DEFAULT_CLOSE_CODE, DEFAULT_RTP_PAYLOAD_TYPE},
+    connection_info::ConnectionInfo,
+    crypto::{EncryptionBackendFactory, EncryptionMode},
+    receive::ReceiveState,
+    state::SharedState,
+    stats::PacketRate,
+    Bitrate,
+    ConnectionState,
+    HandlerEvent,
+    LimiterConfig,
+    LockedAudio,
     Status,
-    audio,
+    OpusConfig,
+    PacingMode,
+    VoiceError,
 };
+use tokio::time::timeout;
 use tracing::{info, error, warn, instrument};
 
-#[instrument(skip(rx))]
-pub(crate) fn start(guild_id: GuildId, mut rx: Receiver<Status>) {
+/// How many heartbeats in a row may go unacknowledged by default before the
+/// connection is resumed.
+const DEFAULT_HEARTBEAT_TIMEOUT: u32 = 3;
+
+/// How long the first handshake with a voice server may take by default.
+pub(crate) const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[instrument(skip(rx, shared))]
+pub(crate) fn start(guild_id: GuildId, mut rx: Receiver<Status>, shared: Arc<SharedState>) {
+    let generation = shared.start_task();
+
     tokio::spawn(async move {
         info!("[Voice] Starts running for guild id: {}", guild_id);
-        runner(&mut rx).await;
+        runner(&mut rx, &shared, generation).await;
+
+        // A task which was replaced leaves the state to its successor.
+        if shared.is_current_task(generation) {
+            shared.set_connection_state(ConnectionState::Disconnected);
+        }
+
         info!("[Voice] Ended running for guild id: {}", guild_id);
     });
 }
 
-#[instrument(skip(rx))]
-async fn runner(rx: &mut Receiver<Status>) {
+/// How far the send loop may fall behind its schedule before the frames it
+/// missed are skipped, rather than sent in a burst to catch up.
+const MAX_DRIFT: Duration = Duration::from_millis(100);
+
+#[instrument(skip(rx, shared))]
+async fn runner(rx: &mut Receiver<Status>, shared: &Arc<SharedState>, generation: u64) {
     let mut senders = Vec::new();
-    let mut receiver = None;
+    let mut receive = ReceiveState::default();
     let mut connection = None;
     let mut timer = Timer::new(20);
-    let mut bitrate = audio::DEFAULT_BITRATE;
+    let mut settings = Settings::default();
     let mut mute = false;
+    let mut tee = None;
+    let mut observer = None;
+    let mut packet_rate = PacketRate::new(Instant::now(), shared.network_stats().packets_sent);
 
     'runner: loop {
+        // Only one task may send for a handler, or listeners would hear its
+        // audio twice, so one which has been replaced stops at once.
+        if !shared.is_current_task(generation) {
+            warn!("[Voice] Task replaced by a newer one, stopping.");
+
+            break 'runner;
+        }
+
         loop {
-            match rx.try_next() {
-                Ok(Some(Status::Connect(info))) => {
-                    connection = match Connection::new(info).await {
-                        Ok(connection) => Some(connection),
-                        Err(why) => {
-                            warn!("[Voice] Error connecting: {:?}", why);
+            let message = rx.try_next();
 
-                            None
-                        },
-                    };
+            if let Ok(Some(_)) = message {
+                shared.message_received();
+            }
+
+            match message {
+                Ok(Some(Status::Connect(info))) => {
+                    reconnect(&mut connection, connect(info, &settings, shared), &settings, shared).await;
+                },
+                Ok(Some(Status::SetConnectTimeout(t))) => {
+                    settings.connect_timeout = t;
                 },
                 Ok(Some(Status::Disconnect)) => {
-                    connection = None;
+                    disconnect(&mut connection, &settings, shared).await;
                 },
                 Ok(Some(Status::SetReceiver(r))) => {
-                    receiver = r;
+                    receive.set_receiver(r);
+                },
+                Ok(Some(Status::AddReceiver(r))) => {
+                    receive.add_receiver(r);
+                },
+                Ok(Some(Status::RemoveReceiver(r))) => {
+                    receive.remove_receiver(&r);
+                },
+                Ok(Some(Status::SetAudioStream(s))) => {
+                    receive.set_stream(s);
+                },
+                Ok(Some(Status::AddUserStream(u, s))) => {
+                    receive.add_user_stream(u, s);
+                },
+                Ok(Some(Status::SetReceiveGain(u, g))) => {
+                    receive.set_gain(u, g);
+                },
+                Ok(Some(Status::SetReceiveAgc(a))) => {
+                    receive.set_agc(a);
+                },
+                Ok(Some(Status::SetReceiveBufferLimit(l))) => {
+                    let dropped = receive.set_buffer_limit(l) as u64;
+
+                    shared.update_stats(|stats| stats.receive_frames_dropped += dropped);
+                },
+                Ok(Some(Status::SetReceiveFormat(r, c))) => {
+                    receive.set_format(r, c);
+                },
+                Ok(Some(Status::SetReceiveFilter(f))) => {
+                    receive.filter = f;
                 },
                 Ok(Some(Status::SetSender(s))) => {
-                    senders.clear();
+                    end_all(&mut senders, EndReason::Stopped, shared).await;
 
                     if let Some(aud) = s {
                         senders.push(aud);
                     }
                 },
+                Ok(Some(Status::FadeToSender(s, duration))) => {
+                    fade_to(&mut senders, s, duration, shared).await;
+                },
                 Ok(Some(Status::AddSender(s))) => {
                     senders.push(s);
                 },
+                Ok(Some(Status::StopAndClear)) => {
+                    // Empty the queue first, so that it cannot advance once
+                    // its current track is ended.
+                    let cleared = shared.queue.clear();
+                    end_all(&mut senders, EndReason::Cleared, shared).await;
+
+                    for track in cleared {
+                        shared.end_track(&mut *track.audio().lock().await, EndReason::Cleared);
+                    }
+                },
+                Ok(Some(Status::ForceHeartbeat)) => {
+                    if let Some(conn) = connection.as_mut() {
+                        if let Err(why) = conn.send_heartbeat().await {
+                            warn!("[Voice] Error sending forced heartbeat: {:?}", why);
+                        }
+                    }
+                },
+                Ok(Some(Status::SendVoiceOp(op, data))) => {
+                    match connection.as_mut() {
+                        Some(conn) => if let Err(why) = conn.send_voice_op(op, data).await {
+                            warn!("[Voice] Error sending {:?} payload: {:?}", op, why);
+                        },
+                        None => warn!("[Voice] Dropped {:?} payload sent without a connection.", op),
+                    }
+                },
+                Ok(Some(Status::Reconnect(done))) => {
+                    let result = match connection.take() {
+                        Some(conn) => {
+                            let info = conn.connection_info().clone();
+                            drop(conn);
+                            shared.set_connection_state(ConnectionState::Connecting);
+
+                            match connect(info, &settings, shared).await {
+                                Ok(conn) => {
+                                    connection = Some(conn);
+                                    shared.set_connection_state(ConnectionState::Connected);
+                                    shared.emit(HandlerEvent::Reconnected);
+
+                                    Ok(())
+                                },
+                                Err(why) => {
+                                    warn!("[Voice] Error reconnecting: {:?}", why);
+                                    shared.set_connection_state(ConnectionState::Disconnected);
+
+                                    Err(why)
+                                },
+                            }
+                        },
+                        None => Err(Error::Voice(VoiceError::NotConnected)),
+                    };
+
+                    let _ = done.send(result);
+                },
                 Ok(Some(Status::SetBitrate(b))) => {
-                    bitrate = b;
+                    settings.opus.bitrate = b;
+
+                    if let Some(conn) = connection.as_mut() {
+                        if let Err(why) = conn.set_bitrate(b) {
+                            warn!("[Voice] Error setting bitrate: {:?}", why);
+                        }
+                    }
+                },
+                Ok(Some(Status::SetOpusConfig(c))) => {
+                    settings.opus = c.clone();
+
+                    if let Some(conn) = connection.as_mut() {
+                        if let Err(why) = conn.set_opus_config(c) {
+                            warn!("[Voice] Error setting Opus config: {:?}", why);
+                        }
+                    }
+                },
+                Ok(Some(Status::ReconfigureEncoder(c))) => {
+                    reconfigure_encoder(connection.as_mut(), c, &mut settings, shared);
+                },
+                Ok(Some(Status::SetOpusComplexity(c))) => {
+                    settings.opus.complexity = c;
+
+                    if let Some(conn) = connection.as_mut() {
+                        if let Err(why) = conn.set_complexity(c) {
+                            warn!("[Voice] Error setting complexity: {:?}", why);
+                        }
+                    }
+                },
+                Ok(Some(Status::SetDtx(d))) => {
+                    settings.opus.dtx = d;
+
+                    if let Some(conn) = connection.as_mut() {
+                        if let Err(why) = conn.set_dtx(d) {
+                            warn!("[Voice] Error setting DTX: {:?}", why);
+                        }
+                    }
+                },
+                Ok(Some(Status::SetAdaptiveComplexity(a))) => {
+                    settings.adaptive_complexity = a;
+
+                    if let Some(conn) = connection.as_mut() {
+                        if let Err(why) = conn.set_adaptive_complexity(a) {
+                            warn!("[Voice] Error setting adaptive complexity: {:?}", why);
+                        }
+                    }
+                },
+                Ok(Some(Status::SetAdaptiveBitrate(steps))) => {
+                    settings.adaptive_bitrate = steps.clone();
+
+                    if let Some(conn) = connection.as_mut() {
+                        if let Err(why) = conn.set_adaptive_bitrate(steps) {
+                            warn!("[Voice] Error setting adaptive bitrate: {:?}", why);
+                        }
+                    }
+                },
+                Ok(Some(Status::SetAdaptiveChannels(a))) => {
+                    settings.adaptive_channels = a;
+
+                    if let Some(conn) = connection.as_mut() {
+                        conn.set_adaptive_channels(a);
+                    }
+                },
+                Ok(Some(Status::SetHeartbeatTimeout(t))) => {
+                    settings.heartbeat_timeout = t;
+
+                    if let Some(conn) = connection.as_mut() {
+                        conn.set_heartbeat_timeout(t);
+                    }
+                },
+                Ok(Some(Status::SetLimiter(l))) => {
+                    if let Some(conn) = connection.as_mut() {
+                        conn.set_limiter(l.as_ref());
+                    }
+
+                    settings.limiter = l;
+                },
+                Ok(Some(Status::SetPlaybackIdleTimeout(t))) => {
+                    settings.playback_idle_timeout = t;
+
+                    if let Some(conn) = connection.as_mut() {
+                        conn.set_playback_idle_timeout(t);
+                    }
+                },
+                Ok(Some(Status::SetSpeakingIndicator(s))) => {
+                    settings.speaking_indicator = s;
+
+                    if let Some(conn) = connection.as_mut() {
+                        if let Err(why) = conn.set_speaking_indicator(s).await {
+                            warn!("[Voice] Error sending speaking update: {:?}", why);
+                        }
+                    }
+                },
+                Ok(Some(Status::SetRtpPayloadType(p))) => {
+                    settings.rtp_payload_type = p;
+
+                    if let Some(conn) = connection.as_mut() {
+                        conn.set_rtp_payload_type(p);
+                    }
+                },
+                Ok(Some(Status::SetRtpHeaderExtension(e))) => {
+                    settings.rtp_header_extension = e;
+
+                    if let Some(conn) = connection.as_mut() {
+                        conn.set_rtp_header_extension(e);
+                    }
+                },
+                Ok(Some(Status::SetEncryptionMode(m))) => {
+                    // The mode is agreed during the handshake, so can only
+                    // change with the next connection.
+                    settings.encryption_mode = m;
+                },
+                Ok(Some(Status::SetEncryptionBackend(b))) => {
+                    settings.encryption_backend = b;
+                },
+                Ok(Some(Status::SetCloseCode(c))) => {
+                    settings.close_code = c;
+                },
+                Ok(Some(Status::SetVolumeRamp(r))) => {
+                    settings.volume_ramp = r;
+
+                    if let Some(conn) = connection.as_mut() {
+                        conn.set_volume_ramp(r);
+                    }
+                },
+                Ok(Some(Status::SetPacingMode(m))) => {
+                    settings.pacing_mode = m;
+
+                    if let Some(conn) = connection.as_mut() {
+                        conn.set_pacing_mode(m);
+                    }
+                },
+                Ok(Some(Status::SetBusVolume(b, v))) => {
+                    if let Some(conn) = connection.as_mut() {
+                        conn.set_bus_volume(b.clone(), v);
+                    }
+
+                    settings.bus_volumes.insert(b, v);
+                },
+                Ok(Some(Status::StopBus(b))) => {
+                    let mut i = 0;
+
+                    while i < senders.len() {
+                        let aud_lock = Arc::clone(&senders[i]);
+                        let mut aud = aud_lock.lock().await;
+
+                        if aud.bus.as_ref() == Some(&b) {
+                            shared.end_track(&mut aud, EndReason::Stopped);
+                            senders.remove(i);
+                        } else {
+                            i += 1;
+                        }
+                    }
+                },
+                Ok(Some(Status::SetCatchSourcePanics(c))) => {
+                    settings.catch_source_panics = c;
+
+                    if let Some(conn) = connection.as_mut() {
+                        conn.set_catch_source_panics(c);
+                    }
+                },
+                Ok(Some(Status::SetOutputTee(t))) => {
+                    tee = t;
+                },
+                Ok(Some(Status::SetPacketObserver(o))) => {
+                    observer = o;
                 },
                 Ok(Some(Status::Mute(m))) => {
                     mute = m;
@@ -73,46 +373,535 @@ async fn runner(rx: &mut Receiver<Status>) {
             }
         }
 
+        if let Some(next) = shared.queue.poll() {
+            senders.push(next);
+        }
+
+        shared.queue.update_presence().await;
+
+        // Frames are paced off a monotonic clock, but a slow source or a
+        // stalled host can leave the loop behind it. A short lag is made up
+        // by sending frames back to back, while a long one is skipped over.
+        let drift = timer.lateness();
+
+        let skipped = if drift > MAX_DRIFT {
+            let skipped = timer.skip_missed();
+
+            if let Some(connection) = connection.as_mut() {
+                warn!("[Voice] Send loop is {:?} behind, skipping {} frames.", drift, skipped);
+
+                connection.skip_frames(skipped);
+            }
+
+            u64::from(skipped)
+        } else {
+            0
+        };
+
+        if connection.is_some() {
+            shared.update_stats(|stats| {
+                stats.drift = drift;
+                stats.frames_skipped += skipped;
+
+                if let Some(rate) = packet_rate.sample(Instant::now(), stats.packets_sent) {
+                    stats.packets_per_second = rate;
+                }
+            });
+        }
+
         // Overall here, check if there's an error.
         //
         // If there is a connection, try to send an update. This should not
-        // error. If there is though for some spurious reason, then set `error`
-        // to `true`.
+        // error. If there is though for some spurious reason, then keep the
+        // error to decide how to recover from it.
         //
         // Otherwise, wait out the timer and do _not_ error and wait to receive
         // another event.
         let error = match connection.as_mut() {
             Some(connection) => {
                 let cycle = connection
-                    .cycle(&mut senders, &mut receiver, &mut timer, bitrate, mute).await;
+                    .cycle(&mut senders, &mut receive, &mut timer, &mut tee, &observer, mute).await;
 
                 match cycle {
-                    Ok(()) => false,
+                    Ok(()) => None,
                     Err(why) => {
                         error!(
                             "[Voice] Error updating connection: {:?}",
                             why
                         );
 
-                        true
+                        Some(why)
                     },
                 }
             },
             None => {
                 timer.hold().await;
 
-                false
+                None
             },
         };
 
-        // If there was an error, then just reset the connection and try to get
-        // another.
-        if error {
-            let mut conn = connection.expect("[Voice] Shouldn't have had a voice connection error without a connection.");
-            connection = conn.reconnect()
-                .await
-                .ok()
-                .map(|_| conn);
+        if let Some(why) = error {
+            let conn = connection.expect("[Voice] Shouldn't have had a voice connection error without a connection.");
+
+            connection = recover(conn, why, &settings, shared).await;
+
+            if connection.is_none() {
+                shared.set_connection_state(ConnectionState::Disconnected);
+            }
+        }
+    }
+
+    end_all(&mut senders, EndReason::Stopped, shared).await;
+}
+
+/// Settings which outlive any one connection, applied to each new one.
+struct Settings {
+    adaptive_bitrate: Vec<Bitrate>,
+    adaptive_channels: bool,
+    adaptive_complexity: bool,
+    bus_volumes: HashMap<String, f32>,
+    catch_source_panics: bool,
+    close_code: u16,
+    connect_timeout: Duration,
+    encryption_mode: EncryptionMode,
+    encryption_backend: Option<EncryptionBackendFactory>,
+    heartbeat_timeout: Option<u32>,
+    limiter: Option<LimiterConfig>,
+    opus: OpusConfig,
+    pacing_mode: PacingMode,
+    playback_idle_timeout: Option<Duration>,
+    rtp_header_extension: bool,
+    rtp_payload_type: u8,
+    speaking_indicator: bool,
+    volume_ramp: Duration,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            adaptive_bitrate: Vec::new(),
+            adaptive_channels: false,
+            adaptive_complexity: false,
+            bus_volumes: HashMap::new(),
+            catch_source_panics: false,
+            close_code: DEFAULT_CLOSE_CODE,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            encryption_mode: EncryptionMode::default(),
+            encryption_backend: None,
+            limiter: None,
+            opus: OpusConfig::default(),
+            pacing_mode: PacingMode::default(),
+            heartbeat_timeout: Some(DEFAULT_HEARTBEAT_TIMEOUT),
+            playback_idle_timeout: None,
+            rtp_header_extension: false,
+            rtp_payload_type: DEFAULT_RTP_PAYLOAD_TYPE,
+            speaking_indicator: true,
+            volume_ramp: DEFAULT_VOLUME_RAMP,
+        }
+    }
+}
+
+/// How a connection can be restored after it errors, judged by the code the
+/// voice gateway closed it with, if any.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Recovery {
+    /// The session can be resumed, or failing that, connected to afresh.
+    Resume,
+    /// The session was rejected, so a new one is needed from the gateway.
+    ///
+    /// The close code is given.
+    Reidentify(u16),
+    /// No connection to the voice server can succeed.
+    ///
+    /// The close code is given.
+    Fatal(u16),
+}
+
+fn recovery(why: &Error) -> Recovery {
+    let code = match why {
+        Error::Gateway(GatewayError::Closed(Some(frame))) => u16::from(frame.code),
+        _ => return Recovery::Resume,
+    };
+
+    match code {
+        voice_close_codes::AUTHENTICATION_FAILED
+        | voice_close_codes::SESSION_NO_LONGER_VALID
+        | voice_close_codes::SESSION_TIMEOUT
+        | voice_close_codes::SERVER_NOT_FOUND
+        | voice_close_codes::DISCONNECTED => Recovery::Reidentify(code),
+        voice_close_codes::UNKNOWN_PROTOCOL
+        | voice_close_codes::UNKNOWN_ENCRYPTION_MODE => Recovery::Fatal(code),
+        _ => Recovery::Resume,
+    }
+}
+
+/// Tries to resume a connection which errored, falling back to a fresh
+/// connection if Discord rejects the resume. Neither is tried if the voice
+/// gateway closed the connection for good, and the rejected session is
+/// marked so that the handler will not use it again.
+async fn recover(
+    mut conn: Connection,
+    why: Error,
+    settings: &Settings,
+    shared: &Arc<SharedState>,
+) -> Option<Connection> {
+    let why = match recovery(&why) {
+        Recovery::Resume => match conn.reconnect().await {
+            Ok(()) => {
+                shared.emit(HandlerEvent::Resumed);
+
+                return Some(conn);
+            },
+            Err(why) => why,
+        },
+        _ => why,
+    };
+
+    match recovery(&why) {
+        Recovery::Resume => {
+            info!("[Voice] Resume failed, reconnecting: {:?}", why);
+
+            let info = conn.connection_info().clone();
+            drop(conn);
+
+            match connect(info, settings, shared).await {
+                Ok(conn) => {
+                    shared.emit(HandlerEvent::Reconnected);
+
+                    Some(conn)
+                },
+                Err(why) => {
+                    warn!("[Voice] Error reconnecting: {:?}", why);
+
+                    None
+                },
+            }
+        },
+        Recovery::Reidentify(code) => {
+            warn!("[Voice] Session invalidated with code {}, awaiting a new one.", code);
+
+            shared.invalidate_session();
+            shared.emit(HandlerEvent::SessionInvalidated { code });
+
+            None
+        },
+        Recovery::Fatal(code) => {
+            error!("[Voice] Connection closed with code {}, not reconnecting.", code);
+
+            None
+        },
+    }
+}
+
+/// Makes a new voice connection, applying the configured settings.
+async fn connect(info: ConnectionInfo, settings: &Settings, shared: &Arc<SharedState>) -> Result<Connection> {
+    let mut connection = Connection::new(
+        info,
+        settings.encryption_mode,
+        settings.encryption_backend.as_ref(),
+        Arc::clone(shared),
+    ).await?;
+    shared.emit(HandlerEvent::KeyNegotiated { at: SystemTime::now() });
+
+    connection.set_opus_config(settings.opus.clone())?;
+    connection.set_adaptive_complexity(settings.adaptive_complexity)?;
+    connection.set_adaptive_bitrate(settings.adaptive_bitrate.clone())?;
+    connection.set_adaptive_channels(settings.adaptive_channels);
+    connection.set_heartbeat_timeout(settings.heartbeat_timeout);
+    connection.set_volume_ramp(settings.volume_ramp);
+    connection.set_pacing_mode(settings.pacing_mode);
+    connection.set_catch_source_panics(settings.catch_source_panics);
+
+    for (bus, &volume) in &settings.bus_volumes {
+        connection.set_bus_volume(bus.clone(), volume);
+    }
+    connection.set_limiter(settings.limiter.as_ref());
+    connection.set_playback_idle_timeout(settings.playback_idle_timeout);
+    connection.set_rtp_payload_type(settings.rtp_payload_type);
+    connection.set_rtp_header_extension(settings.rtp_header_extension);
+    connection.set_speaking_indicator(settings.speaking_indicator).await?;
+
+    if let Some(session) = shared.session_info() {
+        info!("[Voice] Negotiated session: {:?}", session);
+    }
+
+    Ok(connection)
+}
+
+/// Replaces the connection with the one made by `connect`.
+///
+/// The old connection is closed before the new one is made, so that audio is
+/// never sent from two SSRCs at once.
+async fn reconnect(
+    connection: &mut Option<Connection>,
+    connect: impl Future<Output = Result<Connection>>,
+    settings: &Settings,
+    shared: &SharedState,
+) {
+    if let Some(mut conn) = connection.take() {
+        conn.close(settings.close_code).await;
+    }
+
+    let connected = timeout(settings.connect_timeout, connect)
+        .await
+        .unwrap_or(Err(Error::Voice(VoiceError::Timeout)));
+
+    *connection = match connected {
+        Ok(connection) => {
+            shared.set_connection_state(ConnectionState::Connected);
+
+            Some(connection)
+        },
+        Err(why) => {
+            warn!("[Voice] Error connecting: {:?}", why);
+            shared.set_connection_state(ConnectionState::Disconnected);
+
+            None
+        },
+    };
+}
+
+/// Rebuilds the connection's encoder with every setting of `config` at once.
+///
+/// If the encoder cannot be rebuilt, the connection keeps its old one, and
+/// the settings the handler reports are put back to match it.
+fn reconfigure_encoder(connection: Option<&mut Connection>, config: OpusConfig, settings: &mut Settings, shared: &SharedState) {
+    if let Some(conn) = connection {
+        if let Err(why) = conn.reconfigure_encoder(config.clone()) {
+            warn!("[Voice] Error rebuilding Opus encoder: {:?}", why);
+            shared.update_opus_config(|opus| *opus = settings.opus.clone());
+
+            return;
+        }
+    }
+
+    settings.opus = config;
+}
+
+/// Closes the connection, if there is one, with the configured close code.
+async fn disconnect(connection: &mut Option<Connection>, settings: &Settings, shared: &SharedState) {
+    if let Some(mut conn) = connection.take() {
+        conn.close(settings.close_code).await;
+    }
+
+    shared.set_connection_state(ConnectionState::Disconnected);
+}
+
+/// Removes all sources from the mixer, marking each as ended.
+async fn end_all(senders: &mut Vec<LockedAudio>, reason: EndReason, shared: &SharedState) {
+    for sender in senders.drain(..) {
+        shared.end_track(&mut *sender.lock().await, reason);
+    }
+}
+
+/// Fades out all sources in the mixer while fading in `sender` over the given
+/// duration.
+///
+/// Paused sources are not stepped by the mixer, so could never finish fading
+/// out; they are instead ended straight away.
+async fn fade_to(senders: &mut Vec<LockedAudio>, sender: LockedAudio, duration: Duration, shared: &SharedState) {
+    let mut i = 0;
+
+    while i < senders.len() {
+        let mut aud = senders[i].lock().await;
+
+        if aud.playing {
+            aud.fade_out(duration);
+            i += 1;
+        } else {
+            shared.end_track(&mut aud, EndReason::Stopped);
+            drop(aud);
+            senders.remove(i);
+        }
+    }
+
+    sender.lock().await.fade_in(duration);
+    senders.push(sender);
+}
+
+#[cfg(test)]
+mod test {
+    use crate::model::id::GuildId;
+    use futures::channel::mpsc;
+    use std::{io::Cursor, sync::Arc};
+    use super::*;
+    use super::super::{connection::test::{local_connection, LocalServer}, Audio, TrackStatus};
+    use tokio::sync::Mutex;
+
+    fn audio() -> LockedAudio {
+        Arc::new(Mutex::new(Audio::new(crate::voice::pcm(true, Cursor::new(Vec::new())))))
+    }
+
+    #[tokio::test]
+    async fn test_fade_to_ends_paused_senders() {
+        let (tx, _rx) = mpsc::unbounded();
+        let shared = SharedState::new(GuildId(1), tx);
+
+        let playing = audio();
+        let paused = audio();
+        paused.lock().await.pause();
+        let mut senders = vec![playing.clone(), paused.clone()];
+
+        let incoming = audio();
+        fade_to(&mut senders, incoming.clone(), Duration::from_millis(100), &shared).await;
+
+        assert_eq!(senders.len(), 2);
+        assert!(Arc::ptr_eq(&senders[0], &playing));
+        assert!(Arc::ptr_eq(&senders[1], &incoming));
+        assert_eq!(*paused.lock().await.subscribe().borrow(), TrackStatus::Ended(EndReason::Stopped));
+
+        // The playing source stops once its fade has run its course.
+        let mut playing = playing.lock().await;
+        assert_eq!(playing.fade_gain(), 1.0);
+        assert!((0..5).any(|_| playing.step_fade()));
+    }
+
+    fn closed(code: u16) -> Error {
+        use async_tungstenite::tungstenite::protocol::CloseFrame;
+
+        Error::Gateway(GatewayError::Closed(Some(CloseFrame {
+            code: code.into(),
+            reason: "".into(),
+        })))
+    }
+
+    #[test]
+    fn test_recovery() {
+        assert_eq!(recovery(&closed(4006)), Recovery::Reidentify(4006));
+        assert_eq!(recovery(&closed(4014)), Recovery::Reidentify(4014));
+        assert_eq!(recovery(&closed(4016)), Recovery::Fatal(4016));
+
+        // Other codes, and failures without one, are worth resuming after.
+        assert_eq!(recovery(&closed(1000)), Recovery::Resume);
+        assert_eq!(recovery(&closed(4015)), Recovery::Resume);
+        assert_eq!(recovery(&Error::Gateway(GatewayError::Closed(None))), Recovery::Resume);
+        assert_eq!(recovery(&Error::Other("lost")), Recovery::Resume);
+    }
+
+    #[tokio::test]
+    async fn test_disconnect_sends_close() {
+        use async_tungstenite::tungstenite::Message;
+        use futures::StreamExt;
+
+        let (tx, _rx) = mpsc::unbounded();
+        let shared = SharedState::new(GuildId(1), tx);
+        shared.set_connection_state(ConnectionState::Connected);
+        let mut settings = Settings::default();
+        settings.close_code = 4321;
+
+        let (conn, mut server) = local_connection().await;
+        let mut connection = Some(conn);
+        disconnect(&mut connection, &settings, &shared).await;
+
+        assert!(connection.is_none());
+        assert_eq!(shared.connection_state(), ConnectionState::Disconnected);
+        match server.ws.next().await {
+            Some(Ok(Message::Close(Some(frame)))) => assert_eq!(u16::from(frame.code), 4321),
+            other => panic!("expected a close frame, got {:?}", other),
         }
+
+        // Leaving again without a connection changes nothing.
+        disconnect(&mut connection, &settings, &shared).await;
+        assert_eq!(shared.connection_state(), ConnectionState::Disconnected);
+    }
+
+    /// Counts the packets which reach a stand-in voice server, until none
+    /// arrive for a while.
+    async fn packets_received(server: &mut LocalServer) -> usize {
+        let mut packet = [0u8; 2000];
+        let mut count = 0;
+
+        while let Ok(Ok(_)) = timeout(Duration::from_millis(60), server.udp.recv_from(&mut packet)).await {
+            count += 1;
+        }
+
+        count
+    }
+
+    #[tokio::test]
+    async fn test_reconnect_single_stream() {
+        use async_tungstenite::tungstenite::Message;
+        use futures::{future, StreamExt};
+        use super::super::pcm;
+
+        let (tx, _rx) = mpsc::unbounded();
+        let shared = SharedState::new(GuildId(1), tx);
+        let settings = Settings::default();
+        let mut senders = vec![Arc::new(Mutex::new(Audio::new(pcm(true, Cursor::new(vec![1u8; 3840 * 50])))))];
+        let mut receive = ReceiveState::default();
+        let mut timer = Timer::new(20);
+
+        let (old, mut old_server) = local_connection().await;
+        let (new, mut new_server) = local_connection().await;
+        let mut connection = Some(old);
+
+        for _ in 0..3 {
+            let conn = connection.as_mut().unwrap();
+            conn.cycle(&mut senders, &mut receive, &mut timer, &mut None, &None, false).await.unwrap();
+        }
+        assert_eq!(packets_received(&mut old_server).await, 3);
+
+        // Reconnecting mid-playback closes the old connection first.
+        reconnect(&mut connection, future::ok(new), &settings, &shared).await;
+        assert_eq!(shared.connection_state(), ConnectionState::Connected);
+
+        loop {
+            match old_server.ws.next().await {
+                Some(Ok(Message::Close(Some(frame)))) => {
+                    assert_eq!(u16::from(frame.code), DEFAULT_CLOSE_CODE);
+
+                    break;
+                },
+                Some(Ok(_)) => {},
+                other => panic!("expected a close frame, got {:?}", other),
+            }
+        }
+
+        for _ in 0..3 {
+            let conn = connection.as_mut().unwrap();
+            conn.cycle(&mut senders, &mut receive, &mut timer, &mut None, &None, false).await.unwrap();
+        }
+
+        // Playback carries on over the new connection alone.
+        assert_eq!(packets_received(&mut new_server).await, 3);
+        assert_eq!(packets_received(&mut old_server).await, 0);
+
+        // A failed reconnect leaves no connection sending at all.
+        reconnect(&mut connection, future::err(Error::Other("refused")), &settings, &shared).await;
+        assert!(connection.is_none());
+        assert_eq!(shared.connection_state(), ConnectionState::Disconnected);
+    }
+
+    #[tokio::test]
+    async fn test_reconfigure_encoder() {
+        let (tx, _rx) = mpsc::unbounded();
+        let shared = SharedState::new(GuildId(1), tx);
+        let mut settings = Settings::default();
+        let (mut conn, _server) = local_connection().await;
+
+        let mut config = OpusConfig::default();
+        config.complexity(5).inband_fec(true).dtx(true).bitrate(Bitrate::BitsPerSecond(96_000));
+        shared.update_opus_config(|opus| *opus = config.clone());
+        let rebuilds = conn.encoder_rebuilds();
+        reconfigure_encoder(Some(&mut conn), config.clone(), &mut settings, &shared);
+        assert_eq!(settings.opus.complexity, 5);
+
+        // Every setting changed at once takes a single rebuild.
+        assert_eq!(conn.encoder_rebuilds(), rebuilds + 1);
+
+        // The handler validates configurations before sending them, but one
+        // the encoder still refuses leaves every setting as it was.
+        config.complexity(11);
+        shared.update_opus_config(|opus| *opus = config.clone());
+        reconfigure_encoder(Some(&mut conn), config.clone(), &mut settings, &shared);
+        assert_eq!(settings.opus.complexity, 5);
+        assert_eq!(shared.opus_config().complexity, 5);
+        assert_eq!(conn.encoder_rebuilds(), rebuilds + 1);
+
+        // Without a connection, the configuration is kept for the next one.
+        config.complexity(7);
+        reconfigure_encoder(None, config, &mut settings, &shared);
+        assert_eq!(settings.opus.complexity, 7);
     }
 }
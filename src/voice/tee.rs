@@ -0,0 +1,240 @@
+use audiopus::{coder::Decoder as OpusDecoder, Channels};
+use byteorder::{ByteOrder, LittleEndian};
+use std::{
+    io::{Result as IoResult, Seek, SeekFrom, Write},
+    sync::mpsc::{self, Sender},
+    thread,
+};
+use super::audio::SAMPLE_RATE;
+use tracing::warn;
+
+/// The length of a canonical WAV header, in bytes.
+const WAV_HEADER_LEN: u32 = 44;
+
+/// A copy of a handler's outgoing audio, written to a sink on its own thread
+/// so that slow writes never hold up the voice task.
+pub(crate) struct OutputTee {
+    tx: Sender<Vec<u8>>,
+    /// Decodes frames passed through from Opus sources, created on first use.
+    decoder: Option<OpusDecoder>,
+}
+
+impl OutputTee {
+    pub(crate) fn new(mut sink: Box<dyn Write + Send>) -> Self {
+        let (tx, rx) = mpsc::channel::<Vec<u8>>();
+
+        thread::spawn(move || {
+            for frame in rx {
+                if let Err(why) = sink.write_all(&frame) {
+                    warn!("[Voice] Failed to write to output tee: {:?}", why);
+
+                    return;
+                }
+            }
+
+            if let Err(why) = sink.flush() {
+                warn!("[Voice] Failed to flush output tee: {:?}", why);
+            }
+        });
+
+        Self { tx, decoder: None }
+    }
+
+    /// Queues a mixed frame to be written as 16-bit little-endian PCM,
+    /// returning `false` if the sink has failed.
+    pub(crate) fn write(&self, frame: &[f32]) -> bool {
+        let mut bytes = vec![0u8; frame.len() * 2];
+
+        for (chunk, sample) in bytes.chunks_exact_mut(2).zip(frame) {
+            // Samples beyond full scale are clipped to the range of `i16`.
+            let value = (sample * f32::from(i16::MAX))
+                .max(f32::from(i16::MIN))
+                .min(f32::from(i16::MAX)) as i16;
+
+            LittleEndian::write_i16(chunk, value);
+        }
+
+        self.tx.send(bytes).is_ok()
+    }
+
+    /// Decodes an Opus frame, such as one passed through from an Opus source,
+    /// and queues it as a frame of `samples` samples, returning `false` if the
+    /// sink has failed.
+    ///
+    /// A frame which cannot be decoded is written as silence.
+    pub(crate) fn write_opus(&mut self, frame: &[u8], samples: usize) -> bool {
+        let decoder = match self.decoder.as_mut() {
+            Some(decoder) => decoder,
+            None => match OpusDecoder::new(SAMPLE_RATE, Channels::Stereo) {
+                Ok(decoder) => self.decoder.get_or_insert(decoder),
+                Err(why) => {
+                    warn!("[Voice] Failed to create output tee decoder: {:?}", why);
+
+                    return self.write_silence(samples);
+                },
+            },
+        };
+
+        let mut pcm = vec![0f32; samples];
+
+        if let Err(why) = decoder.decode_float(Some(frame), &mut pcm[..], false) {
+            warn!("[Voice] Failed to decode frame for output tee: {:?}", why);
+
+            return self.write_silence(samples);
+        }
+
+        self.write(&pcm)
+    }
+
+    /// Queues a frame of silence, returning `false` if the sink has failed.
+    pub(crate) fn write_silence(&self, samples: usize) -> bool {
+        self.tx.send(vec![0u8; samples * 2]).is_ok()
+    }
+}
+
+/// A writer which wraps raw audio from [`Handler::set_output_tee`] in a WAV
+/// container.
+///
+/// The header is written on creation, and its length fields are updated
+/// whenever the writer is flushed or dropped.
+///
+/// # Examples
+///
+/// Record everything sent by a handler to a file:
+///
+/// ```rust,ignore
+/// use serenity::voice::WavWriter;
+/// use std::fs::File;
+///
+/// let file = File::create("./outgoing.wav")?;
+/// handler.set_output_tee(Some(WavWriter::new(file)?));
+/// ```
+///
+/// [`Handler::set_output_tee`]: struct.Handler.html#method.set_output_tee
+pub struct WavWriter<W: Write + Seek> {
+    inner: W,
+    data_len: u32,
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+    /// Writes a header for 48kHz, 16-bit stereo audio, matching the output of
+    /// a handler.
+    pub fn new(mut inner: W) -> IoResult<Self> {
+        let mut header = [0u8; WAV_HEADER_LEN as usize];
+
+        header[0..4].copy_from_slice(b"RIFF");
+        LittleEndian::write_u32(&mut header[4..8], WAV_HEADER_LEN - 8);
+        header[8..12].copy_from_slice(b"WAVE");
+        header[12..16].copy_from_slice(b"fmt ");
+        LittleEndian::write_u32(&mut header[16..20], 16);
+        // PCM, 2 channels, 48kHz, 192000 bytes/s, 4 bytes/frame, 16 bits.
+        LittleEndian::write_u16(&mut header[20..22], 1);
+        LittleEndian::write_u16(&mut header[22..24], 2);
+        LittleEndian::write_u32(&mut header[24..28], 48_000);
+        LittleEndian::write_u32(&mut header[28..32], 48_000 * 4);
+        LittleEndian::write_u16(&mut header[32..34], 4);
+        LittleEndian::write_u16(&mut header[34..36], 16);
+        header[36..40].copy_from_slice(b"data");
+        LittleEndian::write_u32(&mut header[40..44], 0);
+
+        inner.write_all(&header)?;
+
+        Ok(Self {
+            inner,
+            data_len: 0,
+        })
+    }
+
+    fn update_header(&mut self) -> IoResult<()> {
+        let mut field = [0u8; 4];
+
+        self.inner.seek(SeekFrom::Start(4))?;
+        LittleEndian::write_u32(&mut field, self.data_len.saturating_add(WAV_HEADER_LEN - 8));
+        self.inner.write_all(&field)?;
+
+        self.inner.seek(SeekFrom::Start(40))?;
+        LittleEndian::write_u32(&mut field, self.data_len);
+        self.inner.write_all(&field)?;
+
+        self.inner.seek(SeekFrom::End(0))?;
+
+        Ok(())
+    }
+}
+
+impl<W: Write + Seek> Write for WavWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        let written = self.inner.write(buf)?;
+        self.data_len = self.data_len.saturating_add(written as u32);
+
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        self.update_header()?;
+
+        self.inner.flush()
+    }
+}
+
+impl<W: Write + Seek> Drop for WavWriter<W> {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use audiopus::{coder::Encoder, Application};
+    use std::{
+        io::{Result as IoResult, Write},
+        sync::{Arc, Mutex},
+        thread,
+        time::Duration,
+    };
+    use super::*;
+
+    #[derive(Clone, Default)]
+    struct SharedSink(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedSink {
+        fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> IoResult<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_write_opus_decodes_frame() {
+        let encoder = Encoder::new(SAMPLE_RATE, Channels::Stereo, Application::Audio).unwrap();
+        let tone = (0..1920).map(|i| ((i / 2) as f32 / 10.0).sin() * 0.5).collect::<Vec<_>>();
+        let mut frame = vec![0u8; 4000];
+        // Opus needs a few frames to settle, so only the last is checked.
+        let mut len = 0;
+        for _ in 0..3 {
+            len = encoder.encode_float(&tone, &mut frame).unwrap();
+        }
+
+        let sink = SharedSink::default();
+        let mut tee = OutputTee::new(Box::new(sink.clone()));
+        assert!(tee.write_opus(&frame[..len], tone.len()));
+        drop(tee);
+
+        let mut written = Vec::new();
+        for _ in 0..100 {
+            written = sink.0.lock().unwrap().clone();
+            if written.len() == tone.len() * 2 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        assert_eq!(written.len(), tone.len() * 2);
+        assert!(written.iter().any(|&byte| byte != 0));
+    }
+}
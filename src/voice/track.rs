@@ -0,0 +1,540 @@
+use crate::model::id::UserId;
+use futures::channel::mpsc::UnboundedSender as Sender;
+use std::{sync::Arc, time::Duration};
+use tokio::sync::{watch, Mutex};
+use super::{
+    audio::{self, Audio, EndReason, ErrorHandler, LockedAudio, TrackStatus},
+    events::EventSink,
+    state::SharedState,
+    Bitrate,
+    ConnectionState,
+    Status,
+    EqBand,
+    HandlerEvent,
+    VoiceError,
+};
+
+/// A handle to an [`Audio`] source which has been passed to a [`Handler`].
+///
+/// This is cheap to clone, and gives access to both the [`LockedAudio`] used
+/// to control playback and the status of the track as seen by the voice task.
+///
+/// [`Audio`]: struct.Audio.html
+/// [`Handler`]: struct.Handler.html
+/// [`LockedAudio`]: type.LockedAudio.html
+#[derive(Clone)]
+pub struct TrackHandle {
+    audio: LockedAudio,
+    status: watch::Receiver<TrackStatus>,
+    connection: watch::Receiver<ConnectionState>,
+    /// The listeners for events of the handler which played the track.
+    events: EventSink,
+    id: u64,
+    requester: Option<UserId>,
+    /// The task of the handler which played the track, to which it is sent
+    /// again when restarted.
+    task: Option<(Sender<Status>, Arc<SharedState>)>,
+}
+
+impl TrackHandle {
+    pub(crate) fn new(audio: Audio, connection: watch::Receiver<ConnectionState>, events: EventSink) -> Self {
+        let status = audio.subscribe();
+        let id = audio.id();
+        let requester = audio.requester;
+
+        Self {
+            audio: Arc::new(Mutex::new(audio)),
+            status,
+            connection,
+            events,
+            id,
+            requester,
+            task: None,
+        }
+    }
+
+    /// Lets the track be restarted through the given handler's task after it
+    /// has ended.
+    pub(crate) fn with_task(mut self, sender: Sender<Status>, shared: Arc<SharedState>) -> Self {
+        self.task = Some((sender, shared));
+
+        self
+    }
+
+    /// Returns the locked audio source, used to control playback.
+    pub fn audio(&self) -> &LockedAudio {
+        &self.audio
+    }
+
+    /// Returns the id of the track, as given by [`Audio::id`].
+    ///
+    /// [`Audio::id`]: struct.Audio.html#method.id
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Returns the user who queued this track, as given in its
+    /// [`TrackMetadata`], if any.
+    ///
+    /// [`TrackMetadata`]: struct.TrackMetadata.html
+    pub fn requester(&self) -> Option<UserId> {
+        self.requester
+    }
+
+    /// Returns the last known status of this track.
+    pub fn status(&self) -> TrackStatus {
+        *self.status.borrow()
+    }
+
+    /// Repeats a region of the track, given as its start and end positions,
+    /// until this is called again with `None`.
+    ///
+    /// Refer to [`Audio::set_loop_region`] for details.
+    ///
+    /// [`Audio::set_loop_region`]: struct.Audio.html#method.set_loop_region
+    pub async fn set_loop_region(&self, region: Option<(Duration, Duration)>) -> Result<(), VoiceError> {
+        self.audio.lock().await.set_loop_region(region)
+    }
+
+    /// Moves playback to the given position, clamped to the end of the track
+    /// if its duration is known, returning the position moved to.
+    ///
+    /// Refer to [`Audio::position`] for details.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`VoiceError::NotSeekable`] if the source cannot seek.
+    ///
+    /// [`Audio::position`]: struct.Audio.html#method.position
+    /// [`VoiceError::NotSeekable`]: enum.VoiceError.html#variant.NotSeekable
+    pub async fn seek(&self, position: Duration) -> Result<Duration, VoiceError> {
+        let mut audio = self.audio.lock().await;
+        let target = clamp_position(position, audio.source.total_duration());
+
+        seek_to(&mut audio, target)
+    }
+
+    /// Moves playback forwards, or backwards if `delta` is negative, by the
+    /// given number of milliseconds from the current position, such as for
+    /// "forward 10" and "rewind 10" commands. Returns the position moved to.
+    ///
+    /// The position never moves before the start of the track, nor past its
+    /// end if its duration is known.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`VoiceError::NotSeekable`] if the source cannot seek.
+    ///
+    /// [`VoiceError::NotSeekable`]: enum.VoiceError.html#variant.NotSeekable
+    pub async fn seek_relative(&self, delta: i64) -> Result<Duration, VoiceError> {
+        let mut audio = self.audio.lock().await;
+        let target = relative_position(audio.position, delta, audio.source.total_duration());
+
+        seek_to(&mut audio, target)
+    }
+
+    /// Moves playback to the given fraction of the way through the track,
+    /// from `0.0` for its start to `1.0` for its end, such as for a click on
+    /// a progress bar. Returns the position moved to.
+    ///
+    /// The fraction is clamped to that range.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`VoiceError::UnknownDuration`] if the track's
+    /// [`total_duration`] is unknown, and [`VoiceError::NotSeekable`] if the
+    /// source cannot seek.
+    ///
+    /// [`total_duration`]: trait.AudioSource.html#method.total_duration
+    /// [`VoiceError::NotSeekable`]: enum.VoiceError.html#variant.NotSeekable
+    /// [`VoiceError::UnknownDuration`]: enum.VoiceError.html#variant.UnknownDuration
+    pub async fn seek_percent(&self, fraction: f32) -> Result<Duration, VoiceError> {
+        let mut audio = self.audio.lock().await;
+        let total = audio.source.total_duration().ok_or(VoiceError::UnknownDuration)?;
+
+        seek_to(&mut audio, fraction_of(total, fraction))
+    }
+
+    /// Finds the total duration of the track's source again, returning it
+    /// once known, such as for a live stream which has since become a
+    /// recording of fixed length.
+    ///
+    /// If the duration changes, the source's [`total_duration`] is updated,
+    /// and a [`HandlerEvent::DurationUpdated`] is emitted. For sources which
+    /// cannot find their duration again, only those from [`ffmpeg`] being
+    /// able to, this returns the current duration and does nothing else, as
+    /// it does if the duration cannot be found again.
+    ///
+    /// The track keeps playing meanwhile, as its source is only locked to
+    /// begin and end the search.
+    ///
+    /// [`ffmpeg`]: fn.ffmpeg.html
+    /// [`HandlerEvent::DurationUpdated`]: enum.HandlerEvent.html#variant.DurationUpdated
+    /// [`total_duration`]: trait.AudioSource.html#method.total_duration
+    pub async fn refresh_duration(&self) -> Option<Duration> {
+        let (before, refresh) = {
+            let audio = self.audio.lock().await;
+
+            (audio.source.total_duration(), audio.source.refresh_duration())
+        };
+
+        let after = match refresh {
+            Some(refresh) => refresh.await,
+            None => return before,
+        };
+
+        if after != before {
+            self.events.emit(HandlerEvent::DurationUpdated {
+                track_id: self.id,
+                duration: after,
+            });
+        }
+
+        after
+    }
+
+    /// Silences or restores the track while it keeps playing, such as to
+    /// hush one layer of several mixed together, without stopping it.
+    ///
+    /// Refer to [`Audio::mute`] for details.
+    ///
+    /// [`Audio::mute`]: struct.Audio.html#method.mute
+    pub async fn set_muted(&self, muted: bool) {
+        self.audio.lock().await.mute(muted);
+    }
+
+    /// Groups the track into a named bus, or removes it from its bus if
+    /// `None`, such as right after queueing it, so that it can be controlled
+    /// along with the rest of the bus.
+    ///
+    /// Refer to [`Audio::bus`] for details.
+    ///
+    /// [`Audio::bus`]: struct.Audio.html#method.bus
+    pub async fn set_bus(&self, bus: Option<&str>) {
+        self.audio.lock().await.bus(bus);
+    }
+
+    /// The bus the track is grouped into, if any.
+    pub async fn bus(&self) -> Option<String> {
+        self.audio.lock().await.bus.clone()
+    }
+
+    /// Asks for the handler's audio to be encoded at the given bitrate while
+    /// this track plays, such as for a high quality segment, in place of the
+    /// bitrate set via [`Handler::set_bitrate`], which is restored once the
+    /// track stops playing.
+    ///
+    /// This takes effect from the next packet, through the same path as
+    /// [`Handler::set_bitrate`]. The encoder has one bitrate for the whole
+    /// mix, so this is best used while the track plays alone: if several
+    /// tracks which have asked for bitrates are mixed, the one played first
+    /// is used, and the bitrate of tracks mixed alongside it is raised too.
+    /// While [adaptive bitrate] is enabled, its bitrate is used instead.
+    ///
+    /// Values are clamped as with [`Handler::set_bitrate`].
+    ///
+    /// # Errors
+    ///
+    /// Returns [`VoiceError::InvalidBitrate`] if the bitrate is not positive.
+    ///
+    /// [adaptive bitrate]: struct.Handler.html#method.set_adaptive_bitrate
+    /// [`Handler::set_bitrate`]: struct.Handler.html#method.set_bitrate
+    /// [`VoiceError::InvalidBitrate`]: enum.VoiceError.html#variant.InvalidBitrate
+    pub async fn request_bitrate(&self, bitrate: Bitrate) -> Result<(), VoiceError> {
+        let bitrate = audio::clamp_bitrate(bitrate)?;
+        self.audio.lock().await.bitrate = Some(bitrate);
+
+        Ok(())
+    }
+
+    /// Withdraws a bitrate asked for via [`request_bitrate`], restoring the
+    /// handler's own from the next packet.
+    ///
+    /// [`request_bitrate`]: #method.request_bitrate
+    pub async fn clear_bitrate_request(&self) {
+        self.audio.lock().await.bitrate = None;
+    }
+
+    /// Sets the bands of the track's equalizer, or removes it if there are
+    /// none.
+    ///
+    /// Refer to [`Audio::set_equalizer`] for details.
+    ///
+    /// [`Audio::set_equalizer`]: struct.Audio.html#method.set_equalizer
+    pub async fn set_equalizer(&self, bands: &[EqBand]) -> Result<(), VoiceError> {
+        self.audio.lock().await.set_equalizer(bands)
+    }
+
+    /// Sets what happens when the track's source fails to read, such as when
+    /// a stream loses its network connection.
+    ///
+    /// Refer to [`Audio::on_error`] for details.
+    ///
+    /// [`Audio::on_error`]: struct.Audio.html#method.on_error
+    pub async fn on_error(&self, handler: ErrorHandler) {
+        self.audio.lock().await.on_error(handler);
+    }
+
+    /// Rewinds the track to its start and resumes it, reusing this handle,
+    /// such as for a soundboard which plays the same sound again and again.
+    ///
+    /// A track which is still playing is rewound in place. One which has
+    /// ended is added back to its [`Handler`], alongside any other sources,
+    /// and reports its status afresh, ending again as normal.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`VoiceError::NotSeekable`] if the source cannot seek back to
+    /// its start. Returns [`VoiceError::TrackEnded`] if the track has ended
+    /// and cannot be added back, as for tracks of a [`TrackQueue`], which
+    /// should be queued again instead, or if the handler's task has stopped.
+    ///
+    /// [`Handler`]: struct.Handler.html
+    /// [`TrackQueue`]: struct.TrackQueue.html
+    /// [`VoiceError::NotSeekable`]: enum.VoiceError.html#variant.NotSeekable
+    /// [`VoiceError::TrackEnded`]: enum.VoiceError.html#variant.TrackEnded
+    pub async fn restart(&self) -> Result<(), VoiceError> {
+        let mut audio = self.audio.lock().await;
+
+        if !audio.source.is_seekable() {
+            return Err(VoiceError::NotSeekable);
+        }
+
+        if !matches!(self.status(), TrackStatus::Ended(_)) {
+            audio.restart();
+
+            return Ok(());
+        }
+
+        self.start()?;
+        audio.restart();
+
+        Ok(())
+    }
+
+    /// Passes the track to the task of the handler which created it, to be
+    /// played alongside any other sources.
+    pub(crate) fn start(&self) -> Result<(), VoiceError> {
+        let (sender, shared) = self.task.as_ref().ok_or(VoiceError::TrackEnded)?;
+
+        shared.message_sent();
+
+        if sender.unbounded_send(Status::AddSender(Arc::clone(&self.audio))).is_err() {
+            shared.message_received();
+
+            return Err(VoiceError::TrackEnded);
+        }
+
+        Ok(())
+    }
+
+    /// Waits until the track has stopped being played, resolving with the
+    /// reason why.
+    ///
+    /// This resolves with [`VoiceError::NotConnected`] if the [`Handler`]
+    /// which played the track is neither connected nor connecting, or its
+    /// task is gone, as the track would then never end.
+    ///
+    /// [`Handler`]: struct.Handler.html
+    /// [`VoiceError::NotConnected`]: enum.VoiceError.html#variant.NotConnected
+    pub async fn ended(&self) -> Result<EndReason, VoiceError> {
+        let mut status = self.status.clone();
+        let mut connection = self.connection.clone();
+
+        loop {
+            if let TrackStatus::Ended(reason) = *status.borrow() {
+                return Ok(reason);
+            }
+
+            if *connection.borrow() == ConnectionState::Disconnected {
+                return Err(VoiceError::NotConnected);
+            }
+
+            // The handle keeps the track's audio, and so the sender of its
+            // status, alive, so only the connection's channel can close.
+            tokio::select! {
+                _ = status.recv() => {},
+                update = connection.recv() => if update.is_none() {
+                    return Err(VoiceError::NotConnected);
+                },
+            }
+        }
+    }
+
+    /// Waits until the track has been sent over a live voice connection.
+    ///
+    /// This resolves with [`VoiceError::NotConnected`] if the [`Handler`] which
+    /// played the track is neither connected nor connecting, and with
+    /// [`VoiceError::TrackEnded`] if the track was stopped or finished before
+    /// any of it was played.
+    ///
+    /// [`Handler`]: struct.Handler.html
+    /// [`VoiceError::NotConnected`]: enum.VoiceError.html#variant.NotConnected
+    /// [`VoiceError::TrackEnded`]: enum.VoiceError.html#variant.TrackEnded
+    pub async fn await_playing(&self) -> Result<(), VoiceError> {
+        let mut status = self.status.clone();
+        let mut connection = self.connection.clone();
+
+        loop {
+            match *status.borrow() {
+                TrackStatus::Playing => return Ok(()),
+                TrackStatus::Ended(_) => return Err(VoiceError::TrackEnded),
+                TrackStatus::Pending => {},
+            }
+
+            if *connection.borrow() == ConnectionState::Disconnected {
+                return Err(VoiceError::NotConnected);
+            }
+
+            // Either channel closing means that the task is gone, and the
+            // track can no longer be played.
+            tokio::select! {
+                update = status.recv() => if update.is_none() {
+                    return Err(VoiceError::TrackEnded);
+                },
+                update = connection.recv() => if update.is_none() {
+                    return Err(VoiceError::NotConnected);
+                },
+            }
+        }
+    }
+}
+
+fn seek_to(audio: &mut Audio, position: Duration) -> Result<Duration, VoiceError> {
+    if !audio.source.is_seekable() {
+        return Err(VoiceError::NotSeekable);
+    }
+
+    audio.position(position);
+
+    Ok(position)
+}
+
+fn clamp_position(position: Duration, end: Option<Duration>) -> Duration {
+    match end {
+        Some(end) => position.min(end),
+        None => position,
+    }
+}
+
+/// Offsets a position by a number of milliseconds, without moving before the
+/// start or past the end, if known.
+fn relative_position(position: Duration, delta: i64, end: Option<Duration>) -> Duration {
+    // Negating `i64::MIN` wraps back to itself, which is still the right
+    // magnitude once cast.
+    let millis = if delta < 0 { delta.wrapping_neg() as u64 } else { delta as u64 };
+    let offset = Duration::from_millis(millis);
+    let moved = if delta < 0 {
+        position.checked_sub(offset).unwrap_or_default()
+    } else {
+        position + offset
+    };
+
+    clamp_position(moved, end)
+}
+
+/// The position the given fraction of the way through a track, clamping the
+/// fraction to between the start and end.
+fn fraction_of(total: Duration, fraction: f32) -> Duration {
+    if fraction.is_nan() {
+        return Duration::default();
+    }
+
+    total.mul_f64(f64::from(fraction.max(0.0).min(1.0)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::super::{AudioSource, AudioType};
+
+    #[test]
+    fn test_fraction_of() {
+        let secs = Duration::from_secs;
+
+        assert_eq!(fraction_of(secs(60), 0.5), secs(30));
+        assert_eq!(fraction_of(secs(60), 1.5), secs(60));
+        assert_eq!(fraction_of(secs(60), -1.0), secs(0));
+        assert_eq!(fraction_of(secs(60), f32::NAN), secs(0));
+    }
+
+    #[test]
+    fn test_relative_position() {
+        let secs = Duration::from_secs;
+
+        assert_eq!(relative_position(secs(30), 10_000, None), secs(40));
+        assert_eq!(relative_position(secs(30), -10_000, None), secs(20));
+        assert_eq!(relative_position(secs(5), -10_000, None), secs(0));
+        assert_eq!(relative_position(secs(55), 10_000, Some(secs(60))), secs(60));
+        assert_eq!(relative_position(secs(30), i64::MIN, Some(secs(60))), secs(0));
+    }
+
+    #[tokio::test]
+    async fn test_request_bitrate() {
+        use crate::model::id::GuildId;
+        use crate::voice::Handler;
+        use futures::channel::mpsc::unbounded;
+
+        let (tx, _rx) = unbounded();
+        let mut handler = Handler::with_task_sender(GuildId(1), None, UserId(2), tx);
+        let track = handler.play_returning(crate::voice::pcm(true, std::io::Cursor::new(Vec::new())));
+
+        track.request_bitrate(Bitrate::BitsPerSecond(1_000_000)).await.unwrap();
+        assert_eq!(track.audio().lock().await.bitrate, Some(Bitrate::BitsPerSecond(512_000)));
+        assert!(track.request_bitrate(Bitrate::BitsPerSecond(0)).await.is_err());
+
+        track.clear_bitrate_request().await;
+        assert_eq!(track.audio().lock().await.bitrate, None);
+    }
+
+    /// A live stream whose length becomes known when it is searched for
+    /// again.
+    struct Recording;
+
+    #[async_trait::async_trait]
+    impl AudioSource for Recording {
+        async fn is_stereo(&mut self) -> bool {
+            true
+        }
+
+        async fn get_type(&self) -> AudioType {
+            AudioType::Pcm
+        }
+
+        async fn read_pcm_frame(&mut self, _buffer: &mut [i16]) -> Option<usize> {
+            Some(0)
+        }
+
+        async fn read_opus_frame(&mut self) -> Option<Vec<u8>> {
+            None
+        }
+
+        async fn decode_and_add_opus_frame(&mut self, _float_buffer: &mut [f32; 1920], _volume: f32) -> Option<usize> {
+            None
+        }
+
+        fn refresh_duration(&self) -> Option<futures::future::BoxFuture<'static, Option<Duration>>> {
+            Some(Box::pin(async { Some(Duration::from_secs(60)) }))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_refresh_duration_event() {
+        let (_, connection) = watch::channel(ConnectionState::Connected);
+        let events = EventSink::default();
+        let mut rx = events.subscribe();
+        let track = TrackHandle::new(Audio::new(Box::new(Recording)), connection, events);
+        let other = TrackHandle::new(Audio::new(Box::new(Recording)), track.connection.clone(), track.events.clone());
+        assert_ne!(track.id(), other.id());
+
+        assert_eq!(track.refresh_duration().await, Some(Duration::from_secs(60)));
+
+        match rx.try_next() {
+            Ok(Some(HandlerEvent::DurationUpdated { track_id, duration })) => {
+                assert_eq!(track_id, track.id());
+                assert_eq!(duration, Some(Duration::from_secs(60)));
+            },
+            _ => panic!("expected a duration update"),
+        }
+    }
+}
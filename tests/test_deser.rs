@@ -178,6 +178,10 @@ fn typing_start() {
 fn voice_state_update() {
     p!(VoiceStateUpdateEvent, "voice_state_update_1");
     p!(VoiceStateUpdateEvent, "voice_state_update_2");
+
+    let event = p!(VoiceStateUpdateEvent, "voice_state_update_3");
+    assert!(event.voice_state.self_video);
+    assert_eq!(event.voice_state.self_stream, Some(true));
 }
 
 #[test]